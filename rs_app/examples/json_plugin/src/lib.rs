@@ -1,7 +1,6 @@
-use async_trait::async_trait;
 use distributed_transformer::{
     declare_plugin,
-    formats::{DataFormat, SchemaInference},
+    formats::{DataFormat, JsonConfig, JsonFormat},
     plugin::{FormatPlugin, PluginMetadata},
 };
 
@@ -9,55 +8,20 @@ use distributed_transformer::{
 pub struct JsonFormatPlugin;
 
 impl FormatPlugin for JsonFormatPlugin {
-    fn metadata(&self) -> &PluginMetadata {
-        static METADATA: PluginMetadata = PluginMetadata {
-            name: String::from("json"),
-            version: String::from("0.1.0"),
-            extensions: vec![String::from("json")],
-            description: String::from("JSON format plugin"),
-        };
-        &METADATA
+    fn metadata(&self) -> PluginMetadata {
+        PluginMetadata::new("json", "0.1.0", "JSON format plugin").with_extensions(["json", "ndjson", "jsonl"])
     }
-    
-    fn create_format(&self) -> Box<dyn DataFormat + Send + Sync> {
-        Box::new(JsonFormat::default())
-    }
-}
 
-#[derive(Default, Clone)]
-struct JsonFormat {
-    batch_size: usize,
-}
-
-#[async_trait]
-impl SchemaInference for JsonFormat {
-    async fn infer_schema(&self, data: &[u8]) -> anyhow::Result<arrow::datatypes::SchemaRef> {
-        // TODO: Implement JSON schema inference
-        unimplemented!()
-    }
-}
-
-#[async_trait]
-impl DataFormat for JsonFormat {
-    async fn read_batches_from_stream(
-        &self,
-        schema: arrow::datatypes::SchemaRef,
-        stream: distributed_transformer::formats::DataStream,
-    ) -> anyhow::Result<futures::stream::BoxStream<'static, anyhow::Result<arrow::record_batch::RecordBatch>>> {
-        // TODO: Implement JSON streaming
-        unimplemented!()
-    }
-    
-    async fn write_batches(
-        &self,
-        batches: futures::stream::BoxStream<'static, anyhow::Result<arrow::record_batch::RecordBatch>>,
-    ) -> anyhow::Result<bytes::Bytes> {
-        // TODO: Implement JSON writing
-        unimplemented!()
-    }
-    
-    fn clone_box(&self) -> Box<dyn DataFormat + Send + Sync> {
-        Box::new(self.clone())
+    /// Reads `batch_size` from the plugin's config, falling back to the format default
+    fn create_format(&self, config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+        let mut json_config = JsonConfig::default();
+        if let Some(batch_size) = config
+            .and_then(|config| config.get("batch_size"))
+            .and_then(|value| value.as_u64())
+        {
+            json_config.batch_size = batch_size as usize;
+        }
+        Box::new(JsonFormat::new(json_config))
     }
 }
 
@@ -0,0 +1,227 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arrow::record_batch::RecordBatch;
+use futures::TryStreamExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::error::{self, Error};
+use crate::formats::{BatchStream, DataFormat};
+use crate::pipeline::{self, OutputInfo};
+use crate::storage::Storage;
+
+/// Batches buffered for the open file before `write` waits
+const FILE_CHANNEL_CAPACITY: usize = 2;
+
+/// Default amount of data per compacted file
+pub const DEFAULT_TARGET_FILE_SIZE: usize = 128 * 1024 * 1024;
+
+/// A part file currently being written by a background task
+struct OpenFile {
+    sender: mpsc::Sender<error::Result<RecordBatch>>,
+    task: JoinHandle<Result<OutputInfo>>,
+    bytes: usize,
+}
+
+/// Writes batches into numbered `part-NNNNN` files below a directory, starting
+/// a new file once the current one holds `target_file_size` bytes.
+///
+/// Sizes are measured on the decoded Arrow batches, so compressed files come
+/// out smaller than the target. Files roll over between batches; a batch is
+/// never split across files.
+pub struct CompactWriter {
+    storage: Arc<dyn Storage>,
+    format: Arc<dyn DataFormat>,
+    base: Url,
+    extension: String,
+    target_file_size: usize,
+    open: Option<OpenFile>,
+    written: Vec<OutputInfo>,
+}
+
+impl CompactWriter {
+    pub fn new(storage: Arc<dyn Storage>, format: Arc<dyn DataFormat>, mut base: Url) -> Self {
+        // Part files are resolved relative to the base, which must look like a directory
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Self {
+            storage,
+            format,
+            base,
+            extension: "parquet".to_string(),
+            target_file_size: DEFAULT_TARGET_FILE_SIZE,
+            open: None,
+            written: Vec::new(),
+        }
+    }
+
+    pub fn with_target_file_size(mut self, target_file_size: usize) -> Self {
+        self.target_file_size = target_file_size.max(1);
+        self
+    }
+
+    /// File extension of the written part files
+    pub fn with_extension(mut self, extension: impl Into<String>) -> Self {
+        self.extension = extension.into();
+        self
+    }
+
+    /// Send `batch` to the open file, first closing it if it has reached the target size
+    pub async fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        if self.open.as_ref().is_some_and(|file| file.bytes >= self.target_file_size) {
+            self.close().await?;
+        }
+        if self.open.is_none() {
+            self.open = Some(self.open_file()?);
+        }
+
+        let file = self.open.as_mut().expect("file was just opened");
+        file.bytes += batch.get_array_memory_size();
+        if file.sender.send(Ok(batch.clone())).await.is_err() {
+            // The writer task exited early; its result carries the error
+            let file = self.open.take().expect("file is open");
+            file.task.await??;
+            return Err(anyhow!("Writer for {} stopped unexpectedly", self.base));
+        }
+        Ok(())
+    }
+
+    /// Finish the open file and describe every written file, in write order
+    pub async fn finish(mut self) -> Result<Vec<OutputInfo>> {
+        self.close().await?;
+        Ok(self.written)
+    }
+
+    /// Stop the open file without finishing it and delete the files already written
+    pub async fn abort(mut self) {
+        if let Some(file) = self.open.take() {
+            // Failing the stream makes the upload abort instead of completing
+            let _ = file.sender.send(Err(Error::Cancelled)).await;
+            let _ = file.task.await;
+        }
+        // Best effort: the compaction has already failed
+        for output in &self.written {
+            let _ = self.storage.delete(&output.path).await;
+        }
+    }
+
+    fn open_file(&self) -> Result<OpenFile> {
+        let index = self.written.len();
+        let url = self.base.join(&format!("part-{:05}.{}", index, self.extension))?;
+
+        let (sender, receiver) = mpsc::channel(FILE_CHANNEL_CAPACITY);
+        let storage = self.storage.clone();
+        let format = self.format.clone();
+        let task = tokio::spawn(async move {
+            let batches = futures::stream::unfold(receiver, |mut receiver: mpsc::Receiver<error::Result<RecordBatch>>| async move {
+                receiver.recv().await.map(|batch| (batch, receiver))
+            });
+            pipeline::write_batches(format.as_ref(), Box::pin(batches), storage.as_ref(), &url).await
+        });
+        Ok(OpenFile { sender, task, bytes: 0 })
+    }
+
+    async fn close(&mut self) -> Result<()> {
+        let Some(file) = self.open.take() else {
+            return Ok(());
+        };
+        drop(file.sender);
+        self.written.push(file.task.await??);
+        Ok(())
+    }
+}
+
+/// Rewrite `batches` into files of about `target_file_size` bytes below
+/// `output_dir`; files already written are deleted if compaction fails
+pub async fn compact(
+    mut batches: BatchStream,
+    format: Arc<dyn DataFormat>,
+    storage: Arc<dyn Storage>,
+    output_dir: Url,
+    target_file_size: usize,
+) -> Result<Vec<OutputInfo>> {
+    let mut writer = CompactWriter::new(storage, format, output_dir).with_target_file_size(target_file_size);
+    let written: Result<()> = async {
+        while let Some(batch) = batches.try_next().await? {
+            writer.write(&batch).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = written {
+        writer.abort().await;
+        return Err(e);
+    }
+    writer.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{EmptyInputPolicy, ParquetFormat};
+    use crate::pipeline::ReadOptions;
+    use crate::storage::local::LocalStorage;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use tempfile::TempDir;
+
+    /// Write `files` small Parquet files of `rows` rows each and return their URLs
+    async fn small_files(dir: &TempDir, storage: &dyn Storage, files: usize, rows: i64) -> Result<Vec<Url>> {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let format = ParquetFormat::default();
+        let mut urls = Vec::new();
+        for file in 0..files {
+            let ids = Int64Array::from_iter_values((0..rows).map(|row| file as i64 * rows + row));
+            let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(ids)])?;
+            let url = Url::from_file_path(dir.path().join(format!("small-{}.parquet", file))).unwrap();
+            storage.write(&url, format.write_batch(&batch)?).await?;
+            urls.push(url);
+        }
+        Ok(urls)
+    }
+
+    fn read(storage: Arc<dyn Storage>, urls: Vec<Url>) -> BatchStream {
+        let format = Arc::new(ParquetFormat::default());
+        pipeline::read_batches(storage, urls, format, ReadOptions::new(EmptyInputPolicy::Error))
+    }
+
+    async fn total_rows(format: &ParquetFormat, data: &bytes::Bytes) -> Result<usize> {
+        Ok(format.read(data)?.collect().await?.iter().map(RecordBatch::num_rows).sum())
+    }
+
+    #[tokio::test]
+    async fn test_compact_small_files_into_one() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let urls = small_files(&temp_dir, storage.as_ref(), 5, 100).await?;
+        let output_dir = Url::from_directory_path(temp_dir.path().join("compacted")).unwrap();
+
+        let format = Arc::new(ParquetFormat::default());
+        let batches = read(storage.clone(), urls);
+        let files = compact(batches, format.clone(), storage.clone(), output_dir, DEFAULT_TARGET_FILE_SIZE).await?;
+
+        assert_eq!(files.len(), 1);
+        assert!(files[0].path.path().ends_with("/compacted/part-00000.parquet"));
+        assert_eq!(files[0].rows, 500);
+        assert_eq!(total_rows(&format, &storage.read_all(&files[0].path).await?).await?, 500);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compact_rolls_over_at_target_size() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let urls = small_files(&temp_dir, storage.as_ref(), 4, 100).await?;
+        let output_dir = Url::from_directory_path(temp_dir.path().join("compacted")).unwrap();
+
+        // Any single batch reaches a one byte target, so every batch gets its own file
+        let format = Arc::new(ParquetFormat::default());
+        let files = compact(read(storage.clone(), urls), format, storage, output_dir, 1).await?;
+        assert_eq!(files.len(), 4);
+        assert_eq!(files.iter().map(|file| file.rows).sum::<usize>(), 400);
+        Ok(())
+    }
+}
@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 use num_cpus;
 
 /// Global configuration for the system
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     /// Format-specific configurations
     pub formats: FormatConfig,
@@ -19,7 +19,7 @@ pub struct Config {
 }
 
 /// Configuration for different data formats
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FormatConfig {
     /// CSV format configuration
     pub csv: CsvConfig,
@@ -27,10 +27,13 @@ pub struct FormatConfig {
     pub parquet: ParquetConfig,
     /// Default configuration for unknown formats
     pub default: DefaultFormatConfig,
+    /// Rows per batch picked from the width of each input's schema
+    #[serde(default)]
+    pub adaptive_batch_size: AdaptiveBatchSizeConfig,
 }
 
 /// CSV format specific configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CsvConfig {
     /// Default batch size for reading
     pub batch_size: usize,
@@ -40,14 +43,34 @@ pub struct CsvConfig {
     pub schema_sample_size: usize,
     /// Maximum sample size in bytes
     pub max_sample_bytes: usize,
-    /// CSV delimiter
-    pub delimiter: char,
+    /// CSV delimiter: one character or an escape sequence such as `\t`, or
+    /// a fixed string like `||` with `multi_char_delimiter`
+    pub delimiter: String,
+    /// Allow `delimiter` to be several characters; quoted fields are then not supported
+    #[serde(default)]
+    pub multi_char_delimiter: bool,
+    /// Split fields on this regular expression instead of `delimiter`; needs
+    /// the `regex-delimiter` feature and is much slower
+    #[serde(default)]
+    pub delimiter_regex: Option<String>,
     /// Quote character
     pub quote: char,
+    /// Arrow types forced on columns by name instead of inferring them,
+    /// e.g. `zip: Utf8`; one of `Int64`, `Float64`, `Boolean` or `Utf8`
+    #[serde(default)]
+    pub column_types: HashMap<String, String>,
+    /// `chrono` formats of timestamp columns, e.g. `%Y-%m-%d %H:%M:%S` or
+    /// `%+` for ISO 8601; columns matching one are read as timestamps
+    #[serde(default)]
+    pub timestamp_formats: Vec<String>,
+    /// Timezone of timestamp columns and of values without an offset, e.g.
+    /// `Europe/Berlin` or `+02:00` (default: UTC)
+    #[serde(default)]
+    pub default_timezone: Option<String>,
 }
 
 /// Parquet format specific configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ParquetConfig {
     /// Default batch size for reading
     pub batch_size: usize,
@@ -57,10 +80,25 @@ pub struct ParquetConfig {
     pub schema_sample_size: usize,
     /// Maximum sample size in bytes
     pub max_sample_bytes: usize,
+    /// Parquet format version to write, `1.0` or `2.0` (default: 1.0)
+    #[serde(default)]
+    pub writer_version: Option<String>,
+    /// Data page version to write, `v1` or `v2`; v2 pages need writer version 2.0
+    #[serde(default)]
+    pub data_page_version: Option<String>,
+    /// Encoding per column, e.g. `DELTA_BINARY_PACKED`; turns the column's dictionary off
+    #[serde(default)]
+    pub column_encodings: HashMap<String, String>,
+    /// Columns to dictionary encode even when `column_encodings` names them
+    #[serde(default)]
+    pub dictionary_enabled_columns: Vec<String>,
+    /// Columns never dictionary encoded
+    #[serde(default)]
+    pub dictionary_disabled_columns: Vec<String>,
 }
 
 /// Default configuration for unknown formats
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DefaultFormatConfig {
     /// Default batch size for reading
     pub batch_size: usize,
@@ -70,8 +108,49 @@ pub struct DefaultFormatConfig {
     pub max_sample_bytes: usize,
 }
 
+/// Adaptive batch sizing for the CSV and Parquet readers.
+///
+/// When enabled, the rows per batch are `target_batch_bytes` divided by the
+/// estimated width of a row of the inferred schema, clamped to
+/// `min_batch_rows..=max_batch_rows`, instead of the format's `batch_size`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveBatchSizeConfig {
+    /// Pick the rows per batch from the schema instead of `batch_size`
+    pub enabled: bool,
+    /// Decoded bytes each batch should hold
+    pub target_batch_bytes: usize,
+    /// Fewest rows per batch, however wide the rows
+    pub min_batch_rows: usize,
+    /// Most rows per batch, however narrow the rows
+    pub max_batch_rows: usize,
+}
+
+impl Default for AdaptiveBatchSizeConfig {
+    fn default() -> Self {
+        let defaults = crate::formats::AdaptiveBatchSize::default();
+        Self {
+            enabled: false,
+            target_batch_bytes: defaults.target_bytes,
+            min_batch_rows: defaults.min_rows,
+            max_batch_rows: defaults.max_rows,
+        }
+    }
+}
+
+impl AdaptiveBatchSizeConfig {
+    /// The sizing the readers apply, or `None` when disabled
+    pub fn policy(&self) -> Option<crate::formats::AdaptiveBatchSize> {
+        self.enabled.then_some(crate::formats::AdaptiveBatchSize {
+            target_bytes: self.target_batch_bytes,
+            min_rows: self.min_batch_rows,
+            max_rows: self.max_batch_rows,
+        })
+    }
+}
+
 /// Plugin system configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PluginConfig {
     /// Directory containing plugins
     pub directory: PathBuf,
@@ -88,7 +167,7 @@ pub struct PluginConfig {
 }
 
 /// Version compatibility modes for plugins
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum VersionCompatibility {
     /// Only load exact version matches
     Exact,
@@ -101,7 +180,7 @@ pub enum VersionCompatibility {
 }
 
 /// Storage system configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StorageConfig {
     /// S3 configuration
     pub s3: S3Config,
@@ -109,10 +188,53 @@ pub struct StorageConfig {
     pub local_path: PathBuf,
     /// Retry configuration
     pub retry: RetryConfig,
+    /// Read buffer size in bytes
+    #[serde(default = "default_buffer_size")]
+    pub read_buffer_size: usize,
+    /// Write buffer size in bytes, also used as the multipart upload part size
+    #[serde(default = "default_buffer_size")]
+    pub write_buffer_size: usize,
+    /// Writes larger than this many bytes use multipart uploads
+    #[serde(default = "default_multipart_threshold")]
+    pub multipart_threshold: usize,
+    /// Maximum number of concurrent requests against the backend
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    /// Compare the checksum of every written object with the written data
+    /// and rewrite it on a mismatch, up to `retry.max_retries` times
+    #[serde(default)]
+    pub verify_writes: bool,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            s3: S3Config::default(),
+            local_path: PathBuf::from("data"),
+            retry: RetryConfig::default(),
+            read_buffer_size: default_buffer_size(),
+            write_buffer_size: default_buffer_size(),
+            multipart_threshold: default_multipart_threshold(),
+            max_concurrent_requests: default_max_concurrent_requests(),
+            verify_writes: false,
+        }
+    }
+}
+
+fn default_buffer_size() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_multipart_threshold() -> usize {
+    100 * 1024 * 1024
+}
+
+fn default_max_concurrent_requests() -> usize {
+    10
 }
 
 /// S3 configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct S3Config {
     /// AWS region
     pub region: String,
@@ -120,10 +242,54 @@ pub struct S3Config {
     pub endpoint: Option<String>,
     /// Default bucket
     pub bucket: String,
+    /// Use path-style requests (`endpoint/bucket/key`), required by most S3-compatible stores
+    #[serde(default)]
+    pub force_path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            bucket: "default".to_string(),
+            force_path_style: false,
+        }
+    }
+}
+
+impl S3Config {
+    /// Build an S3 configuration from the standard AWS environment variables
+    ///
+    /// Reads `AWS_REGION`/`AWS_DEFAULT_REGION`, `AWS_ENDPOINT` and
+    /// `AWS_S3_FORCE_PATH_STYLE`, falling back to the defaults for anything unset.
+    pub fn from_env() -> Self {
+        Self::default().with_env()
+    }
+
+    /// This configuration with the standard AWS environment variables, where
+    /// set, taking precedence over its region, endpoint and path style
+    pub fn with_env(self) -> Self {
+        self.with_vars(|name| std::env::var(name).ok())
+    }
+
+    fn with_vars(mut self, var: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |name: &str| var(name).filter(|v| !v.is_empty());
+        if let Some(region) = var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")) {
+            self.region = region;
+        }
+        if let Some(endpoint) = var("AWS_ENDPOINT") {
+            self.endpoint = Some(endpoint);
+        }
+        if let Some(value) = var("AWS_S3_FORCE_PATH_STYLE") {
+            self.force_path_style = matches!(value.to_lowercase().as_str(), "1" | "true" | "yes");
+        }
+        self
+    }
 }
 
 /// Retry configuration for storage operations
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RetryConfig {
     /// Maximum number of retries
     pub max_retries: u32,
@@ -131,10 +297,37 @@ pub struct RetryConfig {
     pub initial_delay_ms: u64,
     /// Maximum retry delay in milliseconds
     pub max_delay_ms: u64,
+    /// Multiplier applied to the delay after each failed attempt; 1.0 keeps the delay constant
+    #[serde(default = "default_backoff_multiplier")]
+    pub backoff_multiplier: f64,
+    /// Wait a random time between zero and the computed delay, so operations
+    /// that failed together don't all retry at the same moment
+    #[serde(default = "default_jitter")]
+    pub jitter: bool,
+}
+
+fn default_backoff_multiplier() -> f64 {
+    2.0
+}
+
+fn default_jitter() -> bool {
+    true
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_delay_ms: 100,
+            max_delay_ms: 5000,
+            backoff_multiplier: default_backoff_multiplier(),
+            jitter: true,
+        }
+    }
 }
 
 /// Streaming configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StreamingConfig {
     /// Maximum number of concurrent streams
     pub max_concurrent_streams: usize,
@@ -144,10 +337,25 @@ pub struct StreamingConfig {
     pub timeout: u64,
     /// Whether to use compression for streaming
     pub use_compression: bool,
+    /// Block the reader when `max_in_flight_batches` decoded batches are
+    /// waiting to be written; when disabled the reader never waits
+    #[serde(default = "default_enable_backpressure")]
+    pub enable_backpressure: bool,
+    /// Maximum number of decoded batches buffered between reading and writing
+    #[serde(default = "default_max_in_flight_batches")]
+    pub max_in_flight_batches: usize,
+}
+
+fn default_enable_backpressure() -> bool {
+    true
+}
+
+fn default_max_in_flight_batches() -> usize {
+    4
 }
 
 /// Data processing configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProcessingConfig {
     /// Number of worker threads
     pub num_threads: usize,
@@ -165,20 +373,31 @@ impl Default for Config {
                     has_header: true,
                     schema_sample_size: 1000,
                     max_sample_bytes: 1024 * 1024,
-                    delimiter: ',',
+                    delimiter: ",".to_string(),
+                    multi_char_delimiter: false,
+                    delimiter_regex: None,
                     quote: '"',
+                    column_types: HashMap::new(),
+                    timestamp_formats: Vec::new(),
+                    default_timezone: None,
                 },
                 parquet: ParquetConfig {
                     batch_size: 1024,
                     compression: "snappy".to_string(),
                     schema_sample_size: 1000,
                     max_sample_bytes: 1024 * 1024,
+                    writer_version: None,
+                    data_page_version: None,
+                    column_encodings: HashMap::new(),
+                    dictionary_enabled_columns: Vec::new(),
+                    dictionary_disabled_columns: Vec::new(),
                 },
                 default: DefaultFormatConfig {
                     batch_size: 1024,
                     schema_sample_size: 1000,
                     max_sample_bytes: 1024 * 1024,
                 },
+                adaptive_batch_size: AdaptiveBatchSizeConfig::default(),
             },
             plugins: PluginConfig {
                 directory: PathBuf::from("plugins"),
@@ -188,19 +407,7 @@ impl Default for Config {
                 plugin_configs: HashMap::new(),
                 default_timeout: 30,
             },
-            storage: StorageConfig {
-                s3: S3Config {
-                    region: "us-east-1".to_string(),
-                    endpoint: None,
-                    bucket: "default".to_string(),
-                },
-                local_path: PathBuf::from("data"),
-                retry: RetryConfig {
-                    max_retries: 3,
-                    initial_delay_ms: 100,
-                    max_delay_ms: 5000,
-                },
-            },
+            storage: StorageConfig::default(),
             processing: ProcessingConfig {
                 num_threads: num_cpus,
                 memory_limit: 1024 * 1024 * 1024,
@@ -210,25 +417,199 @@ impl Default for Config {
                 buffer_size: 1024 * 1024,
                 timeout: 30,
                 use_compression: true,
+                enable_backpressure: true,
+                max_in_flight_batches: default_max_in_flight_batches(),
             },
         }
     }
 }
 
 impl Config {
-    /// Load configuration from a file
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    /// Replace every setting that `overrides` specifies, keeping the rest
+    pub fn merge(&mut self, overrides: PartialConfig) {
+        let PartialConfig {
+            formats,
+            plugins,
+            storage,
+            processing,
+            streaming,
+        } = overrides;
+
+        let csv = &mut self.formats.csv;
+        set(&mut csv.batch_size, formats.csv.batch_size);
+        set(&mut csv.has_header, formats.csv.has_header);
+        set(&mut csv.schema_sample_size, formats.csv.schema_sample_size);
+        set(&mut csv.max_sample_bytes, formats.csv.max_sample_bytes);
+        set(&mut csv.delimiter, formats.csv.delimiter);
+        set(&mut csv.multi_char_delimiter, formats.csv.multi_char_delimiter);
+        set(&mut csv.delimiter_regex, formats.csv.delimiter_regex.map(Some));
+        set(&mut csv.quote, formats.csv.quote);
+        set(&mut csv.column_types, formats.csv.column_types);
+        set(&mut csv.timestamp_formats, formats.csv.timestamp_formats);
+        set(&mut csv.default_timezone, formats.csv.default_timezone.map(Some));
+
+        let parquet = &mut self.formats.parquet;
+        set(&mut parquet.batch_size, formats.parquet.batch_size);
+        set(&mut parquet.compression, formats.parquet.compression);
+        set(&mut parquet.schema_sample_size, formats.parquet.schema_sample_size);
+        set(&mut parquet.max_sample_bytes, formats.parquet.max_sample_bytes);
+        set(&mut parquet.writer_version, formats.parquet.writer_version.map(Some));
+        set(&mut parquet.data_page_version, formats.parquet.data_page_version.map(Some));
+        set(&mut parquet.column_encodings, formats.parquet.column_encodings);
+        set(&mut parquet.dictionary_enabled_columns, formats.parquet.dictionary_enabled_columns);
+        set(&mut parquet.dictionary_disabled_columns, formats.parquet.dictionary_disabled_columns);
+
+        let default = &mut self.formats.default;
+        set(&mut default.batch_size, formats.default.batch_size);
+        set(&mut default.schema_sample_size, formats.default.schema_sample_size);
+        set(&mut default.max_sample_bytes, formats.default.max_sample_bytes);
+
+        let adaptive = &mut self.formats.adaptive_batch_size;
+        set(&mut adaptive.enabled, formats.adaptive_batch_size.enabled);
+        set(&mut adaptive.target_batch_bytes, formats.adaptive_batch_size.target_batch_bytes);
+        set(&mut adaptive.min_batch_rows, formats.adaptive_batch_size.min_batch_rows);
+        set(&mut adaptive.max_batch_rows, formats.adaptive_batch_size.max_batch_rows);
+
+        set(&mut self.plugins.directory, plugins.directory);
+        set(&mut self.plugins.version_compatibility, plugins.version_compatibility);
+        set(&mut self.plugins.isolated_loading, plugins.isolated_loading);
+        set(&mut self.plugins.max_instances, plugins.max_instances);
+        set(&mut self.plugins.default_timeout, plugins.default_timeout);
+        self.plugins.plugin_configs.extend(plugins.plugin_configs);
+
+        let s3 = &mut self.storage.s3;
+        set(&mut s3.region, storage.s3.region);
+        set(&mut s3.bucket, storage.s3.bucket);
+        set(&mut s3.force_path_style, storage.s3.force_path_style);
+        if storage.s3.endpoint.is_some() {
+            s3.endpoint = storage.s3.endpoint;
+        }
+        let retry = &mut self.storage.retry;
+        set(&mut retry.max_retries, storage.retry.max_retries);
+        set(&mut retry.initial_delay_ms, storage.retry.initial_delay_ms);
+        set(&mut retry.max_delay_ms, storage.retry.max_delay_ms);
+        set(&mut retry.backoff_multiplier, storage.retry.backoff_multiplier);
+        set(&mut retry.jitter, storage.retry.jitter);
+        set(&mut self.storage.local_path, storage.local_path);
+        set(&mut self.storage.read_buffer_size, storage.read_buffer_size);
+        set(&mut self.storage.write_buffer_size, storage.write_buffer_size);
+        set(&mut self.storage.multipart_threshold, storage.multipart_threshold);
+        set(&mut self.storage.max_concurrent_requests, storage.max_concurrent_requests);
+        set(&mut self.storage.verify_writes, storage.verify_writes);
+
+        set(&mut self.processing.num_threads, processing.num_threads);
+        set(&mut self.processing.memory_limit, processing.memory_limit);
+
+        set(&mut self.streaming.max_concurrent_streams, streaming.max_concurrent_streams);
+        set(&mut self.streaming.buffer_size, streaming.buffer_size);
+        set(&mut self.streaming.timeout, streaming.timeout);
+        set(&mut self.streaming.use_compression, streaming.use_compression);
+        set(&mut self.streaming.enable_backpressure, streaming.enable_backpressure);
+        set(&mut self.streaming.max_in_flight_batches, streaming.max_in_flight_batches);
+    }
+
+    /// Load configuration from a YAML, TOML or JSON file, chosen by extension
     pub fn from_file<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file_type = ConfigFileType::from_path(path)?;
         let content = std::fs::read_to_string(path)?;
-        Ok(serde_json::from_str(&content)?)
+        let config = match file_type {
+            ConfigFileType::Yaml => serde_yaml::from_str(&content)?,
+            ConfigFileType::Toml => toml::from_str(&content)?,
+            ConfigFileType::Json => serde_json::from_str(&content)?,
+        };
+        Ok(config)
     }
 
-    /// Save configuration to a file
+    /// Save configuration to a YAML, TOML or JSON file, chosen by extension
     pub fn save_to_file<P: AsRef<std::path::Path>>(&self, path: P) -> anyhow::Result<()> {
-        let content = serde_json::to_string_pretty(self)?;
+        let path = path.as_ref();
+        let content = match ConfigFileType::from_path(path)? {
+            ConfigFileType::Yaml => serde_yaml::to_string(self)?,
+            ConfigFileType::Toml => toml::to_string_pretty(self)?,
+            ConfigFileType::Json => serde_json::to_string_pretty(self)?,
+        };
         std::fs::write(path, content)?;
         Ok(())
     }
 
+    /// Override settings from `DT_`-prefixed environment variables.
+    ///
+    /// See `docs/configuration.md` for the supported variables. A variable
+    /// with an invalid value is an error rather than being ignored.
+    pub fn apply_env_overrides(&mut self) -> anyhow::Result<()> {
+        self.apply_overrides(std::env::vars().filter(|(name, _)| name.starts_with("DT_")))
+    }
+
+    /// Apply `DT_*` overrides from name/value pairs; unknown names are ignored
+    fn apply_overrides<I>(&mut self, vars: I) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (name, value) in vars {
+            let value = value.as_str();
+            match name.as_str() {
+                "DT_PROCESSING_NUM_THREADS" => self.processing.num_threads = parse_positive(&name, value)?,
+                "DT_PROCESSING_MEMORY_LIMIT" => self.processing.memory_limit = parse_positive(&name, value)?,
+                "DT_CSV_BATCH_SIZE" => self.formats.csv.batch_size = parse_positive(&name, value)?,
+                "DT_CSV_HAS_HEADER" => self.formats.csv.has_header = parse_bool(&name, value)?,
+                "DT_CSV_DELIMITER" => {
+                    // Multi-character delimiters still need `multi_char_delimiter` in the config
+                    if !crate::formats::is_auto_delimiter(value) {
+                        crate::formats::parse_delimiter(value, self.formats.csv.multi_char_delimiter)
+                            .map_err(|e| anyhow::anyhow!("Invalid value '{}' for {}: {}", value, name, e))?;
+                    }
+                    self.formats.csv.delimiter = value.to_string();
+                }
+                "DT_PARQUET_BATCH_SIZE" => self.formats.parquet.batch_size = parse_positive(&name, value)?,
+                "DT_ADAPTIVE_BATCH_SIZE" => self.formats.adaptive_batch_size.enabled = parse_bool(&name, value)?,
+                "DT_TARGET_BATCH_BYTES" => {
+                    self.formats.adaptive_batch_size.target_batch_bytes = parse_positive(&name, value)?
+                }
+                "DT_PARQUET_COMPRESSION" => {
+                    let compression = value.trim().to_lowercase();
+                    crate::formats::parse_compression(&compression)
+                        .map_err(|e| anyhow::anyhow!("Invalid value '{}' for {}: {}", value, name, e))?;
+                    self.formats.parquet.compression = compression;
+                }
+                "DT_STORAGE_RETRY_MAX_RETRIES" => self.storage.retry.max_retries = parse_override(&name, value)?,
+                "DT_STORAGE_RETRY_INITIAL_DELAY_MS" => self.storage.retry.initial_delay_ms = parse_override(&name, value)?,
+                "DT_STORAGE_RETRY_MAX_DELAY_MS" => self.storage.retry.max_delay_ms = parse_override(&name, value)?,
+                "DT_STORAGE_RETRY_JITTER" => self.storage.retry.jitter = parse_bool(&name, value)?,
+                "DT_STORAGE_VERIFY_WRITES" => self.storage.verify_writes = parse_bool(&name, value)?,
+                "DT_STORAGE_S3_REGION" => self.storage.s3.region = value.to_string(),
+                "DT_STORAGE_S3_BUCKET" => self.storage.s3.bucket = value.to_string(),
+                "DT_STORAGE_S3_ENDPOINT" => self.storage.s3.endpoint = Some(value.to_string()),
+                "DT_STREAMING_ENABLE_BACKPRESSURE" => {
+                    self.streaming.enable_backpressure = parse_bool(&name, value)?
+                }
+                "DT_STREAMING_MAX_IN_FLIGHT_BATCHES" => {
+                    self.streaming.max_in_flight_batches = parse_positive(&name, value)?
+                }
+                "DT_PLUGINS_DIRECTORY" => self.plugins.directory = PathBuf::from(value),
+                "DT_PLUGINS_VERSION_COMPATIBILITY" => {
+                    self.plugins.version_compatibility = match value.trim().to_lowercase().as_str() {
+                        "exact" => VersionCompatibility::Exact,
+                        "major" => VersionCompatibility::Major,
+                        "minor" => VersionCompatibility::Minor,
+                        "any" => VersionCompatibility::Any,
+                        _ => anyhow::bail!(
+                            "Invalid value '{}' for {}: expected exact, major, minor or any",
+                            value,
+                            name
+                        ),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     /// Get format-specific configuration
     pub fn get_format_config(&self, format: &str) -> &dyn FormatConfigTrait {
         match format {
@@ -244,6 +625,313 @@ impl Config {
     }
 }
 
+/// A sparse set of overrides layered on top of a `Config` with `Config::merge`.
+///
+/// Every field is optional; only the ones that are set replace the values
+/// they are merged into. Deserializing a file into a `PartialConfig` yields
+/// just the settings the file mentions.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialConfig {
+    pub formats: PartialFormatConfig,
+    pub plugins: PartialPluginConfig,
+    pub storage: PartialStorageConfig,
+    pub processing: PartialProcessingConfig,
+    pub streaming: PartialStreamingConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialFormatConfig {
+    pub csv: PartialCsvConfig,
+    pub parquet: PartialParquetConfig,
+    pub default: PartialDefaultFormatConfig,
+    pub adaptive_batch_size: PartialAdaptiveBatchSizeConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialCsvConfig {
+    pub batch_size: Option<usize>,
+    pub has_header: Option<bool>,
+    pub schema_sample_size: Option<usize>,
+    pub max_sample_bytes: Option<usize>,
+    pub delimiter: Option<String>,
+    pub multi_char_delimiter: Option<bool>,
+    pub delimiter_regex: Option<String>,
+    pub quote: Option<char>,
+    pub column_types: Option<HashMap<String, String>>,
+    pub timestamp_formats: Option<Vec<String>>,
+    pub default_timezone: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialParquetConfig {
+    pub batch_size: Option<usize>,
+    pub compression: Option<String>,
+    pub schema_sample_size: Option<usize>,
+    pub max_sample_bytes: Option<usize>,
+    pub writer_version: Option<String>,
+    pub data_page_version: Option<String>,
+    pub column_encodings: Option<HashMap<String, String>>,
+    pub dictionary_enabled_columns: Option<Vec<String>>,
+    pub dictionary_disabled_columns: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialDefaultFormatConfig {
+    pub batch_size: Option<usize>,
+    pub schema_sample_size: Option<usize>,
+    pub max_sample_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialAdaptiveBatchSizeConfig {
+    pub enabled: Option<bool>,
+    pub target_batch_bytes: Option<usize>,
+    pub min_batch_rows: Option<usize>,
+    pub max_batch_rows: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialPluginConfig {
+    pub directory: Option<PathBuf>,
+    pub version_compatibility: Option<VersionCompatibility>,
+    pub isolated_loading: Option<bool>,
+    pub max_instances: Option<usize>,
+    /// Merged per plugin; a plugin's entry replaces its previous entry as a whole
+    pub plugin_configs: HashMap<String, serde_json::Value>,
+    pub default_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialStorageConfig {
+    pub s3: PartialS3Config,
+    pub local_path: Option<PathBuf>,
+    pub retry: PartialRetryConfig,
+    pub read_buffer_size: Option<usize>,
+    pub write_buffer_size: Option<usize>,
+    pub multipart_threshold: Option<usize>,
+    pub max_concurrent_requests: Option<usize>,
+    pub verify_writes: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialS3Config {
+    pub region: Option<String>,
+    pub endpoint: Option<String>,
+    pub bucket: Option<String>,
+    pub force_path_style: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialRetryConfig {
+    pub max_retries: Option<u32>,
+    pub initial_delay_ms: Option<u64>,
+    pub max_delay_ms: Option<u64>,
+    pub backoff_multiplier: Option<f64>,
+    pub jitter: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialProcessingConfig {
+    pub num_threads: Option<usize>,
+    pub memory_limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PartialStreamingConfig {
+    pub max_concurrent_streams: Option<usize>,
+    pub buffer_size: Option<usize>,
+    pub timeout: Option<u64>,
+    pub use_compression: Option<bool>,
+    pub enable_backpressure: Option<bool>,
+    pub max_in_flight_batches: Option<usize>,
+}
+
+/// Replace `target` when an override is present
+fn set<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+/// Builds a `Config` from defaults with fluent setters and layered overrides
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from an existing configuration, such as one loaded from a file
+    pub fn from_config(config: Config) -> Self {
+        Self { config }
+    }
+
+    /// Layer a sparse override on top of the configuration built so far
+    pub fn merge(mut self, overrides: PartialConfig) -> Self {
+        self.config.merge(overrides);
+        self
+    }
+
+    pub fn csv_batch_size(mut self, batch_size: usize) -> Self {
+        self.config.formats.csv.batch_size = batch_size;
+        self
+    }
+
+    pub fn csv_has_header(mut self, has_header: bool) -> Self {
+        self.config.formats.csv.has_header = has_header;
+        self
+    }
+
+    pub fn csv_delimiter(mut self, delimiter: impl Into<String>) -> Self {
+        self.config.formats.csv.delimiter = delimiter.into();
+        self
+    }
+
+    pub fn parquet_batch_size(mut self, batch_size: usize) -> Self {
+        self.config.formats.parquet.batch_size = batch_size;
+        self
+    }
+
+    /// Pick the rows per batch from the width of each input's schema
+    pub fn adaptive_batch_size(mut self, enabled: bool) -> Self {
+        self.config.formats.adaptive_batch_size.enabled = enabled;
+        self
+    }
+
+    pub fn parquet_compression(mut self, compression: impl Into<String>) -> Self {
+        self.config.formats.parquet.compression = compression.into();
+        self
+    }
+
+    pub fn s3_region(mut self, region: impl Into<String>) -> Self {
+        self.config.storage.s3.region = region.into();
+        self
+    }
+
+    pub fn s3_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.storage.s3.endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn s3_bucket(mut self, bucket: impl Into<String>) -> Self {
+        self.config.storage.s3.bucket = bucket.into();
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.config.storage.retry.max_retries = max_retries;
+        self
+    }
+
+    pub fn retry_jitter(mut self, jitter: bool) -> Self {
+        self.config.storage.retry.jitter = jitter;
+        self
+    }
+
+    pub fn verify_writes(mut self, verify_writes: bool) -> Self {
+        self.config.storage.verify_writes = verify_writes;
+        self
+    }
+
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.config.processing.num_threads = num_threads;
+        self
+    }
+
+    pub fn memory_limit(mut self, memory_limit: usize) -> Self {
+        self.config.processing.memory_limit = memory_limit;
+        self
+    }
+
+    pub fn max_in_flight_batches(mut self, max_in_flight_batches: usize) -> Self {
+        self.config.streaming.max_in_flight_batches = max_in_flight_batches;
+        self
+    }
+
+    pub fn plugin_directory(mut self, directory: impl Into<PathBuf>) -> Self {
+        self.config.plugins.directory = directory.into();
+        self
+    }
+
+    pub fn version_compatibility(mut self, version_compatibility: VersionCompatibility) -> Self {
+        self.config.plugins.version_compatibility = version_compatibility;
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+/// Parse an environment override, naming the variable in the error
+fn parse_override<T>(name: &str, value: &str) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid value '{}' for {}: {}", value, name, e))
+}
+
+/// Parse an override that must be greater than zero
+fn parse_positive(name: &str, value: &str) -> anyhow::Result<usize> {
+    match parse_override(name, value)? {
+        0 => anyhow::bail!("Invalid value '{}' for {}: must be greater than zero", value, name),
+        n => Ok(n),
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> anyhow::Result<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" => Ok(false),
+        _ => anyhow::bail!("Invalid value '{}' for {}: expected true or false", value, name),
+    }
+}
+
+/// Serialization formats supported for configuration files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFileType {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFileType {
+    fn from_path(path: &std::path::Path) -> anyhow::Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match extension.as_deref() {
+            Some("yaml") | Some("yml") => Ok(Self::Yaml),
+            Some("toml") => Ok(Self::Toml),
+            Some("json") => Ok(Self::Json),
+            _ => anyhow::bail!(
+                "Unsupported config file '{}': expected a .yaml, .yml, .toml or .json extension",
+                path.display()
+            ),
+        }
+    }
+}
+
 /// Trait for format-specific configurations
 pub trait FormatConfigTrait {
     fn batch_size(&self) -> usize;
@@ -279,3 +967,172 @@ impl FormatConfigTrait for DefaultFormatConfig {
         self.schema_sample_size
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn sample_config() -> Config {
+        let mut config = Config::default();
+        config.storage.s3.endpoint = Some("http://localhost:9000".to_string());
+        config.formats.csv.delimiter = ";".to_string();
+        config
+            .plugins
+            .plugin_configs
+            .insert("json".to_string(), serde_json::json!({ "batch_size": 512 }));
+        config
+    }
+
+    #[test]
+    fn test_s3_environment_takes_precedence_over_file() {
+        let file = S3Config {
+            region: "eu-west-1".to_string(),
+            endpoint: Some("http://localhost:9000".to_string()),
+            bucket: "data".to_string(),
+            force_path_style: true,
+        };
+
+        assert_eq!(file.clone().with_vars(|_| None), file);
+
+        let env = |name: &str| match name {
+            "AWS_DEFAULT_REGION" => Some("us-west-2".to_string()),
+            "AWS_ENDPOINT" => Some(String::new()),
+            "AWS_S3_FORCE_PATH_STYLE" => Some("false".to_string()),
+            _ => None,
+        };
+        let merged = file.clone().with_vars(env);
+        assert_eq!(merged.region, "us-west-2");
+        assert_eq!(merged.endpoint, file.endpoint);
+        assert!(!merged.force_path_style);
+    }
+
+    #[test]
+    fn test_round_trip_each_file_type() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        let config = sample_config();
+        for name in ["config.yaml", "config.yml", "config.toml", "config.json"] {
+            let path = temp_dir.path().join(name);
+            config.save_to_file(&path)?;
+            assert_eq!(Config::from_file(&path)?, config, "round trip through {}", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_older_configs_fill_in_new_fields() -> anyhow::Result<()> {
+        // Settings added after the first release may be missing from existing files
+        let mut value = serde_json::to_value(Config::default())?;
+        value["storage"]["retry"].as_object_mut().unwrap().remove("backoff_multiplier");
+        value["streaming"].as_object_mut().unwrap().remove("max_in_flight_batches");
+        let storage = value["storage"].as_object_mut().unwrap();
+        for field in ["read_buffer_size", "write_buffer_size", "multipart_threshold", "max_concurrent_requests"] {
+            storage.remove(field);
+        }
+        let config: Config = serde_json::from_value(value)?;
+        assert_eq!(config, Config::default());
+        Ok(())
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_env_overrides_are_merged() -> anyhow::Result<()> {
+        // The only test that touches the process environment
+        std::env::set_var("DT_PROCESSING_NUM_THREADS", "3");
+        std::env::set_var("DT_PARQUET_COMPRESSION", "ZSTD");
+        std::env::set_var("DT_STORAGE_RETRY_MAX_RETRIES", "7");
+        let mut config = Config::default();
+        let result = config.apply_env_overrides();
+        std::env::remove_var("DT_PROCESSING_NUM_THREADS");
+        std::env::remove_var("DT_PARQUET_COMPRESSION");
+        std::env::remove_var("DT_STORAGE_RETRY_MAX_RETRIES");
+        result?;
+
+        let mut expected = Config::default();
+        expected.processing.num_threads = 3;
+        expected.formats.parquet.compression = "zstd".to_string();
+        expected.storage.retry.max_retries = 7;
+        assert_eq!(config, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overrides_parse_each_type() -> anyhow::Result<()> {
+        let mut config = Config::default();
+        config.apply_overrides(vars(&[
+            ("DT_CSV_HAS_HEADER", "false"),
+            ("DT_CSV_DELIMITER", "|"),
+            ("DT_STORAGE_S3_ENDPOINT", "http://localhost:9000"),
+            ("DT_PLUGINS_VERSION_COMPATIBILITY", "Exact"),
+            ("DT_UNRELATED", "ignored"),
+        ]))?;
+        assert!(!config.formats.csv.has_header);
+        assert_eq!(config.formats.csv.delimiter, "|");
+        assert_eq!(config.storage.s3.endpoint.as_deref(), Some("http://localhost:9000"));
+        assert_eq!(config.plugins.version_compatibility, VersionCompatibility::Exact);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_override_is_an_error() {
+        for (name, value) in [
+            ("DT_PROCESSING_NUM_THREADS", "many"),
+            ("DT_PROCESSING_NUM_THREADS", "0"),
+            ("DT_PARQUET_COMPRESSION", "lzma"),
+            ("DT_CSV_DELIMITER", "::"),
+            ("DT_CSV_HAS_HEADER", "maybe"),
+        ] {
+            let err = Config::default().apply_overrides(vars(&[(name, value)])).unwrap_err();
+            assert!(err.to_string().contains(name), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_merge_only_changes_specified_fields() {
+        let mut overrides = PartialConfig::default();
+        overrides.formats.csv.batch_size = Some(4096);
+        overrides.storage.s3.endpoint = Some("http://localhost:9000".to_string());
+        overrides.streaming.max_in_flight_batches = Some(8);
+
+        let mut config = Config::default();
+        config.merge(overrides);
+
+        let mut expected = Config::default();
+        expected.formats.csv.batch_size = 4096;
+        expected.storage.s3.endpoint = Some("http://localhost:9000".to_string());
+        expected.streaming.max_in_flight_batches = 8;
+        assert_eq!(config, expected);
+
+        // An empty override is a no-op
+        config.merge(PartialConfig::default());
+        assert_eq!(config, expected);
+    }
+
+    #[test]
+    fn test_builder_layers_overrides() -> anyhow::Result<()> {
+        let file_layer: PartialConfig = serde_json::from_str(
+            r#"{ "formats": { "parquet": { "compression": "gzip" } }, "processing": { "num_threads": 2 } }"#,
+        )?;
+        let config = Config::builder()
+            .merge(file_layer)
+            .num_threads(6)
+            .s3_bucket("data")
+            .build();
+
+        assert_eq!(config.formats.parquet.compression, "gzip");
+        assert_eq!(config.processing.num_threads, 6);
+        assert_eq!(config.storage.s3.bucket, "data");
+        assert_eq!(config.formats.csv, Config::default().formats.csv);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_extension_is_rejected() {
+        let err = Config::default().save_to_file("config.ini").unwrap_err();
+        assert!(err.to_string().contains(".yaml, .yml, .toml or .json"));
+        assert!(Config::from_file("config").is_err());
+    }
+}
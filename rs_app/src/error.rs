@@ -0,0 +1,222 @@
+use arrow::error::ArrowError;
+use datafusion::error::DataFusionError;
+use parquet::errors::ParquetError;
+
+use crate::schema::SchemaDiff;
+
+/// Errors returned by the format and storage traits
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Inputs or batches whose schemas don't line up
+    #[error("Schema mismatch: {0}")]
+    SchemaMismatch(String),
+    /// A schema that differs from the expected one, with the differing fields
+    #[error("Schema mismatch: {context}:\n{diff}")]
+    SchemaDiff { context: String, diff: SchemaDiff },
+    /// A file format this build can't read or write
+    #[error("Unsupported format: {0}")]
+    UnsupportedFormat(String),
+    /// Input that can't be decoded, such as a malformed value
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    /// A zero-byte input, or one without rows to infer a schema from
+    #[error("Empty input: {0}")]
+    EmptyInput(String),
+    /// An output object that exists and may not be replaced
+    #[error("Output already exists: {0}")]
+    OutputExists(String),
+    /// A setting with an invalid value
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    /// A storage backend failed or rejected a path
+    #[error("Storage error: {0}")]
+    Storage(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] ParquetError),
+    #[error("CSV error: {0}")]
+    Csv(String),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Arrow error: {0}")]
+    Arrow(ArrowError),
+    #[error("DataFusion error: {0}")]
+    DataFusion(DataFusionError),
+    /// An operation that didn't finish in time
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// The operation was cancelled through its cancellation token
+    #[error("Operation was cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// A storage error with only a message
+    pub fn storage(message: impl Into<String>) -> Self {
+        Error::Storage(message.into().into())
+    }
+
+    /// A schema mismatch described by `context`, keeping the field-level diff
+    /// when `source` carries one
+    pub fn schema_mismatch(context: impl Into<String>, source: anyhow::Error) -> Self {
+        match source.downcast::<SchemaDiff>() {
+            Ok(diff) => Error::SchemaDiff {
+                context: context.into(),
+                diff,
+            },
+            Err(e) => Error::SchemaMismatch(format!("{}: {}", context.into(), e)),
+        }
+    }
+
+    /// Whether the error reports a missing object or file
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Error::Storage(source) => matches!(
+                source.downcast_ref::<object_store::Error>(),
+                Some(object_store::Error::NotFound { .. })
+            ),
+            Error::Io(e) => e.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    /// Short name of the variant, used to label error metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::SchemaMismatch(_) | Error::SchemaDiff { .. } => "schema_mismatch",
+            Error::UnsupportedFormat(_) => "unsupported_format",
+            Error::InvalidInput(_) => "invalid_input",
+            Error::EmptyInput(_) => "empty_input",
+            Error::OutputExists(_) => "output_exists",
+            Error::Config(_) => "config",
+            Error::Storage(_) => "storage",
+            Error::Url(_) => "url",
+            Error::Io(_) => "io",
+            Error::Parquet(_) => "parquet",
+            Error::Csv(_) => "csv",
+            Error::Json(_) => "json",
+            Error::Arrow(_) => "arrow",
+            Error::DataFusion(_) => "datafusion",
+            Error::Timeout(_) => "timeout",
+            Error::Cancelled => "cancelled",
+            Error::Other(_) => "other",
+        }
+    }
+}
+
+impl From<object_store::Error> for Error {
+    fn from(e: object_store::Error) -> Self {
+        Error::Storage(Box::new(e))
+    }
+}
+
+impl From<ArrowError> for Error {
+    fn from(e: ArrowError) -> Self {
+        match e {
+            ArrowError::CsvError(message) => Error::Csv(message),
+            e => Error::Arrow(e),
+        }
+    }
+}
+
+/// Unwraps errors of this crate that DataFusion passed through as external errors
+impl From<DataFusionError> for Error {
+    fn from(e: DataFusionError) -> Self {
+        match e {
+            DataFusionError::External(e) => match e.downcast::<Error>() {
+                Ok(e) => *e,
+                Err(e) => Error::DataFusion(DataFusionError::External(e)),
+            },
+            e => Error::DataFusion(e),
+        }
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Csv(e.to_string())
+    }
+}
+
+impl From<std::convert::Infallible> for Error {
+    fn from(e: std::convert::Infallible) -> Self {
+        match e {}
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for Error {
+    fn from(e: tokio::time::error::Elapsed) -> Self {
+        Error::Timeout(e.to_string())
+    }
+}
+
+/// Keeps the variant of errors that passed through `anyhow` on the way here
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{CsvFormat, DataFormat, EmptyInputPolicy, ParquetFormat};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_error_kinds() {
+        let parquet = ParquetFormat::default().read(&Bytes::from("not parquet"));
+        assert!(matches!(parquet, Err(Error::Parquet(_))));
+
+        let csv = CsvFormat::default().read(&Bytes::from_static(b"a,b\n1,\xff\n"));
+        assert!(matches!(csv, Err(Error::Csv(_))), "{:?}", csv.err());
+
+        let empty = crate::formats::empty_input(EmptyInputPolicy::Error, "file:///empty.csv");
+        assert!(matches!(empty, Err(Error::EmptyInput(_))));
+
+        let scheme = crate::storage::from_url("ftp://host/file.csv");
+        assert!(matches!(scheme, Err(Error::Storage(_))));
+
+        let codec = crate::formats::parse_compression("zip");
+        assert!(matches!(codec, Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_anyhow_round_trip_keeps_variant() {
+        let error: anyhow::Error = Error::SchemaMismatch("a vs b".to_string()).into();
+        assert!(matches!(Error::from(error), Error::SchemaMismatch(_)));
+
+        let error = anyhow::anyhow!("something else");
+        assert!(matches!(Error::from(error), Error::Other(_)));
+    }
+
+    #[test]
+    fn test_schema_mismatch_keeps_diff() {
+        use arrow::datatypes::{DataType, Field, Schema};
+
+        let expected = Schema::new(vec![Field::new("id", DataType::Int64, false)]);
+        let actual = Schema::new(vec![Field::new("id", DataType::Utf8, false)]);
+        let diff = crate::schema::schema_diff(&expected, &actual, false).unwrap();
+
+        let error = Error::schema_mismatch("Schema of b.csv does not match", diff.clone().into());
+        assert!(matches!(&error, Error::SchemaDiff { diff: d, .. } if *d == diff));
+        assert_eq!(error.kind(), "schema_mismatch");
+        assert_eq!(
+            error.to_string(),
+            "Schema mismatch: Schema of b.csv does not match:\nfield 0 'id': expected Int64 (non-nullable), found Utf8 (non-nullable)"
+        );
+
+        let error = Error::schema_mismatch("Schema of b.csv does not match", anyhow::anyhow!("bad nulls"));
+        assert!(matches!(error, Error::SchemaMismatch(_)));
+    }
+}
@@ -18,19 +18,36 @@ use datafusion::physical_plan::{
 use futures::stream::Stream;
 use futures::ready;
 
+use crate::formats::BatchStream;
+
+/// Opens a fresh stream over one partition of the scanned data each time
+/// that partition is executed
+pub type BatchSource = Arc<dyn Fn(usize) -> BatchStream + Send + Sync>;
+
+/// Scans batches from a format, applying filters, projection and limit.
+///
+/// Filters are evaluated against the full table schema before projecting.
+/// Each of the plan's partitions is a separate stream from the source, and
+/// the limit applies to each partition on its own.
 pub struct FormatExecPlan {
-    stream: Pin<Box<dyn Stream<Item = Result<RecordBatch, anyhow::Error>> + Send + Sync + 'static>>,
+    source: BatchSource,
+    partitions: usize,
     schema: SchemaRef,
+    projected_schema: SchemaRef,
     projection: Option<Vec<usize>>,
     filters: Vec<Arc<dyn PhysicalExpr>>,
     limit: Option<usize>,
+    /// Statistics of the unfiltered table
+    statistics: Statistics,
 }
 
 impl std::fmt::Debug for FormatExecPlan {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("FormatExecPlan")
             .field("schema", &self.schema)
+            .field("partitions", &self.partitions)
             .field("projection", &self.projection)
+            .field("filters", &self.filters)
             .field("limit", &self.limit)
             .finish()
     }
@@ -38,19 +55,39 @@ impl std::fmt::Debug for FormatExecPlan {
 
 impl FormatExecPlan {
     pub fn new(
-        stream: Pin<Box<dyn Stream<Item = Result<RecordBatch, anyhow::Error>> + Send + Sync + 'static>>,
+        source: BatchSource,
         schema: SchemaRef,
         projection: Option<Vec<usize>>,
         filters: Vec<Arc<dyn PhysicalExpr>>,
         limit: Option<usize>,
-    ) -> Self {
-        Self {
-            stream,
+    ) -> Result<Self> {
+        let projected_schema = match &projection {
+            Some(indices) => Arc::new(schema.project(indices)?),
+            None => schema.clone(),
+        };
+        Ok(Self {
+            source,
+            partitions: 1,
             schema,
+            projected_schema,
             projection,
             filters,
             limit,
-        }
+            statistics: Statistics::default(),
+        })
+    }
+
+    /// Split the scan into `partitions` streams, which DataFusion may run in
+    /// parallel; the source is called with each index below `partitions`
+    pub fn with_partitions(mut self, partitions: usize) -> Self {
+        self.partitions = partitions.max(1);
+        self
+    }
+
+    /// Statistics of the whole table, before filters, projection and limit
+    pub fn with_statistics(mut self, statistics: Statistics) -> Self {
+        self.statistics = statistics;
+        self
     }
 }
 
@@ -60,11 +97,11 @@ impl ExecutionPlan for FormatExecPlan {
     }
 
     fn schema(&self) -> SchemaRef {
-        self.schema.clone()
+        self.projected_schema.clone()
     }
 
     fn output_partitioning(&self) -> Partitioning {
-        Partitioning::UnknownPartitioning(1)
+        Partitioning::UnknownPartitioning(self.partitions)
     }
 
     fn output_ordering(&self) -> Option<&[datafusion::physical_expr::PhysicalSortExpr]> {
@@ -87,15 +124,16 @@ impl ExecutionPlan for FormatExecPlan {
         partition: usize,
         _context: Arc<TaskContext>,
     ) -> Result<SendableRecordBatchStream, DataFusionError> {
-        if partition != 0 {
+        if partition >= self.partitions {
             return Err(DataFusionError::Internal(format!(
-                "Invalid partition {partition}"
+                "Invalid partition {partition} of {}",
+                self.partitions
             )));
         }
 
         Ok(Box::pin(FormatStream {
-            schema: self.schema.clone(),
-            stream: Box::pin(futures::stream::once(futures::future::ready(Ok(RecordBatch::new_empty(self.schema.clone()))))),
+            schema: self.projected_schema.clone(),
+            stream: (self.source)(partition),
             projection: self.projection.clone(),
             filters: self.filters.clone(),
             limit: self.limit,
@@ -103,8 +141,21 @@ impl ExecutionPlan for FormatExecPlan {
         }))
     }
 
+    /// The table's statistics narrowed to the projection; filters and the
+    /// limit make them an estimate
     fn statistics(&self) -> Statistics {
-        Statistics::default()
+        let mut statistics = self.statistics.clone();
+        if let (Some(indices), Some(columns)) = (&self.projection, &statistics.column_statistics) {
+            statistics.column_statistics = Some(indices.iter().map(|&i| columns[i].clone()).collect());
+        }
+        if !self.filters.is_empty() {
+            statistics.is_exact = false;
+        }
+        if let Some(limit) = self.limit {
+            statistics.num_rows = Some(statistics.num_rows.map_or(limit, |rows| rows.min(limit)));
+            statistics.is_exact = false;
+        }
+        statistics
     }
 }
 
@@ -112,7 +163,13 @@ impl DisplayAs for FormatExecPlan {
     fn fmt_as(&self, t: DisplayFormatType, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match t {
             DisplayFormatType::Default | DisplayFormatType::Verbose => {
-                write!(f, "FormatExecPlan")
+                write!(
+                    f,
+                    "FormatExecPlan: partitions={}, filters={}, limit={:?}",
+                    self.partitions,
+                    self.filters.len(),
+                    self.limit
+                )
             }
         }
     }
@@ -120,13 +177,47 @@ impl DisplayAs for FormatExecPlan {
 
 pub struct FormatStream {
     schema: SchemaRef,
-    stream: Pin<Box<dyn Stream<Item = Result<RecordBatch, anyhow::Error>> + Send + Sync + 'static>>,
+    stream: BatchStream,
     projection: Option<Vec<usize>>,
     filters: Vec<Arc<dyn PhysicalExpr>>,
     limit: Option<usize>,
+    /// Rows returned so far
     count: usize,
 }
 
+impl FormatStream {
+    fn apply(&self, batch: RecordBatch) -> Result<RecordBatch, DataFusionError> {
+        // Apply filters
+        let mut filtered_batch = batch;
+        for filter in &self.filters {
+            let mask = filter
+                .evaluate(&filtered_batch)?
+                .into_array(filtered_batch.num_rows());
+            let mask_array = mask
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .ok_or_else(|| {
+                    DataFusionError::Internal("Filter did not evaluate to boolean".to_string())
+                })?;
+            filtered_batch = filter_record_batch(&filtered_batch, mask_array)?;
+        }
+
+        // Apply projection if needed
+        let projected_batch = match &self.projection {
+            Some(indices) => filtered_batch.project(indices)?,
+            None => filtered_batch,
+        };
+
+        // Truncate the batch that crosses the limit
+        Ok(match self.limit {
+            Some(limit) if self.count + projected_batch.num_rows() > limit => {
+                projected_batch.slice(0, limit - self.count)
+            }
+            _ => projected_batch,
+        })
+    }
+}
+
 impl Stream for FormatStream {
     type Item = Result<RecordBatch, DataFusionError>;
 
@@ -140,37 +231,11 @@ impl Stream for FormatStream {
         let batch = ready!(self.stream.as_mut().poll_next(cx));
         Poll::Ready(match batch {
             Some(Ok(batch)) => {
-                // Apply filters
-                let mut filtered_batch = batch;
-                for filter in &self.filters {
-                    let mask = filter
-                        .evaluate(&filtered_batch)
-                        .map_err(|e| DataFusionError::Internal(e.to_string()))?
-                        .into_array(filtered_batch.num_rows());
-                    let mask_array = mask
-                        .as_any()
-                        .downcast_ref::<BooleanArray>()
-                        .ok_or_else(|| {
-                            DataFusionError::Internal("Filter did not evaluate to boolean".to_string())
-                        })?;
-                    filtered_batch = filter_record_batch(&filtered_batch, mask_array)
-                        .map_err(|e| DataFusionError::Internal(e.to_string()))?;
-                }
-
-                // Apply projection if needed
-                let projected_batch = if let Some(ref indices) = self.projection {
-                    RecordBatch::try_new(
-                        Arc::new(filtered_batch.schema().project(indices).unwrap()),
-                        indices.iter().map(|&i| filtered_batch.column(i).clone()).collect(),
-                    ).map_err(|e| DataFusionError::Internal(e.to_string()))?
-                } else {
-                    filtered_batch
-                };
-
-                self.count += 1;
-                Some(Ok(projected_batch))
+                let batch = self.apply(batch)?;
+                self.count += batch.num_rows();
+                Some(Ok(batch))
             }
-            Some(Err(e)) => Some(Err(DataFusionError::Internal(e.to_string()))),
+            Some(Err(e)) => Some(Err(DataFusionError::External(e.into()))),
             None => None,
         })
     }
@@ -181,3 +246,67 @@ impl RecordBatchStream for FormatStream {
         self.schema.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::prelude::SessionContext;
+    use futures::{StreamExt, TryStreamExt};
+
+    #[tokio::test]
+    async fn test_limit_counts_rows() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from_iter_values(0..10))])?;
+        let source: BatchSource = Arc::new(move |_: usize| {
+            let batches = vec![Ok(batch.clone()), Ok(batch.clone()), Ok(batch.clone())];
+            futures::stream::iter(batches).boxed()
+        });
+
+        let plan = FormatExecPlan::new(source, schema, None, Vec::new(), Some(3))?;
+        let batches: Vec<RecordBatch> = plan.execute(0, SessionContext::new().task_ctx())?.try_collect().await?;
+        let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+        assert_eq!(rows, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parquet_plan_has_statistics() -> Result<()> {
+        use crate::formats::{DataFormat, ParquetFormat};
+        use crate::storage::local::LocalStorage;
+        use crate::storage::Storage;
+        use crate::table_provider::FormatTableProvider;
+        use datafusion::datasource::TableProvider;
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("v", DataType::Int64, false),
+            Field::new("w", DataType::Int64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from_iter_values(5..15)),
+                Arc::new(Int64Array::from_iter((0..10).map(|i| (i % 2 == 1).then_some(i)))),
+            ],
+        )?;
+        let format = ParquetFormat::default();
+        let path = temp_dir.path().join("data.parquet");
+        std::fs::write(&path, format.write_batch(&batch)?)?;
+        let url = url::Url::from_file_path(&path).unwrap();
+
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let provider = FormatTableProvider::new(Arc::new(format), storage, vec![url], schema);
+        let ctx = SessionContext::new();
+        let plan = provider.scan(&ctx.state(), Some(&vec![1]), &[], None).await?;
+
+        let statistics = plan.statistics();
+        assert_eq!(statistics.num_rows, Some(10));
+        assert!(statistics.is_exact);
+        let columns = statistics.column_statistics.expect("column statistics");
+        assert_eq!(columns.len(), 1);
+        assert_eq!(columns[0].null_count, Some(5));
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use arrow::datatypes::{DataType, Schema};
+
+/// Bytes assumed per value of a string or binary column, whose width the
+/// schema doesn't tell
+const VARIABLE_WIDTH_BYTES: usize = 32;
+/// Items assumed per value of a list or map column
+const LIST_LENGTH: usize = 4;
+
+/// Picks the rows per batch from the width of a schema's rows, so that a
+/// batch of narrow rows isn't needlessly small and a batch of wide rows
+/// doesn't take a lot of memory.
+///
+/// The width is estimated from the column types alone, see
+/// [`estimated_row_bytes`], so it is known as soon as the schema is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdaptiveBatchSize {
+    /// Decoded bytes each batch should hold
+    pub target_bytes: usize,
+    /// Fewest rows per batch, however wide the rows
+    pub min_rows: usize,
+    /// Most rows per batch, however narrow the rows
+    pub max_rows: usize,
+}
+
+impl Default for AdaptiveBatchSize {
+    fn default() -> Self {
+        Self {
+            target_bytes: 8 * 1024 * 1024,
+            min_rows: 64,
+            max_rows: 64 * 1024,
+        }
+    }
+}
+
+impl AdaptiveBatchSize {
+    /// Rows per batch for `schema`: `target_bytes` divided by the estimated
+    /// row width, clamped to `min_rows..=max_rows`
+    pub fn rows_for(&self, schema: &Schema) -> usize {
+        let min_rows = self.min_rows.max(1);
+        let rows = self.target_bytes / estimated_row_bytes(schema).max(1);
+        rows.clamp(min_rows, self.max_rows.max(min_rows))
+    }
+}
+
+/// Rows per batch for `schema`: chosen by `adaptive` when it is set, otherwise `batch_size`
+pub fn batch_size_for(schema: &Schema, batch_size: usize, adaptive: Option<AdaptiveBatchSize>) -> usize {
+    match adaptive {
+        Some(adaptive) => adaptive.rows_for(schema),
+        None => batch_size.max(1),
+    }
+}
+
+/// Estimated decoded size in bytes of one row of `schema`.
+///
+/// Fixed-width columns count their width; strings and binaries count their
+/// offset and an assumed average length, and lists an assumed number of items.
+pub fn estimated_row_bytes(schema: &Schema) -> usize {
+    schema.fields().iter().map(|field| estimated_value_bytes(field.data_type())).sum()
+}
+
+fn estimated_value_bytes(data_type: &DataType) -> usize {
+    match data_type {
+        DataType::Null => 0,
+        DataType::Boolean => 1,
+        DataType::Utf8 | DataType::Binary => 4 + VARIABLE_WIDTH_BYTES,
+        DataType::LargeUtf8 | DataType::LargeBinary => 8 + VARIABLE_WIDTH_BYTES,
+        DataType::FixedSizeBinary(width) => (*width).max(0) as usize,
+        DataType::List(field) | DataType::Map(field, _) => 4 + LIST_LENGTH * estimated_value_bytes(field.data_type()),
+        DataType::LargeList(field) => 8 + LIST_LENGTH * estimated_value_bytes(field.data_type()),
+        DataType::FixedSizeList(field, length) => (*length).max(0) as usize * estimated_value_bytes(field.data_type()),
+        DataType::Struct(fields) => fields.iter().map(|field| estimated_value_bytes(field.data_type())).sum(),
+        DataType::Dictionary(key, _) => estimated_value_bytes(key),
+        data_type => data_type.primitive_width().unwrap_or(8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::datatypes::Field;
+
+    #[test]
+    fn test_wide_rows_get_smaller_batches() {
+        let narrow = Schema::new(vec![Field::new("id", DataType::Int64, false), Field::new("flag", DataType::Boolean, true)]);
+        let wide = Schema::new(
+            (0..200)
+                .map(|i| Field::new(format!("column_{}", i), if i % 2 == 0 { DataType::Utf8 } else { DataType::Float64 }, true))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(estimated_row_bytes(&narrow), 9);
+        assert_eq!(estimated_row_bytes(&wide), 100 * 36 + 100 * 8);
+
+        let adaptive = AdaptiveBatchSize {
+            target_bytes: 1024 * 1024,
+            min_rows: 16,
+            max_rows: 1_000_000,
+        };
+        let (narrow_rows, wide_rows) = (adaptive.rows_for(&narrow), adaptive.rows_for(&wide));
+        assert!(wide_rows < narrow_rows, "{} vs {}", wide_rows, narrow_rows);
+        assert_eq!(wide_rows, 1024 * 1024 / 4400);
+
+        // Clamped at both ends
+        assert_eq!(AdaptiveBatchSize { max_rows: 1000, ..adaptive }.rows_for(&narrow), 1000);
+        assert_eq!(AdaptiveBatchSize { min_rows: 500, ..adaptive }.rows_for(&wide), 500);
+        assert_eq!(batch_size_for(&wide, 1024, None), 1024);
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::Read;
+
+use bytes::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use super::DataStream;
+use crate::error::{Error, Result};
+
+/// Bytes decompressed per chunk of a decompressed stream
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Decompressed chunks buffered ahead of the reader
+const CHUNK_CHANNEL_CAPACITY: usize = 4;
+
+/// Wraps a reader of compressed bytes in one that yields the decompressed bytes
+type Decoder = fn(Box<dyn Read + Send>) -> std::io::Result<Box<dyn Read + Send>>;
+
+/// File extensions and names of the compressions text inputs may use; a row
+/// here is all it takes to read another one
+const CODECS: &[(&[&str], Decoder)] = &[
+    (&["gz", "gzip"], |reader| Ok(Box::new(flate2::read::MultiGzDecoder::new(reader)))),
+    (&["zst", "zstd"], |reader| Ok(Box::new(zstd::stream::read::Decoder::new(reader)?))),
+];
+
+fn decoder(ext_or_codec: &str) -> Option<Decoder> {
+    let name = super::normalize_extension(ext_or_codec);
+    CODECS
+        .iter()
+        .find(|(names, _)| names.contains(&name.as_str()))
+        .map(|(_, decoder)| *decoder)
+}
+
+fn unknown_codec(ext_or_codec: &str) -> Error {
+    Error::UnsupportedFormat(format!("Unknown compression '{}'", ext_or_codec))
+}
+
+/// The compression extension `path` ends with, such as `gz` for `data.json.gz`
+pub fn compression_extension(path: &str) -> Option<&str> {
+    let (_, extension) = path.rsplit_once('.')?;
+    decoder(extension).map(|_| extension)
+}
+
+/// `path` without its compression extension, so `data.json.gz` becomes
+/// `data.json`; other paths are returned unchanged
+pub fn strip_compression_extension(path: &str) -> &str {
+    match compression_extension(path) {
+        Some(extension) => &path[..path.len() - extension.len() - 1],
+        None => path,
+    }
+}
+
+/// Decompress `data` compressed with `ext_or_codec`, a file extension such
+/// as `gz` or a codec name such as `zstd`
+pub fn decompress(ext_or_codec: &str, data: Bytes) -> Result<Bytes> {
+    let decoder = decoder(ext_or_codec).ok_or_else(|| unknown_codec(ext_or_codec))?;
+    let mut decompressed = Vec::new();
+    decoder(Box::new(std::io::Cursor::new(data)))
+        .and_then(|mut reader| reader.read_to_end(&mut decompressed))
+        .map_err(|e| Error::InvalidInput(format!("Failed to decompress {} data: {}", ext_or_codec, e)))?;
+    Ok(decompressed.into())
+}
+
+/// Decompress `stream` as it is read, like [`decompress`].
+///
+/// Decoding runs on a blocking thread and at most a few chunks are buffered,
+/// so memory use does not grow with the size of the input.
+pub fn decompress_stream(ext_or_codec: &str, stream: DataStream) -> Result<DataStream> {
+    let decoder = decoder(ext_or_codec).ok_or_else(|| unknown_codec(ext_or_codec))?;
+    let codec = ext_or_codec.to_string();
+    let reader = SyncIoBridge::new(StreamReader::new(
+        stream.map_err(std::io::Error::other),
+    ));
+    let (sender, receiver) = tokio::sync::mpsc::channel(CHUNK_CHANNEL_CAPACITY);
+    tokio::task::spawn_blocking(move || {
+        let decoded = (|| -> std::io::Result<()> {
+            let mut reader = decoder(Box::new(reader))?;
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let read = reader.read(&mut buf)?;
+                // Stop early when the reader has been dropped
+                if read == 0 || sender.blocking_send(Ok(Bytes::copy_from_slice(&buf[..read]))).is_err() {
+                    return Ok(());
+                }
+            }
+        })();
+        if let Err(e) = decoded {
+            let error = Error::InvalidInput(format!("Failed to decompress {} data: {}", codec, e));
+            let _ = sender.blocking_send(Err(error));
+        }
+    });
+    Ok(futures::stream::unfold(receiver, |mut receiver: tokio::sync::mpsc::Receiver<Result<Bytes>>| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{DataFormat, JsonFormat};
+    use crate::storage::local::LocalStorage;
+    use crate::storage::Storage;
+    use std::io::Write;
+    use std::sync::Arc;
+    use url::Url;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_decompress_by_extension_or_codec() -> Result<()> {
+        let data = b"id,name\n1,a\n";
+        assert_eq!(decompress("gz", gzip(data).into())?, &data[..]);
+        assert_eq!(decompress("zstd", zstd::encode_all(&data[..], 3)?.into())?, &data[..]);
+        assert!(decompress("lzo", Bytes::from_static(data)).is_err());
+        assert!(decompress("gz", Bytes::from_static(data)).is_err());
+
+        assert_eq!(compression_extension("s3://bucket/data.json.GZ"), Some("GZ"));
+        assert_eq!(strip_compression_extension("/data/events.ndjson.zst"), "/data/events.ndjson");
+        assert_eq!(strip_compression_extension("/data/events.ndjson"), "/data/events.ndjson");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_zstd_ndjson() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let lines: String = (0..2500).map(|i| format!("{{\"id\": {}, \"name\": \"n{}\"}}\n", i, i)).collect();
+        let path = temp_dir.path().join("events.ndjson.zst");
+        std::fs::write(&path, zstd::encode_all(lines.as_bytes(), 3)?)?;
+
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let url = Url::from_file_path(&path).unwrap();
+        let size = storage.size(&url).await?;
+        let batches: Vec<_> = JsonFormat::default()
+            .read_batches_from_storage(storage, &url, size)
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(batches.iter().map(|batch| batch.num_rows()).sum::<usize>(), 2500);
+        assert_eq!(batches[0].num_columns(), 2);
+        Ok(())
+    }
+}
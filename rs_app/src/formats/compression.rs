@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arrow::array::Int32Array;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{BrotliLevel, Compression, GzipLevel, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+
+use crate::error::{Error, Result};
+
+/// Compression codec names and the `parquet` crate feature that provides each one
+const COMPRESSION_CODECS: &[(&str, Option<&str>)] = &[
+    ("uncompressed", None),
+    ("none", None),
+    ("snappy", Some("snap")),
+    ("gzip", Some("flate2")),
+    ("brotli", Some("brotli")),
+    ("lz4", Some("lz4")),
+    ("lz4_raw", Some("lz4")),
+    ("zstd", Some("zstd")),
+];
+
+/// Split `zstd(9)` into the codec name and its level
+fn split_level(spec: &str) -> Result<(String, Option<&str>)> {
+    let spec = spec.trim();
+    match spec.split_once('(') {
+        Some((name, rest)) => {
+            let level = rest
+                .strip_suffix(')')
+                .ok_or_else(|| Error::Config(format!("Invalid Parquet compression '{}': missing ')'", spec)))?;
+            Ok((name.trim().to_lowercase(), Some(level.trim())))
+        }
+        None => Ok((spec.to_lowercase(), None)),
+    }
+}
+
+fn is_builtin(name: &str) -> bool {
+    COMPRESSION_CODECS.iter().any(|(codec, _)| *codec == name)
+}
+
+/// The built-in name of the codec `compression` uses
+fn codec_name(compression: Compression) -> &'static str {
+    match compression {
+        Compression::UNCOMPRESSED => "uncompressed",
+        Compression::SNAPPY => "snappy",
+        Compression::GZIP(_) => "gzip",
+        Compression::LZO => "lzo",
+        Compression::BROTLI(_) => "brotli",
+        Compression::LZ4 => "lz4",
+        Compression::ZSTD(_) => "zstd",
+        Compression::LZ4_RAW => "lz4_raw",
+    }
+}
+
+/// Additional names that resolve to a compression, such as `fast` for LZ4
+pub struct CompressionRegistry {
+    codecs: HashMap<String, Compression>,
+}
+
+impl CompressionRegistry {
+    fn new() -> Self {
+        Self { codecs: HashMap::new() }
+    }
+
+    /// Resolve `name`, case-insensitively, to `compression`; the built-in
+    /// codec names can't be redefined
+    pub fn register(&mut self, name: &str, compression: Compression) -> Result<()> {
+        let name = name.trim().to_lowercase();
+        if is_builtin(&name) {
+            return Err(Error::Config(format!("'{}' is a built-in compression codec and can't be registered", name)));
+        }
+        if name.is_empty() || name.contains(['(', ')']) {
+            return Err(Error::Config(format!("Invalid compression name '{}'", name)));
+        }
+        self.codecs.insert(name, compression);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Compression> {
+        self.codecs.get(&name.trim().to_lowercase()).copied()
+    }
+
+    /// Registered names, sorted
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.codecs.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+static COMPRESSION_REGISTRY: Lazy<RwLock<CompressionRegistry>> = Lazy::new(|| RwLock::new(CompressionRegistry::new()));
+
+/// Make `name` usable wherever a compression codec is configured, e.g.
+/// `register_compression("fast", Compression::LZ4)`
+pub fn register_compression(name: &str, compression: Compression) -> Result<()> {
+    COMPRESSION_REGISTRY.write().register(name, compression)
+}
+
+/// Parse a compression codec such as `snappy` or `zstd`, optionally with a
+/// level for the codecs that have one: `zstd(9)`, `gzip(6)`, `brotli(5)`.
+/// Names added with `register_compression` are resolved as well.
+pub fn parse_compression(spec: &str) -> Result<Compression> {
+    let (name, level) = split_level(spec)?;
+    let registered = COMPRESSION_REGISTRY.read().get(&name);
+    if let Some(compression) = registered {
+        if level.is_some() {
+            return Err(Error::Config(format!("Compression '{}' does not take a level", name)));
+        }
+        return Ok(compression);
+    }
+    let invalid_level = |level: &str| Error::Config(format!("Invalid compression level '{}' in '{}'", level, spec));
+    let parse_level = |level: &str| -> Result<i64> { level.parse().map_err(|_| invalid_level(level)) };
+    let compression = match (name.as_str(), level) {
+        ("uncompressed" | "none", None) => Compression::UNCOMPRESSED,
+        ("snappy", None) => Compression::SNAPPY,
+        ("lz4", None) => Compression::LZ4,
+        ("lz4_raw", None) => Compression::LZ4_RAW,
+        ("gzip", None) => Compression::GZIP(GzipLevel::default()),
+        ("gzip", Some(level)) => Compression::GZIP(GzipLevel::try_new(
+            u32::try_from(parse_level(level)?).map_err(|_| invalid_level(level))?,
+        )?),
+        ("brotli", None) => Compression::BROTLI(BrotliLevel::default()),
+        ("brotli", Some(level)) => Compression::BROTLI(BrotliLevel::try_new(
+            u32::try_from(parse_level(level)?).map_err(|_| invalid_level(level))?,
+        )?),
+        ("zstd", None) => Compression::ZSTD(ZstdLevel::default()),
+        ("zstd", Some(level)) => Compression::ZSTD(ZstdLevel::try_new(
+            i32::try_from(parse_level(level)?).map_err(|_| invalid_level(level))?,
+        )?),
+        (name, Some(_)) if is_builtin(name) => {
+            return Err(Error::Config(format!("Parquet compression codec '{}' does not take a level", name)));
+        }
+        _ => {
+            let mut names: Vec<String> = COMPRESSION_CODECS.iter().map(|(name, _)| name.to_string()).collect();
+            names.extend(COMPRESSION_REGISTRY.read().names());
+            return Err(Error::Config(format!(
+                "Unknown Parquet compression codec '{}'; expected one of {}",
+                spec,
+                names.join(", ")
+            )));
+        }
+    };
+    Ok(compression)
+}
+
+/// Whether `compression` can be written by this build, found by writing a one-row file
+fn codec_is_available(compression: Compression) -> bool {
+    let schema = Arc::new(Schema::new(vec![Field::new("value", DataType::Int32, false)]));
+    let batch = match RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![1]))]) {
+        Ok(batch) => batch,
+        Err(_) => return false,
+    };
+    let props = WriterProperties::builder().set_compression(compression).build();
+    match ArrowWriter::try_new(Vec::new(), schema, Some(props)) {
+        Ok(mut writer) => writer.write(&batch).is_ok() && writer.close().is_ok(),
+        Err(_) => false,
+    }
+}
+
+static SUPPORTED_COMPRESSIONS: Lazy<Vec<&'static str>> = Lazy::new(|| {
+    COMPRESSION_CODECS
+        .iter()
+        .filter(|(name, _)| parse_compression(name).map(codec_is_available).unwrap_or(false))
+        .map(|(name, _)| *name)
+        .collect()
+});
+
+/// Built-in compression codec names this build can write
+pub fn supported_compressions() -> Vec<&'static str> {
+    SUPPORTED_COMPRESSIONS.clone()
+}
+
+/// Check that `spec` names a known codec that is among `supported`,
+/// explaining which `parquet` feature enables it otherwise. A registered
+/// name is checked by the codec it resolves to.
+pub fn check_compression_supported(spec: &str, supported: &[&str]) -> Result<()> {
+    let name = codec_name(parse_compression(spec)?);
+    if supported.contains(&name) {
+        return Ok(());
+    }
+    let feature = COMPRESSION_CODECS
+        .iter()
+        .find(|(codec, _)| *codec == name)
+        .and_then(|(_, feature)| *feature);
+    Err(Error::Config(match feature {
+        Some(feature) => format!(
+            "{} compression requested but not enabled; rebuild with the `{}` feature of the parquet crate",
+            name,
+            feature
+        ),
+        None => format!("{} compression is not supported by this build", name),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{DataFormat, ParquetConfig, ParquetFormat};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    #[test]
+    fn test_unavailable_codec_names_its_feature() {
+        assert!(supported_compressions().contains(&"uncompressed"));
+        assert!(check_compression_supported("snappy", &["uncompressed", "snappy"]).is_ok());
+
+        let err = check_compression_supported("zstd", &["uncompressed", "snappy"]).unwrap_err();
+        assert!(err.to_string().contains("`zstd` feature"), "{}", err);
+        assert!(check_compression_supported("lzma", &["lzma"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_compression_levels() -> Result<()> {
+        assert_eq!(parse_compression("zstd")?, Compression::ZSTD(ZstdLevel::default()));
+        assert_eq!(parse_compression("ZSTD(9)")?, Compression::ZSTD(ZstdLevel::try_new(9)?));
+        assert_eq!(parse_compression("gzip( 6 )")?, Compression::GZIP(GzipLevel::try_new(6)?));
+        assert_eq!(parse_compression("brotli(5)")?, Compression::BROTLI(BrotliLevel::try_new(5)?));
+        assert!(parse_compression("gzip(99)").is_err());
+        assert!(parse_compression("zstd(fast)").is_err());
+        assert!(parse_compression("snappy(3)").is_err());
+        assert!(parse_compression("zstd(3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_registered_alias_is_used_for_writes() -> Result<()> {
+        register_compression("Fast", Compression::LZ4)?;
+        assert!(register_compression("snappy", Compression::LZ4).is_err());
+        assert_eq!(parse_compression("fast")?, Compression::LZ4);
+        assert!(parse_compression("fast(3)").is_err());
+        // The alias is checked against the codec it stands for
+        assert!(check_compression_supported("fast", &["lz4"]).is_ok());
+        let err = check_compression_supported("fast", &["snappy"]).unwrap_err();
+        assert!(err.to_string().contains("`lz4` feature"), "{}", err);
+        if !supported_compressions().contains(&"lz4") {
+            return Ok(());
+        }
+
+        let schema = Arc::new(Schema::new(vec![Field::new("value", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from_iter_values(0..100))])?;
+        let format = ParquetFormat::new(ParquetConfig {
+            compression: Some("fast".to_string()),
+            ..ParquetConfig::default()
+        });
+        let reader = SerializedFileReader::new(format.write_batch(&batch)?)?;
+        assert_eq!(reader.metadata().row_group(0).column(0).compression(), Compression::LZ4);
+        Ok(())
+    }
+}
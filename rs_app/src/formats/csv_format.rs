@@ -1,18 +1,127 @@
-use anyhow::Result;
-use arrow::csv::{ReaderBuilder, WriterBuilder};
-use arrow::datatypes::{Field, Schema};
+use arrow::array::timezone::Tz;
+use arrow::array::{Array, ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, TimestampNanosecondArray};
+use arrow::csv::WriterBuilder;
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
 use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
 use bytes::Bytes;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone};
 use csv;
 use datafusion::dataframe::DataFrame;
 use datafusion::execution::context::SessionContext;
-use std::io::Cursor;
+use datafusion::physical_plan::Statistics;
+use futures::{StreamExt, TryStreamExt};
+use std::collections::HashMap;
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+use super::line_stream::{chunk_lines, non_empty_lines};
+use super::{
+    already_finalized, apply_read_options, is_auto_delimiter, parse_delimiter, sniff_delimiter, AdaptiveBatchSize,
+    BatchStream, BatchWriter, DataSink, DataStream, FieldSeparator, ReadOptions,
+};
+use crate::error::{Error, Result};
+use crate::storage::Storage;
+
+/// Bytes read from the start of a file to estimate its row count
+const STATS_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Timezone of timestamp columns when `default_timezone` isn't set
+const DEFAULT_TIMEZONE: &str = "UTC";
+
+/// What to do with a row that can't be read: a CSV syntax error, a row with
+/// more fields than the schema (or a different number under
+/// `strict_column_count`), or a value that doesn't parse as its column's type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorMode {
+    /// Abort the read
+    #[default]
+    Fail,
+    /// Drop the row, only counting it in `RejectedRows`
+    Skip,
+    /// Drop the row and keep it, with its line number, in `RejectedRows`
+    Collect,
+}
+
+/// A row dropped under `ErrorMode::Collect`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedRow {
+    /// Line the row starts on, counting from 1 and including the header
+    pub line: u64,
+    /// The row's fields joined by the delimiter; empty when the row couldn't
+    /// be split into fields at all
+    pub record: String,
+    pub reason: String,
+}
+
+/// Rows dropped while reading, shared by every copy of a `CsvConfig`
+#[derive(Debug, Clone, Default)]
+pub struct RejectedRows(Arc<parking_lot::Mutex<(usize, Vec<RejectedRow>)>>);
+
+impl RejectedRows {
+    /// Number of rows dropped so far, under either `Skip` or `Collect`
+    pub fn count(&self) -> usize {
+        self.0.lock().0
+    }
+
+    /// Remove and return the rows collected so far
+    pub fn take(&self) -> Vec<RejectedRow> {
+        std::mem::take(&mut self.0.lock().1)
+    }
+
+    fn reject(&self, mode: ErrorMode, row: RejectedRow) {
+        tracing::debug!(line = row.line, reason = %row.reason, "rejected CSV row");
+        crate::metrics::csv_row_rejected();
+        let mut rejected = self.0.lock();
+        rejected.0 += 1;
+        if mode == ErrorMode::Collect {
+            rejected.1.push(row);
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct CsvConfig {
     pub has_header: bool,
     pub delimiter: u8,
+    /// Splits lines into fields instead of `delimiter` when reading, for
+    /// delimiters longer than a byte; writing still uses `delimiter`
+    pub separator: Option<FieldSeparator>,
+    /// Detect `delimiter` from the first lines of every input with
+    /// `sniff_delimiter`; written output still uses `delimiter`
+    pub detect_delimiter: bool,
+    /// Maximum number of rows per batch when streaming
+    pub batch_size: usize,
+    /// Pick the rows per batch from the width of the inferred schema instead
+    /// of `batch_size` when reading from storage
+    pub adaptive_batch_size: Option<AdaptiveBatchSize>,
+    /// Infer integer, float and boolean columns instead of reading everything as strings
+    pub infer_types: bool,
+    /// Accept `NaN`, `Infinity` and `-Infinity` (and `inf`) as float values
+    pub special_floats: bool,
+    /// Number of rows sampled when inferring column types
+    pub schema_sample_size: usize,
+    /// Bytes fetched from the start of a stored file to infer its schema
+    pub max_sample_bytes: usize,
+    /// Reject rows whose number of fields differs from the header. Otherwise
+    /// the schema grows to the longest row seen while inferring it, with
+    /// `column_N` names for the extra columns, and missing fields are null.
+    pub strict_column_count: bool,
+    /// Types forced on columns by name instead of inferring them; applied
+    /// even without `infer_types`
+    pub column_types: HashMap<String, DataType>,
+    /// `chrono` format strings, e.g. `%Y-%m-%d %H:%M:%S` or `%+` for
+    /// ISO 8601, tried in order. A column whose values all match one of them
+    /// is read as `Timestamp(Nanosecond, tz)`; empty disables timestamp inference.
+    pub timestamp_formats: Vec<String>,
+    /// Timezone of timestamp columns, e.g. `Europe/Berlin` or `+02:00`, and
+    /// the one values without an offset are assumed to be in (default: UTC)
+    pub default_timezone: Option<String>,
+    /// What to do with rows that can't be read, including timestamps that
+    /// match none of `timestamp_formats`
+    pub on_error: ErrorMode,
+    /// Rows dropped under `ErrorMode::Skip` or `ErrorMode::Collect`
+    pub rejected_rows: RejectedRows,
 }
 
 impl Default for CsvConfig {
@@ -20,75 +129,653 @@ impl Default for CsvConfig {
         Self {
             has_header: true,
             delimiter: b',',
+            separator: None,
+            detect_delimiter: false,
+            batch_size: 1024,
+            adaptive_batch_size: None,
+            infer_types: true,
+            special_floats: true,
+            schema_sample_size: 1000,
+            max_sample_bytes: 1024 * 1024,
+            strict_column_count: false,
+            column_types: HashMap::new(),
+            timestamp_formats: Vec::new(),
+            default_timezone: None,
+            on_error: ErrorMode::Fail,
+            rejected_rows: RejectedRows::default(),
         }
     }
 }
 
-pub struct CsvFormat {
-    config: CsvConfig,
-}
+impl CsvConfig {
+    /// Whether string columns are converted to other types after parsing
+    fn converts_types(&self) -> bool {
+        self.infer_types || !self.column_types.is_empty()
+    }
 
-impl Default for CsvFormat {
-    fn default() -> Self {
+    /// Type of the columns read as timestamps
+    fn timestamp_type(&self) -> DataType {
+        let timezone = self.default_timezone.as_deref().unwrap_or(DEFAULT_TIMEZONE);
+        DataType::Timestamp(TimeUnit::Nanosecond, Some(timezone.into()))
+    }
+
+    /// This config with the delimiter sniffed from `sample`, the start of an input
+    fn with_detected_delimiter(&self, sample: &[u8]) -> Self {
         Self {
-            config: CsvConfig::default(),
+            delimiter: sniff_delimiter(sample),
+            detect_delimiter: false,
+            ..self.clone()
+        }
+    }
+
+    /// Drop the row at `line` unless `on_error` is `Fail`, which returns `error` instead
+    fn reject(&self, line: u64, record: String, error: Error) -> Result<()> {
+        if self.on_error == ErrorMode::Fail {
+            return Err(error);
+        }
+        self.rejected_rows.reject(
+            self.on_error,
+            RejectedRow {
+                line,
+                record,
+                reason: error.to_string(),
+            },
+        );
+        Ok(())
+    }
+}
+
+impl TryFrom<&crate::config::CsvConfig> for CsvConfig {
+    type Error = Error;
+
+    fn try_from(config: &crate::config::CsvConfig) -> Result<Self> {
+        let detect_delimiter = is_auto_delimiter(&config.delimiter) && config.delimiter_regex.is_none();
+        let (delimiter, mut separator) = match parse_delimiter(&config.delimiter, config.multi_char_delimiter) {
+            _ if is_auto_delimiter(&config.delimiter) => (b',', None),
+            Ok(bytes) if bytes.len() == 1 => (bytes[0], None),
+            Ok(bytes) => (b',', Some(FieldSeparator::Fixed(bytes))),
+            Err(e) => return Err(e),
+        };
+        if let Some(pattern) = &config.delimiter_regex {
+            separator = Some(FieldSeparator::regex(pattern)?);
+        }
+        let column_types = config
+            .column_types
+            .iter()
+            .map(|(column, name)| Ok((column.clone(), parse_column_type(name)?)))
+            .collect::<Result<_>>()?;
+        if let Some(timezone) = &config.default_timezone {
+            parse_timezone(timezone)?;
         }
+        Ok(Self {
+            has_header: config.has_header,
+            delimiter,
+            separator,
+            detect_delimiter,
+            batch_size: config.batch_size,
+            schema_sample_size: config.schema_sample_size,
+            max_sample_bytes: config.max_sample_bytes,
+            column_types,
+            timestamp_formats: config.timestamp_formats.clone(),
+            default_timezone: config.default_timezone.clone(),
+            ..Self::default()
+        })
     }
 }
 
+/// Parse the name of a type a CSV column can be forced to: `Int64`,
+/// `Float64`, `Boolean` or `Utf8`
+pub fn parse_column_type(name: &str) -> Result<DataType> {
+    match name.trim() {
+        "Int64" => Ok(DataType::Int64),
+        "Float64" => Ok(DataType::Float64),
+        "Boolean" => Ok(DataType::Boolean),
+        "Utf8" => Ok(DataType::Utf8),
+        other => Err(Error::Config(format!(
+            "Unknown CSV column type '{}': expected Int64, Float64, Boolean or Utf8",
+            other
+        ))),
+    }
+}
+
+#[derive(Default)]
+pub struct CsvFormat {
+    config: CsvConfig,
+}
+
 impl CsvFormat {
     pub fn new(config: CsvConfig) -> Self {
         Self { config }
     }
 
-    fn infer_schema(&self, data: &Bytes) -> Result<Arc<Schema>> {
-        let cursor = Cursor::new(data);
-        let mut reader = csv::Reader::from_reader(cursor);
-        let headers: Vec<String> = if self.config.has_header {
-            reader.headers()?.iter().map(|s| s.to_string()).collect()
-        } else {
-            (0..reader.headers()?.len())
-                .map(|i| format!("column_{}", i))
-                .collect()
+    /// Rows dropped so far under `ErrorMode::Skip` or `ErrorMode::Collect`
+    pub fn rejected_rows(&self) -> &RejectedRows {
+        &self.config.rejected_rows
+    }
+
+    /// Split a whole file into its header schema and data records; quoted
+    /// fields may contain newlines
+    fn parse(&self, data: &Bytes) -> Result<(SchemaRef, Vec<csv::StringRecord>)> {
+        let mut records = parse_rows(&self.config, data, 1)?;
+        if records.is_empty() {
+            return Ok((Arc::new(Schema::empty()), records));
+        }
+        let schema = header_schema(&self.config, &records[0]);
+        if self.config.has_header {
+            records.remove(0);
+        }
+        let schema = fit_schema(&self.config, schema, &records)?;
+        Ok((schema, records))
+    }
+}
+
+/// Build the schema from the first line of a file: the header names, or
+/// `column_N` names when the file has no header
+#[tracing::instrument(name = "infer_schema", level = "debug", skip_all, fields(format = "csv", header = config.has_header))]
+fn schema_from_first_line(config: &CsvConfig, line: &[u8]) -> Result<SchemaRef> {
+    let record = parse_records(config, line)?.into_iter().next().unwrap_or_default();
+    let schema = header_schema(config, &record);
+    tracing::debug!(columns = schema.fields().len(), "inferred schema");
+    Ok(schema)
+}
+
+fn header_schema(config: &CsvConfig, record: &csv::StringRecord) -> SchemaRef {
+    let fields: Vec<Field> = record
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let name = if config.has_header { name.to_string() } else { format!("column_{}", i) };
+            Field::new(name, arrow::datatypes::DataType::Utf8, true)
+        })
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+/// Parse CSV `data` without a header, allowing rows with any number of fields.
+///
+/// Unparseable rows fail under `ErrorMode::Fail` and are dropped silently
+/// otherwise; use `parse_rows` for data that should report them.
+fn parse_records(config: &CsvConfig, data: &[u8]) -> Result<Vec<csv::StringRecord>> {
+    read_records(config, data, |_, _| Ok(()))
+}
+
+/// Parse like `parse_records`, reporting unparseable rows to `on_error` and
+/// numbering the records from `first_line`
+fn parse_rows(config: &CsvConfig, data: &[u8], first_line: u64) -> Result<Vec<csv::StringRecord>> {
+    let mut records = read_records(config, data, |line, error| {
+        config.reject(line + first_line - 1, String::new(), error)
+    })?;
+    for (index, record) in records.iter_mut().enumerate() {
+        let line = record.position().map_or(index as u64 + 1, |position| position.line());
+        let mut position = csv::Position::new();
+        position.set_line(line + first_line - 1);
+        record.set_position(Some(position));
+    }
+    Ok(records)
+}
+
+fn read_records(
+    config: &CsvConfig,
+    data: &[u8],
+    mut rejected: impl FnMut(u64, Error) -> Result<()>,
+) -> Result<Vec<csv::StringRecord>> {
+    if let Some(separator) = &config.separator {
+        return separator.records(data);
+    }
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(config.delimiter)
+        .from_reader(data);
+    let mut records = Vec::new();
+    for record in reader.records() {
+        match record {
+            Ok(record) => records.push(record),
+            Err(e) if config.on_error == ErrorMode::Fail => return Err(e.into()),
+            Err(e) => rejected(e.position().map_or(0, |position| position.line()), e.into())?,
+        }
+    }
+    Ok(records)
+}
+
+/// Read the start of `stream` until `max_lines` complete lines or `max_bytes`
+/// bytes have been read, whichever comes first. The rest of the stream is
+/// never polled. Also returns whether the stream ended before either limit.
+async fn sample_lines(mut stream: DataStream, max_bytes: usize, max_lines: usize) -> Result<(Bytes, bool)> {
+    let mut sample = bytes::BytesMut::new();
+    let mut lines = 0;
+    while let Some(chunk) = stream.try_next().await? {
+        let chunk = &chunk[..chunk.len().min(max_bytes - sample.len())];
+        let newlines: Vec<usize> = chunk.iter().enumerate().filter(|(_, &b)| b == b'\n').map(|(i, _)| i).collect();
+        if let Some(&end) = newlines.get(max_lines - lines - 1) {
+            sample.extend_from_slice(&chunk[..=end]);
+            return Ok((sample.freeze(), false));
+        }
+        lines += newlines.len();
+        sample.extend_from_slice(chunk);
+        if sample.len() >= max_bytes {
+            return Ok((sample.freeze(), false));
+        }
+    }
+    Ok((sample.freeze(), true))
+}
+
+/// `lines` as CSV data, each ending in a newline
+fn join_lines<'a>(lines: impl Iterator<Item = &'a Bytes>) -> Vec<u8> {
+    lines.flat_map(|line| line.iter().copied().chain([b'\n'])).collect()
+}
+
+/// Line a record parsed by `parse_rows` starts on
+fn record_line(record: &csv::StringRecord) -> u64 {
+    record.position().map_or(0, |position| position.line())
+}
+
+/// Check `records` against the header `schema` with `strict_column_count`,
+/// or widen it to the longest record otherwise
+fn fit_schema(config: &CsvConfig, schema: SchemaRef, records: &[csv::StringRecord]) -> Result<SchemaRef> {
+    let columns = schema.fields().len();
+    if config.strict_column_count {
+        // Otherwise the rows are dropped when they are decoded
+        if config.on_error == ErrorMode::Fail {
+            check_column_counts(config, columns, records)?;
+        }
+        return Ok(schema);
+    }
+    let widest = records.iter().map(csv::StringRecord::len).max().unwrap_or(0);
+    if widest <= columns {
+        return Ok(schema);
+    }
+    tracing::debug!(columns, widest, "widened schema to the longest row");
+    let mut fields: Vec<Field> = schema.fields().iter().map(|field| field.as_ref().clone()).collect();
+    fields.extend((columns..widest).map(|i| Field::new(format!("column_{}", i), DataType::Utf8, true)));
+    Ok(Arc::new(Schema::new(fields)))
+}
+
+/// Reject records with more fields than `columns`, or with a different
+/// number of fields under `strict_column_count`
+fn check_column_counts(config: &CsvConfig, columns: usize, records: &[csv::StringRecord]) -> Result<()> {
+    match records.iter().find_map(|record| column_count_error(config, columns, record)) {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+fn column_count_error(config: &CsvConfig, columns: usize, record: &csv::StringRecord) -> Option<Error> {
+    let ragged = record.len() > columns || (config.strict_column_count && record.len() != columns);
+    ragged.then(|| {
+        Error::InvalidInput(format!(
+            "CSV row has {} fields but the schema has {} columns: {}",
+            record.len(),
+            columns,
+            join_record(config, record.iter())
+        ))
+    })
+}
+
+fn join_record<'a>(config: &CsvConfig, fields: impl Iterator<Item = &'a str>) -> String {
+    fields.collect::<Vec<_>>().join(char::from(config.delimiter).to_string().as_str())
+}
+
+/// Build a batch of string columns from `records`; empty and missing fields
+/// are null. Also returns the line each row starts on.
+fn records_to_batch(
+    config: &CsvConfig,
+    schema: &SchemaRef,
+    records: &[csv::StringRecord],
+) -> Result<(RecordBatch, Vec<u64>)> {
+    let columns = schema.fields().len();
+    let mut kept = Vec::with_capacity(records.len());
+    for record in records {
+        match column_count_error(config, columns, record) {
+            Some(error) => config.reject(record_line(record), join_record(config, record.iter()), error)?,
+            None => kept.push(record),
+        }
+    }
+    let batch = string_batch(schema, &kept)?;
+    Ok((batch, kept.iter().map(|record| record_line(record)).collect()))
+}
+
+fn string_batch(schema: &SchemaRef, records: &[&csv::StringRecord]) -> Result<RecordBatch> {
+    let columns = (0..schema.fields().len())
+        .map(|i| {
+            let values = records.iter().map(|record| record.get(i).filter(|value| !value.is_empty()));
+            Arc::new(StringArray::from_iter(values)) as ArrayRef
+        })
+        .collect();
+    Ok(RecordBatch::try_new_with_options(
+        schema.clone(),
+        columns,
+        &arrow::record_batch::RecordBatchOptions::new().with_row_count(Some(records.len())),
+    )?)
+}
+
+/// Build a batch of string columns from the sampled rows in `data` to infer
+/// column types from. Rows that don't fit `schema` are left out here and
+/// reported when they are decoded.
+fn sample_batch(config: &CsvConfig, schema: &SchemaRef, data: &[u8]) -> Result<RecordBatch> {
+    let records: Vec<csv::StringRecord> = parse_records(config, data)?
+        .into_iter()
+        .filter(|record| column_count_error(config, schema.fields().len(), record).is_none())
+        .collect();
+    string_batch(schema, &records.iter().collect::<Vec<_>>())
+}
+
+/// Parse a chunk of complete lines, the first of which is line `first_line`
+/// of the file, into a single batch
+fn parse_lines(
+    config: &CsvConfig,
+    schema: &SchemaRef,
+    lines: &[Bytes],
+    first_line: u64,
+) -> Result<(RecordBatch, Vec<u64>)> {
+    let mut data = Vec::with_capacity(lines.iter().map(|l| l.len() + 1).sum());
+    for line in lines {
+        data.extend_from_slice(line);
+        data.push(b'\n');
+    }
+    records_to_batch(config, schema, &parse_rows(config, &data, first_line)?)
+}
+
+/// In-memory sink shared with an `arrow::csv::Writer`, drained after every batch
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<parking_lot::Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock())
+    }
+}
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reject a batch whose column names or types differ from `schema`
+fn check_batch_schema(schema: &SchemaRef, batch: &RecordBatch) -> Result<()> {
+    match crate::schema::schema_diff(schema, &batch.schema(), false) {
+        Some(diff) => Err(Error::SchemaDiff {
+            context: "CSV batch schema does not match the first batch's".to_string(),
+            diff,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Appends CSV rows to an in-memory buffer, writing the header before the first batch
+struct CsvBatchWriter {
+    writer: Option<arrow::csv::Writer<Vec<u8>>>,
+}
+
+/// Fail with a clear error on columns a CSV cell can't hold, such as structs,
+/// lists and maps, before the writer rejects them halfway through a file
+fn check_flat_schema(schema: &Schema) -> Result<()> {
+    match schema.fields().iter().find(|field| field.data_type().is_nested()) {
+        Some(field) => Err(Error::UnsupportedFormat(format!(
+            "CSV can't hold nested column '{}' of type {}; select its fields with --filter-sql, e.g. SELECT {}['name'], or write Parquet or JSON",
+            field.name(),
+            field.data_type(),
+            field.name()
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[async_trait]
+impl BatchWriter for CsvBatchWriter {
+    async fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        self.writer.as_mut().ok_or_else(already_finalized)?.write(batch)?;
+        Ok(())
+    }
+
+    /// Every row is terminated by a newline, so there is no trailing state to flush
+    async fn finalize(&mut self) -> Result<Bytes> {
+        let writer = self.writer.take().ok_or_else(already_finalized)?;
+        Ok(Bytes::from(writer.into_inner()))
+    }
+}
+
+/// Parse a float, accepting scientific notation and, when enabled, the
+/// special tokens `NaN`, `inf`/`Infinity` and their signed forms
+fn parse_float(config: &CsvConfig, value: &str) -> Option<f64> {
+    let value = value.trim();
+    // Rust's parser accepts the special tokens itself, so reject them explicitly
+    let special = value
+        .trim_start_matches(['+', '-'])
+        .chars()
+        .any(|c| c.is_ascii_alphabetic() && c != 'e' && c != 'E');
+    if special && !config.special_floats {
+        return None;
+    }
+    value.parse::<f64>().ok()
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a timestamp with the first of `timestamp_formats` that matches, as
+/// nanoseconds since the epoch. Formats with an offset (`%z`, `%+`) keep it;
+/// other values, including bare dates, are local times in `timezone`.
+fn parse_timestamp<T: TimeZone>(config: &CsvConfig, timezone: &T, value: &str) -> Option<i64> {
+    let value = value.trim();
+    config.timestamp_formats.iter().find_map(|format| {
+        if let Ok(timestamp) = DateTime::parse_from_str(value, format) {
+            return timestamp.timestamp_nanos_opt();
+        }
+        let local = match NaiveDateTime::parse_from_str(value, format) {
+            Ok(local) => local,
+            Err(_) => NaiveDate::parse_from_str(value, format).ok()?.and_hms_opt(0, 0, 0)?,
         };
+        timezone.from_local_datetime(&local).single()?.timestamp_nanos_opt()
+    })
+}
 
-        let fields: Vec<Field> = headers
-            .into_iter()
-            .map(|name| Field::new(name, arrow::datatypes::DataType::Utf8, true))
-            .collect();
+/// Parse a timezone name like `Europe/Berlin` or a fixed offset like `+02:00`
+pub fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse().map_err(|e| Error::Config(format!("Unknown timezone '{}': {}", name, e)))
+}
+
+/// The timezone of a `Timestamp` column's type
+fn column_timezone(data_type: &DataType) -> Result<Option<Tz>> {
+    match data_type {
+        DataType::Timestamp(_, timezone) => parse_timezone(timezone.as_deref().unwrap_or(DEFAULT_TIMEZONE)).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Infer the narrowest of Int64, Float64, Boolean, Timestamp and Utf8 that
+/// fits every non-empty value
+fn infer_type(config: &CsvConfig, values: &StringArray, sample: usize) -> DataType {
+    let (mut ints, mut floats, mut bools, mut seen) = (true, true, true, false);
+    let mut timestamps = !config.timestamp_formats.is_empty();
+    for value in values.iter().take(sample).flatten().filter(|v| !v.trim().is_empty()) {
+        seen = true;
+        ints &= value.trim().parse::<i64>().is_ok();
+        floats &= parse_float(config, value).is_some();
+        bools &= parse_bool(value).is_some();
+        // The timezone only matters for converting, not for whether a format matches
+        timestamps = timestamps && parse_timestamp(config, &chrono::Utc, value).is_some();
+    }
+    match (seen, ints, floats, bools, timestamps) {
+        (false, ..) => DataType::Utf8,
+        (_, true, ..) => DataType::Int64,
+        (_, _, true, ..) => DataType::Float64,
+        (_, _, _, true, _) => DataType::Boolean,
+        (.., true) => config.timestamp_type(),
+        _ => DataType::Utf8,
+    }
+}
+
+/// Infer column types from a batch whose columns are all strings; columns
+/// named in `column_types` get the configured type instead
+#[tracing::instrument(name = "infer_schema", level = "debug", skip_all, fields(format = "csv", rows = raw.num_rows()))]
+fn infer_typed_schema(config: &CsvConfig, raw: &RecordBatch) -> SchemaRef {
+    let fields: Vec<Field> = raw
+        .schema()
+        .fields()
+        .iter()
+        .zip(raw.columns())
+        .map(|(field, column)| {
+            let forced = config.column_types.get(field.name());
+            let data_type = match (forced, column.as_any().downcast_ref::<StringArray>()) {
+                (Some(data_type), _) => data_type.clone(),
+                (None, Some(values)) if config.infer_types => infer_type(config, values, config.schema_sample_size),
+                (None, _) => column.data_type().clone(),
+            };
+            Field::new(field.name(), data_type, true)
+        })
+        .collect();
+    tracing::debug!(columns = fields.len(), "inferred column types");
+    Arc::new(Schema::new(fields))
+}
+
+/// Drop the rows of `raw`, starting on `lines`, that hold a value that doesn't
+/// parse as its column's type in `schema`
+fn drop_unconvertible(config: &CsvConfig, raw: &RecordBatch, schema: &SchemaRef, lines: &[u64]) -> Result<RecordBatch> {
+    let mut errors: Vec<Option<Error>> = (0..raw.num_rows()).map(|_| None).collect();
+    for (field, column) in schema.fields().iter().zip(raw.columns()) {
+        let Some(values) = column.as_any().downcast_ref::<StringArray>() else {
+            continue;
+        };
+        let timezone = column_timezone(field.data_type())?;
+        for (row, value) in values.iter().enumerate() {
+            let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            let valid = match field.data_type() {
+                DataType::Int64 => value.parse::<i64>().is_ok(),
+                DataType::Float64 => parse_float(config, value).is_some(),
+                DataType::Boolean => parse_bool(value).is_some(),
+                DataType::Timestamp(..) => {
+                    timezone.as_ref().and_then(|timezone| parse_timestamp(config, timezone, value)).is_some()
+                }
+                _ => true,
+            };
+            if !valid && errors[row].is_none() {
+                errors[row] = Some(Error::InvalidInput(format!(
+                    "Value '{}' in column '{}' is not a valid {}",
+                    value,
+                    field.name(),
+                    field.data_type()
+                )));
+            }
+        }
+    }
+    if errors.iter().all(Option::is_none) {
+        return Ok(raw.clone());
+    }
 
-        Ok(Arc::new(Schema::new(fields)))
+    let mut keep = Vec::with_capacity(errors.len());
+    for (row, error) in errors.into_iter().enumerate() {
+        keep.push(error.is_none());
+        if let Some(error) = error {
+            let fields = raw.columns().iter().map(|column| {
+                let values = column.as_any().downcast_ref::<StringArray>();
+                values.filter(|values| values.is_valid(row)).map_or("", |values| values.value(row))
+            });
+            config.reject(lines[row], join_record(config, fields), error)?;
+        }
     }
+    Ok(arrow::compute::filter_record_batch(raw, &BooleanArray::from(keep))?)
 }
 
+/// Convert the string columns of `raw`, whose rows start on `lines`, to the
+/// types in `schema`; empty values become nulls. Rows that don't convert are
+/// dropped unless `on_error` is `Fail`.
+fn convert_batch(config: &CsvConfig, raw: &RecordBatch, schema: &SchemaRef, lines: &[u64]) -> Result<RecordBatch> {
+    let filtered;
+    let raw = if config.on_error == ErrorMode::Fail {
+        raw
+    } else {
+        filtered = drop_unconvertible(config, raw, schema, lines)?;
+        &filtered
+    };
+
+    fn parse_column<T>(
+        values: &StringArray,
+        name: &str,
+        data_type: &DataType,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Result<Vec<Option<T>>> {
+        values
+            .iter()
+            .map(|value| match value.map(str::trim) {
+                None | Some("") => Ok(None),
+                Some(value) => parse(value).map(Some).ok_or_else(|| {
+                    Error::InvalidInput(format!("Value '{}' in column '{}' is not a valid {}", value, name, data_type))
+                }),
+            })
+            .collect()
+    }
+
+    let columns = schema
+        .fields()
+        .iter()
+        .zip(raw.columns())
+        .map(|(field, column)| -> Result<ArrayRef> {
+            let Some(values) = column.as_any().downcast_ref::<StringArray>() else {
+                return Ok(column.clone());
+            };
+            let (name, data_type) = (field.name(), field.data_type());
+            Ok(match data_type {
+                DataType::Int64 => Arc::new(Int64Array::from(parse_column(values, name, data_type, |v| v.parse().ok())?)),
+                DataType::Float64 => Arc::new(Float64Array::from(parse_column(values, name, data_type, |v| {
+                    parse_float(config, v)
+                })?)),
+                DataType::Boolean => Arc::new(BooleanArray::from(parse_column(values, name, data_type, parse_bool)?)),
+                DataType::Timestamp(TimeUnit::Nanosecond, timezone_name) => {
+                    let timezone = parse_timezone(timezone_name.as_deref().unwrap_or(DEFAULT_TIMEZONE))?;
+                    let nanos = parse_column(values, name, data_type, |v| parse_timestamp(config, &timezone, v))?;
+                    Arc::new(TimestampNanosecondArray::from(nanos).with_timezone_opt(timezone_name.clone()))
+                }
+                _ => column.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+#[async_trait]
 impl super::DataFormat for CsvFormat {
     fn read(&self, data: &Bytes) -> Result<DataFrame> {
-        let schema = self.infer_schema(data)?;
-        let cursor = Cursor::new(data);
-        let reader = ReaderBuilder::new(schema.clone())
-            .has_header(self.config.has_header)
-            .with_delimiter(self.config.delimiter)
-            .build(cursor)?;
-
-        let mut batches = Vec::new();
-        for result in reader {
-            batches.push(result?);
+        if self.config.detect_delimiter {
+            return CsvFormat::new(self.config.with_detected_delimiter(data)).read(data);
         }
-        
-        let ctx = SessionContext::new();
-        let df = if !batches.is_empty() {
-            let df = ctx.read_batch(batches[0].clone())?;
-            for batch in batches.into_iter().skip(1) {
-                ctx.read_batch(batch)?;
+        let (schema, records) = self.parse(data)?;
+        let raw = records
+            .chunks(self.config.batch_size.max(1))
+            .map(|chunk| records_to_batch(&self.config, &schema, chunk))
+            .collect::<Result<Vec<_>>>()?;
+        let (schema, batches) = match raw.first() {
+            Some((first, _)) if self.config.converts_types() => {
+                let typed = infer_typed_schema(&self.config, first);
+                let batches = raw
+                    .iter()
+                    .map(|(batch, lines)| convert_batch(&self.config, batch, &typed, lines))
+                    .collect::<Result<Vec<_>>>()?;
+                (typed, batches)
             }
-            df
-        } else {
-            ctx.read_batch(RecordBatch::new_empty(schema))?
+            _ => (schema, raw.into_iter().map(|(batch, _)| batch).collect()),
         };
-        Ok(df)
+        
+        // Every batch, not only the first, so rows past `batch_size` are kept
+        let table = datafusion::datasource::MemTable::try_new(schema, vec![batches])?;
+        Ok(SessionContext::new().read_table(Arc::new(table))?)
     }
 
     fn write(&self, df: &DataFrame) -> Result<Bytes> {
+        check_flat_schema(&Schema::try_from(df.schema())?)?;
         let mut buf = Vec::new();
         let mut writer = WriterBuilder::new()
             .has_headers(self.config.has_header)
@@ -105,6 +792,7 @@ impl super::DataFormat for CsvFormat {
     }
 
     fn write_batch(&self, batch: &RecordBatch) -> Result<Bytes> {
+        check_flat_schema(&batch.schema())?;
         let mut buf = Vec::new();
         let mut writer = WriterBuilder::new()
             .has_headers(self.config.has_header)
@@ -116,4 +804,722 @@ impl super::DataFormat for CsvFormat {
 
         Ok(Bytes::from(buf))
     }
+
+    fn batch_writer(&self, schema: SchemaRef) -> Result<Box<dyn BatchWriter + '_>> {
+        check_flat_schema(&schema)?;
+        let writer = WriterBuilder::new()
+            .has_headers(self.config.has_header)
+            .with_delimiter(self.config.delimiter)
+            .build(Vec::new());
+        Ok(Box::new(CsvBatchWriter { writer: Some(writer) }))
+    }
+
+    /// Rows stream line by line; every column is decoded
+    fn capabilities(&self) -> super::FormatCapabilities {
+        super::FormatCapabilities {
+            streaming_read: true,
+            ..super::FormatCapabilities::default()
+        }
+    }
+
+    /// Stream rows line by line. Quoted fields containing newlines are not
+    /// supported on this path; use `read` for such files. Column types, and
+    /// the column count unless `strict_column_count` is set, are inferred from
+    /// the first `schema_sample_size` rows, as by `infer_schema`, and so is
+    /// the delimiter with `detect_delimiter`. The batches holding the sample
+    /// are buffered until it is complete; later values that don't fit the
+    /// inferred types are handled as `on_error` says.
+    fn read_batches_from_stream(&self, stream: DataStream) -> Result<BatchStream> {
+        let mut config = self.config.clone();
+        let mut lines = non_empty_lines(stream);
+        let batches = futures::stream::once(async move {
+            // The header is taken off before the lines are chunked, so only
+            // rows count towards `batch_size`
+            let header = match config.has_header {
+                true => lines.try_next().await?,
+                false => None,
+            };
+            let mut chunks = chunk_lines(lines, config.batch_size);
+            let mut sample: Vec<Vec<Bytes>> = Vec::new();
+            while sample.iter().map(Vec::len).sum::<usize>() < config.schema_sample_size.max(1) {
+                match chunks.try_next().await? {
+                    Some(lines) => sample.push(lines),
+                    None => break,
+                }
+            }
+            let Some(first_line) = header.clone().or_else(|| sample.first().and_then(|lines| lines.first()).cloned())
+            else {
+                return Ok::<_, Error>(futures::stream::empty().boxed());
+            };
+
+            if config.detect_delimiter {
+                config = config.with_detected_delimiter(&join_lines(header.iter().chain(sample.iter().flatten())));
+            }
+            let mut schema = schema_from_first_line(&config, &first_line)?;
+            let mut next_line = 1 + config.has_header as u64;
+            let rows = join_lines(sample.iter().flatten());
+            if !config.strict_column_count {
+                // Later rows can't widen a schema that batches were already emitted with
+                schema = fit_schema(&config, schema, &parse_records(&config, &rows)?)?;
+            }
+            let typed = if config.converts_types() {
+                Some(infer_typed_schema(&config, &sample_batch(&config, &schema, &rows)?))
+            } else {
+                None
+            };
+
+            let config = Arc::new(config);
+            let chunks = futures::stream::iter(sample.into_iter().map(Ok)).chain(chunks);
+            let batches = chunks
+                .try_filter(|lines| futures::future::ready(!lines.is_empty()))
+                .map_ok(move |lines| {
+                    let first_line = next_line;
+                    next_line += lines.len() as u64;
+                    (lines, first_line)
+                })
+                .and_then(move |(lines, first_line)| {
+                    let (config, schema, typed) = (config.clone(), schema.clone(), typed.clone());
+                    async move {
+                        let (batch, batch_lines) = parse_lines(&config, &schema, &lines, first_line)?;
+                        tracing::trace!(rows = batch.num_rows(), "decoded batch");
+                        match &typed {
+                            Some(typed) => convert_batch(&config, &batch, typed, &batch_lines),
+                            None => Ok(batch),
+                        }
+                    }
+                });
+            Ok(batches.boxed())
+        })
+        .try_flatten();
+        Ok(batches.boxed())
+    }
+
+    fn read_batches_with(&self, stream: DataStream, options: &ReadOptions) -> Result<BatchStream> {
+        let format = CsvFormat::new(CsvConfig {
+            batch_size: options.batch_size.unwrap_or(self.config.batch_size),
+            ..self.config.clone()
+        });
+        let batches = format.read_batches_from_stream(stream)?;
+        Ok(apply_read_options(batches, &ReadOptions { batch_size: None, ..options.clone() }))
+    }
+
+    /// With `adaptive_batch_size`, the schema is inferred from a sample
+    /// first and the rows per batch picked from its width
+    async fn read_batches_from_storage(&self, storage: Arc<dyn Storage>, url: &url::Url, size: usize) -> Result<BatchStream> {
+        let stream = super::read_decompressed(storage.as_ref(), url).await?;
+        let Some(adaptive) = self.config.adaptive_batch_size.filter(|_| size > 0) else {
+            return self.read_batches_from_stream(stream);
+        };
+        let schema = self.infer_schema(storage.clone(), url, size).await?;
+        let format = CsvFormat::new(CsvConfig {
+            batch_size: adaptive.rows_for(&schema),
+            adaptive_batch_size: None,
+            ..self.config.clone()
+        });
+        format.read_batches_from_stream(stream)
+    }
+
+    /// Only the first `max_sample_bytes` are fetched, with a range request;
+    /// the last, possibly truncated line of the sample is ignored. Compressed
+    /// files are decompressed as a stream up to the first batch instead.
+    /// Types are inferred from at most `schema_sample_size` rows and
+    /// `max_sample_bytes` bytes, whichever limit is reached first
+    #[tracing::instrument(level = "debug", skip_all, fields(format = "csv", url = %url, size))]
+    async fn infer_schema(&self, storage: Arc<dyn Storage>, url: &url::Url, size: usize) -> Result<SchemaRef> {
+        super::check_not_empty(url, size)?;
+        let max_bytes = self.config.max_sample_bytes.max(1);
+        let max_lines = self.config.schema_sample_size.max(1) + self.config.has_header as usize;
+        let (sample, whole_file) = if super::compression_extension(url.path()).is_some() {
+            let stream = super::read_decompressed(storage.as_ref(), url).await?;
+            sample_lines(stream, max_bytes, max_lines).await?
+        } else {
+            let range = storage.read_range(url, 0..size.min(max_bytes)).await?;
+            let range_len = range.len();
+            let stream = futures::stream::iter(vec![Ok(range)]).boxed();
+            let (sample, ended) = sample_lines(stream, max_bytes, max_lines).await?;
+            (sample, ended && range_len == size)
+        };
+        let sample = match sample.iter().rposition(|&b| b == b'\n') {
+            Some(end) if !whole_file => sample.slice(..=end),
+            Some(_) => sample,
+            None if whole_file => sample,
+            None => {
+                return Err(Error::InvalidInput(format!(
+                    "Cannot infer a schema from {}: its first line is longer than {} bytes",
+                    url, self.config.max_sample_bytes
+                )));
+            }
+        };
+        let stream = futures::stream::iter(vec![Ok(sample.clone())]).boxed();
+        if let Some(batch) = self.read_batches_from_stream(stream)?.try_next().await? {
+            return Ok(batch.schema());
+        }
+        // A header without rows still names the columns: they are strings
+        // unless `column_types` says otherwise
+        let header = sample.split(|&b| b == b'\n').next().map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        match header.filter(|line| !line.is_empty()) {
+            Some(header) if self.config.has_header => {
+                let config = if self.config.detect_delimiter {
+                    self.config.with_detected_delimiter(&sample)
+                } else {
+                    self.config.clone()
+                };
+                let schema = schema_from_first_line(&config, header)?;
+                let config = CsvConfig { infer_types: false, ..config };
+                Ok(infer_typed_schema(&config, &RecordBatch::new_empty(schema)))
+            }
+            _ => Err(Error::EmptyInput(format!("Cannot infer a schema from {}: it has no rows", url))),
+        }
+    }
+
+    /// Lines are counted in the first `STATS_SAMPLE_SIZE` bytes and scaled to
+    /// the file size. Always an estimate, since quoted fields may span lines;
+    /// compressed files only report their size.
+    async fn plan_statistics(&self, storage: Arc<dyn Storage>, url: &url::Url, size: usize) -> Result<Statistics> {
+        if super::compression_extension(url.path()).is_some() {
+            return Ok(Statistics {
+                total_byte_size: Some(size),
+                ..Statistics::default()
+            });
+        }
+        let sample = storage.read_range(url, 0..size.min(STATS_SAMPLE_SIZE)).await?;
+        let mut lines = sample.iter().filter(|&&b| b == b'\n').count();
+        let whole_file = sample.len() == size;
+        if whole_file && sample.last().is_some_and(|&b| b != b'\n') {
+            lines += 1;
+        }
+        let rows = lines.saturating_sub(self.config.has_header as usize);
+        let num_rows = if whole_file {
+            rows
+        } else {
+            (rows as f64 * size as f64 / sample.len() as f64).round() as usize
+        };
+        Ok(Statistics {
+            num_rows: Some(num_rows),
+            total_byte_size: Some(size),
+            column_statistics: None,
+            is_exact: false,
+        })
+    }
+
+    /// Only a new file gets a header row
+    async fn write_batches_for_append(&self, batches: BatchStream, new_file: bool) -> Result<Bytes> {
+        let format = CsvFormat::new(CsvConfig {
+            has_header: self.config.has_header && new_file,
+            ..self.config.clone()
+        });
+        format.write_batches(batches).await
+    }
+
+    /// Each batch is encoded with a single writer and flushed to `sink` as it
+    /// arrives, so only the first one carries the header row. Every batch must
+    /// have the first one's column names and types.
+    async fn write_batches_to(&self, mut batches: BatchStream, mut sink: DataSink) -> Result<()> {
+        let buffer = SharedBuffer::default();
+        let mut writer = WriterBuilder::new()
+            .has_headers(self.config.has_header)
+            .with_delimiter(self.config.delimiter)
+            .build(buffer.clone());
+        let mut schema: Option<SchemaRef> = None;
+        while let Some(batch) = batches.try_next().await? {
+            match &schema {
+                Some(schema) => check_batch_schema(schema, &batch)?,
+                None => {
+                    check_flat_schema(&batch.schema())?;
+                    schema = Some(batch.schema());
+                }
+            }
+            writer.write(&batch)?;
+            let encoded = buffer.take();
+            tracing::trace!(rows = batch.num_rows(), bytes = encoded.len(), "encoded batch");
+            sink.write_all(&encoded).await?;
+        }
+        sink.shutdown().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::DataFormat;
+    use crate::storage::testing::MockStorage;
+
+    #[tokio::test]
+    async fn test_stream_csv_across_chunk_boundaries() -> Result<()> {
+        let chunks = vec!["id,na", "me\n1,a\n2", ",b\r\n3,c"];
+        let stream = futures::stream::iter(chunks.into_iter().map(|c| Ok(Bytes::from(c)))).boxed();
+        let format = CsvFormat::new(CsvConfig {
+            batch_size: 2,
+            ..CsvConfig::default()
+        });
+
+        let batches: Vec<RecordBatch> = format.read_batches_from_stream(stream)?.try_collect().await?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+        assert_eq!(batches[0].schema().field(1).name(), "name");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_csv_infers_types_from_sample() -> Result<()> {
+        let read = |schema_sample_size| {
+            let stream = futures::stream::iter(vec![Ok(Bytes::from("id,v\n1,1\n2,2\n3,x\n4,4\n"))]).boxed();
+            let format = CsvFormat::new(CsvConfig {
+                batch_size: 2,
+                schema_sample_size,
+                ..CsvConfig::default()
+            });
+            async move { format.read_batches_from_stream(stream)?.try_collect::<Vec<RecordBatch>>().await }
+        };
+
+        // The value in the second batch makes the column a string in every batch
+        let batches = read(1000).await?;
+        assert_eq!(batches.len(), 2);
+        assert!(batches.iter().all(|batch| batch.schema().field(1).data_type() == &DataType::Utf8));
+
+        // Outside the sample it doesn't fit the inferred type
+        let err = read(2).await.unwrap_err();
+        assert!(err.to_string().contains("Value 'x' in column 'v' is not a valid Int64"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_infer_scientific_notation_as_float() -> Result<()> {
+        let data = Bytes::from("id,value\n1,1e9\n2,-2.5E-3\n3,42\n");
+        let batches = futures::executor::block_on(CsvFormat::default().read(&data)?.collect())?;
+        let schema = batches[0].schema();
+        assert_eq!(schema.field(0).data_type(), &DataType::Int64);
+        assert_eq!(schema.field(1).data_type(), &DataType::Float64);
+
+        let values = batches[0].column(1).as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(values.values(), &[1e9, -2.5e-3, 42.0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_infer_special_floats() -> Result<()> {
+        let input = "value\nNaN\nInfinity\n-Infinity\n1.5\n";
+        let stream = futures::stream::iter(vec![Ok(Bytes::from(input))]).boxed();
+        let batches: Vec<RecordBatch> = CsvFormat::default().read_batches_from_stream(stream)?.try_collect().await?;
+
+        let values = batches[0].column(0).as_any().downcast_ref::<Float64Array>().unwrap();
+        assert!(values.value(0).is_nan());
+        assert_eq!(values.value(1), f64::INFINITY);
+        assert_eq!(values.value(2), f64::NEG_INFINITY);
+        assert_eq!(values.value(3), 1.5);
+
+        // With special tokens disabled the column stays a string column
+        let stream = futures::stream::iter(vec![Ok(Bytes::from(input))]).boxed();
+        let format = CsvFormat::new(CsvConfig {
+            special_floats: false,
+            ..CsvConfig::default()
+        });
+        let batches: Vec<RecordBatch> = format.read_batches_from_stream(stream)?.try_collect().await?;
+        assert_eq!(batches[0].schema().field(0).data_type(), &DataType::Utf8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finalize_is_idempotent_and_complete() -> Result<()> {
+        let format = CsvFormat::default();
+        let input = Bytes::from("id,name\n1,a\n2,b\n");
+        let batches = format.read(&input)?.collect().await?;
+
+        let mut writer = format.batch_writer(batches[0].schema())?;
+        for batch in &batches {
+            writer.write(batch).await?;
+        }
+        assert_eq!(writer.finalize().await?, input);
+        assert!(writer.finalize().await.unwrap_err().to_string().contains("already been finalized"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_batches_to_writes_header_once() -> Result<()> {
+        let format = CsvFormat::default();
+        let input = futures::stream::iter(vec![Ok(Bytes::from("id,name\n1,a\n2,b\n"))]).boxed();
+        let batches = CsvFormat::new(CsvConfig {
+            batch_size: 1,
+            ..CsvConfig::default()
+        })
+        .read_batches_from_stream(input)?;
+
+        let (writer, mut reader) = tokio::io::duplex(1024);
+        format.write_batches_to(batches, Box::pin(writer)).await?;
+        let mut output = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut output).await?;
+        assert_eq!(output, "id,name\n1,a\n2,b\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_many_batches_emits_one_header() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batches: Vec<Result<RecordBatch>> = (0..100)
+            .map(|i| Ok(RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(vec![i]))])?))
+            .collect();
+        let output = CsvFormat::default().write_batches(futures::stream::iter(batches).boxed()).await?;
+        let output = std::str::from_utf8(&output).unwrap();
+        assert_eq!(output.lines().filter(|line| *line == "id").count(), 1);
+        assert_eq!(output.lines().count(), 101);
+        assert!(output.starts_with("id\n0\n1\n"));
+
+        let other = Arc::new(Schema::new(vec![Field::new("name", DataType::Utf8, false)]));
+        let mismatched = vec![
+            Ok(RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1]))])?),
+            Ok(RecordBatch::try_new(other, vec![Arc::new(StringArray::from(vec!["a"]))])?),
+        ];
+        let err = CsvFormat::default().write_batches(futures::stream::iter(mismatched).boxed()).await.unwrap_err();
+        assert!(matches!(err, Error::SchemaDiff { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn test_read_enters_infer_schema_span() -> Result<()> {
+        let stream = futures::stream::iter(vec![Ok(Bytes::from("id,name\n1,a\n2,b\n"))]).boxed();
+        let batches: Vec<RecordBatch> = CsvFormat::default().read_batches_from_stream(stream)?.try_collect().await?;
+        assert_eq!(batches[0].num_rows(), 2);
+        assert!(logs_contain("infer_schema"));
+        assert!(logs_contain("inferred schema columns=2"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_infer_schema_reads_only_a_prefix() -> Result<()> {
+        let rows: String = (0..10_000).map(|i| format!("{},name{}\n", i, i)).collect();
+        let data = Bytes::from(format!("id,name\n{}", rows));
+        let storage = Arc::new(MockStorage::new());
+        let url = url::Url::parse("s3://bucket/large.csv").unwrap();
+        storage.put(&url, data.clone()).await?;
+        let format = CsvFormat::new(CsvConfig {
+            max_sample_bytes: 4096,
+            ..CsvConfig::default()
+        });
+
+        let schema = format.infer_schema(storage.clone(), &url, data.len()).await?;
+        assert_eq!(schema.field(0).data_type(), &DataType::Int64);
+        assert_eq!(schema.field(1).data_type(), &DataType::Utf8);
+        assert_eq!(storage.reads(), 0);
+        assert_eq!(storage.ranges(), vec![0..4096]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_infer_schema_stops_at_either_sample_limit() -> Result<()> {
+        // Three wide rows with integer ids, then one whose id is not an integer
+        let wide = "x".repeat(10_000);
+        let data = format!("id,payload\n1,{w}\n2,{w}\n3,{w}\nfour,{w}\n", w = wide);
+        let infer = |config: CsvConfig, path: &'static str, data: Bytes| async move {
+            let storage = Arc::new(MockStorage::new());
+            let url = url::Url::parse(path).unwrap();
+            storage.put(&url, data.clone()).await?;
+            let schema = CsvFormat::new(config).infer_schema(storage.clone(), &url, data.len()).await?;
+            let read: usize = storage.ranges().iter().map(|range| range.len()).sum();
+            Ok::<_, Error>((schema.field(0).data_type().clone(), read))
+        };
+
+        // The byte limit ends sampling after three rows, despite the large row limit
+        let by_bytes = CsvConfig {
+            max_sample_bytes: 35_000,
+            schema_sample_size: 1000,
+            ..CsvConfig::default()
+        };
+        let (data_type, read) = infer(by_bytes.clone(), "s3://bucket/wide.csv", Bytes::from(data.clone())).await?;
+        assert_eq!(data_type, DataType::Int64);
+        assert!(read <= 35_000, "read {} bytes", read);
+
+        // The row limit ends it first when it is smaller
+        let by_rows = CsvConfig {
+            schema_sample_size: 3,
+            ..CsvConfig::default()
+        };
+        let (data_type, _) = infer(by_rows, "s3://bucket/wide.csv", Bytes::from(data.clone())).await?;
+        assert_eq!(data_type, DataType::Int64);
+        let (data_type, _) = infer(CsvConfig::default(), "s3://bucket/wide.csv", Bytes::from(data.clone())).await?;
+        assert_eq!(data_type, DataType::Utf8);
+
+        // Compressed inputs are streamed, and stop at the same limits
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, data.as_bytes())?;
+        let gzipped = Bytes::from(encoder.finish()?);
+        let (data_type, _) = infer(by_bytes, "s3://bucket/wide.csv.gz", gzipped.clone()).await?;
+        assert_eq!(data_type, DataType::Int64);
+        let (data_type, _) = infer(CsvConfig::default(), "s3://bucket/wide.csv.gz", gzipped).await?;
+        assert_eq!(data_type, DataType::Utf8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_column_types_override_inferred_types() -> Result<()> {
+        let data = "zip,score,name\n02134,1,a\n10001,2,b\n";
+        let url = url::Url::parse("s3://bucket/people.csv").unwrap();
+        let storage = Arc::new(MockStorage::new());
+        storage.put(&url, Bytes::from(data)).await?;
+        let format = CsvFormat::new(CsvConfig {
+            column_types: HashMap::from([
+                ("zip".to_string(), parse_column_type("Utf8")?),
+                ("score".to_string(), parse_column_type("Float64")?),
+            ]),
+            ..CsvConfig::default()
+        });
+
+        let schema = format.infer_schema(storage, &url, data.len()).await?;
+        assert_eq!(schema.field(0).data_type(), &DataType::Utf8);
+        assert_eq!(schema.field(1).data_type(), &DataType::Float64);
+        assert_eq!(schema.field(2).data_type(), &DataType::Utf8);
+
+        let batches = read_stream(format.config.clone(), data).await?;
+        let zips = batches[0].column(0).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(zips.value(0), "02134");
+        assert!(parse_column_type("Decimal").is_err());
+        Ok(())
+    }
+
+    async fn read_stream(config: CsvConfig, input: &'static str) -> Result<Vec<RecordBatch>> {
+        let stream = futures::stream::iter(vec![Ok(Bytes::from(input))]).boxed();
+        CsvFormat::new(config).read_batches_from_stream(stream)?.try_collect().await
+    }
+
+    #[tokio::test]
+    async fn test_read_batches_with_overrides_batch_size() -> Result<()> {
+        let rows: String = (0..100).map(|i| format!("{},name{}\n", i, i)).collect();
+        let data = Bytes::from(format!("id,name\n{}", rows));
+        let format = CsvFormat::default();
+        let read = |options: ReadOptions| -> Result<BatchStream> {
+            format.read_batches_with(futures::stream::iter(vec![Ok(data.clone())]).boxed(), &options)
+        };
+
+        let small: Vec<RecordBatch> = read(ReadOptions::default().with_batch_size(10))?.try_collect().await?;
+        let large: Vec<RecordBatch> = read(ReadOptions::default().with_batch_size(64))?.try_collect().await?;
+        assert_eq!(small.iter().map(RecordBatch::num_rows).collect::<Vec<_>>(), vec![10; 10]);
+        assert_eq!(large.iter().map(RecordBatch::num_rows).collect::<Vec<_>>(), vec![64, 36]);
+
+        let options = ReadOptions::default().with_projection(vec![1]).with_limit(15).with_batch_size(10);
+        let limited: Vec<RecordBatch> = read(options)?.try_collect().await?;
+        assert_eq!(limited.iter().map(RecordBatch::num_rows).collect::<Vec<_>>(), vec![10, 5]);
+        assert_eq!(limited[0].schema().field(0).name(), "name");
+        assert_eq!(limited[0].num_columns(), 1);
+        Ok(())
+    }
+
+    fn csv_config(delimiter: &str, multi_char_delimiter: bool) -> Result<CsvConfig> {
+        CsvConfig::try_from(&crate::config::CsvConfig {
+            delimiter: delimiter.to_string(),
+            multi_char_delimiter,
+            ..crate::config::Config::default().formats.csv
+        })
+    }
+
+    #[tokio::test]
+    async fn test_escaped_tab_delimiter() -> Result<()> {
+        let batches = read_stream(csv_config("\\t", false)?, "id\tname\n1\ta,b\n").await?;
+        let schema = batches[0].schema();
+        assert_eq!(schema.field(0).name(), "id");
+        assert_eq!(schema.field(1).name(), "name");
+        let names = batches[0].column(1).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(names.value(0), "a,b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_auto_delimiter_splits_semicolon_file() -> Result<()> {
+        let input = "id;name;price\n1;a;1,5\n2;b,c;2\n";
+        let config = csv_config("auto", false)?;
+        assert!(config.detect_delimiter);
+        for batches in [
+            read_stream(config.clone(), input).await?,
+            CsvFormat::new(config.clone()).read(&Bytes::from(input))?.collect().await?,
+        ] {
+            let batch = &batches[0];
+            let names: Vec<_> = batch.schema().fields().iter().map(|field| field.name().clone()).collect();
+            assert_eq!(names, ["id", "name", "price"]);
+            assert_eq!(batch.num_rows(), 2);
+            let values = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+            assert_eq!(values.value(1), "b,c");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multi_char_delimiter() -> Result<()> {
+        assert!(csv_config("||", false).is_err());
+        let batches = read_stream(csv_config("||", true)?, "id||name||score\n1||a|b||2.5\n2||||3\n").await?;
+        let batch = &batches[0];
+        assert_eq!(batch.num_columns(), 3);
+        assert_eq!(batch.schema().field(2).data_type(), &DataType::Float64);
+        let names = batch.column(1).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(names.value(0), "a|b");
+        assert!(names.is_null(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_short_row_is_padded_with_nulls() -> Result<()> {
+        let batches = read_stream(CsvConfig::default(), "a,b,c\n1,2,3\n4,5\n").await?;
+        assert_eq!(batches[0].num_columns(), 3);
+        assert!(batches[0].column(2).is_null(1));
+
+        let strict = CsvConfig {
+            strict_column_count: true,
+            ..CsvConfig::default()
+        };
+        let err = read_stream(strict, "a,b,c\n1,2,3\n4,5\n").await.unwrap_err();
+        assert!(err.to_string().contains("row has 2 fields but the schema has 3 columns"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_long_row_widens_schema() -> Result<()> {
+        let batches = read_stream(CsvConfig::default(), "a,b\n1,2\n3,4,5\n").await?;
+        let schema = batches[0].schema();
+        let names: Vec<&str> = schema.fields().iter().map(|field| field.name().as_str()).collect();
+        assert_eq!(names, ["a", "b", "column_2"]);
+        let extra = batches[0].column(2).as_any().downcast_ref::<Int64Array>().unwrap();
+        assert!(extra.is_null(0));
+        assert_eq!(extra.value(1), 5);
+
+        let strict = CsvConfig {
+            strict_column_count: true,
+            ..CsvConfig::default()
+        };
+        let err = read_stream(strict, "a,b\n1,2\n3,4,5\n").await.unwrap_err();
+        assert!(err.to_string().contains("row has 3 fields but the schema has 2 columns"), "{}", err);
+
+        // The in-memory reader widens to the longest row of the whole file
+        let df = CsvFormat::default().read(&Bytes::from("a,b\n1,2\n3,4,5\n"))?;
+        assert_eq!(df.schema().fields().len(), 3);
+        Ok(())
+    }
+
+    const MALFORMED: &str = "id,score,name\n1,1.5,a\n2,high,b\n3,2.5\n4,3.5,d,extra\nfive,4.5,e\n6,5.5,f\n";
+
+    fn malformed_config(on_error: ErrorMode) -> CsvConfig {
+        CsvConfig {
+            on_error,
+            strict_column_count: true,
+            column_types: HashMap::from([
+                ("id".to_string(), DataType::Int64),
+                ("score".to_string(), DataType::Float64),
+            ]),
+            ..CsvConfig::default()
+        }
+    }
+
+    fn ids(batches: &[RecordBatch]) -> Vec<i64> {
+        batches
+            .iter()
+            .flat_map(|batch| batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap().values().to_vec())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_on_error_fail_aborts_the_read() -> Result<()> {
+        let err = read_stream(malformed_config(ErrorMode::Fail), MALFORMED).await.unwrap_err();
+        assert!(err.to_string().contains("row has 2 fields but the schema has 3 columns"), "{}", err);
+
+        let lenient = CsvConfig {
+            strict_column_count: false,
+            ..malformed_config(ErrorMode::Fail)
+        };
+        let err = read_stream(lenient, "id,score\n1,1.5\n2,high\n").await.unwrap_err();
+        assert!(err.to_string().contains("Value 'high' in column 'score' is not a valid Float64"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_on_error_skip_drops_malformed_rows() -> Result<()> {
+        let config = malformed_config(ErrorMode::Skip);
+        let batches = read_stream(config.clone(), MALFORMED).await?;
+        assert_eq!(ids(&batches), vec![1, 6]);
+        assert_eq!(config.rejected_rows.count(), 4);
+        assert!(config.rejected_rows.take().is_empty());
+
+        // Rows after the first batch are numbered from the right line too
+        let format = CsvFormat::new(CsvConfig {
+            batch_size: 2,
+            ..malformed_config(ErrorMode::Skip)
+        });
+        let df = format.read(&Bytes::from(MALFORMED))?;
+        let batches = df.collect().await?;
+        assert_eq!(ids(&batches), vec![1, 6]);
+        assert_eq!(format.rejected_rows().count(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_on_error_collect_returns_rows_with_line_numbers() -> Result<()> {
+        for batch_size in [1, 2, 8192] {
+            let config = CsvConfig {
+                batch_size,
+                ..malformed_config(ErrorMode::Collect)
+            };
+            let batches = read_stream(config.clone(), MALFORMED).await?;
+            assert_eq!(ids(&batches), vec![1, 6]);
+
+            // Ragged rows, short ones too under `strict_column_count`, are
+            // dropped before values are converted
+            let mut rejected = config.rejected_rows.take();
+            rejected.sort_by_key(|row| row.line);
+            let lines: Vec<u64> = rejected.iter().map(|row| row.line).collect();
+            assert_eq!(lines, vec![3, 4, 5, 6], "batch size {}", batch_size);
+            assert_eq!(rejected[0].record, "2,high,b");
+            assert!(rejected[0].reason.contains("Value 'high' in column 'score'"), "{}", rejected[0].reason);
+            assert_eq!(rejected[1].record, "3,2.5");
+            assert!(rejected[1].reason.contains("row has 2 fields"), "{}", rejected[1].reason);
+            assert_eq!(rejected[2].record, "4,3.5,d,extra");
+            assert!(rejected[2].reason.contains("row has 4 fields"), "{}", rejected[2].reason);
+            assert_eq!(rejected[3].record, "five,4.5,e");
+            assert_eq!(config.rejected_rows.count(), 4);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_columns() -> Result<()> {
+        let config = CsvConfig {
+            timestamp_formats: vec!["%Y-%m-%d %H:%M:%S".to_string(), "%+".to_string()],
+            default_timezone: Some("+01:00".to_string()),
+            ..CsvConfig::default()
+        };
+        let data = "local,iso,name\n2023-01-02 03:04:05,2023-01-02T03:04:05+00:00,a\n,2023-06-30T23:59:59.5-02:00,b\n";
+        let batches = read_stream(config.clone(), data).await?;
+        let schema = batches[0].schema();
+        let expected = DataType::Timestamp(TimeUnit::Nanosecond, Some("+01:00".into()));
+        assert_eq!(schema.field(0).data_type(), &expected);
+        assert_eq!(schema.field(1).data_type(), &expected);
+        assert_eq!(schema.field(2).data_type(), &DataType::Utf8);
+
+        let local = batches[0].column(0).as_any().downcast_ref::<TimestampNanosecondArray>().unwrap();
+        // 03:04:05 at +01:00 is 02:04:05 UTC
+        assert_eq!(local.value(0), 1_672_625_045_000_000_000);
+        assert!(local.is_null(1));
+        let iso = batches[0].column(1).as_any().downcast_ref::<TimestampNanosecondArray>().unwrap();
+        assert_eq!(iso.value(0), 1_672_628_645_000_000_000);
+        assert_eq!(iso.value(1), 1_688_176_799_500_000_000);
+
+        // Without formats timestamps stay strings, and without a timezone they are UTC
+        let batches = read_stream(CsvConfig::default(), data).await?;
+        assert_eq!(batches[0].schema().field(0).data_type(), &DataType::Utf8);
+        let utc = CsvConfig {
+            default_timezone: None,
+            ..config.clone()
+        };
+        let batches = read_stream(utc, data).await?;
+        let local = batches[0].column(0).as_any().downcast_ref::<TimestampNanosecondArray>().unwrap();
+        assert_eq!(local.timezone(), Some("UTC"));
+        assert_eq!(local.value(0), 1_672_628_645_000_000_000);
+
+        // A value past the schema sample matching no format fails once the
+        // column is known to hold timestamps
+        let strict = CsvConfig {
+            batch_size: 1,
+            schema_sample_size: 1,
+            ..config
+        };
+        let err = read_stream(strict, "at\n2023-01-02 03:04:05\nyesterday\n").await.unwrap_err();
+        assert!(err.to_string().contains("Value 'yesterday' in column 'at' is not a valid Timestamp"), "{}", err);
+        Ok(())
+    }
 }
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+
+/// Separates the fields of CSV lines that a single delimiter byte can't
+/// describe. Lines are split on it before parsing, so quoted fields are not
+/// supported.
+#[derive(Debug, Clone)]
+pub enum FieldSeparator {
+    /// A fixed string of several bytes, such as `||`
+    Fixed(Vec<u8>),
+    /// A regular expression; matched against every line, so much slower than
+    /// a fixed delimiter
+    #[cfg(feature = "regex-delimiter")]
+    Regex(regex::bytes::Regex),
+}
+
+impl FieldSeparator {
+    /// Compile `pattern` into a regex separator
+    #[cfg(feature = "regex-delimiter")]
+    pub fn regex(pattern: &str) -> Result<Self> {
+        regex::bytes::Regex::new(pattern)
+            .map(Self::Regex)
+            .map_err(|e| Error::Config(format!("Invalid CSV delimiter regex '{}': {}", pattern, e)))
+    }
+
+    /// Compile `pattern` into a regex separator
+    #[cfg(not(feature = "regex-delimiter"))]
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Err(Error::Config(format!(
+            "CSV delimiter regex '{}' needs the regex-delimiter feature",
+            pattern
+        )))
+    }
+
+    /// Split `line` into its fields
+    pub fn split<'a>(&self, line: &'a [u8]) -> Vec<&'a [u8]> {
+        match self {
+            Self::Fixed(separator) => split_fixed(line, separator),
+            #[cfg(feature = "regex-delimiter")]
+            Self::Regex(regex) => regex.split(line).collect(),
+        }
+    }
+
+    /// Split `data` into records of string fields, one per non-empty line
+    pub(crate) fn records(&self, data: &[u8]) -> Result<Vec<csv::StringRecord>> {
+        data.split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let fields = self
+                    .split(line)
+                    .into_iter()
+                    .map(std::str::from_utf8)
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|e| Error::InvalidInput(format!("CSV line is not valid UTF-8: {}", e)))?;
+                Ok(csv::StringRecord::from(fields))
+            })
+            .collect()
+    }
+}
+
+fn split_fixed<'a>(line: &'a [u8], separator: &[u8]) -> Vec<&'a [u8]> {
+    if separator.is_empty() {
+        return vec![line];
+    }
+    let mut fields = Vec::new();
+    let (mut start, mut i) = (0, 0);
+    while i + separator.len() <= line.len() {
+        if line[i..].starts_with(separator) {
+            fields.push(&line[start..i]);
+            i += separator.len();
+            start = i;
+        } else {
+            i += 1;
+        }
+    }
+    fields.push(&line[start..]);
+    fields
+}
+
+/// Delimiter specification that detects the delimiter from the data; see `sniff_delimiter`
+pub const AUTO_DELIMITER: &str = "auto";
+
+/// Delimiters `sniff_delimiter` chooses from, comma first so it wins ties
+const DELIMITER_CANDIDATES: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+/// Lines of a sample `sniff_delimiter` looks at
+const SNIFF_LINES: usize = 20;
+
+/// Whether `spec` asks for the delimiter to be detected from the data
+pub fn is_auto_delimiter(spec: &str) -> bool {
+    spec.trim().eq_ignore_ascii_case(AUTO_DELIMITER)
+}
+
+/// Guess the delimiter of the CSV lines at the start of `sample` among `,`,
+/// `;`, tab and `|`.
+///
+/// Each candidate is counted outside quotes on every line, and the one that
+/// occurs the same number of times on the most lines wins. Ties, and samples
+/// with none of the candidates, fall back to comma if it is among them.
+pub fn sniff_delimiter(sample: &[u8]) -> u8 {
+    let lines: Vec<&[u8]> = sample
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .take(SNIFF_LINES)
+        .collect();
+    // Lines on which each candidate occurs its most common non-zero number of times
+    let consistency: Vec<(u8, usize)> = DELIMITER_CANDIDATES
+        .iter()
+        .map(|&candidate| {
+            let mut counts: HashMap<usize, usize> = HashMap::new();
+            for line in &lines {
+                let count = count_unquoted(line, candidate);
+                if count > 0 {
+                    *counts.entry(count).or_default() += 1;
+                }
+            }
+            (candidate, counts.into_values().max().unwrap_or(0))
+        })
+        .collect();
+    let best = consistency.iter().map(|&(_, lines)| lines).max().unwrap_or(0);
+    let tied: Vec<u8> = consistency
+        .iter()
+        .filter(|&&(_, lines)| lines == best)
+        .map(|&(candidate, _)| candidate)
+        .collect();
+    let delimiter = tied[0];
+    if best == 0 || tied.len() > 1 {
+        tracing::info!(
+            delimiter = %char::from(delimiter).escape_default(),
+            candidates = ?tied.iter().map(|&c| char::from(c)).collect::<Vec<_>>(),
+            "CSV delimiter is ambiguous, using the preferred candidate"
+        );
+    } else {
+        tracing::debug!(delimiter = %char::from(delimiter).escape_default(), "detected CSV delimiter");
+    }
+    delimiter
+}
+
+/// Occurrences of `byte` in `line` outside double-quoted fields
+fn count_unquoted(line: &[u8], byte: u8) -> usize {
+    let mut quoted = false;
+    let mut count = 0;
+    for &b in line {
+        if b == b'"' {
+            quoted = !quoted;
+        } else if b == byte && !quoted {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Parse a delimiter specification into its bytes: characters stand for
+/// themselves, and `\t`, `\\`, `\0` and `\xHH` are escape sequences.
+///
+/// Unless `multi_char` is set the result must be a single byte.
+pub fn parse_delimiter(spec: &str, multi_char: bool) -> Result<Vec<u8>> {
+    let invalid = |reason: &str| Error::Config(format!("Invalid CSV delimiter '{}': {}", spec, reason));
+    let mut bytes = Vec::new();
+    let mut chars = spec.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('t') => bytes.push(b'\t'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('0') => bytes.push(0),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2)
+                    .ok_or_else(|| invalid("\\x must be followed by two hex digits"))?;
+                bytes.push(byte);
+            }
+            Some(other) => return Err(invalid(&format!("unknown escape sequence \\{}", other))),
+            None => return Err(invalid("trailing backslash")),
+        }
+    }
+    match bytes.len() {
+        0 => Err(invalid("it is empty")),
+        _ if bytes.contains(&b'\n') || bytes.contains(&b'\r') => Err(invalid("it contains a line break")),
+        1 => Ok(bytes),
+        _ if multi_char => Ok(bytes),
+        _ => Err(invalid("several characters need multi_char_delimiter")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_delimiter_escapes() -> Result<()> {
+        assert_eq!(parse_delimiter(",", false)?, b",");
+        assert_eq!(parse_delimiter("\\t", false)?, b"\t");
+        assert_eq!(parse_delimiter("\\x1f", false)?, b"\x1f");
+        assert_eq!(parse_delimiter("||", true)?, b"||");
+        assert!(parse_delimiter("||", false).is_err());
+        assert!(parse_delimiter("\\q", false).is_err());
+        assert!(parse_delimiter("", false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sniff_delimiter() {
+        assert_eq!(sniff_delimiter(b"id;name;price\n1;a;1,5\n2;b;2,25\n"), b';');
+        assert_eq!(sniff_delimiter(b"id\tname\r\n1\t\"x;y\"\r\n"), b'\t');
+        assert_eq!(sniff_delimiter(b"a|b|c\n1|2|3\n"), b'|');
+        // One column, or every candidate equally likely: comma
+        assert_eq!(sniff_delimiter(b"id\n1\n"), b',');
+        assert_eq!(sniff_delimiter(b"a,b;c\n1,2;3\n"), b',');
+        assert!(is_auto_delimiter(" Auto"));
+    }
+
+    #[test]
+    fn test_fixed_separator_keeps_empty_fields() -> Result<()> {
+        let separator = FieldSeparator::Fixed(b"||".to_vec());
+        let records = separator.records(b"a||b||c\r\n1||||3\n")?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].iter().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(records[1].iter().collect::<Vec<_>>(), ["1", "", "3"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,431 @@
+use arrow::datatypes::{Schema, SchemaRef};
+use arrow::json::reader::infer_json_schema_from_iterator;
+use arrow::json::{ArrayWriter, LineDelimitedWriter, ReaderBuilder};
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use bytes::Bytes;
+use datafusion::dataframe::DataFrame;
+use datafusion::datasource::MemTable;
+use datafusion::execution::context::SessionContext;
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+use super::line_stream::line_chunks;
+use super::{
+    already_finalized, apply_read_options, BatchStream, BatchWriter, DataFormat, DataSink, DataStream,
+    FormatCapabilities, ReadOptions,
+};
+use crate::error::{Error, Result};
+
+/// Physical layout of a JSON document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JsonLayout {
+    /// Detect the layout from the first non-whitespace byte
+    Auto,
+    /// A single top-level array of objects
+    Array,
+    /// One object per line (NDJSON)
+    Lines,
+}
+
+impl JsonLayout {
+    /// Detect the layout of `data`: `[` means an array, anything else is treated as lines
+    pub fn sniff(data: &[u8]) -> JsonLayout {
+        let data = data.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(data);
+        match data.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'[') => JsonLayout::Array,
+            _ => JsonLayout::Lines,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JsonConfig {
+    pub layout: JsonLayout,
+    pub batch_size: usize,
+    /// Number of lines the schema of streamed newline-delimited input is
+    /// inferred from; a field first seen after them is an error
+    pub schema_sample_size: usize,
+}
+
+impl Default for JsonConfig {
+    fn default() -> Self {
+        Self {
+            layout: JsonLayout::Auto,
+            batch_size: 1024,
+            schema_sample_size: 1000,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct JsonFormat {
+    config: JsonConfig,
+}
+
+impl JsonFormat {
+    pub fn new(config: JsonConfig) -> Self {
+        Self { config }
+    }
+
+    fn layout_for(&self, data: &[u8]) -> JsonLayout {
+        match self.config.layout {
+            JsonLayout::Auto => JsonLayout::sniff(data),
+            layout => layout,
+        }
+    }
+
+    fn decode(&self, data: &Bytes) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+        let values: Vec<serde_json::Value> = match self.layout_for(data) {
+            JsonLayout::Array => match serde_json::from_slice(data)? {
+                serde_json::Value::Array(values) => values,
+                _ => return Err(Error::InvalidInput("Expected a top-level JSON array".to_string())),
+            },
+            _ => serde_json::Deserializer::from_slice(data)
+                .into_iter::<serde_json::Value>()
+                .collect::<Result<_, _>>()?,
+        };
+
+        let schema = Arc::new(infer_json_schema_from_iterator(values.iter().map(Ok))?);
+        let mut decoder = ReaderBuilder::new(schema.clone())
+            .with_batch_size(self.config.batch_size)
+            .build_decoder()?;
+
+        let mut batches = Vec::new();
+        for chunk in values.chunks(self.config.batch_size.max(1)) {
+            decoder.serialize(chunk)?;
+            if let Some(batch) = decoder.flush()? {
+                batches.push(batch);
+            }
+        }
+        Ok((schema, batches))
+    }
+
+    fn encode(&self, batches: &[RecordBatch]) -> Result<Bytes> {
+        let mut buf = Vec::new();
+        let refs: Vec<&RecordBatch> = batches.iter().collect();
+        match self.config.layout {
+            JsonLayout::Array => {
+                let mut writer = ArrayWriter::new(&mut buf);
+                writer.write_batches(&refs)?;
+                writer.finish()?;
+            }
+            _ => {
+                let mut writer = LineDelimitedWriter::new(&mut buf);
+                writer.write_batches(&refs)?;
+                writer.finish()?;
+            }
+        }
+        Ok(Bytes::from(buf))
+    }
+}
+
+enum JsonWriter {
+    Array(ArrayWriter<Vec<u8>>),
+    Lines(LineDelimitedWriter<Vec<u8>>),
+}
+
+/// Encodes batches into an in-memory buffer; an array is closed on `finalize`
+struct JsonBatchWriter {
+    writer: Option<JsonWriter>,
+}
+
+#[async_trait]
+impl BatchWriter for JsonBatchWriter {
+    async fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        match self.writer.as_mut().ok_or_else(already_finalized)? {
+            JsonWriter::Array(writer) => writer.write(batch)?,
+            JsonWriter::Lines(writer) => writer.write(batch)?,
+        }
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<Bytes> {
+        let data = match self.writer.take().ok_or_else(already_finalized)? {
+            JsonWriter::Array(mut writer) => {
+                writer.finish()?;
+                writer.into_inner()
+            }
+            JsonWriter::Lines(mut writer) => {
+                writer.finish()?;
+                writer.into_inner()
+            }
+        };
+        Ok(Bytes::from(data))
+    }
+}
+
+#[async_trait]
+impl DataFormat for JsonFormat {
+    fn read(&self, data: &Bytes) -> Result<DataFrame> {
+        let (schema, batches) = if data.iter().all(|b| b.is_ascii_whitespace()) {
+            (Arc::new(Schema::empty()), Vec::new())
+        } else {
+            self.decode(data)?
+        };
+        let ctx = SessionContext::new();
+        let table = MemTable::try_new(schema, vec![batches])?;
+        Ok(ctx.read_table(Arc::new(table))?)
+    }
+
+    fn write(&self, df: &DataFrame) -> Result<Bytes> {
+        let batches = futures::executor::block_on(df.clone().collect())?;
+        self.encode(&batches)
+    }
+
+    fn write_batch(&self, batch: &RecordBatch) -> Result<Bytes> {
+        self.encode(std::slice::from_ref(batch))
+    }
+
+    fn batch_writer(&self, _schema: SchemaRef) -> Result<Box<dyn BatchWriter + '_>> {
+        let writer = match self.config.layout {
+            JsonLayout::Array => JsonWriter::Array(ArrayWriter::new(Vec::new())),
+            _ => JsonWriter::Lines(LineDelimitedWriter::new(Vec::new())),
+        };
+        Ok(Box::new(JsonBatchWriter { writer: Some(writer) }))
+    }
+
+    /// Only newline-delimited input streams; a layout that may be an array
+    /// is buffered
+    fn capabilities(&self) -> FormatCapabilities {
+        FormatCapabilities {
+            streaming_read: self.config.layout == JsonLayout::Lines,
+            ..FormatCapabilities::default()
+        }
+    }
+
+    /// Newline-delimited input is decoded line by line, inferring the schema
+    /// from the first `schema_sample_size` lines. Arrays have to be buffered completely.
+    fn read_batches_from_stream(&self, stream: DataStream) -> Result<BatchStream> {
+        let mut config = self.config.clone();
+        if config.layout != JsonLayout::Auto {
+            return Ok(stream_batches(config, stream));
+        }
+
+        // Sniff the layout from the first chunk that isn't just whitespace
+        let batches = futures::stream::once(async move {
+            let mut stream = stream;
+            let mut head = Vec::new();
+            config.layout = JsonLayout::Lines;
+            while let Some(chunk) = stream.try_next().await? {
+                let is_blank = chunk.iter().all(|b| b.is_ascii_whitespace());
+                if !is_blank {
+                    config.layout = JsonLayout::sniff(&chunk);
+                }
+                head.push(Ok(chunk));
+                if !is_blank {
+                    break;
+                }
+            }
+            let stream = futures::stream::iter(head).chain(stream).boxed();
+            Ok::<_, Error>(stream_batches(config, stream))
+        })
+        .try_flatten();
+        Ok(batches.boxed())
+    }
+
+    fn read_batches_with(&self, stream: DataStream, options: &ReadOptions) -> Result<BatchStream> {
+        let format = JsonFormat::new(JsonConfig {
+            batch_size: options.batch_size.unwrap_or(self.config.batch_size),
+            ..self.config.clone()
+        });
+        let batches = format.read_batches_from_stream(stream)?;
+        Ok(apply_read_options(batches, &ReadOptions { batch_size: None, ..options.clone() }))
+    }
+
+    /// Newline-delimited output can be appended to; a JSON array can't
+    async fn write_batches_for_append(&self, batches: BatchStream, _new_file: bool) -> Result<Bytes> {
+        if self.config.layout == JsonLayout::Array {
+            return Err(Error::UnsupportedFormat(
+                "A JSON array can't be appended to; write newline-delimited JSON instead".to_string(),
+            ));
+        }
+        self.write_batches(batches).await
+    }
+
+    /// Newline-delimited output is written batch by batch. A JSON array has to
+    /// be closed after the last batch, so it is encoded in one go.
+    async fn write_batches_to(&self, mut batches: BatchStream, mut sink: DataSink) -> Result<()> {
+        if self.config.layout == JsonLayout::Array {
+            let batches: Vec<RecordBatch> = batches.try_collect().await?;
+            sink.write_all(&self.encode(&batches)?).await?;
+        } else {
+            while let Some(batch) = batches.try_next().await? {
+                sink.write_all(&self.encode(std::slice::from_ref(&batch))?).await?;
+            }
+        }
+        sink.shutdown().await?;
+        Ok(())
+    }
+}
+
+fn stream_batches(config: JsonConfig, stream: DataStream) -> BatchStream {
+    if config.layout == JsonLayout::Array {
+        let format = JsonFormat::new(config);
+        let batches = futures::stream::once(async move {
+            let data: Vec<Bytes> = stream.try_collect().await?;
+            let (_, batches) = format.decode(&Bytes::from(data.concat()))?;
+            Ok::<_, Error>(futures::stream::iter(batches.into_iter().map(Ok)))
+        })
+        .try_flatten();
+        return batches.boxed();
+    }
+
+    let (batch_size, sample_size) = (config.batch_size, config.schema_sample_size.max(1));
+    let mut chunks = line_chunks(stream, batch_size);
+    let batches = futures::stream::once(async move {
+        // Lines are parsed once; the chunks holding the sample are kept until it is complete
+        let mut sample: Vec<Vec<serde_json::Value>> = Vec::new();
+        while sample.iter().map(Vec::len).sum::<usize>() < sample_size {
+            match chunks.try_next().await? {
+                Some(lines) => sample.push(parse_lines(&lines)?),
+                None => break,
+            }
+        }
+        let schema = Arc::new(infer_json_schema_from_iterator(sample.iter().flatten().take(sample_size).map(Ok))?);
+        let mut decoder = ReaderBuilder::new(schema.clone())
+            .with_batch_size(batch_size)
+            .with_strict_mode(true)
+            .build_decoder()?;
+
+        let rest = chunks.and_then(|lines| futures::future::ready(parse_lines(&lines)));
+        let batches = futures::stream::iter(sample.into_iter().map(Ok)).chain(rest).and_then(move |values| {
+            let batch = decoder
+                .serialize(&values)
+                .and_then(|_| decoder.flush())
+                .map_err(|e| {
+                    Error::InvalidInput(format!(
+                        "{}; the schema was inferred from the first {} lines, see `schema_sample_size`",
+                        e, sample_size
+                    ))
+                })
+                .map(|batch| batch.unwrap_or_else(|| RecordBatch::new_empty(schema.clone())));
+            futures::future::ready(batch)
+        });
+        Ok::<_, Error>(batches)
+    })
+    .try_flatten();
+    batches.boxed()
+}
+
+fn parse_lines(lines: &[Bytes]) -> Result<Vec<serde_json::Value>> {
+    Ok(lines
+        .iter()
+        .map(|line| serde_json::from_slice::<serde_json::Value>(line))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_layout() {
+        assert_eq!(JsonLayout::sniff(b"  \n[{\"a\": 1}]"), JsonLayout::Array);
+        assert_eq!(JsonLayout::sniff(b"{\"a\": 1}\n{\"a\": 2}\n"), JsonLayout::Lines);
+    }
+
+    #[tokio::test]
+    async fn test_read_json_array() -> Result<()> {
+        let data = Bytes::from(r#"[{"a": 1, "b": "x"}, {"a": 2, "b": "y"}]"#);
+        let df = JsonFormat::default().read(&data)?;
+        let batches = df.collect().await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        assert_eq!(batches[0].schema().field(0).name(), "a");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_json_lines() -> Result<()> {
+        let data = Bytes::from("{\"a\": 1}\n{\"a\": 2}\n{\"a\": 3}\n");
+        let format = JsonFormat::new(JsonConfig {
+            layout: JsonLayout::Lines,
+            ..JsonConfig::default()
+        });
+        let batches = format.read(&data)?.collect().await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_json_lines_across_chunk_boundaries() -> Result<()> {
+        let chunks = vec!["{\"a\": 1}\n{\"a\"", ": 2}\r\n{\"a\": 3}"];
+        let stream = futures::stream::iter(chunks.into_iter().map(|c| Ok(Bytes::from(c)))).boxed();
+        let format = JsonFormat::new(JsonConfig {
+            layout: JsonLayout::Lines,
+            batch_size: 2,
+            ..JsonConfig::default()
+        });
+
+        let batches: Vec<RecordBatch> = format.read_batches_from_stream(stream)?.try_collect().await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_json_lines_infers_schema_from_sample() -> Result<()> {
+        let data = "{\"a\": 1}\n{\"a\": 2}\n{\"a\": 3, \"b\": \"x\"}\n{\"a\": 4, \"c\": true}\n";
+        let read = |schema_sample_size| {
+            let stream = futures::stream::iter(vec![Ok(Bytes::from(data))]).boxed();
+            let format = JsonFormat::new(JsonConfig {
+                layout: JsonLayout::Lines,
+                batch_size: 2,
+                schema_sample_size,
+            });
+            async move { format.read_batches_from_stream(stream)?.try_collect::<Vec<RecordBatch>>().await }
+        };
+
+        // A field first seen in a later chunk of the sample is part of the schema
+        let batches = read(4).await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 4);
+        let names: Vec<_> = batches[0].schema().fields().iter().map(|f| f.name().clone()).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert!(batches.iter().all(|batch| batch.schema() == batches[0].schema()));
+
+        // One first seen after the sample is an error rather than dropped
+        let err = read(3).await.unwrap_err();
+        assert!(err.to_string().contains("schema_sample_size"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_sniffs_json_array() -> Result<()> {
+        let chunks = vec!["  ", "[{\"a\": 1},", " {\"a\": 2}]"];
+        let stream = futures::stream::iter(chunks.into_iter().map(|c| Ok(Bytes::from(c)))).boxed();
+
+        let batches: Vec<RecordBatch> = JsonFormat::default().read_batches_from_stream(stream)?.try_collect().await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finalize_closes_json_array() -> Result<()> {
+        let data = Bytes::from(r#"[{"a": 1}, {"a": 2}]"#);
+        let batches = JsonFormat::default().read(&data)?.collect().await?;
+        let format = JsonFormat::new(JsonConfig {
+            layout: JsonLayout::Array,
+            ..JsonConfig::default()
+        });
+
+        let mut writer = format.batch_writer(batches[0].schema())?;
+        for batch in &batches {
+            writer.write(batch).await?;
+        }
+        let written = writer.finalize().await?;
+        let values: serde_json::Value = serde_json::from_slice(&written)?;
+        assert_eq!(values, serde_json::json!([{"a": 1}, {"a": 2}]));
+        assert!(writer.finalize().await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_explicit_array_layout_rejects_lines() {
+        let data = Bytes::from("{\"a\": 1}\n{\"a\": 2}\n");
+        let format = JsonFormat::new(JsonConfig {
+            layout: JsonLayout::Array,
+            ..JsonConfig::default()
+        });
+        assert!(format.read(&data).is_err());
+    }
+}
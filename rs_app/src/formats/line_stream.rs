@@ -0,0 +1,122 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::error::Result;
+use bytes::{Bytes, BytesMut};
+use futures::stream::{BoxStream, Stream, StreamExt, TryStreamExt};
+use futures::ready;
+
+use super::DataStream;
+
+/// Splits a stream of arbitrary byte chunks into lines.
+///
+/// Lines may span chunk boundaries. Both `\n` and `\r\n` terminators are
+/// accepted and stripped, and a final line without a trailing newline is
+/// still yielded.
+pub struct LineStream {
+    inner: DataStream,
+    buffer: BytesMut,
+    /// Bytes of `buffer` already scanned for a newline
+    scanned: usize,
+    done: bool,
+}
+
+impl LineStream {
+    pub fn new(inner: DataStream) -> Self {
+        Self {
+            inner,
+            buffer: BytesMut::new(),
+            scanned: 0,
+            done: false,
+        }
+    }
+
+    fn take_line(&mut self, end: usize, consumed: usize) -> Bytes {
+        let mut line = self.buffer.split_to(consumed).freeze();
+        line.truncate(end);
+        if line.last() == Some(&b'\r') {
+            line.truncate(line.len() - 1);
+        }
+        self.scanned = 0;
+        line
+    }
+}
+
+impl Stream for LineStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let this = &mut *self;
+            if let Some(offset) = this.buffer[this.scanned..].iter().position(|b| *b == b'\n') {
+                let end = this.scanned + offset;
+                return Poll::Ready(Some(Ok(this.take_line(end, end + 1))));
+            }
+            this.scanned = this.buffer.len();
+
+            if this.done {
+                if this.buffer.is_empty() {
+                    return Poll::Ready(None);
+                }
+                let len = this.buffer.len();
+                return Poll::Ready(Some(Ok(this.take_line(len, len))));
+            }
+
+            match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => this.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => this.done = true,
+            }
+        }
+    }
+}
+
+/// The non-empty lines of `stream`
+pub fn non_empty_lines(stream: DataStream) -> BoxStream<'static, Result<Bytes>> {
+    LineStream::new(stream)
+        .try_filter(|line| futures::future::ready(!line.is_empty()))
+        .boxed()
+}
+
+/// Group `lines` into chunks of at most `size` lines
+pub fn chunk_lines(lines: BoxStream<'static, Result<Bytes>>, size: usize) -> BoxStream<'static, Result<Vec<Bytes>>> {
+    lines.try_chunks(size.max(1)).map_err(|e| e.1).boxed()
+}
+
+/// Group the non-empty lines of `stream` into chunks of at most `size` lines
+pub fn line_chunks(stream: DataStream, size: usize) -> BoxStream<'static, Result<Vec<Bytes>>> {
+    chunk_lines(non_empty_lines(stream), size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_of(chunks: &'static [&'static str]) -> DataStream {
+        futures::stream::iter(chunks.iter().map(|c| Ok(Bytes::from_static(c.as_bytes())))).boxed()
+    }
+
+    async fn lines(chunks: &'static [&'static str]) -> Vec<Bytes> {
+        LineStream::new(stream_of(chunks)).try_collect().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_lines_split_across_chunks() {
+        let result = lines(&["a,b\n1,", "2\n3", ",4\n"]).await;
+        assert_eq!(result, vec!["a,b", "1,2", "3,4"]);
+    }
+
+    #[tokio::test]
+    async fn test_crlf_and_missing_final_newline() {
+        let result = lines(&["a\r", "\nb\r\n", "c"]).await;
+        assert_eq!(result, vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_empty_lines_are_preserved_but_skipped_by_chunks() {
+        assert_eq!(lines(&["a\n\nb\n"]).await, vec!["a", "", "b"]);
+
+        let chunks: Vec<Vec<Bytes>> = line_chunks(stream_of(&["a\n\nb\nc\n"]), 2).try_collect().await.unwrap();
+        assert_eq!(chunks, vec![vec![Bytes::from("a"), Bytes::from("b")], vec![Bytes::from("c")]]);
+    }
+}
@@ -1,24 +1,541 @@
-use anyhow::Result;
+use std::pin::Pin;
+
+use arrow::datatypes::SchemaRef;
 use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
 use bytes::Bytes;
 use datafusion::dataframe::DataFrame;
+use datafusion::physical_plan::Statistics;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::stats::{self, InputStats};
+use crate::storage::Storage;
 
-pub use csv_format::CsvFormat;
-pub use parquet_format::ParquetFormat;
+pub use batch_size::{batch_size_for, estimated_row_bytes, AdaptiveBatchSize};
+pub use codec::{compression_extension, decompress, decompress_stream, strip_compression_extension};
+pub use compression::{
+    check_compression_supported, parse_compression, register_compression, supported_compressions, CompressionRegistry,
+};
+pub use csv_format::{parse_column_type, parse_timezone, CsvConfig, CsvFormat, ErrorMode, RejectedRow, RejectedRows};
+pub use delimiter::{is_auto_delimiter, parse_delimiter, sniff_delimiter, FieldSeparator, AUTO_DELIMITER};
+pub use json_format::{JsonConfig, JsonFormat, JsonLayout};
+pub use line_stream::LineStream;
+pub use parquet_format::{parse_encoding, parse_writer_version, ParquetConfig, ParquetFormat, RemoteParquetFile};
 
+mod batch_size;
+mod codec;
+mod compression;
 mod csv_format;
+mod delimiter;
+mod json_format;
+mod line_stream;
 mod parquet_format;
 
+/// A stream of raw bytes read from storage
+pub type DataStream = BoxStream<'static, Result<Bytes>>;
+
+/// A stream of decoded record batches
+pub type BatchStream = BoxStream<'static, Result<RecordBatch>>;
+
+/// A destination for encoded output, such as a file or an upload pipe
+pub type DataSink = Pin<Box<dyn AsyncWrite + Send>>;
+
+/// What a `DataFormat` can do beyond decoding whole files, so callers can
+/// pick a code path before reading anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FormatCapabilities {
+    /// `read_batches_from_stream` yields batches before the whole object has arrived
+    pub streaming_read: bool,
+    /// Unselected columns are skipped while decoding, not dropped afterwards;
+    /// see `read_row_groups_from_storage`
+    pub projection_pushdown: bool,
+    /// Rows or row groups that can't match a filter are skipped while decoding
+    pub predicate_pushdown: bool,
+    /// `infer_schema` reads stored metadata instead of sampling rows
+    pub schema_without_sample: bool,
+    /// Arrow field metadata and schema key-value metadata survive a write and read
+    pub field_metadata: bool,
+}
+
+#[async_trait]
 pub trait DataFormat: Send + Sync {
     fn read(&self, data: &Bytes) -> Result<DataFrame>;
     fn write(&self, df: &DataFrame) -> Result<Bytes>;
     fn write_batch(&self, batch: &RecordBatch) -> Result<Bytes>;
+
+    /// What this format supports; the default claims nothing
+    fn capabilities(&self) -> FormatCapabilities {
+        FormatCapabilities::default()
+    }
+
+    /// Decode record batches incrementally from a stream of byte chunks
+    fn read_batches_from_stream(&self, stream: DataStream) -> Result<BatchStream>;
+
+    /// Decode like `read_batches_from_stream`, with `options` overriding the
+    /// format's batch size for this call and selecting columns and rows.
+    ///
+    /// The default implementation rechunks the format's own batches; the
+    /// built-in formats decode batches of the requested size directly.
+    fn read_batches_with(&self, stream: DataStream, options: &ReadOptions) -> Result<BatchStream> {
+        Ok(apply_read_options(self.read_batches_from_stream(stream)?, options))
+    }
+
+    /// Create a writer that encodes batches of `schema` one at a time.
+    ///
+    /// The default implementation buffers the batches and encodes them with
+    /// `write_batches` when finalized.
+    fn batch_writer(&self, schema: SchemaRef) -> Result<Box<dyn BatchWriter + '_>> {
+        let _ = schema;
+        Ok(Box::new(BufferedBatchWriter {
+            format: self,
+            batches: Some(Vec::new()),
+        }))
+    }
+
+    /// Decode the `size`-byte object at `url`.
+    ///
+    /// The default implementation streams the object through
+    /// `read_batches_from_stream`, decompressing it first when its extension
+    /// names a compression such as `.gz` or `.zst`; formats that need random
+    /// access override it.
+    async fn read_batches_from_storage(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, _size: usize) -> Result<BatchStream> {
+        self.read_batches_from_stream(read_decompressed(storage.as_ref(), url).await?)
+    }
+
+    /// Infer the schema of the `size`-byte object at `url`, reading as little as possible.
+    ///
+    /// The default implementation decodes only the first batch.
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url, size))]
+    async fn infer_schema(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<SchemaRef> {
+        let mut batches = self.read_batches_from_storage(storage, url, size).await?;
+        match batches.try_next().await? {
+            Some(batch) => {
+                tracing::debug!(columns = batch.num_columns(), "inferred schema");
+                Ok(batch.schema())
+            }
+            None => Err(Error::EmptyInput(format!("Cannot infer a schema from {}: it has no rows", url))),
+        }
+    }
+
+    /// Count the rows of the `size`-byte object at `url` and collect per-column statistics.
+    ///
+    /// The default implementation decodes every batch; formats with stored
+    /// statistics override it to avoid decoding.
+    async fn compute_stats(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<InputStats> {
+        let mut batches = self.read_batches_from_storage(storage, url, size).await?;
+        let Some(first) = batches.try_next().await? else {
+            return Ok(InputStats::new(&std::sync::Arc::new(arrow::datatypes::Schema::empty())));
+        };
+        let schema = first.schema();
+        let batches = futures::stream::once(futures::future::ready(Ok(first))).chain(batches).boxed();
+        stats::collect_stats(&schema, batches).await
+    }
+
+    /// Statistics of the `size`-byte object at `url` for query planning,
+    /// read without decoding the whole object.
+    ///
+    /// The default implementation only knows the size; formats override it
+    /// with exact numbers from their metadata or with estimates.
+    async fn plan_statistics(&self, _storage: std::sync::Arc<dyn Storage>, _url: &Url, size: usize) -> Result<Statistics> {
+        Ok(Statistics {
+            total_byte_size: Some(size),
+            ..Statistics::default()
+        })
+    }
+
+    /// Number of row groups in the `size`-byte object at `url`, for formats
+    /// whose files can be decoded a row group at a time.
+    ///
+    /// The default implementation returns `None`: the object is read whole.
+    async fn row_group_count(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<Option<usize>> {
+        let _ = (storage, url, size);
+        Ok(None)
+    }
+
+    /// Decode only the row groups at `row_groups` of the `size`-byte object
+    /// at `url`, in the order given, and only the columns at the ascending
+    /// indices of `projection` when it is set. Formats that report a
+    /// `row_group_count` must override it; formats that don't report
+    /// `projection_pushdown` may decode every column.
+    async fn read_row_groups_from_storage(
+        &self,
+        storage: std::sync::Arc<dyn Storage>,
+        url: &Url,
+        size: usize,
+        row_groups: Vec<usize>,
+        projection: Option<Vec<usize>>,
+    ) -> Result<BatchStream> {
+        let _ = (storage, size, row_groups, projection);
+        Err(Error::UnsupportedFormat(format!("{} can't be read by row group", url)))
+    }
+
+    /// Encode a stream of batches into a single in-memory file.
+    ///
+    /// The default implementation drives `write_batches_to` through a pipe.
+    async fn write_batches(&self, batches: BatchStream) -> Result<Bytes> {
+        let (writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let mut buf = Vec::new();
+        let (written, read) = tokio::join!(
+            self.write_batches_to(batches, Box::pin(writer)),
+            tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut buf)
+        );
+        written?;
+        read?;
+        Ok(Bytes::from(buf))
+    }
+
+    /// Encode `batches` to be appended to a file of this format; `new_file`
+    /// is set when there is no existing file, e.g. to write a CSV header.
+    ///
+    /// The default implementation fails, since formats like Parquet can't be
+    /// appended to in place.
+    async fn write_batches_for_append(&self, batches: BatchStream, new_file: bool) -> Result<Bytes> {
+        let _ = (batches, new_file);
+        Err(Error::UnsupportedFormat(
+            "This format can't be appended to; write new files into a partitioned layout with --partition-by instead"
+                .to_string(),
+        ))
+    }
+
+    /// Encode `batches` into `sink` as they arrive and shut the sink down when done.
+    ///
+    /// The default implementation collects every batch and calls `write`;
+    /// the built-in formats override it to flush output incrementally.
+    async fn write_batches_to(&self, batches: BatchStream, mut sink: DataSink) -> Result<()> {
+        let batches: Vec<RecordBatch> = batches.try_collect().await?;
+        let schema = batches
+            .first()
+            .map(|batch| batch.schema())
+            .unwrap_or_else(|| std::sync::Arc::new(arrow::datatypes::Schema::empty()));
+        let table = datafusion::datasource::MemTable::try_new(schema, vec![batches])?;
+        let df = datafusion::execution::context::SessionContext::new().read_table(std::sync::Arc::new(table))?;
+        sink.write_all(&self.write(&df)?).await?;
+        sink.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// A boxed format, such as one from the format registry, is a format too, so
+/// an `Arc<Box<dyn DataFormat>>` can be shared as an `Arc<dyn DataFormat>`
+#[async_trait]
+impl<T: DataFormat + ?Sized> DataFormat for Box<T> {
+    fn read(&self, data: &Bytes) -> Result<DataFrame> {
+        (**self).read(data)
+    }
+
+    fn write(&self, df: &DataFrame) -> Result<Bytes> {
+        (**self).write(df)
+    }
+
+    fn write_batch(&self, batch: &RecordBatch) -> Result<Bytes> {
+        (**self).write_batch(batch)
+    }
+
+    fn capabilities(&self) -> FormatCapabilities {
+        (**self).capabilities()
+    }
+
+    fn read_batches_from_stream(&self, stream: DataStream) -> Result<BatchStream> {
+        (**self).read_batches_from_stream(stream)
+    }
+
+    fn read_batches_with(&self, stream: DataStream, options: &ReadOptions) -> Result<BatchStream> {
+        (**self).read_batches_with(stream, options)
+    }
+
+    fn batch_writer(&self, schema: SchemaRef) -> Result<Box<dyn BatchWriter + '_>> {
+        (**self).batch_writer(schema)
+    }
+
+    async fn read_batches_from_storage(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<BatchStream> {
+        (**self).read_batches_from_storage(storage, url, size).await
+    }
+
+    async fn infer_schema(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<SchemaRef> {
+        (**self).infer_schema(storage, url, size).await
+    }
+
+    async fn compute_stats(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<InputStats> {
+        (**self).compute_stats(storage, url, size).await
+    }
+
+    async fn plan_statistics(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<Statistics> {
+        (**self).plan_statistics(storage, url, size).await
+    }
+
+    async fn row_group_count(&self, storage: std::sync::Arc<dyn Storage>, url: &Url, size: usize) -> Result<Option<usize>> {
+        (**self).row_group_count(storage, url, size).await
+    }
+
+    async fn read_row_groups_from_storage(
+        &self,
+        storage: std::sync::Arc<dyn Storage>,
+        url: &Url,
+        size: usize,
+        row_groups: Vec<usize>,
+        projection: Option<Vec<usize>>,
+    ) -> Result<BatchStream> {
+        (**self).read_row_groups_from_storage(storage, url, size, row_groups, projection).await
+    }
+
+    async fn write_batches(&self, batches: BatchStream) -> Result<Bytes> {
+        (**self).write_batches(batches).await
+    }
+
+    async fn write_batches_for_append(&self, batches: BatchStream, new_file: bool) -> Result<Bytes> {
+        (**self).write_batches_for_append(batches, new_file).await
+    }
+
+    async fn write_batches_to(&self, batches: BatchStream, sink: DataSink) -> Result<()> {
+        (**self).write_batches_to(batches, sink).await
+    }
+}
+
+/// Incrementally encodes batches into a single file
+#[async_trait]
+pub trait BatchWriter: Send {
+    async fn write(&mut self, batch: &RecordBatch) -> Result<()>;
+
+    /// Flush anything still buffered, such as a Parquet footer, and return the
+    /// encoded file. The writer can't be used afterwards; calling `finalize`
+    /// again is an error.
+    async fn finalize(&mut self) -> Result<Bytes>;
+}
+
+/// Per-call options for [`DataFormat::read_batches_with`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Rows per batch; the format's configured size when `None`
+    pub batch_size: Option<usize>,
+    /// Indices of the columns to keep, in the order given
+    pub projection: Option<Vec<usize>>,
+    /// Stop after this many rows
+    pub limit: Option<usize>,
+}
+
+impl ReadOptions {
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = Some(batch_size);
+        self
+    }
+
+    pub fn with_projection(mut self, projection: Vec<usize>) -> Self {
+        self.projection = Some(projection);
+        self
+    }
+
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+}
+
+/// Project, limit and then rechunk `batches` to `options.batch_size`
+pub(crate) fn apply_read_options(mut batches: BatchStream, options: &ReadOptions) -> BatchStream {
+    if let Some(projection) = options.projection.clone() {
+        batches = batches
+            .and_then(move |batch| futures::future::ready(batch.project(&projection).map_err(Error::from)))
+            .boxed();
+    }
+    if let Some(limit) = options.limit {
+        batches = futures::stream::try_unfold((batches, limit), |(mut batches, remaining)| async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+            let Some(batch) = batches.try_next().await? else {
+                return Ok(None);
+            };
+            let batch = batch.slice(0, batch.num_rows().min(remaining));
+            let remaining = remaining - batch.num_rows();
+            Ok(Some((batch, (batches, remaining))))
+        })
+        .boxed();
+    }
+    match options.batch_size {
+        Some(size) => rechunk(batches, size.max(1)),
+        None => batches,
+    }
+}
+
+/// Batches not yet emitted by [`rechunk`] and their total row count
+struct Rechunk {
+    input: Option<BatchStream>,
+    pending: Vec<RecordBatch>,
+    rows: usize,
+}
+
+/// Regroup `batches` into batches of exactly `size` rows, except the last
+fn rechunk(batches: BatchStream, size: usize) -> BatchStream {
+    let state = Rechunk {
+        input: Some(batches),
+        pending: Vec::new(),
+        rows: 0,
+    };
+    futures::stream::try_unfold(state, move |state| next_chunk(state, size)).boxed()
+}
+
+async fn next_chunk(mut state: Rechunk, size: usize) -> Result<Option<(RecordBatch, Rechunk)>> {
+    loop {
+        if state.rows >= size || (state.input.is_none() && state.rows > 0) {
+            // A single pending batch is sliced without copying
+            let all = match state.pending.len() {
+                1 => state.pending.remove(0),
+                _ => arrow::compute::concat_batches(&state.pending[0].schema(), &state.pending)?,
+            };
+            let take = all.num_rows().min(size);
+            let rest = all.slice(take, all.num_rows() - take);
+            state.pending = if rest.num_rows() > 0 { vec![rest] } else { Vec::new() };
+            state.rows -= take;
+            return Ok(Some((all.slice(0, take), state)));
+        }
+        let Some(input) = state.input.as_mut() else {
+            return Ok(None);
+        };
+        match input.try_next().await? {
+            Some(batch) if batch.num_rows() > 0 => {
+                state.rows += batch.num_rows();
+                state.pending.push(batch);
+            }
+            Some(_) => {}
+            None => state.input = None,
+        }
+    }
+}
+
+/// Error returned when a writer is used after `finalize`
+pub(crate) fn already_finalized() -> Error {
+    Error::InvalidInput("Writer has already been finalized".to_string())
+}
+
+struct BufferedBatchWriter<'a, F: DataFormat + ?Sized> {
+    format: &'a F,
+    batches: Option<Vec<RecordBatch>>,
+}
+
+#[async_trait]
+impl<F: DataFormat + ?Sized> BatchWriter for BufferedBatchWriter<'_, F> {
+    async fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        self.batches.as_mut().ok_or_else(already_finalized)?.push(batch.clone());
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<Bytes> {
+        let batches = self.batches.take().ok_or_else(already_finalized)?;
+        let stream = futures::stream::iter(batches.into_iter().map(Ok)).boxed();
+        self.format.write_batches(stream).await
+    }
+}
+
+/// What to do when an input object contains zero bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmptyInputPolicy {
+    /// Fail with a descriptive error
+    #[default]
+    Error,
+    /// Treat the input as an empty table with no columns
+    EmptyOutput,
+}
+
+/// Read `data` with `format`, handling zero-length input according to `policy`
+/// before the format gets a chance to fail with a parse error.
+pub fn read_or_empty(
+    format: &dyn DataFormat,
+    data: &Bytes,
+    policy: EmptyInputPolicy,
+    source: &str,
+) -> Result<DataFrame> {
+    if !data.is_empty() {
+        return format.read(data);
+    }
+    match policy {
+        EmptyInputPolicy::Error => Err(Error::EmptyInput(format!("{} is empty (0 bytes)", source))),
+        EmptyInputPolicy::EmptyOutput => {
+            let ctx = datafusion::execution::context::SessionContext::new();
+            let schema = std::sync::Arc::new(arrow::datatypes::Schema::empty());
+            Ok(ctx.read_batch(RecordBatch::new_empty(schema))?)
+        }
+    }
+}
+
+/// The batches produced for a zero-byte input under `policy`
+pub fn empty_input(policy: EmptyInputPolicy, source: &str) -> Result<BatchStream> {
+    match policy {
+        EmptyInputPolicy::Error => Err(Error::EmptyInput(format!("{} is empty (0 bytes)", source))),
+        EmptyInputPolicy::EmptyOutput => Ok(futures::stream::empty().boxed()),
+    }
+}
+
+/// Streaming counterpart of [`read_or_empty`]: the first non-empty chunk is
+/// awaited before `format` sees any data, so a zero-byte object is reported
+/// according to `policy` instead of as a parse error.
+pub fn read_stream_or_empty(
+    format: std::sync::Arc<dyn DataFormat>,
+    stream: DataStream,
+    policy: EmptyInputPolicy,
+    source: &str,
+) -> BatchStream {
+    let source = source.to_string();
+    futures::stream::once(async move {
+        let mut stream = stream;
+        let mut head = None;
+        while let Some(chunk) = stream.try_next().await? {
+            if !chunk.is_empty() {
+                head = Some(chunk);
+                break;
+            }
+        }
+        match head {
+            Some(head) => {
+                let stream = futures::stream::once(futures::future::ready(Ok(head))).chain(stream).boxed();
+                format.read_batches_from_stream(stream)
+            }
+            None => empty_input(policy, &source),
+        }
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Fail with [`Error::EmptyInput`] when the object at `url` has zero bytes,
+/// for formats that can't infer anything from it
+pub(crate) fn check_not_empty(url: &Url, size: usize) -> Result<()> {
+    if size == 0 {
+        return Err(Error::EmptyInput(format!("{} is empty (0 bytes)", url)));
+    }
+    Ok(())
+}
+
+/// `batches`, or a single empty batch of `schema` when the stream ends
+/// without any, so that writers still encode the schema: a header-only CSV
+/// file or a Parquet file without row groups instead of a file with no columns.
+/// A schema without columns has nothing to encode and adds no batch.
+pub fn or_empty_batch(batches: BatchStream, schema: SchemaRef) -> BatchStream {
+    if schema.fields().is_empty() {
+        return batches;
+    }
+    futures::stream::once(async move {
+        let mut batches = batches;
+        let batches = match batches.try_next().await? {
+            Some(first) => futures::stream::once(futures::future::ready(Ok(first))).chain(batches).boxed(),
+            None => futures::stream::once(futures::future::ready(Ok(RecordBatch::new_empty(schema)))).boxed(),
+        };
+        Ok::<_, Error>(batches)
+    })
+    .try_flatten()
+    .boxed()
 }
 
 pub struct FormatRegistry {
     formats: std::collections::HashMap<String, std::sync::Arc<Box<dyn DataFormat + Send + Sync>>>,
+    /// Format name registered for each file extension
+    extensions: std::collections::HashMap<String, String>,
 }
 
 impl FormatRegistry {
@@ -32,7 +549,14 @@ impl FormatRegistry {
             "parquet".to_string(),
             std::sync::Arc::new(Box::new(ParquetFormat::default()) as Box<dyn DataFormat + Send + Sync>),
         );
-        Self { formats }
+        formats.insert(
+            "json".to_string(),
+            std::sync::Arc::new(Box::new(JsonFormat::default()) as Box<dyn DataFormat + Send + Sync>),
+        );
+        Self {
+            formats,
+            extensions: std::collections::HashMap::new(),
+        }
     }
 
     pub fn get_format(&self, format_name: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
@@ -47,30 +571,327 @@ impl FormatRegistry {
         self.formats.insert(format_name, std::sync::Arc::new(format));
     }
 
+    /// Resolve files with `extension` (with or without the leading dot) to the format named `format_name`
+    pub fn register_extension(&mut self, extension: &str, format_name: String) {
+        self.extensions.insert(normalize_extension(extension), format_name);
+    }
+
+    /// The format registered for `extension`, if any
+    pub fn get_registered_format_for_extension(&self, extension: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
+        self.extensions
+            .get(&normalize_extension(extension))
+            .and_then(|name| self.get_format(name))
+    }
+
+    /// The format for `path`, by its registered extension first and then the
+    /// built-ins; a compression extension such as `.gz` is skipped
     pub fn get_format_for_path(&self, path: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
-        let extension = path.split('.').last()?;
-        match extension {
-            "csv" => Some(std::sync::Arc::new(Box::new(CsvFormat::default()) as Box<dyn DataFormat + Send + Sync>)),
-            "parquet" => Some(std::sync::Arc::new(Box::new(ParquetFormat::default()) as Box<dyn DataFormat + Send + Sync>)),
-            _ => None,
-        }
+        let extension = strip_compression_extension(path).rsplit('.').next()?;
+        self.get_registered_format_for_extension(extension)
+            .or_else(|| builtin_format_for_extension(extension))
     }
 }
 
+/// A default instance of the built-in format that handles `extension`
+fn builtin_format_for_extension(extension: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
+    let format: Box<dyn DataFormat + Send + Sync> = match normalize_extension(extension).as_str() {
+        "csv" => Box::new(CsvFormat::default()),
+        "parquet" => Box::new(ParquetFormat::default()),
+        "json" | "ndjson" | "jsonl" => Box::new(JsonFormat::default()),
+        _ => return None,
+    };
+    Some(std::sync::Arc::new(format))
+}
+
 static FORMAT_REGISTRY: Lazy<RwLock<FormatRegistry>> = Lazy::new(|| RwLock::new(FormatRegistry::new()));
 
 pub fn register_format(name: &str, format: Box<dyn DataFormat + Send + Sync>) {
     FORMAT_REGISTRY.write().register_format(name.to_string(), format);
 }
 
+/// Register a format together with the file extensions it handles
+pub fn register_format_with_extensions(name: &str, format: Box<dyn DataFormat + Send + Sync>, extensions: &[&str]) {
+    let mut registry = FORMAT_REGISTRY.write();
+    registry.register_format(name.to_string(), format);
+    for extension in extensions {
+        registry.register_extension(extension, name.to_string());
+    }
+}
+
 pub fn get_format(name: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
     FORMAT_REGISTRY.read().get_format(name)
 }
 
+/// Resolve files with `extension` to the registered format `name`
+pub fn register_extension(extension: &str, name: &str) {
+    FORMAT_REGISTRY.write().register_extension(extension, name.to_string());
+}
+
+pub(crate) fn normalize_extension(extension: &str) -> String {
+    extension.trim_start_matches('.').to_ascii_lowercase()
+}
+
+/// The format for files with `extension`: a registered extension first, then the built-ins.
+///
+/// Loaded plugins register their extensions here, so plugin formats resolve
+/// through the registry as well.
 pub fn get_format_for_extension(extension: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
-    match extension {
-        "csv" => Some(std::sync::Arc::new(Box::new(CsvFormat::default()) as Box<dyn DataFormat + Send + Sync>)),
-        "parquet" => Some(std::sync::Arc::new(Box::new(ParquetFormat::default()) as Box<dyn DataFormat + Send + Sync>)),
-        _ => None,
+    FORMAT_REGISTRY
+        .read()
+        .get_registered_format_for_extension(extension)
+        .or_else(|| builtin_format_for_extension(extension))
+}
+
+/// The format registered for `extension`, without falling back to the built-ins
+pub fn get_registered_format_for_extension(extension: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
+    FORMAT_REGISTRY.read().get_registered_format_for_extension(extension)
+}
+
+/// The format for the file at `path` by its extension, see [`FormatRegistry::get_format_for_path`]
+pub fn get_format_for_path(path: &str) -> Option<std::sync::Arc<Box<dyn DataFormat + Send + Sync>>> {
+    FORMAT_REGISTRY.read().get_format_for_path(path)
+}
+
+/// Stream the object at `url`, decompressed when its extension names a compression
+pub async fn read_decompressed(storage: &dyn Storage, url: &Url) -> Result<DataStream> {
+    let stream = storage.read(url).await?;
+    match compression_extension(url.path()) {
+        Some(codec) => decompress_stream(codec, stream),
+        None => Ok(stream),
+    }
+}
+
+/// Bytes from the start of a file that [`sniff`] needs to recognize its format
+pub const SNIFF_SIZE: usize = 8 * 1024;
+
+/// Lines of a text sample that must agree on their field count to look like CSV
+const SNIFF_CSV_LINES: usize = 20;
+
+/// Guess the format of a file from its first bytes.
+///
+/// Parquet is recognized by its `PAR1` magic at either end of `bytes`, JSON
+/// by text starting with `{` or `[`, and CSV by text whose complete lines all
+/// have the same number of comma-separated fields, at least two.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    const PARQUET_MAGIC: &[u8] = b"PAR1";
+    if bytes.starts_with(PARQUET_MAGIC) || bytes.ends_with(PARQUET_MAGIC) {
+        return Some("parquet");
+    }
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        // The sample may end in the middle of a character
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&bytes[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    if text.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t')) {
+        return None;
+    }
+    if text.trim_start().starts_with(['{', '[']) {
+        return Some("json");
+    }
+
+    // The last line may be cut off unless the sample ends with a newline
+    let complete = match text.rfind('\n') {
+        Some(end) => &text[..=end],
+        None => text,
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(complete.as_bytes());
+    let counts: Vec<usize> = reader
+        .records()
+        .take(SNIFF_CSV_LINES)
+        .map(|record| record.map(|record| record.len()))
+        .collect::<std::result::Result<_, _>>()
+        .ok()?;
+    let first = *counts.first()?;
+    (first > 1 && counts.iter().all(|&count| count == first)).then_some("csv")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_capabilities() {
+        let parquet = ParquetFormat::default().capabilities();
+        assert!(parquet.projection_pushdown);
+        assert!(parquet.schema_without_sample);
+        assert!(parquet.field_metadata);
+        assert!(!parquet.streaming_read);
+
+        let csv = CsvFormat::default().capabilities();
+        assert!(!csv.projection_pushdown);
+        assert!(!csv.field_metadata);
+        assert!(csv.streaming_read);
+
+        let json = |layout| JsonFormat::new(JsonConfig { layout, ..JsonConfig::default() }).capabilities();
+        assert!(json(JsonLayout::Lines).streaming_read);
+        assert!(!json(JsonLayout::Array).streaming_read);
+    }
+
+    #[tokio::test]
+    async fn test_zero_length_input_errors_by_default() {
+        let data = Bytes::new();
+        for format in [&CsvFormat::default() as &dyn DataFormat, &ParquetFormat::default()] {
+            let err = read_or_empty(format, &data, EmptyInputPolicy::Error, "file:///tmp/empty")
+                .err()
+                .expect("empty input should fail");
+            assert!(err.to_string().contains("is empty"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_length_input_empty_output() -> Result<()> {
+        let data = Bytes::new();
+        for format in [&CsvFormat::default() as &dyn DataFormat, &ParquetFormat::default()] {
+            let df = read_or_empty(format, &data, EmptyInputPolicy::EmptyOutput, "file:///tmp/empty")?;
+            let rows: usize = df.collect().await?.iter().map(|b| b.num_rows()).sum();
+            assert_eq!(rows, 0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_files_read_without_rows() -> Result<()> {
+        use crate::storage::local::LocalStorage;
+        use std::sync::Arc;
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let path = temp_dir.path().join("empty");
+        std::fs::write(&path, "")?;
+        let url = Url::from_file_path(&path).unwrap();
+
+        for format in [&CsvFormat::default() as &dyn DataFormat, &ParquetFormat::default()] {
+            let rows: usize = format.read(&Bytes::new())?.collect().await?.iter().map(|b| b.num_rows()).sum();
+            assert_eq!(rows, 0);
+            let batches: Vec<RecordBatch> = format.read_batches_from_storage(storage.clone(), &url, 0).await?.try_collect().await?;
+            assert!(batches.is_empty());
+            let err = format.infer_schema(storage.clone(), &url, 0).await.err().expect("no schema to infer");
+            assert!(matches!(err, Error::EmptyInput(_)), "{:?}", err);
+        }
+
+        // A header alone still names the columns
+        std::fs::write(&path, "id,name\r\n")?;
+        let schema = CsvFormat::default().infer_schema(storage.clone(), &url, 9).await?;
+        let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, ["id", "name"]);
+        assert_eq!(schema.field(0).data_type(), &arrow::datatypes::DataType::Utf8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_result_is_written_with_its_schema() -> Result<()> {
+        use arrow::datatypes::{DataType, Field, Schema};
+        use std::sync::Arc;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]));
+        let empty = || or_empty_batch(futures::stream::empty().boxed(), schema.clone());
+
+        let csv = CsvFormat::default().write_batches(empty()).await?;
+        assert_eq!(csv, Bytes::from("id,name\n"));
+
+        let parquet = ParquetFormat::default().write_batches(empty()).await?;
+        let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(parquet)?;
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+        assert_eq!(reader.schema().fields(), schema.fields());
+
+        // Batches that do arrive pass through unchanged
+        let batch = RecordBatch::new_empty(Arc::new(Schema::new(vec![Field::new("other", DataType::Utf8, true)])));
+        let stream = futures::stream::iter(vec![Ok(batch)]).boxed();
+        let batches: Vec<RecordBatch> = or_empty_batch(stream, schema.clone()).try_collect().await?;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].schema().field(0).name(), "other");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_registered_extension_resolves_custom_format() -> Result<()> {
+        let array_json = JsonFormat::new(JsonConfig {
+            layout: JsonLayout::Array,
+            ..JsonConfig::default()
+        });
+        register_format_with_extensions("foo-format", Box::new(array_json), &["foo"]);
+
+        let format = get_format_for_extension("foo").expect("registered by extension");
+        let batch = RecordBatch::try_from_iter(vec![(
+            "a",
+            std::sync::Arc::new(arrow::array::Int32Array::from(vec![1])) as arrow::array::ArrayRef,
+        )])?;
+        assert!(format.write_batch(&batch)?.starts_with(b"["));
+        assert!(get_format("foo-format").is_some());
+        assert!(get_format_for_extension(".FOO").is_some());
+
+        // Paths resolve by their last extension, skipping a compression one
+        let format: std::sync::Arc<dyn DataFormat> = get_format_for_path("/data/v1.2/rows.foo.gz").expect("by path");
+        assert!(format.write_batch(&batch)?.starts_with(b"["));
+        assert!(get_format_for_path("/data/rows.bar").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_builtin_extensions() {
+        for extension in ["csv", "parquet", "json", "ndjson", "jsonl"] {
+            assert!(get_format_for_extension(extension).is_some(), "{}", extension);
+        }
+        assert!(get_format_for_extension("unknown").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_batches_to_matches_write_batches() -> Result<()> {
+        let batch = RecordBatch::try_from_iter(vec![(
+            "a",
+            std::sync::Arc::new(arrow::array::Int32Array::from(vec![1, 2, 3])) as arrow::array::ArrayRef,
+        )])?;
+        let stream = |batch: &RecordBatch| -> BatchStream {
+            futures::stream::iter(vec![Ok(batch.clone()), Ok(batch.clone())]).boxed()
+        };
+
+        for format in [&CsvFormat::default() as &dyn DataFormat, &ParquetFormat::default()] {
+            let expected = format.write_batches(stream(&batch)).await?;
+            let (writer, mut reader) = tokio::io::duplex(1024 * 1024);
+            format.write_batches_to(stream(&batch), Box::pin(writer)).await?;
+            let mut output = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut output).await?;
+            assert_eq!(Bytes::from(output), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sniff_parquet_and_csv() -> Result<()> {
+        let csv = Bytes::from("id,name,score\n1,a,2.5\n2,\"b, c\",3\n");
+        assert_eq!(sniff(&csv), Some("csv"));
+
+        let batches = CsvFormat::default()
+            .read_batches_from_stream(futures::stream::iter(vec![Ok(csv)]).boxed())?;
+        let parquet = ParquetFormat::default().write_batches(batches).await?;
+        assert_eq!(sniff(&parquet), Some("parquet"));
+        assert_eq!(sniff(&parquet[..SNIFF_SIZE.min(parquet.len())]), Some("parquet"));
+
+        assert_eq!(sniff(b"[{\"id\": 1}]"), Some("json"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sniff_rejects_random_bytes() {
+        // xorshift, so the bytes are the same on every run
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let random: Vec<u8> = (0..SNIFF_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert_eq!(sniff(&random), None);
+        assert_eq!(sniff(b"just one line of prose"), None);
+        assert_eq!(sniff(b""), None);
     }
 }
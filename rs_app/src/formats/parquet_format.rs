@@ -1,25 +1,245 @@
-use anyhow::Result;
-use arrow::datatypes::Schema;
+use arrow::datatypes::{DataType, Schema, SchemaRef};
 use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
 use bytes::Bytes;
 use datafusion::dataframe::DataFrame;
 use datafusion::execution::context::SessionContext;
-use parquet::arrow::ArrowWriter;
+use datafusion::physical_plan::Statistics as PlanStatistics;
+use futures::{StreamExt, TryStreamExt};
+use futures::future::BoxFuture;
+use parquet::arrow::arrow_reader::{
+    ArrowReaderMetadata, ArrowReaderOptions, ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder,
+};
+use parquet::arrow::async_reader::{fetch_parquet_metadata, AsyncFileReader};
+use parquet::arrow::{
+    parquet_to_arrow_schema, ArrowWriter, AsyncArrowWriter, ParquetRecordBatchStreamBuilder, ProjectionMask,
+};
+use parquet::basic::Encoding;
+use parquet::errors::ParquetError;
+use parquet::file::metadata::ParquetMetaData;
+use parquet::file::properties::{WriterProperties, WriterVersion};
+use parquet::file::statistics::Statistics;
+use parquet::schema::types::ColumnPath;
+use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::Arc;
+use url::Url;
 
-use super::DataFormat;
+use super::{
+    already_finalized, apply_read_options, batch_size_for, parse_compression, AdaptiveBatchSize, BatchStream, BatchWriter,
+    DataFormat, DataSink, DataStream, FormatCapabilities, ReadOptions,
+};
+use crate::error::{Error, Result};
+use crate::schema;
+use crate::stats::{self, InputStats};
+use crate::storage::Storage;
+
+/// Bytes of encoded row groups buffered before they are flushed to the sink
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Bytes fetched from the end of a file to read the footer, usually enough
+/// to get the metadata in a single request
+const FOOTER_SIZE_HINT: usize = 64 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct ParquetConfig {
+    /// Codec with an optional level, e.g. `snappy` or `zstd(9)`; see `parse_compression`
     pub compression: Option<String>,
+    /// Columns to write a bloom filter for
+    pub bloom_filter_columns: Vec<String>,
+    /// Target false positive probability of the bloom filters
+    pub bloom_filter_fpp: Option<f64>,
+    /// Expected number of distinct values per column, used to size the bloom filters
+    pub bloom_filter_ndv: Option<u64>,
+    /// Maximum number of rows per row group; a row group is the unit flushed when streaming
+    pub max_row_group_size: Option<usize>,
+    /// Cast batches with differing schemas to a unified schema before writing,
+    /// see `schema::unify_schemas`. All batches are buffered to compute it.
+    pub coerce_schema: bool,
+    /// Maximum number of rows per decoded batch
+    pub batch_size: usize,
+    /// Pick the rows per decoded batch from the width of the file's schema
+    /// instead of `batch_size`
+    pub adaptive_batch_size: Option<AdaptiveBatchSize>,
+    /// Parquet format version written: `1.0` or `2.0`; see `parse_writer_version`
+    pub writer_version: Option<String>,
+    /// Data page header version written: `v1` or `v2`
+    pub data_page_version: Option<String>,
+    /// Encoding per column, e.g. `id` => `DELTA_BINARY_PACKED`; see `parse_encoding`
+    pub column_encodings: HashMap<String, String>,
+    /// Columns to dictionary encode, overriding the default and `column_encodings`
+    pub dictionary_enabled_columns: Vec<String>,
+    /// Columns never dictionary encoded
+    pub dictionary_disabled_columns: Vec<String>,
 }
 
 impl Default for ParquetConfig {
     fn default() -> Self {
-        Self { compression: None }
+        Self {
+            compression: None,
+            bloom_filter_columns: Vec::new(),
+            bloom_filter_fpp: None,
+            bloom_filter_ndv: None,
+            max_row_group_size: None,
+            coerce_schema: false,
+            batch_size: 1024,
+            adaptive_batch_size: None,
+            writer_version: None,
+            data_page_version: None,
+            column_encodings: HashMap::new(),
+            dictionary_enabled_columns: Vec::new(),
+            dictionary_disabled_columns: Vec::new(),
+        }
+    }
+}
+
+impl ParquetConfig {
+    /// Rows per decoded batch of a file with `schema`
+    fn batch_size_for(&self, schema: &Schema) -> usize {
+        batch_size_for(schema, self.batch_size, self.adaptive_batch_size)
+    }
+
+    /// Check the per-column encoding settings: encodings must be known and a
+    /// column can't have its dictionary both enabled and disabled
+    pub fn validate_column_encodings(&self) -> Result<()> {
+        for (column, encoding) in &self.column_encodings {
+            parse_encoding(encoding).map_err(|e| Error::Config(format!("Column '{}': {}", column, e)))?;
+        }
+        match self.dictionary_enabled_columns.iter().find(|column| self.dictionary_disabled_columns.contains(column)) {
+            Some(column) => Err(Error::Config(format!(
+                "Column '{}' has dictionary encoding both enabled and disabled",
+                column
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check the bloom filter false positive probability, which the `parquet`
+    /// crate asserts is strictly between 0 and 1
+    pub fn validate_bloom_filter(&self) -> Result<()> {
+        match self.bloom_filter_fpp {
+            Some(fpp) if fpp > 0.0 && fpp < 1.0 => Ok(()),
+            Some(fpp) => Err(Error::Config(format!(
+                "Bloom filter false positive probability must be between 0 and 1 (exclusive), got {}",
+                fpp
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Every column named by a per-column setting, with the setting's name for errors
+    fn configured_columns(&self) -> impl Iterator<Item = (&'static str, &String)> {
+        let bloom = self.bloom_filter_columns.iter().map(|column| ("Bloom filter", column));
+        let encoding = self.column_encodings.keys().map(|column| ("Encoding", column));
+        let enabled = self.dictionary_enabled_columns.iter().map(|column| ("Dictionary", column));
+        let disabled = self.dictionary_disabled_columns.iter().map(|column| ("Dictionary", column));
+        bloom.chain(encoding).chain(enabled).chain(disabled)
+    }
+}
+
+/// Parse a column encoding name such as `PLAIN`, `DELTA_BINARY_PACKED` or
+/// `BYTE_STREAM_SPLIT`, case-insensitively.
+///
+/// Dictionary encodings are rejected: they are chosen with the dictionary
+/// settings, the encoding given here is what's written without a dictionary.
+pub fn parse_encoding(name: &str) -> Result<Encoding> {
+    match name.trim().to_ascii_uppercase().as_str() {
+        "PLAIN" => Ok(Encoding::PLAIN),
+        "RLE" => Ok(Encoding::RLE),
+        "DELTA_BINARY_PACKED" => Ok(Encoding::DELTA_BINARY_PACKED),
+        "DELTA_LENGTH_BYTE_ARRAY" => Ok(Encoding::DELTA_LENGTH_BYTE_ARRAY),
+        "DELTA_BYTE_ARRAY" => Ok(Encoding::DELTA_BYTE_ARRAY),
+        "BYTE_STREAM_SPLIT" => Ok(Encoding::BYTE_STREAM_SPLIT),
+        "PLAIN_DICTIONARY" | "RLE_DICTIONARY" => Err(Error::Config(format!(
+            "Parquet encoding '{}' can't be set per column, use the dictionary column settings",
+            name
+        ))),
+        _ => Err(Error::Config(format!(
+            "Unknown Parquet encoding '{}': expected PLAIN, RLE, DELTA_BINARY_PACKED, \
+             DELTA_LENGTH_BYTE_ARRAY, DELTA_BYTE_ARRAY or BYTE_STREAM_SPLIT",
+            name
+        ))),
+    }
+}
+
+/// Resolve a Parquet writer version (`1.0` or `2.0`) and data page version
+/// (`v1` or `v2`) into the writer version to use, `None` meaning the default.
+///
+/// The `parquet` crate writes v2 data pages exactly when the writer version
+/// is 2.0, so a data page version alone selects the writer version and the
+/// two must agree when both are given.
+pub fn parse_writer_version(writer_version: Option<&str>, data_page_version: Option<&str>) -> Result<Option<WriterVersion>> {
+    let writer_version = match writer_version.map(str::trim) {
+        None => None,
+        Some("1.0") => Some(WriterVersion::PARQUET_1_0),
+        Some("2.0") => Some(WriterVersion::PARQUET_2_0),
+        Some(other) => {
+            return Err(Error::Config(format!("Unknown Parquet writer version '{}': expected 1.0 or 2.0", other)))
+        }
+    };
+    let data_page_version = match data_page_version.map(|version| version.trim().to_ascii_lowercase()) {
+        None => None,
+        Some(version) if version == "v1" => Some(WriterVersion::PARQUET_1_0),
+        Some(version) if version == "v2" => Some(WriterVersion::PARQUET_2_0),
+        Some(other) => {
+            return Err(Error::Config(format!("Unknown Parquet data page version '{}': expected v1 or v2", other)))
+        }
+    };
+    match (writer_version, data_page_version) {
+        (Some(writer), Some(page)) if writer != page => Err(Error::Config(format!(
+            "Parquet data page version {} requires writer version {}",
+            data_page_version_name(page),
+            writer_version_name(page)
+        ))),
+        (writer, page) => Ok(writer.or(page)),
+    }
+}
+
+fn writer_version_name(version: WriterVersion) -> &'static str {
+    match version {
+        WriterVersion::PARQUET_1_0 => "1.0",
+        WriterVersion::PARQUET_2_0 => "2.0",
+    }
+}
+
+fn data_page_version_name(version: WriterVersion) -> &'static str {
+    match version {
+        WriterVersion::PARQUET_1_0 => "v1",
+        WriterVersion::PARQUET_2_0 => "v2",
+    }
+}
+
+/// Buffer a whole Parquet file from `stream` and decode it in batches of
+/// `batch_size` rows, or as many as `adaptive` picks for the file's schema
+fn decode_stream(stream: DataStream, batch_size: usize, adaptive: Option<AdaptiveBatchSize>) -> BatchStream {
+    futures::stream::once(async move {
+        let chunks: Vec<Bytes> = stream.try_collect().await?;
+        let builder = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(chunks.concat()))?;
+        let batch_size = batch_size_for(builder.schema(), batch_size, adaptive);
+        let metadata = builder.schema().metadata().clone();
+        let reader = builder.with_batch_size(batch_size).build()?;
+        let batches = futures::stream::iter(reader.map(|batch| batch.map_err(Into::into))).boxed();
+        Ok::<_, Error>(with_schema_metadata(batches, metadata))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Decoded batches carry field metadata but not the file's schema-level
+/// key-value metadata, so put that back on every batch's schema
+fn with_schema_metadata(batches: BatchStream, metadata: HashMap<String, String>) -> BatchStream {
+    if metadata.is_empty() {
+        return batches;
     }
+    batches
+        .and_then(move |batch| {
+            let schema = batch.schema().as_ref().clone().with_metadata(metadata.clone());
+            futures::future::ready(batch.with_schema(Arc::new(schema)).map_err(Into::into))
+        })
+        .boxed()
 }
 
+#[derive(Default)]
 pub struct ParquetFormat {
     config: ParquetConfig,
 }
@@ -28,19 +248,328 @@ impl ParquetFormat {
     pub fn new(config: ParquetConfig) -> Self {
         Self { config }
     }
+
+    /// Writer properties for files of `schema`; columns named by bloom filter,
+    /// encoding and dictionary settings must be among its fields, so a
+    /// misspelled name doesn't silently change nothing
+    fn writer_properties(&self, schema: &Schema) -> Result<WriterProperties> {
+        let missing = self.config.configured_columns().find(|(_, column)| schema.field_with_name(column).is_err());
+        if let (Some((setting, column)), false) = (missing, schema.fields().is_empty()) {
+            return Err(Error::Config(format!("{} column '{}' does not exist in the output", setting, column)));
+        }
+        self.config.validate_column_encodings()?;
+        self.config.validate_bloom_filter()?;
+        let mut builder = WriterProperties::builder();
+        if let Some(compression) = &self.config.compression {
+            builder = builder.set_compression(parse_compression(compression)?);
+        }
+        let version =
+            parse_writer_version(self.config.writer_version.as_deref(), self.config.data_page_version.as_deref())?;
+        if let Some(version) = version {
+            builder = builder.set_writer_version(version);
+        }
+        if let Some(rows) = self.config.max_row_group_size {
+            builder = builder.set_max_row_group_size(rows);
+        }
+        for column in &self.config.bloom_filter_columns {
+            let path = ColumnPath::from(column.as_str());
+            builder = builder.set_column_bloom_filter_enabled(path.clone(), true);
+            if let Some(fpp) = self.config.bloom_filter_fpp {
+                builder = builder.set_column_bloom_filter_fpp(path.clone(), fpp);
+            }
+            if let Some(ndv) = self.config.bloom_filter_ndv {
+                builder = builder.set_column_bloom_filter_ndv(path, ndv);
+            }
+        }
+        // With a dictionary the column encoding is only a fallback, so an
+        // explicit encoding turns the dictionary off unless it's enabled too
+        for (column, encoding) in &self.config.column_encodings {
+            let path = ColumnPath::from(column.as_str());
+            builder = builder
+                .set_column_encoding(path.clone(), parse_encoding(encoding)?)
+                .set_column_dictionary_enabled(path, false);
+        }
+        for column in &self.config.dictionary_disabled_columns {
+            builder = builder.set_column_dictionary_enabled(ColumnPath::from(column.as_str()), false);
+        }
+        for column in &self.config.dictionary_enabled_columns {
+            builder = builder.set_column_dictionary_enabled(ColumnPath::from(column.as_str()), true);
+        }
+        Ok(builder.build())
+    }
+
+    /// Decode only the row groups at `row_groups`, in the order given.
+    ///
+    /// Lets callers split a large file across workers by row group.
+    /// Indices past the file's last row group are an error.
+    pub fn read_row_groups(&self, data: Bytes, row_groups: &[usize]) -> Result<BatchStream> {
+        let builder = ParquetRecordBatchReaderBuilder::try_new(data)?;
+        let available = builder.metadata().num_row_groups();
+        if let Some(index) = row_groups.iter().find(|&&index| index >= available) {
+            return Err(Error::InvalidInput(format!(
+                "Row group {} is out of range; the file has {} row groups",
+                index, available
+            )));
+        }
+        let batch_size = self.config.batch_size_for(builder.schema());
+        let reader = builder.with_row_groups(row_groups.to_vec()).with_batch_size(batch_size).build()?;
+        Ok(futures::stream::iter(reader.map(|batch| batch.map_err(Into::into))).boxed())
+    }
+
+    /// Open the Parquet object at `url` for ranged reads.
+    ///
+    /// Only the footer is fetched here; [`RemoteParquetFile::read`] then
+    /// fetches the column chunks of the selected columns as batches are
+    /// pulled, so projected reads of large remote files download little.
+    pub async fn open_remote(&self, storage: Arc<dyn Storage>, url: &Url) -> Result<RemoteParquetFile> {
+        let size = storage.size(url).await?;
+        let mut reader = StorageFileReader::new(storage.clone(), url.clone(), size);
+        let metadata = ArrowReaderMetadata::load_async(&mut reader, ArrowReaderOptions::new()).await?;
+        Ok(RemoteParquetFile {
+            storage,
+            url: url.clone(),
+            size,
+            batch_size: self.config.batch_size_for(metadata.schema()),
+            metadata,
+        })
+    }
+
+    /// `batches` cast to their unified schema when `coerce_schema` is set
+    async fn coerced(&self, batches: BatchStream) -> Result<BatchStream> {
+        if !self.config.coerce_schema {
+            return Ok(batches);
+        }
+        let batches: Vec<RecordBatch> = batches.try_collect().await?;
+        let schemas: Vec<SchemaRef> = batches.iter().map(|batch| batch.schema()).collect();
+        let unified = schema::unify_schemas(schemas.iter().map(|schema| schema.as_ref()))
+            .map_err(|e| Error::SchemaMismatch(e.to_string()))?;
+        let batches = batches
+            .iter()
+            .map(|batch| schema::coerce_batch(batch, &unified).map_err(|e| Error::SchemaMismatch(e.to_string())))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(futures::stream::iter(batches.into_iter().map(Ok)).boxed())
+    }
 }
 
-impl Default for ParquetFormat {
-    fn default() -> Self {
+/// Build statistics from the row group metadata alone.
+///
+/// Returns `None` when the metadata can't answer exactly: nested columns,
+/// row groups without statistics, or unsigned columns whose stored bounds
+/// are not ordered numerically.
+fn stats_from_metadata(schema: &SchemaRef, metadata: &ParquetMetaData) -> Option<InputStats> {
+    // Leaf column statistics don't describe a struct or list as a whole
+    if schema.fields().len() != metadata.file_metadata().schema_descr().num_columns()
+        || schema.fields().iter().any(|field| field.data_type().is_nested())
+    {
+        return None;
+    }
+    let mut result = InputStats::new(schema);
+    for row_group in metadata.row_groups() {
+        result.rows += row_group.num_rows() as usize;
+        for (index, column) in result.columns.iter_mut().enumerate() {
+            let statistics = row_group.column(index).statistics()?;
+            let null_count = statistics.null_count() as usize;
+            column.null_count += null_count;
+
+            let data_type = schema.field(index).data_type();
+            if !stats::is_numeric(data_type) {
+                continue;
+            }
+            if matches!(data_type, DataType::UInt8 | DataType::UInt16 | DataType::UInt32 | DataType::UInt64) {
+                return None;
+            }
+            if !statistics.has_min_max_set() {
+                // Without bounds only an all-null row group is still exact
+                if null_count < row_group.num_rows() as usize {
+                    return None;
+                }
+                continue;
+            }
+            let (min, max) = match statistics {
+                Statistics::Int32(s) => (*s.min() as f64, *s.max() as f64),
+                Statistics::Int64(s) => (*s.min() as f64, *s.max() as f64),
+                Statistics::Float(s) => (*s.min() as f64, *s.max() as f64),
+                Statistics::Double(s) => (*s.min(), *s.max()),
+                _ => return None,
+            };
+            column.min = stats::merge_min(column.min, Some(min));
+            column.max = stats::merge_max(column.max, Some(max));
+        }
+    }
+    Some(result)
+}
+
+/// Random access to a Parquet file in storage through ranged reads.
+///
+/// The footer is fetched once and every row group is read only when the
+/// batch stream reaches it, so decoding starts without downloading the file.
+pub struct StorageFileReader {
+    storage: Arc<dyn Storage>,
+    url: Url,
+    size: usize,
+}
+
+impl StorageFileReader {
+    pub fn new(storage: Arc<dyn Storage>, url: Url, size: usize) -> Self {
+        Self { storage, url, size }
+    }
+}
+
+impl AsyncFileReader for StorageFileReader {
+    fn get_bytes(&mut self, range: Range<usize>) -> BoxFuture<'_, parquet::errors::Result<Bytes>> {
+        Box::pin(async move {
+            self.storage
+                .read_range(&self.url, range)
+                .await
+                .map_err(|e| ParquetError::External(e.into()))
+        })
+    }
+
+    fn get_metadata(&mut self) -> BoxFuture<'_, parquet::errors::Result<Arc<ParquetMetaData>>> {
+        Box::pin(async move {
+            let (storage, url) = (&self.storage, &self.url);
+            let metadata = fetch_parquet_metadata(
+                |range| async move {
+                    storage
+                        .read_range(url, range)
+                        .await
+                        .map_err(|e| ParquetError::External(e.into()))
+                },
+                self.size,
+                Some(FOOTER_SIZE_HINT),
+            )
+            .await?;
+            Ok(Arc::new(metadata))
+        })
+    }
+}
+
+/// A Parquet file in storage whose footer has been read, see [`ParquetFormat::open_remote`]
+pub struct RemoteParquetFile {
+    storage: Arc<dyn Storage>,
+    url: Url,
+    size: usize,
+    metadata: ArrowReaderMetadata,
+    batch_size: usize,
+}
+
+impl RemoteParquetFile {
+    pub fn schema(&self) -> SchemaRef {
+        self.metadata.schema().clone()
+    }
+
+    pub fn metadata(&self) -> &Arc<ParquetMetaData> {
+        self.metadata.metadata()
+    }
+
+    /// Size of the object in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Decode the columns at `projection`, in the order given, or every column
+    /// when it is `None`. Column chunks of other columns are never fetched.
+    pub fn read(&self, projection: Option<&[usize]>) -> Result<BatchStream> {
+        let reader = StorageFileReader::new(self.storage.clone(), self.url.clone(), self.size);
+        let builder = ParquetRecordBatchStreamBuilder::new_with_metadata(reader, self.metadata.clone())
+            .with_batch_size(self.batch_size);
+        let metadata = self.metadata.schema().metadata().clone();
+        let Some(projection) = projection else {
+            return Ok(with_schema_metadata(builder.build()?.map_err(Into::into).boxed(), metadata));
+        };
+
+        let columns = self.metadata.schema().fields().len();
+        if let Some(index) = projection.iter().find(|&&index| index >= columns) {
+            return Err(Error::InvalidInput(format!(
+                "Column {} of {} is out of range; the file has {} columns",
+                index, self.url, columns
+            )));
+        }
+        // The decoder emits selected columns in file order
+        let mut selected = projection.to_vec();
+        selected.sort_unstable();
+        selected.dedup();
+        let reorder: Vec<usize> = projection
+            .iter()
+            .map(|index| selected.binary_search(index).unwrap_or_default())
+            .collect();
+        let mask = ProjectionMask::roots(builder.parquet_schema(), selected);
+        let stream = builder.with_projection(mask).build()?;
+        let batches = stream
+            .map_err(Into::into)
+            .and_then(move |batch| futures::future::ready(batch.project(&reorder).map_err(Into::into)))
+            .boxed();
+        Ok(with_schema_metadata(batches, metadata))
+    }
+}
+
+/// Emits a `debug` event for every row group the writer completes.
+///
+/// The writer cuts a row group once it holds `max_row_group_size` rows, so
+/// completed row groups follow from the number of rows written.
+struct RowGroupTracker {
+    max_row_group_size: usize,
+    rows: usize,
+    row_groups: usize,
+}
+
+impl RowGroupTracker {
+    fn new(max_row_group_size: usize) -> Self {
         Self {
-            config: ParquetConfig::default(),
+            max_row_group_size: max_row_group_size.max(1),
+            rows: 0,
+            row_groups: 0,
         }
     }
+
+    fn written(&mut self, rows: usize) {
+        tracing::trace!(rows, "encoded batch");
+        self.rows += rows;
+        while self.rows >= (self.row_groups + 1) * self.max_row_group_size {
+            self.flushed(self.max_row_group_size);
+        }
+    }
+
+    /// Closing the writer flushes the final, partial row group
+    fn closed(&mut self) {
+        let remaining = self.rows - self.row_groups * self.max_row_group_size;
+        if remaining > 0 {
+            self.flushed(remaining);
+        }
+    }
+
+    fn flushed(&mut self, rows: usize) {
+        tracing::debug!(row_group = self.row_groups, rows, "flushed row group");
+        self.row_groups += 1;
+    }
 }
 
+/// Writes row groups into an in-memory buffer; the footer is written on `finalize`
+struct ParquetBatchWriter {
+    writer: Option<ArrowWriter<Vec<u8>>>,
+}
+
+#[async_trait]
+impl BatchWriter for ParquetBatchWriter {
+    async fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        self.writer.as_mut().ok_or_else(already_finalized)?.write(batch)?;
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<Bytes> {
+        let writer = self.writer.take().ok_or_else(already_finalized)?;
+        Ok(Bytes::from(writer.into_inner()?))
+    }
+}
+
+#[async_trait]
 impl DataFormat for ParquetFormat {
+    /// Zero bytes are read as an empty table with no columns, like the other formats
     fn read(&self, data: &Bytes) -> Result<DataFrame> {
-        let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReader::try_new(data.clone(), 1024)?;
+        if data.is_empty() {
+            return Ok(SessionContext::new().read_batch(RecordBatch::new_empty(Arc::new(Schema::empty())))?);
+        }
+        let reader = ParquetRecordBatchReader::try_new(data.clone(), 1024)?;
         let mut batches = Vec::new();
         for result in reader {
             batches.push(result?);
@@ -63,7 +592,8 @@ impl DataFormat for ParquetFormat {
     fn write(&self, df: &DataFrame) -> Result<Bytes> {
         let mut buf = Vec::new();
         let schema = Arc::new(Schema::try_from(df.schema())?);
-        let mut writer = ArrowWriter::try_new(&mut buf, schema, None)?;
+        let props = self.writer_properties(&schema)?;
+        let mut writer = ArrowWriter::try_new(&mut buf, schema, Some(props))?;
 
         let batches = futures::executor::block_on(df.clone().collect())?;
         for batch in batches {
@@ -77,11 +607,640 @@ impl DataFormat for ParquetFormat {
     fn write_batch(&self, batch: &RecordBatch) -> Result<Bytes> {
         let mut buf = Vec::new();
         let schema = batch.schema();
-        let mut writer = ArrowWriter::try_new(&mut buf, schema, None)?;
+        let props = self.writer_properties(&schema)?;
+        let mut writer = ArrowWriter::try_new(&mut buf, schema, Some(props))?;
 
         writer.write(batch)?;
         writer.close()?;
 
         Ok(Bytes::from(buf))
     }
+
+    /// Columns are stored separately and the schema is in the footer
+    fn capabilities(&self) -> FormatCapabilities {
+        FormatCapabilities {
+            projection_pushdown: true,
+            schema_without_sample: true,
+            field_metadata: true,
+            ..FormatCapabilities::default()
+        }
+    }
+
+    /// Parquet metadata lives in the footer, so the stream is buffered
+    /// completely before decoding starts
+    fn read_batches_from_stream(&self, stream: DataStream) -> Result<BatchStream> {
+        Ok(decode_stream(stream, self.config.batch_size, self.config.adaptive_batch_size))
+    }
+
+    fn read_batches_with(&self, stream: DataStream, options: &ReadOptions) -> Result<BatchStream> {
+        let batches = match options.batch_size {
+            Some(batch_size) => decode_stream(stream, batch_size, None),
+            None => decode_stream(stream, self.config.batch_size, self.config.adaptive_batch_size),
+        };
+        Ok(apply_read_options(batches, &ReadOptions { batch_size: None, ..options.clone() }))
+    }
+
+    fn batch_writer(&self, schema: SchemaRef) -> Result<Box<dyn BatchWriter + '_>> {
+        let props = self.writer_properties(&schema)?;
+        let writer = ArrowWriter::try_new(Vec::new(), schema, Some(props))?;
+        Ok(Box::new(ParquetBatchWriter { writer: Some(writer) }))
+    }
+
+    /// Row groups are fetched with ranged reads as the stream reaches them,
+    /// after reading the footer once. A zero-byte object has no footer and
+    /// yields no batches, like an empty file of the other formats.
+    async fn read_batches_from_storage(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> Result<BatchStream> {
+        if size == 0 {
+            return Ok(futures::stream::empty().boxed());
+        }
+        let reader = StorageFileReader::new(storage, url.clone(), size);
+        let builder = ParquetRecordBatchStreamBuilder::new(reader).await?;
+        let batch_size = self.config.batch_size_for(builder.schema());
+        let metadata = builder.schema().metadata().clone();
+        let stream = builder.with_batch_size(batch_size).build()?;
+        Ok(with_schema_metadata(stream.map_err(Into::into).boxed(), metadata))
+    }
+
+    /// Only the footer is read
+    async fn row_group_count(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> Result<Option<usize>> {
+        if size == 0 {
+            return Ok(Some(0));
+        }
+        let mut reader = StorageFileReader::new(storage, url.clone(), size);
+        Ok(Some(reader.get_metadata().await?.num_row_groups()))
+    }
+
+    /// Like `read_batches_from_storage`, fetching only the given row groups
+    async fn read_row_groups_from_storage(
+        &self,
+        storage: Arc<dyn Storage>,
+        url: &Url,
+        size: usize,
+        row_groups: Vec<usize>,
+        projection: Option<Vec<usize>>,
+    ) -> Result<BatchStream> {
+        let reader = StorageFileReader::new(storage, url.clone(), size);
+        let mut builder = ParquetRecordBatchStreamBuilder::new(reader).await?;
+        let available = builder.metadata().num_row_groups();
+        if let Some(index) = row_groups.iter().find(|&&index| index >= available) {
+            return Err(Error::InvalidInput(format!(
+                "Row group {} of {} is out of range; the file has {} row groups",
+                index, url, available
+            )));
+        }
+        // Projected batches are only as wide as the columns they keep
+        let mut batch_size = self.config.batch_size_for(builder.schema());
+        if let Some(projection) = projection {
+            let columns = builder.schema().fields().len();
+            if let Some(index) = projection.iter().find(|&&index| index >= columns) {
+                return Err(Error::InvalidInput(format!(
+                    "Column {} of {} is out of range; the file has {} columns",
+                    index, url, columns
+                )));
+            }
+            batch_size = self.config.batch_size_for(&builder.schema().project(&projection)?);
+            let mask = ProjectionMask::roots(builder.parquet_schema(), projection);
+            builder = builder.with_projection(mask);
+        }
+        let metadata = builder.schema().metadata().clone();
+        let stream = builder
+            .with_row_groups(row_groups)
+            .with_batch_size(batch_size)
+            .build()?;
+        Ok(with_schema_metadata(stream.map_err(Into::into).boxed(), metadata))
+    }
+
+    /// Answered from the footer's statistics when possible, otherwise by decoding the file
+    async fn compute_stats(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> Result<InputStats> {
+        if size == 0 {
+            return Ok(InputStats::new(&Arc::new(Schema::empty())));
+        }
+        let mut reader = StorageFileReader::new(storage.clone(), url.clone(), size);
+        let metadata = reader.get_metadata().await?;
+        let file_metadata = metadata.file_metadata();
+        let schema = Arc::new(parquet_to_arrow_schema(file_metadata.schema_descr(), file_metadata.key_value_metadata())?);
+        if let Some(result) = stats_from_metadata(&schema, &metadata) {
+            return Ok(result);
+        }
+        let batches = self.read_batches_from_storage(storage, url, size).await?;
+        stats::collect_stats(&schema, batches).await
+    }
+
+    /// Exact row count from the footer, plus null counts and integer bounds
+    /// when the row group statistics cover every column
+    async fn plan_statistics(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> Result<PlanStatistics> {
+        if size == 0 {
+            return Ok(PlanStatistics {
+                num_rows: Some(0),
+                total_byte_size: Some(0),
+                is_exact: true,
+                ..PlanStatistics::default()
+            });
+        }
+        let mut reader = StorageFileReader::new(storage, url.clone(), size);
+        let metadata = reader.get_metadata().await?;
+        let file_metadata = metadata.file_metadata();
+        let schema = Arc::new(parquet_to_arrow_schema(file_metadata.schema_descr(), file_metadata.key_value_metadata())?);
+        let statistics = match stats_from_metadata(&schema, &metadata) {
+            Some(stats) => stats.to_statistics(&schema),
+            None => PlanStatistics {
+                num_rows: Some(file_metadata.num_rows() as usize),
+                is_exact: true,
+                ..PlanStatistics::default()
+            },
+        };
+        Ok(PlanStatistics {
+            total_byte_size: Some(size),
+            ..statistics
+        })
+    }
+
+    /// Only the footer is read
+    #[tracing::instrument(level = "debug", skip_all, fields(format = "parquet", url = %url, size))]
+    async fn infer_schema(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> Result<SchemaRef> {
+        super::check_not_empty(url, size)?;
+        let reader = StorageFileReader::new(storage, url.clone(), size);
+        let builder = ParquetRecordBatchStreamBuilder::new(reader).await?;
+        tracing::debug!(columns = builder.schema().fields().len(), "inferred schema");
+        Ok(builder.schema().clone())
+    }
+
+    /// Batches are written as they arrive; only the encoded output and the
+    /// current row group are held in memory, unless `coerce_schema` is set
+    async fn write_batches(&self, batches: BatchStream) -> Result<Bytes> {
+        let mut batches = self.coerced(batches).await?;
+        let first = batches.try_next().await?;
+        let schema = first
+            .as_ref()
+            .map(|batch| batch.schema())
+            .unwrap_or_else(|| Arc::new(Schema::empty()));
+        let mut buf = Vec::new();
+        let props = self.writer_properties(&schema)?;
+        let mut writer = ArrowWriter::try_new(&mut buf, schema, Some(props))?;
+        if let Some(batch) = first {
+            writer.write(&batch)?;
+        }
+        while let Some(batch) = batches.try_next().await? {
+            writer.write(&batch)?;
+        }
+        writer.close()?;
+
+        Ok(Bytes::from(buf))
+    }
+
+    /// Row groups are flushed to `sink` as soon as they are complete, so only
+    /// the row group being built is held in memory, unless `coerce_schema` is set
+    async fn write_batches_to(&self, batches: BatchStream, sink: DataSink) -> Result<()> {
+        let mut batches = self.coerced(batches).await?;
+        let first = batches.try_next().await?;
+        let schema = first
+            .as_ref()
+            .map(|batch| batch.schema())
+            .unwrap_or_else(|| Arc::new(Schema::empty()));
+        let props = self.writer_properties(&schema)?;
+        let mut row_groups = RowGroupTracker::new(props.max_row_group_size());
+        let mut writer = AsyncArrowWriter::try_new(sink, schema, WRITE_BUFFER_SIZE, Some(props))?;
+        if let Some(batch) = first {
+            writer.write(&batch).await?;
+            row_groups.written(batch.num_rows());
+        }
+        while let Some(batch) = batches.try_next().await? {
+            writer.write(&batch).await?;
+            row_groups.written(batch.num_rows());
+        }
+        writer.close().await?;
+        row_groups.closed();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::supported_compressions;
+    use crate::storage::testing::MockStorage;
+    use arrow::array::{Int32Array, Int64Array, StringArray};
+    use arrow::datatypes::Field;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    fn sample_batch() -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+                Arc::new(StringArray::from(vec!["a", "b", "c"])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bloom_filter_written_for_selected_columns() -> Result<()> {
+        let format = ParquetFormat::new(ParquetConfig {
+            bloom_filter_columns: vec!["id".to_string()],
+            bloom_filter_fpp: Some(0.01),
+            bloom_filter_ndv: Some(100),
+            ..ParquetConfig::default()
+        });
+        let data = format.write_batch(&sample_batch())?;
+
+        let reader = SerializedFileReader::new(data)?;
+        let row_group = reader.metadata().row_group(0);
+        assert!(row_group.column(0).bloom_filter_offset().is_some());
+        assert!(row_group.column(1).bloom_filter_offset().is_none());
+
+        let misspelled = ParquetFormat::new(ParquetConfig {
+            bloom_filter_columns: vec!["idd".to_string()],
+            ..ParquetConfig::default()
+        });
+        let err = misspelled.write_batch(&sample_batch()).unwrap_err();
+        assert!(matches!(err, Error::Config(_)), "{}", err);
+
+        // Out of range probabilities are errors, not panics in the writer
+        for fpp in [0.0, 1.0, 1.5, -0.1, f64::NAN] {
+            let format = ParquetFormat::new(ParquetConfig {
+                bloom_filter_columns: vec!["id".to_string()],
+                bloom_filter_fpp: Some(fpp),
+                ..ParquetConfig::default()
+            });
+            let err = format.write_batch(&sample_batch()).unwrap_err();
+            assert!(matches!(err, Error::Config(_)), "{}: {}", fpp, err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_level_changes_output_size() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("text", DataType::Utf8, false)]));
+        let values: Vec<String> = (0..20_000).map(|i| format!("row {} value {}", i % 97, i * 7919 % 10_007)).collect();
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(StringArray::from(values))])?;
+
+        let size = |compression: &str| -> Result<usize> {
+            let format = ParquetFormat::new(ParquetConfig {
+                compression: Some(compression.to_string()),
+                ..ParquetConfig::default()
+            });
+            Ok(format.write_batch(&batch)?.len())
+        };
+        for codec in ["zstd", "gzip", "brotli"] {
+            if !supported_compressions().contains(&codec) {
+                continue;
+            }
+            let (low, high) = match codec {
+                "zstd" => (size("zstd(1)")?, size("zstd(19)")?),
+                "gzip" => (size("gzip(1)")?, size("gzip(9)")?),
+                _ => (size("brotli(1)")?, size("brotli(11)")?),
+            };
+            assert_ne!(low, high, "{} levels produced the same size", codec);
+            assert!(high < low, "{}: level sizes {} and {}", codec, low, high);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writer_and_data_page_version() -> Result<()> {
+        use parquet::column::page::Page;
+
+        // The file version and the header type of the first data page
+        let write = |writer_version: Option<&'static str>, data_page_version: Option<&'static str>| async move {
+            let format = ParquetFormat::new(ParquetConfig {
+                writer_version: writer_version.map(str::to_string),
+                data_page_version: data_page_version.map(str::to_string),
+                ..ParquetConfig::default()
+            });
+            let data = format.write_batches(futures::stream::iter(vec![Ok(sample_batch())]).boxed()).await?;
+            let reader = SerializedFileReader::new(data)?;
+            let version = reader.metadata().file_metadata().version();
+            let mut pages = reader.get_row_group(0)?.get_column_page_reader(0)?;
+            let v2 = loop {
+                match pages.next().transpose()? {
+                    Some(Page::DataPage { .. }) => break false,
+                    Some(Page::DataPageV2 { .. }) => break true,
+                    Some(Page::DictionaryPage { .. }) => continue,
+                    None => panic!("no data page"),
+                }
+            };
+            Ok::<_, Error>((version, v2))
+        };
+
+        assert_eq!(write(None, None).await?, (1, false));
+        assert_eq!(write(None, Some("v2")).await?, (2, true));
+        assert_eq!(write(Some("2.0"), None).await?, (2, true));
+        assert_eq!(write(Some("1.0"), Some("V1")).await?, (1, false));
+        assert!(write(Some("1.0"), Some("v2")).await.is_err());
+        assert!(parse_writer_version(Some("2"), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_column_encodings_written() -> Result<()> {
+        let format = ParquetFormat::new(ParquetConfig {
+            column_encodings: HashMap::from([("id".to_string(), "delta_binary_packed".to_string())]),
+            dictionary_disabled_columns: vec!["name".to_string()],
+            ..ParquetConfig::default()
+        });
+        let data = format.write_batch(&sample_batch())?;
+
+        let reader = SerializedFileReader::new(data)?;
+        let row_group = reader.metadata().row_group(0);
+        let id = row_group.column(0).encodings();
+        assert!(id.contains(&Encoding::DELTA_BINARY_PACKED), "{:?}", id);
+        assert!(!id.contains(&Encoding::RLE_DICTIONARY), "{:?}", id);
+        let name = row_group.column(1).encodings();
+        assert!(name.contains(&Encoding::PLAIN), "{:?}", name);
+        assert!(!name.contains(&Encoding::RLE_DICTIONARY), "{:?}", name);
+
+        let default = ParquetFormat::default().write_batch(&sample_batch())?;
+        let reader = SerializedFileReader::new(default)?;
+        assert!(reader.metadata().row_group(0).column(0).encodings().contains(&Encoding::RLE_DICTIONARY));
+
+        for config in [
+            ParquetConfig {
+                column_encodings: HashMap::from([("id".to_string(), "ZIGZAG".to_string())]),
+                ..ParquetConfig::default()
+            },
+            ParquetConfig {
+                column_encodings: HashMap::from([("idd".to_string(), "PLAIN".to_string())]),
+                ..ParquetConfig::default()
+            },
+            ParquetConfig {
+                dictionary_enabled_columns: vec!["id".to_string()],
+                dictionary_disabled_columns: vec!["id".to_string()],
+                ..ParquetConfig::default()
+            },
+        ] {
+            let err = ParquetFormat::new(config).write_batch(&sample_batch()).unwrap_err();
+            assert!(matches!(err, Error::Config(_)), "{}", err);
+        }
+        assert!(parse_encoding("RLE_DICTIONARY").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_batches_streams_many_batches() -> Result<()> {
+        const BATCHES: usize = 500;
+
+        let batches = futures::stream::iter(0..BATCHES).map(|_| Ok(sample_batch())).boxed();
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(100),
+            ..ParquetConfig::default()
+        });
+        let data = format.write_batches(batches).await?;
+
+        let reader = SerializedFileReader::new(data)?;
+        assert_eq!(reader.metadata().file_metadata().num_rows() as usize, BATCHES * 3);
+        assert!(reader.metadata().num_row_groups() > 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_finalize_writes_footer_once() -> Result<()> {
+        let format = ParquetFormat::default();
+        let mut writer = format.batch_writer(sample_batch().schema())?;
+        writer.write(&sample_batch()).await?;
+        writer.write(&sample_batch()).await?;
+        let data = writer.finalize().await?;
+
+        let reader = SerializedFileReader::new(data)?;
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 6);
+
+        let err = writer.finalize().await.unwrap_err();
+        assert!(err.to_string().contains("already been finalized"));
+        assert!(writer.write(&sample_batch()).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_from_storage_fetches_each_row_group_once() -> Result<()> {
+        const BATCHES: usize = 400;
+
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(3),
+            ..ParquetConfig::default()
+        });
+        let data = format
+            .write_batches(futures::stream::iter(0..BATCHES).map(|_| Ok(sample_batch())).boxed())
+            .await?;
+
+        let url = Url::parse("memory:///many_row_groups.parquet").unwrap();
+        let storage = Arc::new(MockStorage::new());
+        storage.put(&url, data.clone()).await?;
+
+        let batches = format.read_batches_from_storage(storage.clone(), &url, data.len()).await?;
+        let rows = batches
+            .try_fold(0, |rows, batch| futures::future::ready(Ok(rows + batch.num_rows())))
+            .await?;
+        assert_eq!(rows, BATCHES * 3);
+
+        // Reads grow linearly: the footer once, then each column chunk of each row group
+        let columns = sample_batch().num_columns();
+        let reads = storage.ranges().len();
+        let bytes = storage.ranges().iter().map(|range| range.len()).sum::<usize>();
+        assert!(reads <= BATCHES * columns + 2, "{} reads", reads);
+        assert!(bytes <= data.len() + FOOTER_SIZE_HINT, "{} bytes read of {}", bytes, data.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_infer_schema_reads_only_the_footer() -> Result<()> {
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(3),
+            ..ParquetConfig::default()
+        });
+        let data = format
+            .write_batches(futures::stream::iter(0..100).map(|_| Ok(sample_batch())).boxed())
+            .await?;
+
+        let url = Url::parse("memory:///schema.parquet").unwrap();
+        let storage = Arc::new(MockStorage::new());
+        storage.put(&url, data.clone()).await?;
+
+        let schema = format.infer_schema(storage.clone(), &url, data.len()).await?;
+        assert_eq!(schema.fields().len(), sample_batch().num_columns());
+        // The footer hint, plus at most one more read if the metadata is larger
+        assert!(storage.ranges().len() <= 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stats_come_from_the_footer() -> Result<()> {
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(3),
+            ..ParquetConfig::default()
+        });
+        let data = format
+            .write_batches(futures::stream::iter(0..100).map(|_| Ok(sample_batch())).boxed())
+            .await?;
+
+        let url = Url::parse("memory:///stats.parquet").unwrap();
+        let storage = Arc::new(MockStorage::new());
+        storage.put(&url, data.clone()).await?;
+
+        let result = format.compute_stats(storage.clone(), &url, data.len()).await?;
+        assert_eq!(result.rows, 300);
+        assert_eq!(result.columns[0].null_count, 0);
+        assert_eq!((result.columns[0].min, result.columns[0].max), (Some(1.0), Some(3.0)));
+        // No column chunk is read, only the footer
+        assert!(storage.ranges().len() <= 2);
+        Ok(())
+    }
+
+    /// Sink that records how many bytes have been written so far
+    struct CountingSink {
+        data: Arc<parking_lot::Mutex<Vec<u8>>>,
+    }
+
+    impl tokio::io::AsyncWrite for CountingSink {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.data.lock().extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_batches_to_flushes_row_groups_incrementally() -> Result<()> {
+        const BATCHES: usize = 200;
+        const ROWS: usize = 1000;
+
+        let data = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let written_before_last = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (observed, recorded) = (data.clone(), written_before_last.clone());
+        let batches = futures::stream::iter(0..BATCHES)
+            .map(move |i| {
+                if i == BATCHES - 1 {
+                    recorded.store(observed.lock().len(), std::sync::atomic::Ordering::SeqCst);
+                }
+                let ids: Vec<i64> = (0..ROWS).map(|r| (i * ROWS + r) as i64).collect();
+                let names: Vec<String> = ids.iter().map(|id| format!("name-{}", id)).collect();
+                let schema = sample_batch().schema();
+                Ok(RecordBatch::try_new(
+                    schema,
+                    vec![Arc::new(Int64Array::from(ids)), Arc::new(StringArray::from(names))],
+                )?)
+            })
+            .boxed();
+
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(ROWS),
+            ..ParquetConfig::default()
+        });
+        format
+            .write_batches_to(batches, Box::pin(CountingSink { data: data.clone() }))
+            .await?;
+
+        // Output must have reached the sink before the input was exhausted
+        assert!(written_before_last.load(std::sync::atomic::Ordering::SeqCst) > 0);
+
+        let bytes = Bytes::from(data.lock().clone());
+        let rows: usize = ParquetRecordBatchReader::try_new(bytes, 1024)?
+            .map(|batch| batch.map(|b| b.num_rows()))
+            .sum::<Result<usize, _>>()?;
+        assert_eq!(rows, BATCHES * ROWS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_coerce_schema_widens_mixed_batches() -> Result<()> {
+        let narrow = RecordBatch::try_from_iter(vec![("v", Arc::new(Int32Array::from(vec![1, 2])) as _)])?;
+        let wide = RecordBatch::try_from_iter(vec![("v", Arc::new(Int64Array::from(vec![3_i64 << 40])) as _)])?;
+        let batches = futures::stream::iter(vec![Ok(narrow), Ok(wide)]).boxed();
+
+        let format = ParquetFormat::new(ParquetConfig {
+            coerce_schema: true,
+            ..ParquetConfig::default()
+        });
+        let data = format.write_batches(batches).await?;
+        let read: Vec<RecordBatch> = ParquetRecordBatchReader::try_new(data, 1024)?.collect::<Result<_, _>>()?;
+        assert_eq!(read[0].schema().field(0).data_type(), &DataType::Int64);
+        let values: Vec<i64> = read
+            .iter()
+            .flat_map(|batch| batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap().values().to_vec())
+            .collect();
+        assert_eq!(values, vec![1, 2, 3 << 40]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_row_groups() -> Result<()> {
+        let batches = futures::stream::iter(0..10).map(|_| Ok(sample_batch())).boxed();
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(5),
+            ..ParquetConfig::default()
+        });
+        let data = format.write_batches(batches).await?;
+        let metadata = SerializedFileReader::new(data.clone())?.metadata().clone();
+        assert_eq!(metadata.num_row_groups(), 6);
+
+        let selected = [2, 3, 5];
+        let expected: i64 = selected.iter().map(|&i| metadata.row_group(i).num_rows()).sum();
+        let rows: usize = format
+            .read_row_groups(data.clone(), &selected)?
+            .map_ok(|batch| batch.num_rows())
+            .try_fold(0, |total, rows| async move { Ok(total + rows) })
+            .await?;
+        assert_eq!(rows as i64, expected);
+        assert_eq!(expected, 5 + 5 + 5);
+
+        let err = format.read_row_groups(data, &[1, 6]).err().expect("out of range");
+        assert!(matches!(err, Error::InvalidInput(_)));
+        assert!(err.to_string().contains("Row group 6 is out of range"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_open_remote_fetches_only_projected_columns() -> Result<()> {
+        const ROWS: i64 = 100_000;
+        const COLUMNS: usize = 8;
+
+        let schema = Arc::new(Schema::new(
+            (0..COLUMNS).map(|i| Field::new(format!("c{}", i), DataType::Int64, false)).collect::<Vec<_>>(),
+        ));
+        let columns: Vec<arrow::array::ArrayRef> = (0..COLUMNS as i64)
+            .map(|c| Arc::new(Int64Array::from_iter_values((0..ROWS).map(|i| (i * 7919 + c).wrapping_mul(2654435761)))) as _)
+            .collect();
+        let batch = RecordBatch::try_new(schema, columns)?;
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(20_000),
+            ..ParquetConfig::default()
+        });
+        let data = format.write_batch(&batch)?;
+
+        let url = Url::parse("memory:///wide.parquet").unwrap();
+        let storage = Arc::new(MockStorage::new());
+        storage.put(&url, data.clone()).await?;
+
+        let file = format.open_remote(storage.clone(), &url).await?;
+        assert_eq!(file.size(), data.len());
+        assert_eq!(file.schema().fields().len(), COLUMNS);
+        let batches: Vec<RecordBatch> = file.read(Some(&[5, 2]))?.try_collect().await?;
+
+        let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+        assert_eq!(rows, ROWS as usize);
+        assert_eq!(batches[0].schema().field(0).name(), "c5");
+        assert_eq!(batches[0].schema().field(1).name(), "c2");
+        assert_eq!(batches[0].column(1), &batch.column(2).slice(0, batches[0].num_rows()));
+
+        // Two of eight columns plus the footer
+        let bytes = storage.ranges().iter().map(|range| range.len()).sum::<usize>();
+        assert!(bytes < data.len() / 3, "{} bytes read of {}", bytes, data.len());
+
+        assert!(matches!(file.read(Some(&[COLUMNS])), Err(Error::InvalidInput(_))));
+        Ok(())
+    }
 }
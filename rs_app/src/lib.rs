@@ -1,13 +1,27 @@
+pub mod compact;
 pub mod config;
+pub mod error;
 pub mod formats;
 pub mod storage;
 pub mod table_provider;
 pub mod execution;
+pub mod metrics;
+pub mod partitioned;
+pub mod pipeline;
 pub mod plugin;
+pub mod progress;
+pub mod schema;
+pub mod stats;
+pub mod streaming;
+pub mod validation;
 
 // Re-export key traits and types
 pub use config::Config;
+pub use error::{Error, Result};
 pub use formats::{CsvFormat, DataFormat, ParquetFormat};
+pub use pipeline::{ConvertStats, OutputInfo};
 pub use plugin::{FormatPlugin, PluginMetadata, PluginManager};
+pub use progress::{Progress, ProgressReporter};
+pub use schema::schema_fingerprint;
 pub use storage::Storage;
-pub use table_provider::FormatTableProvider;
+pub use table_provider::{register_as_table, FormatTableProvider};
@@ -1,20 +1,39 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use dotenv::dotenv;
 use url::Url;
-use datafusion::arrow::util::pretty;
 
-use crate::formats::{CsvFormat, DataFormat, ParquetFormat};
-use crate::storage::azure::AzureStorage;
-use crate::storage::local::LocalStorage;
-use crate::storage::s3::S3Storage;
+use crate::formats::{
+    CsvConfig, CsvFormat, DataFormat, EmptyInputPolicy, JsonConfig, JsonFormat, JsonLayout, ParquetConfig,
+    ParquetFormat,
+};
+use crate::storage::stats::StorageStatsCollector;
+use crate::storage::InputOrder;
 
+mod compact;
+mod config;
+mod error;
 mod formats;
+mod metrics;
+mod partitioned;
+mod pipeline;
+mod plugin;
+mod progress;
+mod schema;
+mod stats;
 mod storage;
 mod table_provider;
 mod execution;
+mod validation;
 
+use std::sync::Arc;
+use std::time::Duration;
+
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
 use datafusion::prelude::*;
+use futures::{StreamExt, TryStreamExt};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -25,97 +44,980 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Convert {
-        #[arg(short, long)]
-        input: String,
-        #[arg(short, long)]
-        output: String,
-        #[arg(long)]
-        filter_sql: Option<String>,
-    },
+    Convert(ConvertArgs),
+    /// Print the schema inferred for the input without converting it
+    Schema(SchemaArgs),
+    /// Count rows and summarize each column without writing output
+    Count(CountArgs),
+    /// Rewrite many small Parquet files into fewer, larger ones
+    Compact(CompactArgs),
+    /// List the objects below a URL prefix with their sizes and modification times
+    List(ListArgs),
+}
+
+#[derive(Args, Debug)]
+struct ListArgs {
+    /// Directory URL to list, e.g. `s3://bucket/data/`
+    #[arg(short, long)]
+    prefix: String,
+    /// List the objects of every subdirectory instead of one `DIR` line per subdirectory
+    #[arg(short, long)]
+    recursive: bool,
+    /// Print at most this many entries
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Config file (.yaml, .yml, .toml or .json); `DT_*` environment variables override it.
+    /// Its `storage` settings, such as credentials and endpoints, apply to the listing
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct CompactArgs {
+    /// Parquet files to compact, usually a glob such as `s3://bucket/data/*.parquet`
+    #[arg(short, long)]
+    input: String,
+    /// Directory to write the `part-NNNNN.parquet` files to
+    #[arg(short, long)]
+    output: String,
+    /// Decoded bytes of data per output file; a new file is started once it is reached
+    #[arg(long, default_value_t = compact::DEFAULT_TARGET_FILE_SIZE)]
+    target_file_size: usize,
+    /// Require every input file to match the first one's nullability and metadata,
+    /// not just its field names and types
+    #[arg(long)]
+    strict_schema: bool,
+    /// Order of the files matched by `input`; they are always sorted so the output is reproducible
+    #[arg(long, value_enum, default_value_t = InputOrder::Lexical)]
+    sort_inputs: InputOrder,
+    /// Config file (.yaml, .yml, .toml or .json); `DT_*` environment variables override it
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct CountArgs {
+    /// Input file; a glob counts every matching file
+    #[arg(short, long)]
+    input: String,
+    /// Input format; `auto` detects it from the extension and contents
+    #[arg(long, value_enum, default_value_t = InputFormat::Auto)]
+    input_format: InputFormat,
+    /// Config file (.yaml, .yml, .toml or .json); `DT_*` environment variables override it.
+    /// Its `formats` settings, such as the CSV delimiter and column types, apply to the input
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct SchemaArgs {
+    /// Input file; for a glob the first matching file is inspected
+    #[arg(short, long)]
+    input: String,
+    /// Input format; `auto` detects it from the extension and contents
+    #[arg(long, value_enum, default_value_t = InputFormat::Auto)]
+    input_format: InputFormat,
+    /// Print the schema as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+    /// Also print the first N rows as a table, reading only as much of the input as they need
+    #[arg(long, value_name = "N", conflicts_with = "json")]
+    head: Option<usize>,
+    /// Config file (.yaml, .yml, .toml or .json); `DT_*` environment variables override it.
+    /// Its `formats` settings, such as the CSV delimiter and column types, apply to the input
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ConvertArgs {
+    /// Input file or glob; `-` reads standard input
+    #[arg(short, long)]
+    input: String,
+    /// Output file, or directory with `--partition-by`; `-` writes to standard output
+    #[arg(short, long)]
+    output: String,
+    #[arg(long)]
+    filter_sql: Option<String>,
+    /// Plugin library (or `.wasm` module) whose UDFs `--filter-sql` may call; may be repeated
+    #[arg(long)]
+    udf_plugin: Vec<std::path::PathBuf>,
+    /// Comma-separated input columns to keep, in this order; `--filter-sql` only sees these
+    #[arg(long, value_delimiter = ',', conflicts_with = "drop")]
+    select: Vec<String>,
+    /// Comma-separated input columns to leave out of the output
+    #[arg(long, value_delimiter = ',')]
+    drop: Vec<String>,
+    /// Rename a column after `--filter-sql`, as `old=new`; may be repeated
+    #[arg(long)]
+    rename: Vec<String>,
+    /// Cast a column after `--filter-sql`, as `column=Type` (e.g. `id=Utf8`); may be repeated.
+    /// Renames and casts are applied in the order they are given
+    #[arg(long)]
+    cast: Vec<String>,
+    /// `--rename` and `--cast` in command line order, filled in by `column_transforms`
+    #[arg(skip)]
+    transforms: Vec<schema::ColumnTransform>,
+    /// Input format; `auto` detects it from the extension and contents
+    #[arg(long, value_enum, default_value_t = InputFormat::Auto)]
+    input_format: InputFormat,
+    /// CSV delimiter, overriding `formats.csv.delimiter`; `auto` detects it from the first lines
+    #[arg(long)]
+    delimiter: Option<String>,
+    /// Output format; `auto` picks it from the extension, so writing to `-` needs it
+    #[arg(long, value_enum, default_value_t = OutputFormat::Auto)]
+    output_format: OutputFormat,
+    /// How to handle a zero-byte input object
+    #[arg(long, value_enum, default_value_t = EmptyInputPolicy::Error)]
+    empty_input: EmptyInputPolicy,
+    /// Require every input file to match the first one's nullability and metadata,
+    /// not just its field names and types
+    #[arg(long)]
+    strict_schema: bool,
+    /// Order of the files matched by `input`; they are always sorted so the output is reproducible
+    #[arg(long, value_enum, default_value_t = InputOrder::Lexical)]
+    sort_inputs: InputOrder,
+    /// Add the `key=value` directories of each input's path as columns
+    #[arg(long)]
+    hive_partitioning: bool,
+    /// Comma-separated columns to write Parquet bloom filters for
+    #[arg(long, value_delimiter = ',')]
+    bloom_filter: Vec<String>,
+    /// False positive probability of the bloom filters
+    #[arg(long)]
+    bloom_filter_fpp: Option<f64>,
+    /// Expected number of distinct values per bloom filter column
+    #[arg(long)]
+    bloom_filter_ndv: Option<u64>,
+    /// Cast the output to exactly match the schema of this file
+    #[arg(long)]
+    coerce_schema_from: Option<String>,
+    /// Keep the input's Arrow field metadata and schema key-value metadata on the output;
+    /// formats without a place for it get a `.meta.json` sidecar
+    #[arg(long)]
+    preserve_metadata: bool,
+    /// Re-read the written output and verify its schema
+    #[arg(long)]
+    trailer_schema_check: bool,
+    /// Fail before writing when the output schema's fingerprint differs from the one
+    /// cached in `<output>.schema.json` by an earlier run; the first run writes it
+    #[arg(long)]
+    enforce_schema_stability: bool,
+    /// Maximum number of decoded batches held in memory between reading and writing;
+    /// defaults to `streaming.max_in_flight_batches` from the config
+    #[arg(long)]
+    max_in_flight_batches: Option<usize>,
+    /// Size CSV and Parquet input batches by the estimated width of their rows,
+    /// as set by `formats.adaptive_batch_size`, instead of a fixed row count
+    #[arg(long)]
+    adaptive_batch_size: bool,
+    /// Config file (.yaml, .yml, .toml or .json); `DT_*` environment variables override it
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+    /// Comma-separated columns to partition Parquet output by; `output` is then a directory
+    #[arg(long, value_delimiter = ',')]
+    partition_by: Vec<String>,
+    /// Maximum number of partition files written concurrently; also capped
+    /// by `storage.max_concurrent_requests` from the config
+    #[arg(long, default_value_t = 16)]
+    max_open_partitions: usize,
+    /// Convert every input file to its own Parquet file below `output`, in parallel
+    /// up to `processing.num_threads` files at a time
+    #[arg(long)]
+    per_file: bool,
+    /// Replace outputs that already exist; without it an existing output is an error
+    #[arg(long, conflicts_with = "no_clobber")]
+    overwrite: bool,
+    /// Leave outputs that already exist as they are and succeed without writing them;
+    /// with `--partition-by` or `--per-file` this applies to each file
+    #[arg(long)]
+    no_clobber: bool,
+    /// Append the converted rows to an existing CSV or newline-delimited JSON
+    /// output, which must have the same columns; the output is created if missing
+    #[arg(long)]
+    append: bool,
+    /// Print bytes read and rows converted to stderr while converting
+    #[arg(long)]
+    progress: bool,
+    /// Infer the schema and plan `--filter-sql` against it, then print the
+    /// output schema without reading the data or writing output
+    #[arg(long)]
+    dry_run: bool,
+    /// Once every output is written, write a `_manifest.json` listing them and an
+    /// empty `_SUCCESS` object into the output directory
+    #[arg(long)]
+    write_success_marker: bool,
+    /// Print the storage requests (GET, PUT, LIST, ...) and bytes of the conversion per backend
+    #[arg(long)]
+    stats: bool,
+}
+
+impl ConvertArgs {
+    /// Layer defaults, the config file, environment overrides and then flags
+    fn load_config(&self) -> Result<config::Config> {
+        let mut builder = config::ConfigBuilder::from_config(read_config(self.config.as_deref())?);
+        if let Some(max_in_flight_batches) = self.max_in_flight_batches {
+            builder = builder.max_in_flight_batches(max_in_flight_batches);
+        }
+        if let Some(delimiter) = &self.delimiter {
+            builder = builder.csv_delimiter(delimiter.as_str());
+        }
+        if self.adaptive_batch_size {
+            builder = builder.adaptive_batch_size(true);
+        }
+        let config = builder.build();
+        validation::validate_config(&config)?;
+        self.parquet_config(&config).validate_bloom_filter()?;
+        Ok(config)
+    }
+
+    /// Parse `--rename` and `--cast` into `transforms`, ordered by their
+    /// position on the command line since clap collects each flag separately
+    fn column_transforms(&mut self, matches: &ArgMatches) -> Result<()> {
+        let positions = |id: &str| -> Vec<usize> {
+            matches.indices_of(id).map(|indices| indices.collect()).unwrap_or_default()
+        };
+        let mut transforms = Vec::new();
+        for (position, spec) in positions("rename").into_iter().zip(&self.rename) {
+            transforms.push((position, schema::ColumnTransform::parse_rename(spec)?));
+        }
+        for (position, spec) in positions("cast").into_iter().zip(&self.cast) {
+            transforms.push((position, schema::ColumnTransform::parse_cast(spec)?));
+        }
+        transforms.sort_by_key(|(position, _)| *position);
+        self.transforms = transforms.into_iter().map(|(_, transform)| transform).collect();
+        Ok(())
+    }
+
+    /// What to do with outputs that exist, from `--overwrite` and `--no-clobber`
+    fn existing_output(&self) -> pipeline::ExistingOutput {
+        if self.overwrite {
+            pipeline::ExistingOutput::Overwrite
+        } else if self.no_clobber {
+            pipeline::ExistingOutput::Skip
+        } else {
+            pipeline::ExistingOutput::Error
+        }
+    }
+
+    /// The `--select` or `--drop` columns, if either was given
+    fn column_selection(&self) -> Option<pipeline::ColumnSelection> {
+        if !self.select.is_empty() {
+            Some(pipeline::ColumnSelection::Select(self.select.clone()))
+        } else if !self.drop.is_empty() {
+            Some(pipeline::ColumnSelection::Drop(self.drop.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Read options for `urls`, discovering Hive partitions below `input_root` when enabled
+    fn read_options(&self, input_root: &Url, urls: &[Url]) -> Result<pipeline::ReadOptions> {
+        let mut options = pipeline::ReadOptions::new(self.empty_input).with_strict_schema(self.strict_schema);
+        if self.progress {
+            options = options.with_progress(show_progress());
+        }
+        if let Some(columns) = self.column_selection() {
+            options = options.with_columns(columns);
+        }
+        if !self.hive_partitioning {
+            return Ok(options);
+        }
+        Ok(options.with_hive_partitioning(partitioned::HivePartitioning::discover(input_root, urls)?))
+    }
+
+    fn parquet_config(&self, config: &config::Config) -> ParquetConfig {
+        ParquetConfig {
+            compression: Some(config.formats.parquet.compression.clone()),
+            batch_size: config.formats.parquet.batch_size,
+            bloom_filter_columns: self.bloom_filter.clone(),
+            bloom_filter_fpp: self.bloom_filter_fpp,
+            bloom_filter_ndv: self.bloom_filter_ndv,
+            writer_version: config.formats.parquet.writer_version.clone(),
+            data_page_version: config.formats.parquet.data_page_version.clone(),
+            column_encodings: config.formats.parquet.column_encodings.clone(),
+            dictionary_enabled_columns: config.formats.parquet.dictionary_enabled_columns.clone(),
+            dictionary_disabled_columns: config.formats.parquet.dictionary_disabled_columns.clone(),
+            ..ParquetConfig::default()
+        }
+    }
+}
+
+/// Defaults overlaid with the config file at `path` and then `DT_*` environment variables
+fn read_config(path: Option<&std::path::Path>) -> Result<config::Config> {
+    let mut config = match path {
+        Some(path) => config::Config::from_file(path)?,
+        None => config::Config::default(),
+    };
+    config.apply_env_overrides()?;
+    Ok(config)
+}
+
+/// Render progress snapshots on a single, continuously rewritten stderr line
+fn show_progress() -> progress::ProgressReporter {
+    let (reporter, mut receiver) = progress::ProgressReporter::channel(16, Duration::from_millis(250));
+    tokio::spawn(async move {
+        while let Some(progress) = receiver.recv().await {
+            let file = progress.file.as_ref().map(Url::as_str).unwrap_or("");
+            eprint!(
+                "\r{} bytes read, {} rows read, {} rows written {}",
+                progress.bytes_read, progress.rows_in, progress.rows_out, file
+            );
+        }
+        eprintln!();
+    });
+    reporter
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InputFormat {
+    Auto,
+    Csv,
+    Parquet,
+    JsonArray,
+    JsonLines,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Auto,
+    Csv,
+    Parquet,
+    Json,
+}
+
+/// Storage for `url`, retrying transient failures of remote backends as `config.retry` says
+async fn get_storage_for_url(url: &Url, config: &config::StorageConfig) -> Result<Arc<dyn storage::Storage>> {
+    Ok(storage::from_url_with_config(url, config)?.into())
+}
+
+/// Reader settings for the formats an input may turn out to have
+#[derive(Debug, Clone, Default)]
+struct InputConfig {
+    csv: CsvConfig,
+    parquet: ParquetConfig,
+}
+
+impl InputConfig {
+    fn from_config(config: &config::Config) -> Result<Self> {
+        let adaptive_batch_size = config.formats.adaptive_batch_size.policy();
+        Ok(Self {
+            csv: CsvConfig { adaptive_batch_size, ..CsvConfig::try_from(&config.formats.csv)? },
+            parquet: ParquetConfig {
+                batch_size: config.formats.parquet.batch_size,
+                adaptive_batch_size,
+                ..ParquetConfig::default()
+            },
+        })
+    }
+}
+
+/// The format for `url` by its extension, resolved through the format
+/// registry so that formats registered for an extension, e.g. by plugins,
+/// come before the built-ins. The built-in CSV and Parquet formats are
+/// configured from `input`.
+async fn get_format_for_url(url: &Url, input: &InputConfig) -> Result<Arc<dyn DataFormat + Send + Sync>> {
+    // `data.csv.gz` is a CSV file; the format decompresses it while reading
+    let path = formats::strip_compression_extension(url.path());
+    let extension = path.rsplit('.').next().unwrap_or_default();
+    if formats::get_registered_format_for_extension(extension).is_none() {
+        match formats::normalize_extension(extension).as_str() {
+            "csv" => return Ok(Arc::new(CsvFormat::new(input.csv.clone()))),
+            "parquet" => return Ok(Arc::new(ParquetFormat::new(input.parquet.clone()))),
+            _ => {}
+        }
+    }
+    match formats::get_format_for_path(url.path()) {
+        Some(format) => Ok(format),
+        None => Err(error::Error::UnsupportedFormat(format!("no format for file extension of {}", url)).into()),
+    }
+}
+
+/// The format of `url` by its extension or, when that is unknown, by sniffing its first bytes
+async fn detect_format(
+    storage: &dyn storage::Storage,
+    url: &Url,
+    input: &InputConfig,
+) -> Result<Arc<dyn DataFormat + Send + Sync>> {
+    if let Ok(format) = get_format_for_url(url, input).await {
+        return Ok(format);
+    }
+    let size = storage.size(url).await?;
+    let head = storage.read_range(url, 0..size.min(formats::SNIFF_SIZE)).await?;
+    match formats::sniff(&head) {
+        Some("parquet") => Ok(Arc::new(ParquetFormat::new(input.parquet.clone()))),
+        Some("json") => Ok(Arc::new(JsonFormat::default())),
+        Some("csv") => Ok(Arc::new(CsvFormat::new(input.csv.clone()))),
+        _ => Err(error::Error::UnsupportedFormat(format!(
+            "cannot detect the format of {} from its extension or content; pass --input-format",
+            url
+        ))
+        .into()),
+    }
+}
+
+async fn get_input_format(
+    storage: &dyn storage::Storage,
+    url: &Url,
+    input_format: InputFormat,
+    input: &InputConfig,
+) -> Result<Arc<dyn DataFormat + Send + Sync>> {
+    let json = |layout| -> Arc<dyn DataFormat + Send + Sync> {
+        Arc::new(JsonFormat::new(JsonConfig { layout, ..JsonConfig::default() }))
+    };
+    match input_format {
+        InputFormat::Auto => detect_format(storage, url, input).await,
+        InputFormat::Csv => Ok(Arc::new(CsvFormat::new(input.csv.clone()))),
+        InputFormat::Parquet => Ok(Arc::new(ParquetFormat::new(input.parquet.clone()))),
+        InputFormat::JsonArray => Ok(json(JsonLayout::Array)),
+        InputFormat::JsonLines => Ok(json(JsonLayout::Lines)),
+    }
+}
+
+async fn get_output_format(
+    url: &Url,
+    args: &ConvertArgs,
+    config: &config::Config,
+) -> Result<Arc<dyn DataFormat + Send + Sync>> {
+    let csv = CsvConfig::try_from(&config.formats.csv)?;
+    match args.output_format {
+        OutputFormat::Csv => return Ok(Arc::new(CsvFormat::new(csv))),
+        OutputFormat::Parquet => return Ok(Arc::new(ParquetFormat::new(args.parquet_config(config)))),
+        OutputFormat::Json => return Ok(Arc::new(JsonFormat::default())),
+        OutputFormat::Auto => {}
+    }
+    match url.path().rsplit('.').next() {
+        Some("parquet") => Ok(Arc::new(ParquetFormat::new(args.parquet_config(config)))),
+        _ => get_format_for_url(url, &InputConfig { csv, ..InputConfig::default() }).await,
+    }
 }
 
-async fn get_storage_for_url(url: &Url) -> Result<Box<dyn storage::Storage>> {
-    match url.scheme() {
-        "s3" => Ok(Box::new(S3Storage::new(url.host_str().unwrap().to_string())?)),
-        "azure" => Ok(Box::new(AzureStorage::new(url.host_str().unwrap().to_string())?)),
-        "file" | _ => Ok(Box::new(LocalStorage::new()?)),
+/// Read the schema of `reference` and cast every batch of `df` to it as it streams past
+async fn coerce_to_reference(
+    df: DataFrame,
+    reference: &str,
+    input: &InputConfig,
+    config: &config::Config,
+) -> Result<DataFrame> {
+    let reference_url = Url::parse(reference)?;
+    let reference_storage = get_storage_for_url(&reference_url, &config.storage).await?;
+    let reference_format = get_format_for_url(&reference_url, input).await?;
+    let size = reference_storage.size(&reference_url).await?;
+    let target = reference_format.infer_schema(reference_storage, &reference_url, size).await?;
+
+    let coerce_target = target.clone();
+    let batches = pipeline::execute_stream(df)
+        .await?
+        .and_then(move |batch| {
+            futures::future::ready(schema::coerce_batch(&batch, &coerce_target).map_err(error::Error::from))
+        })
+        .boxed();
+    pipeline::stream_dataframe(target, batches)
+}
+
+/// Gate the requests `storage` sends with the shared `requests` semaphore
+fn limit_requests(storage: Arc<dyn storage::Storage>, requests: &Arc<Semaphore>) -> Arc<dyn storage::Storage> {
+    Arc::new(storage::limit::LimitedStorage::new(storage, requests.clone()))
+}
+
+/// Like [`get_storage_for_url`], counting the requests sent to the backend of `url` into `stats`
+async fn get_counted_storage_for_url(
+    url: &Url,
+    config: &config::StorageConfig,
+    stats: &StorageStatsCollector,
+) -> Result<Arc<dyn storage::Storage>> {
+    Ok(storage::from_url_with_stats(url, config, stats)?.into())
+}
+
+/// Storage for writing `url`, verifying writes when `storage.verify_writes` is set
+async fn open_output(
+    url: &Url,
+    config: &config::Config,
+    requests: &Arc<Semaphore>,
+    stats: &StorageStatsCollector,
+) -> Result<Arc<dyn storage::Storage>> {
+    let mut storage = get_counted_storage_for_url(url, &config.storage, stats).await?;
+    if config.storage.verify_writes {
+        storage = Arc::new(storage::verify::VerifiedStorage::new(storage, config.storage.retry.clone()));
     }
+    Ok(limit_requests(storage, requests))
 }
 
-async fn get_format_for_url(url: &Url) -> Result<Box<dyn DataFormat + Send + Sync>> {
-    let path = url.path();
-    match path.split('.').last() {
-        Some("csv") => Ok(Box::new(CsvFormat::default())),
-        Some("parquet") => Ok(Box::new(ParquetFormat::default())),
-        _ => Err(anyhow::anyhow!("Unsupported file format")),
+/// Expand `input` into the URLs to read, listing matches in `order` when it is a glob
+async fn resolve_inputs(input: &str, storage: &dyn storage::Storage, order: InputOrder) -> Result<Vec<Url>> {
+    if !storage::glob::is_glob(input) {
+        return Ok(vec![Url::parse(input)?]);
+    }
+    let urls = storage.list_glob_ordered(input, order).await?;
+    if urls.is_empty() {
+        return Err(anyhow::anyhow!("No files match {}", input));
     }
+    Ok(urls)
 }
 
-async fn print_dataframe(df: &DataFrame) -> Result<()> {
-    let batches = df.clone().collect().await?;
-    if !batches.is_empty() {
-        let table = pretty::pretty_format_batches(&batches)?;
-        println!("\nQuery Results:");
-        println!("{}", table.to_string());
-        println!("\nTotal rows: {}", batches.iter().map(|b| b.num_rows()).sum::<usize>());
+/// Open the storage for `input` and resolve it to the URLs to read.
+///
+/// A glob input is resolved against the storage of its base URL, its matches
+/// sorted into `order`, and `-` is the standard input. Its requests are counted into `stats`.
+async fn open_inputs(
+    input: &str,
+    order: InputOrder,
+    config: &config::Config,
+    stats: &StorageStatsCollector,
+) -> Result<(Arc<dyn storage::Storage>, Vec<Url>)> {
+    let input = storage::stdio::input_arg(input);
+    let (input_base, _) = if storage::glob::is_glob(input) {
+        storage::glob::split_url_pattern(input)?
     } else {
-        println!("\nNo results found.");
+        (input, "")
+    };
+    let input_base = Url::parse(input_base)?;
+    let storage = get_counted_storage_for_url(&input_base, &config.storage, stats).await?;
+    let urls = resolve_inputs(input, storage.as_ref(), order).await?;
+    Ok((storage, urls))
+}
+
+/// Directory the inputs are resolved below: the literal part of a glob, or
+/// the directory of a single input. The standard input is its own root.
+fn input_root(input: &str, urls: &[Url]) -> Result<Url> {
+    if storage::stdio::is_stdio(&urls[0]) {
+        Ok(urls[0].clone())
+    } else if storage::glob::is_glob(input) {
+        Ok(Url::parse(storage::glob::literal_prefix(input))?)
+    } else {
+        Ok(urls[0].join(".")?)
+    }
+}
+
+async fn print_schema(args: &SchemaArgs) -> Result<()> {
+    let config = read_config(args.config.as_deref())?;
+    validation::validate_config(&config)?;
+    let input = InputConfig::from_config(&config)?;
+    let (storage, urls) =
+        open_inputs(&args.input, InputOrder::Lexical, &config, &StorageStatsCollector::default()).await?;
+    let url = &urls[0];
+    let format = get_input_format(storage.as_ref(), url, args.input_format, &input).await?;
+    let size = storage.size(url).await?;
+    let schema = format.infer_schema(storage.clone(), url, size).await?;
+
+    if args.json {
+        println!("{}", schema::format_schema_json(&schema)?);
+    } else {
+        println!("{}", schema::format_schema_table(&schema)?);
+    }
+    if let Some(rows) = args.head {
+        let options = pipeline::ReadOptions::new(EmptyInputPolicy::EmptyOutput);
+        let batches = pipeline::read_batches(storage, vec![url.clone()], format, options);
+        let rows = pipeline::head(batches, rows).await?;
+        println!("\n{}", datafusion::arrow::util::pretty::pretty_format_batches(&rows)?);
+    }
+    Ok(())
+}
+
+async fn count(args: &CountArgs) -> Result<()> {
+    let config = read_config(args.config.as_deref())?;
+    validation::validate_config(&config)?;
+    let input = InputConfig::from_config(&config)?;
+    let (storage, urls) =
+        open_inputs(&args.input, InputOrder::Lexical, &config, &StorageStatsCollector::default()).await?;
+    let format = get_input_format(storage.as_ref(), &urls[0], args.input_format, &input).await?;
+
+    let mut total: Option<stats::InputStats> = None;
+    for url in &urls {
+        let size = storage.size(url).await?;
+        let file_stats = format.compute_stats(storage.clone(), url, size).await?;
+        total = Some(match total {
+            Some(total) => total.merge(file_stats)?,
+            None => file_stats,
+        });
+    }
+    if let Some(total) = total {
+        println!("{}", total.to_table()?);
+    }
+    Ok(())
+}
+
+async fn list(args: &ListArgs) -> Result<()> {
+    let config = read_config(args.config.as_deref())?;
+    validation::validate_config(&config)?;
+
+    let prefix = Url::parse(&args.prefix)?;
+    let storage = get_storage_for_url(&prefix, &config.storage).await?;
+    for entry in storage::list_prefix(storage.as_ref(), &prefix, args.recursive, args.limit).await? {
+        println!("{}", entry);
+    }
+    Ok(())
+}
+
+/// Rewrite the Parquet files matching `--input` into `part-NNNNN.parquet`
+/// files of about `--target-file-size` bytes each below `--output`
+async fn compact(args: &CompactArgs) -> Result<()> {
+    let config = read_config(args.config.as_deref())?;
+    validation::validate_config(&config)?;
+
+    let requests = Arc::new(Semaphore::new(config.storage.max_concurrent_requests));
+    let storage_stats = StorageStatsCollector::default();
+    let (input_storage, input_urls) = open_inputs(&args.input, args.sort_inputs, &config, &storage_stats).await?;
+    let input_storage = limit_requests(input_storage, &requests);
+    let output_dir = Url::parse(&args.output)?;
+    let output_storage = open_output(&output_dir, &config, &requests, &storage_stats).await?;
+
+    let input_format: Arc<dyn DataFormat + Send + Sync> = Arc::new(ParquetFormat::new(ParquetConfig {
+        batch_size: config.formats.parquet.batch_size,
+        adaptive_batch_size: config.formats.adaptive_batch_size.policy(),
+        ..ParquetConfig::default()
+    }));
+    let output_format: Arc<dyn DataFormat + Send + Sync> = Arc::new(ParquetFormat::new(ParquetConfig {
+        compression: Some(config.formats.parquet.compression.clone()),
+        writer_version: config.formats.parquet.writer_version.clone(),
+        data_page_version: config.formats.parquet.data_page_version.clone(),
+        column_encodings: config.formats.parquet.column_encodings.clone(),
+        dictionary_enabled_columns: config.formats.parquet.dictionary_enabled_columns.clone(),
+        dictionary_disabled_columns: config.formats.parquet.dictionary_disabled_columns.clone(),
+        ..ParquetConfig::default()
+    }));
+
+    // Inputs are checked against the first file's schema as they are read
+    let input_files = input_urls.len();
+    let options = pipeline::ReadOptions::new(EmptyInputPolicy::Error).with_strict_schema(args.strict_schema);
+    let batches = pipeline::read_batches(input_storage, input_urls, input_format, options);
+    let batches = pipeline::read_ahead(batches, &config.streaming);
+    let files = compact::compact(batches, output_format, output_storage, output_dir.clone(), args.target_file_size).await?;
+
+    for file in &files {
+        println!("Wrote {} rows ({} bytes) to {}", file.rows, file.bytes, file.path);
+    }
+    println!(
+        "\nCompacted {} files into {} files ({} rows) below {}",
+        input_files,
+        files.len(),
+        files.iter().map(|file| file.rows).sum::<usize>(),
+        output_dir
+    );
+    Ok(())
+}
+
+/// Convert each input file independently into `<output>/<relative path>.parquet`
+async fn convert_per_file(
+    args: &ConvertArgs,
+    config: &config::Config,
+    token: CancellationToken,
+    storage_stats: &StorageStatsCollector,
+) -> Result<()> {
+    if !args.partition_by.is_empty()
+        || args.coerce_schema_from.is_some()
+        || args.trailer_schema_check
+        || args.enforce_schema_stability
+        || args.append
+    {
+        return Err(anyhow::anyhow!(
+            "--per-file can't be combined with --partition-by, --coerce-schema-from, --trailer-schema-check, \
+             --enforce-schema-stability or --append"
+        ));
+    }
+    if args.input == "-" || args.output == "-" {
+        return Err(anyhow::anyhow!("--per-file writes a directory of files and can't read or write `-`"));
+    }
+    let requests = Arc::new(Semaphore::new(config.storage.max_concurrent_requests));
+    let (input_storage, input_urls) = open_inputs(&args.input, args.sort_inputs, config, storage_stats).await?;
+    let input_storage = limit_requests(input_storage, &requests);
+    let input_root = input_root(&args.input, &input_urls)?;
+    let output_dir = Url::parse(&args.output)?;
+    let jobs = input_urls
+        .iter()
+        .map(|input| Ok((input.clone(), pipeline::per_file_output(input, &input_root, &output_dir, "parquet")?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let input = InputConfig::from_config(config)?;
+    let input_format = get_input_format(input_storage.as_ref(), &input_urls[0], args.input_format, &input).await?;
+    let conversion = pipeline::FileConversion {
+        input_storage,
+        input_format,
+        output_storage: open_output(&output_dir, config, &requests, storage_stats).await?,
+        output_format: Arc::new(ParquetFormat::new(args.parquet_config(config))),
+        read_options: args.read_options(&input_root, &input_urls)?,
+        filter_sql: args.filter_sql.clone(),
+        session_setup: udf_session_setup(args, config).await?,
+        transforms: args.transforms.clone(),
+        preserve_metadata: args.preserve_metadata,
+        existing_output: args.existing_output(),
+        streaming: config.streaming.clone(),
+        cancellation: token,
+    };
+    let jobs_total = jobs.len();
+    let outputs = conversion.convert_all(jobs, config.processing.num_threads).await?;
+    if args.write_success_marker {
+        pipeline::write_success_marker(conversion.output_storage.as_ref(), &output_dir, &outputs).await?;
+    }
+    for output in &outputs {
+        println!("\nWrote {} rows ({} bytes) to {}", output.rows, output.bytes, output.path);
+    }
+    let skipped = jobs_total - outputs.len();
+    if skipped > 0 {
+        println!("\nSkipped {} files whose output already exists", skipped);
+    }
+    println!("\nSuccessfully converted {} files to: {}", outputs.len(), output_dir);
+    Ok(())
+}
+
+/// Load the `--udf-plugin` libraries and return the setup that registers their
+/// UDFs in the session `--filter-sql` runs in
+async fn udf_session_setup(args: &ConvertArgs, config: &config::Config) -> Result<Option<pipeline::SessionSetup>> {
+    if args.udf_plugin.is_empty() {
+        return Ok(None);
+    }
+    let plugins = plugin::PluginManager::from_config(&config.plugins);
+    for path in &args.udf_plugin {
+        plugins.load_plugin(path).await?;
+    }
+    Ok(Some(plugins.session_setup()))
+}
+
+/// Check that a conversion would succeed and print its output schema.
+///
+/// Only the bytes needed to infer the schema of the first input are read and
+/// the output location is never accessed.
+async fn dry_run(args: &ConvertArgs, config: &config::Config) -> Result<()> {
+    let input = InputConfig::from_config(config)?;
+    let (input_storage, input_urls) =
+        open_inputs(&args.input, args.sort_inputs, config, &StorageStatsCollector::default()).await?;
+    let url = &input_urls[0];
+    let format = get_input_format(input_storage.as_ref(), url, args.input_format, &input).await?;
+    let size = input_storage.size(url).await?;
+    let mut input_schema = format.infer_schema(input_storage, url, size).await?;
+    if args.hive_partitioning {
+        let partitioning = partitioned::HivePartitioning::discover(&input_root(&args.input, &input_urls)?, &input_urls)?;
+        let fields: Vec<_> = input_schema
+            .fields()
+            .iter()
+            .map(|field| field.as_ref().clone())
+            .chain(partitioning.fields().iter().cloned())
+            .collect();
+        input_schema = Arc::new(Schema::new(fields));
+    }
+    if let Some(columns) = args.column_selection() {
+        input_schema = columns.project_schema(&input_schema)?;
+    }
+
+    let session_setup = udf_session_setup(args, config).await?;
+    let mut output_schema =
+        pipeline::plan_output_schema(input_schema, args.filter_sql.as_deref(), session_setup.as_ref()).await?;
+    output_schema = schema::transform_schema(&output_schema, &args.transforms)?;
+    if let Some(reference) = &args.coerce_schema_from {
+        let reference_url = Url::parse(reference)?;
+        let reference_storage = get_storage_for_url(&reference_url, &config.storage).await?;
+        let reference_format = get_format_for_url(&reference_url, &input).await?;
+        let size = reference_storage.size(&reference_url).await?;
+        output_schema = reference_format.infer_schema(reference_storage, &reference_url, size).await?;
+    }
+    for column in &args.partition_by {
+        if output_schema.index_of(column).is_err() {
+            return Err(anyhow::anyhow!("--partition-by column '{}' is not in the output schema", column));
+        }
     }
+
+    println!("{}", schema::format_schema_table(&output_schema)?);
+    println!("\nDry run succeeded; nothing was written to {}", args.output);
     Ok(())
 }
 
-async fn convert(input: &str, output: &str, filter_sql: Option<String>) -> Result<()> {
-    // Parse URLs
-    let input_url = Url::parse(input)?;
-    let output_url = Url::parse(output)?;
+/// Run a conversion until it finishes or `token` is cancelled; a cancelled
+/// conversion fails with `Error::Cancelled` and leaves no partial output
+async fn convert(args: &ConvertArgs, token: CancellationToken) -> Result<()> {
+    let config = args.load_config()?;
+    if args.dry_run {
+        return dry_run(args, &config).await;
+    }
+    // Requests made before a failure are reported too
+    let storage_stats = StorageStatsCollector::default();
+    let result = if args.per_file {
+        convert_per_file(args, &config, token, &storage_stats).await
+    } else {
+        convert_stream(args, &config, token, &storage_stats).await
+    };
+    if args.stats {
+        let output = Url::parse(storage::stdio::output_arg(&args.output))?;
+        report(&output, format_args!("\n{}", storage_stats.snapshot().to_table()?));
+    }
+    result
+}
+
+/// Convert every input into one output, or one partitioned output directory
+async fn convert_stream(
+    args: &ConvertArgs,
+    config: &config::Config,
+    token: CancellationToken,
+    storage_stats: &StorageStatsCollector,
+) -> Result<()> {
+    if args.append && !args.partition_by.is_empty() {
+        return Err(anyhow::anyhow!(
+            "--append can't be combined with --partition-by; a partitioned layout already adds new part files"
+        ));
+    }
 
-    // Get storage implementations
-    let input_storage = get_storage_for_url(&input_url).await?;
-    let output_storage = get_storage_for_url(&output_url).await?;
+    // Get storage implementations, sharing one limit on concurrent requests
+    let requests = Arc::new(Semaphore::new(config.storage.max_concurrent_requests));
+    let (input_storage, input_urls) = open_inputs(&args.input, args.sort_inputs, config, storage_stats).await?;
+    let input_storage = limit_requests(input_storage, &requests);
+    let output_url = Url::parse(storage::stdio::output_arg(&args.output))?;
+    if storage::stdio::is_stdio(&output_url)
+        && (!args.partition_by.is_empty()
+            || args.append
+            || args.trailer_schema_check
+            || args.enforce_schema_stability
+            || args.write_success_marker)
+    {
+        return Err(anyhow::anyhow!(
+            "Writing to `-` can't be combined with --partition-by, --append, --trailer-schema-check, \
+             --enforce-schema-stability or --write-success-marker"
+        ));
+    }
+    let output_storage = open_output(&output_url, config, &requests, storage_stats).await?;
 
     // Get format implementations
-    let input_format = get_format_for_url(&input_url).await?;
-    let output_format = get_format_for_url(&output_url).await?;
-
-    // Read input data
-    let input_data = input_storage.read_all(&input_url).await?;
-    let mut df = input_format.read(&input_data)?;
-
-    // Apply filter if provided
-    if let Some(sql) = filter_sql {
-        let ctx = SessionContext::new();
-        ctx.register_table("data", df.clone().into_view())?;
-        let sql = if sql.to_lowercase() == "true" {
-            "SELECT * FROM data LIMIT 10".to_string()
-        } else if !sql.to_lowercase().contains("where") {
-            format!("SELECT * FROM data WHERE {} LIMIT 10", sql)
-        } else {
-            format!("SELECT * FROM data {} LIMIT 10", sql)
-        };
-        println!("\nExecuting SQL: {}", sql);
-        df = ctx.sql(&sql).await?;
-        
-        // Print the filtered results
-        print_dataframe(&df).await?;
-    }
-
-    // Write output
-    let output_data = output_format.write(&df)?;
-    output_storage.write(&output_url, output_data).await?;
-    
-    println!("\nSuccessfully wrote output to: {}", output_url);
+    let input = InputConfig::from_config(config)?;
+    let input_format = get_input_format(input_storage.as_ref(), &input_urls[0], args.input_format, &input).await?;
+    let output_format: Arc<dyn DataFormat + Send + Sync> = if args.partition_by.is_empty() {
+        get_output_format(&output_url, args, config).await?
+    } else {
+        Arc::new(ParquetFormat::new(args.parquet_config(config)))
+    };
+
+    // Stream input data; batches are decoded as they are read
+    let read_options = args.read_options(&input_root(&args.input, &input_urls)?, &input_urls)?;
+    let progress = read_options.progress.clone();
+    let batches = pipeline::read_batches(input_storage, input_urls, input_format, read_options);
+    let batches = pipeline::with_cancellation(pipeline::read_ahead(batches, &config.streaming), token);
+    let (input_schema, batches) = pipeline::peek_schema(batches).await?;
+    let mut df = pipeline::stream_dataframe(input_schema.clone(), batches)?;
+
+    // Apply filter if provided; the query's result schema drives the output writer
+    if let Some(sql) = &args.filter_sql {
+        report(&output_url, format_args!("\nExecuting SQL: {}", pipeline::filter_query(sql)));
+        df = pipeline::apply_sql_with(df, sql, udf_session_setup(args, config).await?.as_ref()).await?;
+    }
+    df = pipeline::transform_columns(df, &args.transforms).await?;
+
+    if let Some(reference) = &args.coerce_schema_from {
+        df = coerce_to_reference(df, reference, &input, config).await?;
+    }
+    if args.preserve_metadata {
+        df = pipeline::preserve_metadata(df, &input_schema).await?;
+    }
+    let planned_schema: SchemaRef = Arc::new(df.schema().into());
+    if args.enforce_schema_stability {
+        pipeline::check_schema_stability(output_storage.as_ref(), &output_url, &planned_schema).await?;
+    }
+
+    // Write output, encoding and uploading batches as they are produced
+    let stats = if args.append {
+        pipeline::append_output(df, output_format.clone(), output_storage.clone(), &output_url).await?
+    } else {
+        let options = pipeline::WriteOptions::new(args.existing_output())
+            .with_partition_by(args.partition_by.clone())
+            // Every open partition is an upload in progress
+            .with_max_open_partitions(args.max_open_partitions.min(config.storage.max_concurrent_requests))
+            .with_progress(progress);
+        pipeline::write_output_with(df, output_format.clone(), output_storage.clone(), &output_url, options).await?
+    };
+    for skipped in &stats.skipped {
+        report(&output_url, format_args!("\nSkipped {}, which already exists", skipped));
+    }
+    if args.partition_by.is_empty() && !stats.skipped.is_empty() {
+        return Ok(());
+    }
+
+    if args.trailer_schema_check {
+        // Partition columns are encoded in the directory names, not the files
+        let expected: SchemaRef = Arc::new(Schema::new(
+            stats
+                .output_schema
+                .fields()
+                .iter()
+                .filter(|field| !args.partition_by.contains(field.name()))
+                .map(|field| field.as_ref().clone())
+                .collect::<Vec<_>>(),
+        ));
+        for output in &stats.outputs {
+            let data = output_storage.read_all(&output.path).await?;
+            schema::verify_written_schema(output_format.as_ref(), &data, &expected)?;
+        }
+        println!("\nVerified output schema of {}", output_url);
+    }
+
+    // Partitioned outputs are Parquet, which stores the metadata itself
+    if args.preserve_metadata && !output_format.capabilities().field_metadata && !storage::stdio::is_stdio(&output_url) {
+        if let Some(sidecar) =
+            pipeline::write_metadata_sidecar(output_storage.as_ref(), &output_url, &stats.output_schema).await?
+        {
+            report(&output_url, format_args!("\nWrote metadata to {}", sidecar));
+        }
+    }
+
+    if args.enforce_schema_stability {
+        let sidecar = pipeline::write_schema_sidecar(output_storage.as_ref(), &output_url, &planned_schema).await?;
+        report(&output_url, format_args!("\nCached output schema in {}", sidecar));
+    }
+
+    if args.write_success_marker {
+        // A partitioned output is a directory; otherwise mark the file's directory
+        let output_dir = if args.partition_by.is_empty() { output_url.join(".")? } else { output_url.clone() };
+        pipeline::write_success_marker(output_storage.as_ref(), &output_dir, &stats.outputs).await?;
+    }
+
+    for output in &stats.outputs {
+        report(&output_url, format_args!("\nWrote {} rows ({} bytes) to {}", output.rows, output.bytes, output.path));
+    }
+    report(&output_url, format_args!("\nSuccessfully wrote output to: {}", output_url));
     Ok(())
 }
 
+/// Print a status line of a conversion writing `output`, on stderr when the
+/// output itself goes to standard output
+fn report(output: &Url, message: std::fmt::Arguments) {
+    if storage::stdio::is_stdio(output) {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Log spans and events to stderr, filtered by `RUST_LOG` (e.g. `RUST_LOG=distributed_transformer=debug`)
+#[cfg(feature = "tracing-subscriber")]
+fn init_tracing() {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv().ok();
+    #[cfg(feature = "tracing-subscriber")]
+    init_tracing();
 
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
 
     match cli.command {
-        Commands::Convert { input, output, filter_sql } => convert(&input, &output, filter_sql).await?,
+        Commands::Convert(mut args) => {
+            if let Some(matches) = matches.subcommand_matches("convert") {
+                args.column_transforms(matches)?;
+            }
+            let token = CancellationToken::new();
+            let on_interrupt = token.clone();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    on_interrupt.cancel();
+                }
+            });
+            convert(&args, token).await?
+        }
+        Commands::Schema(args) => print_schema(&args).await?,
+        Commands::Count(args) => count(&args).await?,
+        Commands::Compact(args) => compact(&args).await?,
+        Commands::List(args) => list(&args).await?,
     }
 
     Ok(())
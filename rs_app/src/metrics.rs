@@ -0,0 +1,146 @@
+//! Operational metrics recorded through the `metrics` facade.
+//!
+//! Nothing is exported until a recorder is installed, e.g. with
+//! [`install_prometheus_recorder`]; without one every call is a no-op.
+
+use std::time::Duration;
+
+use ::metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+
+use crate::error::Error;
+
+/// Input files read to the end
+pub const FILES_PROCESSED: &str = "files_processed";
+/// Output files written
+pub const FILES_WRITTEN: &str = "files_written";
+/// Bytes read from storage, labelled by `backend`
+pub const BYTES_READ: &str = "bytes_read";
+/// Bytes written to storage, labelled by `backend`
+pub const BYTES_WRITTEN: &str = "bytes_written";
+/// Storage operations retried after a transient failure
+pub const STORAGE_RETRIES: &str = "storage_retries";
+/// Failed storage operations and conversions, labelled by error `kind`
+pub const ERRORS: &str = "errors";
+/// CSV rows dropped under a `Skip` or `Collect` error mode
+pub const CSV_ROWS_REJECTED: &str = "csv_rows_rejected";
+/// Time from executing a conversion's query to its last output file
+pub const CONVERSION_DURATION: &str = "conversion_duration_seconds";
+
+/// Register descriptions of every metric with the installed recorder
+pub fn describe() {
+    describe_counter!(FILES_PROCESSED, "Input files read to the end");
+    describe_counter!(FILES_WRITTEN, "Output files written");
+    describe_counter!(BYTES_READ, Unit::Bytes, "Bytes read from storage");
+    describe_counter!(BYTES_WRITTEN, Unit::Bytes, "Bytes written to storage");
+    describe_counter!(STORAGE_RETRIES, "Storage operations retried after a transient failure");
+    describe_counter!(ERRORS, "Failed storage operations and conversions by error kind");
+    describe_counter!(CSV_ROWS_REJECTED, "CSV rows dropped because they could not be read");
+    describe_histogram!(CONVERSION_DURATION, Unit::Seconds, "Duration of conversions");
+}
+
+pub(crate) fn file_processed() {
+    counter!(FILES_PROCESSED).increment(1);
+}
+
+pub(crate) fn file_written() {
+    counter!(FILES_WRITTEN).increment(1);
+}
+
+pub(crate) fn bytes_read(backend: &'static str, bytes: usize) {
+    counter!(BYTES_READ, "backend" => backend).increment(bytes as u64);
+}
+
+pub(crate) fn bytes_written(backend: &'static str, bytes: usize) {
+    counter!(BYTES_WRITTEN, "backend" => backend).increment(bytes as u64);
+}
+
+pub(crate) fn storage_retry() {
+    counter!(STORAGE_RETRIES).increment(1);
+}
+
+pub(crate) fn csv_row_rejected() {
+    counter!(CSV_ROWS_REJECTED).increment(1);
+}
+
+pub(crate) fn error(error: &Error) {
+    counter!(ERRORS, "kind" => error.kind()).increment(1);
+}
+
+/// Count a conversion failure; errors from outside this crate are counted as `other`
+pub(crate) fn conversion_error(error: &anyhow::Error) {
+    let kind = error.downcast_ref::<Error>().map_or("other", Error::kind);
+    counter!(ERRORS, "kind" => kind).increment(1);
+}
+
+pub(crate) fn conversion_duration(duration: Duration) {
+    histogram!(CONVERSION_DURATION).record(duration.as_secs_f64());
+}
+
+/// Install a global Prometheus recorder and describe every metric.
+///
+/// Serve `PrometheusHandle::render` from the embedding service's metrics endpoint.
+#[cfg(feature = "prometheus")]
+pub fn install_prometheus_recorder() -> crate::error::Result<metrics_exporter_prometheus::PrometheusHandle> {
+    let handle = metrics_exporter_prometheus::PrometheusBuilder::new()
+        .install_recorder()
+        .map_err(|e| Error::Config(format!("Cannot install the Prometheus recorder: {}", e)))?;
+    describe();
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{CsvFormat, EmptyInputPolicy, ParquetFormat};
+    use crate::pipeline::{self, ReadOptions};
+    use crate::storage::local::LocalStorage;
+    use crate::storage::Storage;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::sync::Arc;
+    use url::Url;
+
+    #[test]
+    fn test_conversion_counts_files_processed() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        std::fs::write(temp_dir.path().join("a.csv"), "id\n1\n2\n")?;
+        std::fs::write(temp_dir.path().join("b.csv"), "id\n3\n")?;
+        let inputs = ["a.csv", "b.csv"]
+            .iter()
+            .map(|name| Url::from_file_path(temp_dir.path().join(name)).unwrap())
+            .collect();
+        let output = Url::from_file_path(temp_dir.path().join("out.parquet")).unwrap();
+
+        // The recorder is thread-local, so every task must run on this thread
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        ::metrics::with_local_recorder(&recorder, || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(async {
+                let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+                let options = ReadOptions::new(EmptyInputPolicy::Error);
+                let batches = pipeline::read_batches(storage.clone(), inputs, Arc::new(CsvFormat::default()), options);
+                let (schema, batches) = pipeline::peek_schema(batches).await?;
+                let df = pipeline::stream_dataframe(schema, batches)?;
+                pipeline::write_output(df, Arc::new(ParquetFormat::default()), storage, &output, &[], 1, None).await
+            })
+        })?;
+
+        let counter = |name: &str| {
+            snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .filter(|(key, ..)| key.key().name() == name)
+                .map(|(.., value)| match value {
+                    DebugValue::Counter(value) => value,
+                    _ => 0,
+                })
+                .sum::<u64>()
+        };
+        assert_eq!(counter(FILES_PROCESSED), 2);
+        assert_eq!(counter(FILES_WRITTEN), 1);
+        assert!(counter(BYTES_READ) > 0);
+        assert!(counter(BYTES_WRITTEN) > 0);
+        Ok(())
+    }
+}
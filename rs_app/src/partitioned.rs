@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arrow::array::{Array, ArrayRef, Float64Array, Int64Array, StringArray, UInt32Array};
+use arrow::compute::take;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::array_value_to_string;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::error::{self, Error};
+use crate::formats::DataFormat;
+use crate::pipeline::{self, ExistingOutput, OutputInfo};
+use crate::storage::Storage;
+
+/// Batches buffered per open partition writer before `write` waits
+const PARTITION_CHANNEL_CAPACITY: usize = 2;
+
+/// Directory name of the partition holding null values
+const HIVE_DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Characters Hive escapes as `%XX` in partition directory names. Escaping
+/// `/`, `?`, `#` and `%` keeps every value in one directory of its own, which
+/// `partition_segments` decodes back to the value.
+const HIVE_ESCAPED: &AsciiSet = &CONTROLS
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'\'')
+    .add(b'*')
+    .add(b'/')
+    .add(b':')
+    .add(b'=')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'[')
+    .add(b']')
+    .add(b'^')
+    .add(b'{');
+
+/// The `column=value` directory name of a partition, escaped Hive-style
+fn partition_directory(column: &str, value: &str) -> String {
+    format!("{}={}", utf8_percent_encode(column, HIVE_ESCAPED), utf8_percent_encode(value, HIVE_ESCAPED))
+}
+
+/// A partition file currently being written by a background task, which
+/// returns `None` when the file exists and is skipped
+struct OpenPartition {
+    url: Url,
+    sender: mpsc::Sender<error::Result<RecordBatch>>,
+    task: JoinHandle<Result<Option<OutputInfo>>>,
+    last_used: u64,
+}
+
+/// Writes batches into Hive-style `column=value` directories below a base URL.
+///
+/// Every open partition streams into its own file on a background task, so
+/// partitions are written in parallel. At most `max_open_partitions` files
+/// are open at once; beyond that the least recently used one is finished and
+/// a later batch for the same partition starts a new `part-N` file.
+///
+/// Each part file is checked against the [`ExistingOutput`] policy when it
+/// is opened, so a rerun into the same directory fails, replaces or skips
+/// file by file.
+pub struct PartitionedWriter {
+    storage: Arc<dyn Storage>,
+    format: Arc<dyn DataFormat>,
+    base: Url,
+    partition_columns: Vec<String>,
+    extension: String,
+    max_open_partitions: usize,
+    keep_partition_columns: bool,
+    existing_output: ExistingOutput,
+    open: HashMap<String, OpenPartition>,
+    files_per_partition: HashMap<String, usize>,
+    written: Vec<OutputInfo>,
+    skipped: Vec<Url>,
+    clock: u64,
+}
+
+impl PartitionedWriter {
+    pub fn new(storage: Arc<dyn Storage>, format: Arc<dyn DataFormat>, mut base: Url, partition_columns: Vec<String>) -> Self {
+        // Partition directories are resolved relative to the base, which must look like a directory
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Self {
+            storage,
+            format,
+            base,
+            partition_columns,
+            extension: "parquet".to_string(),
+            max_open_partitions: 16,
+            keep_partition_columns: false,
+            existing_output: ExistingOutput::Overwrite,
+            open: HashMap::new(),
+            files_per_partition: HashMap::new(),
+            written: Vec::new(),
+            skipped: Vec::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn with_max_open_partitions(mut self, max_open_partitions: usize) -> Self {
+        self.max_open_partitions = max_open_partitions.max(1);
+        self
+    }
+
+    /// Also write the partition columns into the part files; by default they
+    /// are only encoded in the directory names
+    pub fn with_keep_partition_columns(mut self, keep_partition_columns: bool) -> Self {
+        self.keep_partition_columns = keep_partition_columns;
+        self
+    }
+
+    /// What to do with part files that exist; by default they are replaced
+    pub fn with_existing_output(mut self, existing_output: ExistingOutput) -> Self {
+        self.existing_output = existing_output;
+        self
+    }
+
+    /// File extension of the written part files
+    pub fn with_extension(mut self, extension: impl Into<String>) -> Self {
+        self.extension = extension.into();
+        self
+    }
+
+    /// Number of partition files currently open
+    pub fn open_partitions(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Split `batch` by the partition columns and send each slice to its partition's writer
+    pub async fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        for (key, slice) in self.split(batch)? {
+            self.clock += 1;
+            if !self.open.contains_key(&key) {
+                if self.open.len() >= self.max_open_partitions {
+                    self.close_least_recently_used().await?;
+                }
+                let partition = self.open_partition(&key)?;
+                self.open.insert(key.clone(), partition);
+            }
+
+            let partition = self.open.get_mut(&key).expect("partition was just opened");
+            partition.last_used = self.clock;
+            if partition.sender.send(Ok(slice)).await.is_err() {
+                // The writer task exited early; its result carries the error
+                let partition = self.open.remove(&key).expect("partition is open");
+                partition.task.await??;
+                return Err(anyhow!("Writer for partition {} stopped unexpectedly", key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish every open partition and describe every written file, ordered by path
+    pub async fn finish(self) -> Result<Vec<OutputInfo>> {
+        Ok(self.finish_with_skipped().await?.0)
+    }
+
+    /// [`finish`](Self::finish), also returning the part files that were
+    /// skipped because they exist, ordered by URL
+    pub async fn finish_with_skipped(mut self) -> Result<(Vec<OutputInfo>, Vec<Url>)> {
+        let keys: Vec<String> = self.open.keys().cloned().collect();
+        for key in keys {
+            self.close(&key).await?;
+        }
+        self.written.sort_by(|a, b| a.path.cmp(&b.path));
+        self.skipped.sort();
+        Ok((self.written, self.skipped))
+    }
+
+    /// Stop every open partition without finishing its file and delete the
+    /// files already written, e.g. after the conversion was cancelled
+    pub async fn abort(mut self) {
+        for (_, partition) in self.open.drain() {
+            // Failing the stream makes the upload abort instead of completing
+            let _ = partition.sender.send(Err(Error::Cancelled)).await;
+            let _ = partition.task.await;
+        }
+        // Best effort: the conversion has already failed
+        for output in &self.written {
+            let _ = self.storage.delete(&output.path).await;
+        }
+    }
+
+    fn open_partition(&mut self, key: &str) -> Result<OpenPartition> {
+        let index = self.files_per_partition.get(key).copied().unwrap_or(0);
+        self.files_per_partition.insert(key.to_string(), index + 1);
+        let url = self.part_url(key, index)?;
+
+        let (sender, mut receiver) = mpsc::channel(PARTITION_CHANNEL_CAPACITY);
+        let storage = self.storage.clone();
+        let format = self.format.clone();
+        let existing_output = self.existing_output;
+        let task_url = url.clone();
+        let task = tokio::spawn(async move {
+            let url = task_url;
+            if !existing_output.allows(storage.as_ref(), &url).await? {
+                // Keep accepting the partition's batches so `write` doesn't see a stopped writer
+                while receiver.recv().await.is_some() {}
+                return Ok(None);
+            }
+            let batches = futures::stream::unfold(receiver, |mut receiver: mpsc::Receiver<error::Result<RecordBatch>>| async move {
+                receiver.recv().await.map(|batch| (batch, receiver))
+            });
+            Ok(Some(pipeline::write_batches(format.as_ref(), Box::pin(batches), storage.as_ref(), &url).await?))
+        });
+        Ok(OpenPartition {
+            url,
+            sender,
+            task,
+            last_used: self.clock,
+        })
+    }
+
+    async fn close_least_recently_used(&mut self) -> Result<()> {
+        let key = self
+            .open
+            .iter()
+            .min_by_key(|(_, partition)| partition.last_used)
+            .map(|(key, _)| key.clone());
+        match key {
+            Some(key) => self.close(&key).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn close(&mut self, key: &str) -> Result<()> {
+        let Some(partition) = self.open.remove(key) else {
+            return Ok(());
+        };
+        drop(partition.sender);
+        match partition.task.await?? {
+            Some(output) => self.written.push(output),
+            None => self.skipped.push(partition.url),
+        }
+        Ok(())
+    }
+
+    fn part_url(&self, key: &str, index: usize) -> Result<Url> {
+        Ok(self.base.join(&format!("{}/part-{:05}.{}", key, index, self.extension))?)
+    }
+
+    /// Group the rows of `batch` by partition key, dropping the partition
+    /// columns unless `keep_partition_columns` is set
+    fn split(&self, batch: &RecordBatch) -> Result<Vec<(String, RecordBatch)>> {
+        let schema = batch.schema();
+        let key_columns = self
+            .partition_columns
+            .iter()
+            .map(|name| {
+                schema
+                    .index_of(name)
+                    .map_err(|_| anyhow!("Partition column '{}' does not exist", name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut groups: Vec<(String, Vec<u32>)> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for row in 0..batch.num_rows() {
+            let key = key_columns
+                .iter()
+                .map(|&index| -> Result<String> {
+                    let column = batch.column(index);
+                    let value = if column.is_null(row) {
+                        HIVE_DEFAULT_PARTITION.to_string()
+                    } else {
+                        array_value_to_string(column, row)?
+                    };
+                    Ok(partition_directory(schema.field(index).name(), &value))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .join("/");
+            let position = *positions.entry(key.clone()).or_insert_with(|| {
+                groups.push((key, Vec::new()));
+                groups.len() - 1
+            });
+            groups[position].1.push(row as u32);
+        }
+
+        let data_columns: Vec<usize> = (0..schema.fields().len())
+            .filter(|i| self.keep_partition_columns || !key_columns.contains(i))
+            .collect();
+        let data_schema = Arc::new(Schema::new(
+            data_columns.iter().map(|&i| schema.field(i).clone()).collect::<Vec<_>>(),
+        ));
+        groups
+            .into_iter()
+            .map(|(key, rows)| {
+                let indices = UInt32Array::from(rows);
+                let columns = data_columns
+                    .iter()
+                    .map(|&i| take(batch.column(i).as_ref(), &indices, None))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((key, RecordBatch::try_new(data_schema.clone(), columns)?))
+            })
+            .collect()
+    }
+}
+
+/// Partition columns of a Hive-style layout such as `year=2023/month=01/`,
+/// read from the directories between a root and each input file
+#[derive(Debug, Clone)]
+pub struct HivePartitioning {
+    root: Url,
+    fields: Vec<Field>,
+}
+
+impl HivePartitioning {
+    /// Discover the partition keys of `urls` below `root`.
+    ///
+    /// Every file must have the same keys in the same order. A key's type is
+    /// `Int64` or `Float64` when all of its values parse as one, `Utf8` otherwise.
+    pub fn discover(root: &Url, urls: &[Url]) -> error::Result<Self> {
+        let mut keys: Option<Vec<String>> = None;
+        let mut values: Vec<Vec<String>> = Vec::new();
+        for url in urls {
+            let segments = partition_segments(root, url)?;
+            let names: Vec<String> = segments.iter().map(|(key, _)| key.clone()).collect();
+            match &keys {
+                Some(keys) => check_keys(keys, &names, url)?,
+                None => {
+                    values = vec![Vec::new(); names.len()];
+                    keys = Some(names);
+                }
+            }
+            for (column, (_, value)) in values.iter_mut().zip(segments) {
+                column.push(value);
+            }
+        }
+
+        let fields = keys
+            .unwrap_or_default()
+            .into_iter()
+            .zip(values)
+            .map(|(key, values)| Field::new(key, infer_partition_type(&values), true))
+            .collect();
+        Ok(Self {
+            root: root.clone(),
+            fields,
+        })
+    }
+
+    /// The partition columns, in directory order
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Append the partition values of `url` to `batch` as constant columns
+    pub fn append_columns(&self, url: &Url, batch: RecordBatch) -> error::Result<RecordBatch> {
+        if self.fields.is_empty() {
+            return Ok(batch);
+        }
+        let segments = partition_segments(&self.root, url)?;
+        let names: Vec<String> = segments.iter().map(|(key, _)| key.clone()).collect();
+        let keys: Vec<String> = self.fields.iter().map(|field| field.name().clone()).collect();
+        check_keys(&keys, &names, url)?;
+
+        let schema = batch.schema();
+        let mut fields: Vec<Field> = schema.fields().iter().map(|field| field.as_ref().clone()).collect();
+        let mut columns = batch.columns().to_vec();
+        for (field, (key, value)) in self.fields.iter().zip(segments) {
+            if schema.field_with_name(&key).is_ok() {
+                return Err(Error::InvalidInput(format!(
+                    "Partition column '{}' of {} also exists in the file",
+                    key, url
+                )));
+            }
+            columns.push(constant_column(field.data_type(), &value, batch.num_rows())?);
+            fields.push(field.clone());
+        }
+        Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?)
+    }
+}
+
+/// The `key=value` directories between `root` and the file at `url`, decoded
+fn partition_segments(root: &Url, url: &Url) -> error::Result<Vec<(String, String)>> {
+    let relative = url
+        .path()
+        .strip_prefix(root.path())
+        .ok_or_else(|| Error::InvalidInput(format!("{} is not below the input root {}", url, root)))?;
+    let directories = relative.rsplit_once('/').map(|(directories, _)| directories).unwrap_or("");
+    directories
+        .split('/')
+        .filter_map(|segment| segment.split_once('='))
+        .map(|(key, value)| {
+            let decode = |part: &str| percent_encoding::percent_decode_str(part).decode_utf8_lossy().into_owned();
+            Ok((decode(key), decode(value)))
+        })
+        .collect()
+}
+
+fn check_keys(expected: &[String], found: &[String], url: &Url) -> error::Result<()> {
+    if let Some(missing) = expected.iter().find(|key| !found.contains(key)) {
+        return Err(Error::InvalidInput(format!(
+            "{} is missing the partition directory '{}=...'; expected partitions {}",
+            url,
+            missing,
+            expected.join("/")
+        )));
+    }
+    if expected != found {
+        return Err(Error::InvalidInput(format!(
+            "Partition directories of {} are {}, expected {}",
+            url,
+            found.join("/"),
+            expected.join("/")
+        )));
+    }
+    Ok(())
+}
+
+fn infer_partition_type(values: &[String]) -> DataType {
+    let present: Vec<&String> = values.iter().filter(|value| *value != HIVE_DEFAULT_PARTITION).collect();
+    if present.iter().all(|value| value.parse::<i64>().is_ok()) {
+        DataType::Int64
+    } else if present.iter().all(|value| value.parse::<f64>().is_ok()) {
+        DataType::Float64
+    } else {
+        DataType::Utf8
+    }
+}
+
+fn constant_column(data_type: &DataType, value: &str, rows: usize) -> error::Result<ArrayRef> {
+    let value = (value != HIVE_DEFAULT_PARTITION).then_some(value);
+    let invalid = || Error::InvalidInput(format!("Partition value '{}' is not a {}", value.unwrap_or_default(), data_type));
+    let column: ArrayRef = match data_type {
+        DataType::Int64 => {
+            let value = value.map(|v| v.parse::<i64>().map_err(|_| invalid())).transpose()?;
+            Arc::new(Int64Array::from(vec![value; rows]))
+        }
+        DataType::Float64 => {
+            let value = value.map(|v| v.parse::<f64>().map_err(|_| invalid())).transpose()?;
+            Arc::new(Float64Array::from(vec![value; rows]))
+        }
+        _ => Arc::new(StringArray::from(vec![value; rows])),
+    };
+    Ok(column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::ParquetFormat;
+    use crate::storage::local::LocalStorage;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_writers_are_recycled_beyond_the_bound() -> Result<()> {
+        const PARTITIONS: i64 = 10;
+        const ROUNDS: i64 = 3;
+
+        let temp_dir = TempDir::new()?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let base = Url::from_directory_path(temp_dir.path().join("out")).unwrap();
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let mut writer = PartitionedWriter::new(storage.clone(), format.clone(), base, vec!["key".to_string()])
+            .with_max_open_partitions(3);
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("key", DataType::Int64, false),
+            Field::new("value", DataType::Int64, false),
+        ]));
+        for round in 0..ROUNDS {
+            let keys: Vec<i64> = (0..PARTITIONS).collect();
+            let values: Vec<i64> = keys.iter().map(|k| round * PARTITIONS + k).collect();
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(Int64Array::from(keys)), Arc::new(Int64Array::from(values))],
+            )?;
+            writer.write(&batch).await?;
+            assert!(writer.open_partitions() <= 3);
+        }
+        let files = writer.finish().await?;
+
+        // Cycling through more partitions than the bound reopens each one per round
+        assert_eq!(files.len(), (PARTITIONS * ROUNDS) as usize);
+        assert!(files.iter().any(|file| file.path.path().ends_with("/out/key=0/part-00002.parquet")));
+        assert!(files.iter().all(|file| file.rows == 1));
+
+        let mut values = Vec::new();
+        for file in &files {
+            let data = storage.read_all(&file.path).await?;
+            for batch in format.read(&data)?.collect().await? {
+                assert_eq!(batch.num_columns(), 1);
+                let column = batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+                values.extend(column.values().iter().copied());
+            }
+        }
+        values.sort();
+        assert_eq!(values, (0..PARTITIONS * ROUNDS).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hive_layout_with_and_without_partition_columns() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let batch = RecordBatch::try_from_iter(vec![
+            ("country", Arc::new(StringArray::from(vec!["de", "us", "de"])) as _),
+            ("amount", Arc::new(Int64Array::from(vec![1, 2, 3])) as _),
+        ])?;
+
+        for (dir, keep) in [("dropped", false), ("kept", true)] {
+            let base = Url::from_directory_path(temp_dir.path().join(dir)).unwrap();
+            let mut writer = PartitionedWriter::new(storage.clone(), format.clone(), base, vec!["country".to_string()])
+                .with_keep_partition_columns(keep);
+            writer.write(&batch).await?;
+            let files = writer.finish().await?;
+
+            let paths: Vec<&str> = files.iter().map(|file| file.path.path()).collect();
+            assert_eq!(paths.len(), 2);
+            assert!(paths[0].ends_with(&format!("/{}/country=de/part-00000.parquet", dir)));
+            assert!(paths[1].ends_with(&format!("/{}/country=us/part-00000.parquet", dir)));
+            assert_eq!(files[0].rows, 2);
+
+            let data = storage.read_all(&files[0].path).await?;
+            let read = format.read(&data)?.collect().await?;
+            let columns: Vec<String> = read[0].schema().fields().iter().map(|f| f.name().clone()).collect();
+            let expected = if keep { vec!["country", "amount"] } else { vec!["amount"] };
+            assert_eq!(columns, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hive_partitioning_adds_path_columns() -> Result<()> {
+        use crate::formats::{CsvFormat, EmptyInputPolicy};
+        use crate::pipeline::{read_batches, ReadOptions};
+        use futures::TryStreamExt;
+
+        let temp_dir = TempDir::new()?;
+        let mut urls = Vec::new();
+        for (year, month, name) in [(2023, "01", "a"), (2023, "02", "b"), (2024, "01", "c")] {
+            let dir = temp_dir.path().join(format!("year={}/month={}", year, month));
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("data.csv"), format!("name\n{}\n", name))?;
+            urls.push(Url::from_file_path(dir.join("data.csv")).unwrap());
+        }
+        let root = Url::from_directory_path(temp_dir.path()).unwrap();
+
+        let partitioning = HivePartitioning::discover(&root, &urls)?;
+        let types: Vec<&DataType> = partitioning.fields().iter().map(|f| f.data_type()).collect();
+        assert_eq!(types, vec![&DataType::Int64, &DataType::Int64]);
+
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let options = ReadOptions::new(EmptyInputPolicy::Error).with_hive_partitioning(partitioning);
+        let batches: Vec<RecordBatch> =
+            read_batches(storage, urls, Arc::new(CsvFormat::default()), options).try_collect().await?;
+
+        let mut rows = Vec::new();
+        for batch in &batches {
+            let schema = batch.schema();
+            let names: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+            assert_eq!(names, vec!["name", "year", "month"]);
+            let name = batch.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+            let year = batch.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+            let month = batch.column(2).as_any().downcast_ref::<Int64Array>().unwrap();
+            for row in 0..batch.num_rows() {
+                rows.push((name.value(row).to_string(), year.value(row), month.value(row)));
+            }
+        }
+        assert_eq!(
+            rows,
+            vec![
+                ("a".to_string(), 2023, 1),
+                ("b".to_string(), 2023, 2),
+                ("c".to_string(), 2024, 1)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partition_values_are_escaped() -> Result<()> {
+        use crate::storage::memory::MemoryStorage;
+
+        let values = vec!["a/b", "../escape", "..", "x?y", "x#y", "a%20b", "a b"];
+        let batch = RecordBatch::try_from_iter(vec![
+            ("key", Arc::new(StringArray::from(values.clone())) as ArrayRef),
+            ("id", Arc::new(Int64Array::from_iter_values(0..values.len() as i64)) as _),
+        ])?;
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let root = Url::parse("memory:///out/")?;
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let mut writer = PartitionedWriter::new(storage, format, root.clone(), vec!["key".to_string()]);
+        writer.write(&batch).await?;
+        let files = writer.finish().await?;
+
+        // Every value gets its own directory directly below the root and reads back unchanged
+        assert_eq!(files.len(), values.len());
+        let mut read = Vec::new();
+        for file in &files {
+            assert!(file.path.query().is_none() && file.path.fragment().is_none(), "{}", file.path);
+            let segments = partition_segments(&root, &file.path)?;
+            assert_eq!(segments.len(), 1, "{}", file.path);
+            assert_eq!(segments[0].0, "key");
+            read.push(segments[0].1.clone());
+        }
+        read.sort();
+        let mut expected: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+        expected.sort();
+        assert_eq!(read, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hive_partitioning_rejects_missing_segments() {
+        let root = Url::parse("file:///data/").unwrap();
+        let urls = vec![
+            Url::parse("file:///data/year=2023/month=01/a.parquet").unwrap(),
+            Url::parse("file:///data/year=2023/b.parquet").unwrap(),
+        ];
+        let err = HivePartitioning::discover(&root, &urls).unwrap_err();
+        assert!(matches!(err, Error::InvalidInput(_)));
+        assert!(err.to_string().contains("missing the partition directory 'month=...'"), "{}", err);
+    }
+}
@@ -0,0 +1,1769 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use arrow::datatypes::{Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use datafusion::dataframe::DataFrame;
+use datafusion::datasource::streaming::StreamingTable;
+use datafusion::error::DataFusionError;
+use datafusion::execution::context::{SessionContext, TaskContext};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::streaming::PartitionStream;
+use datafusion::physical_plan::SendableRecordBatchStream;
+use datafusion::sql::sqlparser::ast::{
+    Expr as SqlExpr, Ident, ObjectName, SetExpr, Statement, TableFactor, TableWithJoins, Value,
+};
+use datafusion::sql::sqlparser::dialect::{Dialect, GenericDialect};
+use datafusion::sql::sqlparser::keywords::Keyword;
+use datafusion::sql::sqlparser::parser::Parser;
+use datafusion::sql::sqlparser::tokenizer::Token;
+use futures::{StreamExt, TryStreamExt};
+use parking_lot::Mutex;
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::config::StreamingConfig;
+use crate::error::Error;
+use crate::formats::{self, BatchStream, DataFormat, EmptyInputPolicy};
+use crate::metrics;
+use crate::partitioned::{HivePartitioning, PartitionedWriter};
+use crate::progress::ProgressReporter;
+use crate::schema;
+use crate::storage::Storage;
+
+/// Capacity of the in-memory pipe between a format writer and the storage upload
+const PIPE_CAPACITY: usize = 8 * 1024 * 1024;
+
+/// Empty object written next to a job's outputs once all of them are complete
+pub const SUCCESS_MARKER: &str = "_SUCCESS";
+/// JSON object listing a job's outputs, written just before `SUCCESS_MARKER`
+pub const MANIFEST: &str = "_manifest.json";
+
+/// What to do when an output object already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExistingOutput {
+    /// Fail with `Error::OutputExists` before the object is written
+    #[default]
+    Error,
+    /// Replace the object
+    Overwrite,
+    /// Leave the object as it is and carry on without writing it
+    Skip,
+}
+
+impl ExistingOutput {
+    /// Whether `url` should be written, `false` meaning it exists and is skipped.
+    ///
+    /// The standard output is always written.
+    pub async fn allows(self, storage: &dyn Storage, url: &Url) -> Result<bool, Error> {
+        if self == ExistingOutput::Overwrite || crate::storage::stdio::is_stdio(url) || !storage.exists(url).await? {
+            return Ok(true);
+        }
+        match self {
+            ExistingOutput::Skip => {
+                tracing::debug!(url = %url, "output exists, skipping it");
+                Ok(false)
+            }
+            _ => Err(Error::OutputExists(format!(
+                "{}; pass --overwrite to replace it or --no-clobber to skip it",
+                url
+            ))),
+        }
+    }
+}
+
+/// How `write_output_with` lays out its output
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Columns to partition by; the output URL is then a directory
+    pub partition_by: Vec<String>,
+    /// Maximum number of partition files written concurrently
+    pub max_open_partitions: usize,
+    /// Receives the rows written
+    pub progress: Option<ProgressReporter>,
+    /// What to do with outputs that exist; checked per file when partitioned
+    pub existing_output: ExistingOutput,
+}
+
+impl WriteOptions {
+    pub fn new(existing_output: ExistingOutput) -> Self {
+        Self {
+            existing_output,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_partition_by(mut self, partition_by: Vec<String>) -> Self {
+        self.partition_by = partition_by;
+        self
+    }
+
+    pub fn with_max_open_partitions(mut self, max_open_partitions: usize) -> Self {
+        self.max_open_partitions = max_open_partitions;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: Option<ProgressReporter>) -> Self {
+        self.progress = progress;
+        self
+    }
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            partition_by: Vec::new(),
+            max_open_partitions: 16,
+            progress: None,
+            existing_output: ExistingOutput::default(),
+        }
+    }
+}
+
+/// How `read_batches` handles its inputs
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    /// What to do with zero-byte inputs
+    pub empty_input: EmptyInputPolicy,
+    /// Require every file to match the first one's nullability and metadata too
+    pub strict_schema: bool,
+    /// Partition columns to add to each batch from its file's path
+    pub hive_partitioning: Option<Arc<HivePartitioning>>,
+    /// Receives the bytes and rows read and the file being read
+    pub progress: Option<ProgressReporter>,
+    /// Columns to keep from every batch
+    pub columns: Option<ColumnSelection>,
+}
+
+impl ReadOptions {
+    pub fn new(empty_input: EmptyInputPolicy) -> Self {
+        Self {
+            empty_input,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_strict_schema(mut self, strict_schema: bool) -> Self {
+        self.strict_schema = strict_schema;
+        self
+    }
+
+    pub fn with_hive_partitioning(mut self, hive_partitioning: HivePartitioning) -> Self {
+        self.hive_partitioning = Some(Arc::new(hive_partitioning));
+        self
+    }
+
+    pub fn with_progress(mut self, progress: ProgressReporter) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn with_columns(mut self, columns: ColumnSelection) -> Self {
+        self.columns = Some(columns);
+        self
+    }
+}
+
+/// Columns to keep from the input, by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnSelection {
+    /// Keep only these columns, in this order
+    Select(Vec<String>),
+    /// Keep every column except these
+    Drop(Vec<String>),
+}
+
+impl ColumnSelection {
+    /// Indices of the columns of `schema` to keep; naming a column the schema
+    /// doesn't have is an error
+    pub fn projection(&self, schema: &Schema) -> crate::error::Result<Vec<usize>> {
+        let names = match self {
+            Self::Select(names) | Self::Drop(names) => names,
+        };
+        let indices = names
+            .iter()
+            .map(|name| {
+                schema.index_of(name).map_err(|_| {
+                    let columns: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+                    Error::InvalidInput(format!(
+                        "Column '{}' is not in the input schema; the columns are {}",
+                        name,
+                        columns.join(", ")
+                    ))
+                })
+            })
+            .collect::<crate::error::Result<Vec<_>>>()?;
+        Ok(match self {
+            Self::Select(_) => indices,
+            Self::Drop(_) => (0..schema.fields().len()).filter(|i| !indices.contains(i)).collect(),
+        })
+    }
+
+    /// Schema of the kept columns
+    pub fn project_schema(&self, schema: &Schema) -> crate::error::Result<SchemaRef> {
+        Ok(Arc::new(schema.project(&self.projection(schema)?)?))
+    }
+
+    /// Keep only the selected columns of `batch`
+    pub fn project_batch(&self, batch: RecordBatch) -> crate::error::Result<RecordBatch> {
+        Ok(batch.project(&self.projection(&batch.schema())?)?)
+    }
+}
+
+/// Stream the batches of every input URL in order.
+///
+/// Files are opened one at a time and every file must share the field names
+/// and types of the first non-empty one; with `strict_schema` nullability and
+/// metadata must match too. See [`schema::schema_diff`]. A column selection
+/// is applied after that check, so it doesn't hide mismatches.
+pub fn read_batches(
+    storage: Arc<dyn Storage>,
+    urls: Vec<Url>,
+    format: Arc<dyn DataFormat>,
+    options: ReadOptions,
+) -> BatchStream {
+    let ReadOptions {
+        empty_input,
+        strict_schema,
+        hive_partitioning,
+        progress,
+        columns,
+    } = options;
+    let storage = match &progress {
+        Some(progress) => progress.track_reads(storage),
+        None => storage,
+    };
+    let file_progress = progress.clone();
+    let files = futures::stream::iter(urls).then(move |url| {
+        let storage = storage.clone();
+        let format = format.clone();
+        let hive_partitioning = hive_partitioning.clone();
+        let progress = file_progress.clone();
+        async move {
+            if let Some(progress) = &progress {
+                progress.set_file(&url);
+            }
+            let size = storage.size(&url).await?;
+            tracing::debug!(%url, size, "reading file");
+            let batches = if size == 0 {
+                formats::empty_input(empty_input, url.as_str())?
+            } else {
+                format.read_batches_from_storage(storage, &url, size).await?
+            };
+            // Counted once the file has been read to the end without errors
+            let batches = batches.chain(futures::stream::poll_fn(|_| {
+                metrics::file_processed();
+                std::task::Poll::Ready(None)
+            }));
+            Ok::<_, Error>(batches.and_then(move |batch| {
+                let batch = match &hive_partitioning {
+                    Some(partitioning) => partitioning.append_columns(&url, batch),
+                    None => Ok(batch),
+                };
+                futures::future::ready(batch.map(|batch| (url.clone(), batch)))
+            }))
+        }
+    });
+
+    let mut expected: Option<SchemaRef> = None;
+    files
+        .try_flatten()
+        .and_then(move |(url, batch)| {
+            let result = match &expected {
+                Some(first) => schema::conform_batch(batch, first, strict_schema).map_err(|e| {
+                    Error::schema_mismatch(format!("Schema of {} does not match the first input", url), e)
+                }),
+                None => {
+                    expected = Some(batch.schema());
+                    Ok(batch)
+                }
+            };
+            let result = match &columns {
+                Some(columns) => result.and_then(|batch| columns.project_batch(batch)),
+                None => result,
+            };
+            if let (Ok(batch), Some(progress)) = (&result, &progress) {
+                progress.add_rows_in(batch.num_rows());
+            }
+            futures::future::ready(result)
+        })
+        .boxed()
+}
+
+/// Decode `batches` ahead of the consumer on a separate task, holding at most
+/// `max_in_flight` batches that have been read but not yet written
+pub fn bounded(mut batches: BatchStream, max_in_flight: usize) -> BatchStream {
+    let (sender, receiver) = tokio::sync::mpsc::channel(max_in_flight.max(1));
+    tokio::spawn(async move {
+        while let Some(batch) = batches.next().await {
+            let failed = batch.is_err();
+            if sender.send(batch).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|batch| (batch, receiver))
+    })
+    .boxed()
+}
+
+/// Decode `batches` ahead of the consumer on a separate task without a limit
+/// on how far the reader may get ahead
+pub fn unbounded(mut batches: BatchStream) -> BatchStream {
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(batch) = batches.next().await {
+            let failed = batch.is_err();
+            if sender.send(batch).is_err() || failed {
+                break;
+            }
+        }
+    });
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|batch| (batch, receiver))
+    })
+    .boxed()
+}
+
+/// Read `batches` ahead of the writer as configured: bounded by
+/// `max_in_flight_batches` when backpressure is enabled, unbounded otherwise
+pub fn read_ahead(batches: BatchStream, streaming: &StreamingConfig) -> BatchStream {
+    if streaming.enable_backpressure {
+        bounded(batches, streaming.max_in_flight_batches)
+    } else {
+        unbounded(batches)
+    }
+}
+
+/// Stop pulling from `batches` as soon as `token` is cancelled.
+///
+/// A cancelled stream yields a single `Error::Cancelled` and ends, so the
+/// writer consuming it fails and aborts its partial output.
+pub fn with_cancellation(batches: BatchStream, token: CancellationToken) -> BatchStream {
+    futures::stream::unfold(Some((batches, token)), |state| async move {
+        let (mut batches, token) = state?;
+        let next = tokio::select! {
+            biased;
+            _ = token.cancelled() => None,
+            batch = batches.next() => Some(batch),
+        };
+        match next {
+            Some(Some(batch)) => Some((batch, Some((batches, token)))),
+            Some(None) => None,
+            None => Some((Err(Error::Cancelled), None)),
+        }
+    })
+    .boxed()
+}
+
+/// Pull the first batch to learn the schema of `batches`.
+///
+/// The returned stream still yields every batch; an empty stream has an
+/// empty schema.
+pub async fn peek_schema(mut batches: BatchStream) -> Result<(SchemaRef, BatchStream)> {
+    match batches.try_next().await? {
+        Some(first) => {
+            let schema = first.schema();
+            let batches = futures::stream::once(futures::future::ready(Ok(first))).chain(batches);
+            Ok((schema, batches.boxed()))
+        }
+        None => Ok((Arc::new(Schema::empty()), futures::stream::empty().boxed())),
+    }
+}
+
+/// The first `rows` rows of `batches`, with the last batch sliced to fit.
+///
+/// The stream is dropped as soon as enough rows arrived, so streaming formats
+/// stop reading there: CSV after the block holding the last row, Parquet
+/// after the first row group that completes the rows.
+pub async fn head(mut batches: BatchStream, rows: usize) -> Result<Vec<RecordBatch>> {
+    let mut head = Vec::new();
+    let mut remaining = rows;
+    while remaining > 0 {
+        let Some(batch) = batches.try_next().await? else { break };
+        let batch = batch.slice(0, remaining.min(batch.num_rows()));
+        remaining -= batch.num_rows();
+        head.push(batch);
+    }
+    Ok(head)
+}
+
+/// Expose `batches` as a DataFrame without collecting them.
+///
+/// The underlying stream can only be consumed once, so the DataFrame must be
+/// executed exactly once.
+pub fn stream_dataframe(schema: SchemaRef, batches: BatchStream) -> Result<DataFrame> {
+    let partition = OneShotPartition {
+        schema: schema.clone(),
+        batches: Mutex::new(Some(batches)),
+    };
+    let table = StreamingTable::try_new(schema, vec![Arc::new(partition)])?;
+    Ok(SessionContext::new().read_table(Arc::new(table))?)
+}
+
+/// Output URL for `input` when converting files one by one: its path below
+/// `input_root`, moved under `output_dir` with the extension replaced
+pub fn per_file_output(input: &Url, input_root: &Url, output_dir: &Url, extension: &str) -> Result<Url> {
+    let relative = input_root
+        .make_relative(input)
+        .filter(|relative| !relative.starts_with("../"))
+        .or_else(|| input.path_segments().and_then(|mut segments| segments.next_back()).map(str::to_string))
+        .ok_or_else(|| anyhow!("Cannot derive an output name for {}", input))?;
+    let stem = match relative.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !stem.ends_with('/') && !extension.contains('/') => stem,
+        _ => relative.as_str(),
+    };
+    Ok(as_directory(output_dir).join(&format!("{}.{}", stem, extension))?)
+}
+
+/// `url` with a trailing slash, so that joining a name puts it below `url`
+fn as_directory(url: &Url) -> Url {
+    let mut url = url.clone();
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    url
+}
+
+/// Settings shared by every file of a per-file conversion
+#[derive(Clone)]
+pub struct FileConversion {
+    pub input_storage: Arc<dyn Storage>,
+    pub input_format: Arc<dyn DataFormat>,
+    pub output_storage: Arc<dyn Storage>,
+    pub output_format: Arc<dyn DataFormat>,
+    pub read_options: ReadOptions,
+    pub filter_sql: Option<String>,
+    /// Prepares the session `filter_sql` runs in, e.g. to register UDFs
+    pub session_setup: Option<SessionSetup>,
+    /// Renames and casts applied after `filter_sql`
+    pub transforms: Vec<schema::ColumnTransform>,
+    /// Put the input's field and schema metadata back on the output, see [`preserve_metadata`]
+    pub preserve_metadata: bool,
+    /// What to do with output files that exist
+    pub existing_output: ExistingOutput,
+    pub streaming: StreamingConfig,
+    pub cancellation: CancellationToken,
+}
+
+impl FileConversion {
+    /// Read, filter and write a single file; an output that is skipped
+    /// because it exists is checked before the input is read
+    async fn convert(&self, input: Url, output: Url) -> Result<Vec<OutputInfo>> {
+        if !self.existing_output.allows(self.output_storage.as_ref(), &output).await? {
+            return Ok(Vec::new());
+        }
+        let batches = read_batches(
+            self.input_storage.clone(),
+            vec![input],
+            self.input_format.clone(),
+            self.read_options.clone(),
+        );
+        let batches = with_cancellation(read_ahead(batches, &self.streaming), self.cancellation.clone());
+        let (schema, batches) = peek_schema(batches).await?;
+        let mut df = stream_dataframe(schema.clone(), batches)?;
+        if let Some(sql) = &self.filter_sql {
+            df = apply_sql_with(df, sql, self.session_setup.as_ref()).await?;
+        }
+        df = transform_columns(df, &self.transforms).await?;
+        if self.preserve_metadata {
+            df = preserve_metadata(df, &schema).await?;
+        }
+        // Already checked above
+        let options = WriteOptions::new(ExistingOutput::Overwrite)
+            .with_max_open_partitions(1)
+            .with_progress(self.read_options.progress.clone());
+        let stats = write_output_with(df, self.output_format.clone(), self.output_storage.clone(), &output, options).await?;
+        Ok(stats.outputs)
+    }
+
+    /// Convert each `(input, output)` pair independently, at most `parallelism` at a time.
+    ///
+    /// Every file is attempted even when others fail; failures are reported
+    /// together once all files are done.
+    pub async fn convert_all(&self, jobs: Vec<(Url, Url)>, parallelism: usize) -> Result<Vec<OutputInfo>> {
+        let total = jobs.len();
+        let results: Vec<(Url, Result<Vec<OutputInfo>>)> = futures::stream::iter(jobs)
+            .map(|(input, output)| {
+                let conversion = self.clone();
+                let task_input = input.clone();
+                let task = tokio::spawn(async move { conversion.convert(task_input, output).await });
+                async move {
+                    let result = match task.await {
+                        Ok(result) => result,
+                        Err(e) => Err(anyhow!("Conversion task failed: {}", e)),
+                    };
+                    (input, result)
+                }
+            })
+            .buffer_unordered(parallelism.max(1))
+            .collect()
+            .await;
+
+        let mut outputs = Vec::new();
+        let mut failures = Vec::new();
+        for (input, result) in results {
+            match result {
+                Ok(written) => outputs.extend(written),
+                Err(e) => failures.push(format!("  {}: {:#}", input, e)),
+            }
+        }
+        if !failures.is_empty() {
+            failures.sort();
+            return Err(anyhow!(
+                "{} of {} files failed to convert:\n{}",
+                failures.len(),
+                total,
+                failures.join("\n")
+            ));
+        }
+        outputs.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(outputs)
+    }
+}
+
+/// Name of the table that filter queries select from
+pub const FILTER_TABLE: &str = "data";
+
+/// Expand a `--filter-sql` argument into a full query over the `data` table.
+///
+/// Accepts a bare predicate (`a > 5`), a `WHERE` clause, a `SELECT` without
+/// a `FROM` (`SELECT a, b WHERE a > 5`) or a complete query. The argument is
+/// parsed, so column names such as `selection` and string literals such as
+/// `'x from y'` are not mistaken for keywords.
+pub fn filter_query(sql: &str) -> String {
+    let sql = sql.trim().trim_end_matches(';').trim();
+    let dialect = GenericDialect {};
+    if let Ok(mut statements) = Parser::parse_sql(&dialect, sql) {
+        if let [Statement::Query(query)] = statements.as_mut_slice() {
+            if let SetExpr::Select(select) = query.body.as_mut() {
+                if select.from.is_empty() {
+                    select.from.push(TableWithJoins {
+                        relation: TableFactor::Table {
+                            name: ObjectName(vec![Ident::new(FILTER_TABLE)]),
+                            alias: None,
+                            args: None,
+                            with_hints: vec![],
+                            version: None,
+                            partitions: vec![],
+                        },
+                        joins: vec![],
+                    });
+                    return query.to_string();
+                }
+            }
+        }
+        return sql.to_string();
+    }
+    match parse_predicate(&dialect, sql) {
+        Some(SqlExpr::Value(Value::Boolean(true))) => format!("SELECT * FROM {}", FILTER_TABLE),
+        Some(predicate) => format!("SELECT * FROM {} WHERE {}", FILTER_TABLE, predicate),
+        // Not valid SQL either way; planning the query reports the syntax error
+        None => format!("SELECT * FROM {} WHERE {}", FILTER_TABLE, sql),
+    }
+}
+
+/// Parse `sql` as a predicate, optionally preceded by `WHERE`
+fn parse_predicate(dialect: &dyn Dialect, sql: &str) -> Option<SqlExpr> {
+    let mut parser = Parser::new(dialect).try_with_sql(sql).ok()?;
+    // `WHERE` is optional, but once given it must be followed by a predicate
+    if parser.parse_keyword(Keyword::WHERE) && parser.peek_token().token == Token::EOF {
+        return None;
+    }
+    let predicate = parser.parse_expr().ok()?;
+    (parser.peek_token().token == Token::EOF).then_some(predicate)
+}
+
+/// Prepares the session a filter query runs in, e.g. by registering the
+/// scalar and aggregate UDFs the query may call.
+///
+/// Every query gets a fresh session, so the setup runs once per query.
+pub type SessionSetup = Arc<dyn Fn(&SessionContext) -> Result<()> + Send + Sync>;
+
+/// Run a filter query over `df`, registered as the `data` table.
+///
+/// The result is lazy, so a DataFrame over a one-shot stream is still only
+/// executed once, when the result is written.
+pub async fn apply_sql(df: DataFrame, sql: &str) -> Result<DataFrame> {
+    apply_sql_with(df, sql, None).await
+}
+
+/// [`apply_sql`] in a session prepared by `setup`, so that the query can call
+/// functions it registers, e.g. `WHERE my_udf(col) > 0`
+pub async fn apply_sql_with(df: DataFrame, sql: &str, setup: Option<&SessionSetup>) -> Result<DataFrame> {
+    let ctx = SessionContext::new();
+    if let Some(setup) = setup {
+        setup(&ctx)?;
+    }
+    ctx.register_table(FILTER_TABLE, df.into_view())?;
+    Ok(ctx.sql(&filter_query(sql)).await?)
+}
+
+/// Output schema of converting inputs of `schema` with `filter_sql`, without reading any data.
+///
+/// The query is planned and type-checked against an empty table, so unknown
+/// columns and type errors surface before a conversion starts. Functions the
+/// query calls must be registered by `setup`, as for [`apply_sql_with`].
+pub async fn plan_output_schema(
+    schema: SchemaRef,
+    filter_sql: Option<&str>,
+    setup: Option<&SessionSetup>,
+) -> Result<SchemaRef> {
+    let mut df = SessionContext::new().read_batch(arrow::record_batch::RecordBatch::new_empty(schema))?;
+    if let Some(sql) = filter_sql {
+        df = apply_sql_with(df, sql, setup).await?;
+    }
+    let plan = df.into_optimized_plan()?;
+    Ok(Arc::new(plan.schema().as_ref().into()))
+}
+
+/// Rename and cast the columns of `df` batch by batch, see [`schema::transform_batch`].
+///
+/// Unknown columns and casts Arrow can't perform fail here, before any data is read.
+pub async fn transform_columns(df: DataFrame, transforms: &[schema::ColumnTransform]) -> Result<DataFrame> {
+    if transforms.is_empty() {
+        return Ok(df);
+    }
+    let input_schema: Schema = df.schema().into();
+    let output_schema = schema::transform_schema(&input_schema, transforms)?;
+    let transforms = transforms.to_vec();
+    let batches = execute_stream(df)
+        .await?
+        .and_then(move |batch| futures::future::ready(schema::transform_batch(batch, &transforms).map_err(Error::from)))
+        .boxed();
+    stream_dataframe(output_schema, batches)
+}
+
+/// Relabel the columns of `df` with the metadata of `source`, see
+/// [`schema::carry_metadata`].
+///
+/// Queries and casts may drop Arrow field metadata and the schema's key-value
+/// metadata. Every batch is relabeled, since writers such as Parquet take
+/// the schema they store from the batches, not from the plan.
+pub async fn preserve_metadata(df: DataFrame, source: &Schema) -> Result<DataFrame> {
+    let output_schema = schema::carry_metadata(&df.schema().into(), source);
+    let relabeled = output_schema.clone();
+    let batches = execute_stream(df)
+        .await?
+        .and_then(move |batch| {
+            futures::future::ready(RecordBatch::try_new(relabeled.clone(), batch.columns().to_vec()).map_err(Error::from))
+        })
+        .boxed();
+    stream_dataframe(output_schema, batches)
+}
+
+/// Suffix appended to an output's name for the sidecar holding its metadata
+pub const METADATA_SIDECAR_SUFFIX: &str = ".meta.json";
+
+/// Write the metadata of `schema` next to the output at `url`, for formats
+/// such as CSV that have no place for it, see [`schema::metadata_json`].
+///
+/// Returns the sidecar's URL, `<url>.meta.json`, or `None` when `schema` has
+/// no metadata and nothing was written.
+pub async fn write_metadata_sidecar(storage: &dyn Storage, url: &Url, schema: &Schema) -> Result<Option<Url>> {
+    let Some(metadata) = schema::metadata_json(schema) else {
+        return Ok(None);
+    };
+    let mut sidecar = url.clone();
+    sidecar.set_path(&format!("{}{}", url.path(), METADATA_SIDECAR_SUFFIX));
+    storage.write(&sidecar, bytes::Bytes::from(serde_json::to_vec_pretty(&metadata)?)).await?;
+    tracing::debug!(sidecar = %sidecar, "wrote metadata sidecar");
+    Ok(Some(sidecar))
+}
+
+/// Suffix appended to an output's name for the sidecar caching its schema fingerprint
+pub const SCHEMA_SIDECAR_SUFFIX: &str = ".schema.json";
+
+fn schema_sidecar_url(url: &Url) -> Url {
+    let mut sidecar = url.clone();
+    sidecar.set_path(&format!("{}{}", url.path().trim_end_matches('/'), SCHEMA_SIDECAR_SUFFIX));
+    sidecar
+}
+
+/// Check `schema` against the fingerprint an earlier run cached next to the
+/// output at `url` with [`write_schema_sidecar`].
+///
+/// Passes when there is no sidecar yet; fails when the fingerprints differ,
+/// listing the cached fields so the change can be spotted.
+pub async fn check_schema_stability(storage: &dyn Storage, url: &Url, schema: &SchemaRef) -> Result<()> {
+    let sidecar = schema_sidecar_url(url);
+    let cached = match storage.read_all(&sidecar).await {
+        Ok(cached) => cached,
+        Err(e) if e.is_not_found() => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let cached: serde_json::Value = serde_json::from_slice(&cached)
+        .map_err(|e| anyhow!("Schema sidecar {} is not valid JSON: {}", sidecar, e))?;
+    let fingerprint = format!("{:016x}", schema::schema_fingerprint(schema));
+    match cached["fingerprint"].as_str() {
+        Some(cached_fingerprint) if cached_fingerprint == fingerprint => Ok(()),
+        Some(cached_fingerprint) => Err(anyhow!(
+            "Output schema of {} changed since it was cached in {} (fingerprint {}, cached {}); cached fields:\n{}",
+            url,
+            sidecar,
+            fingerprint,
+            cached_fingerprint,
+            serde_json::to_string_pretty(&cached["fields"])?
+        )),
+        None => Err(anyhow!("Schema sidecar {} has no fingerprint", sidecar)),
+    }
+}
+
+/// Cache the fingerprint and fields of `schema` next to the output at `url`,
+/// as `<url>.schema.json`, for [`check_schema_stability`] to compare later runs against
+pub async fn write_schema_sidecar(storage: &dyn Storage, url: &Url, schema: &SchemaRef) -> Result<Url> {
+    let sidecar = schema_sidecar_url(url);
+    let fields: serde_json::Value = serde_json::from_str(&schema::format_schema_json(schema)?)?;
+    let cached = serde_json::json!({
+        "fingerprint": format!("{:016x}", schema::schema_fingerprint(schema)),
+        "fields": fields,
+    });
+    storage.write(&sidecar, bytes::Bytes::from(serde_json::to_vec_pretty(&cached)?)).await?;
+    tracing::debug!(sidecar = %sidecar, "wrote schema sidecar");
+    Ok(sidecar)
+}
+
+/// Execute `df` as a stream of batches
+pub async fn execute_stream(df: DataFrame) -> Result<BatchStream> {
+    Ok(df.execute_stream().await?.map_err(Error::from).boxed())
+}
+
+/// A file written by a conversion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputInfo {
+    pub path: Url,
+    pub rows: usize,
+    pub bytes: usize,
+}
+
+/// What a conversion wrote
+#[derive(Debug, Clone)]
+pub struct ConvertStats {
+    /// Schema of the converted data, including any partition columns
+    pub output_schema: SchemaRef,
+    pub outputs: Vec<OutputInfo>,
+    /// Outputs left alone because they exist, see [`ExistingOutput::Skip`]
+    pub skipped: Vec<Url>,
+}
+
+impl ConvertStats {
+    pub fn total_rows(&self) -> usize {
+        self.outputs.iter().map(|output| output.rows).sum()
+    }
+}
+
+/// Mark the job that wrote `outputs` as complete by writing `_manifest.json`
+/// and an empty `_SUCCESS` object into `dir`.
+///
+/// Only call this once every output has been written. The manifest lists each
+/// output's path, rows and bytes and the total row count; it is written first,
+/// so readers that wait for `_SUCCESS` always find it.
+pub async fn write_success_marker(storage: &dyn Storage, dir: &Url, outputs: &[OutputInfo]) -> Result<()> {
+    let dir = as_directory(dir);
+    let files: Vec<serde_json::Value> = outputs
+        .iter()
+        .map(|output| serde_json::json!({ "path": output.path.as_str(), "rows": output.rows, "bytes": output.bytes }))
+        .collect();
+    let manifest = serde_json::json!({
+        "files": files,
+        "total_rows": outputs.iter().map(|output| output.rows).sum::<usize>(),
+    });
+    storage
+        .write(&dir.join(MANIFEST)?, bytes::Bytes::from(serde_json::to_vec_pretty(&manifest)?))
+        .await?;
+    storage.write(&dir.join(SUCCESS_MARKER)?, bytes::Bytes::new()).await?;
+    tracing::debug!(dir = %dir, files = outputs.len(), "wrote success marker");
+    Ok(())
+}
+
+/// Encode `batches` with `format` and upload them to `url` concurrently.
+///
+/// The encoder and the upload are connected by a bounded pipe, so memory use
+/// does not grow with the size of the output.
+#[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+pub async fn write_batches(format: &dyn DataFormat, batches: BatchStream, storage: &dyn Storage, url: &Url) -> Result<OutputInfo> {
+    let rows = Arc::new(AtomicUsize::new(0));
+    let bytes = Arc::new(AtomicUsize::new(0));
+    let (row_count, byte_count) = (rows.clone(), bytes.clone());
+    let batches = batches
+        .inspect_ok(move |batch| {
+            row_count.fetch_add(batch.num_rows(), Ordering::Relaxed);
+        })
+        .boxed();
+
+    let (writer, reader) = tokio::io::duplex(PIPE_CAPACITY);
+    let chunks = ReaderStream::new(reader)
+        .inspect_ok(move |chunk| {
+            byte_count.fetch_add(chunk.len(), Ordering::Relaxed);
+        })
+        .map_err(Error::from)
+        .boxed();
+    let upload = storage.write_stream(url, chunks);
+    let encode = format.write_batches_to(batches, Box::pin(writer));
+    tokio::try_join!(encode, upload)?;
+
+    let output = OutputInfo {
+        path: url.clone(),
+        rows: rows.load(Ordering::Relaxed),
+        bytes: bytes.load(Ordering::Relaxed),
+    };
+    tracing::debug!(rows = output.rows, bytes = output.bytes, "wrote file");
+    metrics::file_written();
+    Ok(output)
+}
+
+/// Execute `df` and write it to `url`, or below it in `column=value`
+/// directories when `partition_by` is not empty, replacing existing files.
+///
+/// Rows handed to the writer are reported to `progress`, which receives a
+/// final snapshot once the output is complete. A result without rows is still
+/// written to `url` with its columns, see [`formats::or_empty_batch`]; a
+/// partitioned one writes no files.
+pub async fn write_output(
+    df: DataFrame,
+    format: Arc<dyn DataFormat>,
+    storage: Arc<dyn Storage>,
+    url: &Url,
+    partition_by: &[String],
+    max_open_partitions: usize,
+    progress: Option<&ProgressReporter>,
+) -> Result<ConvertStats> {
+    let options = WriteOptions::new(ExistingOutput::Overwrite)
+        .with_partition_by(partition_by.to_vec())
+        .with_max_open_partitions(max_open_partitions)
+        .with_progress(progress.cloned());
+    write_output_with(df, format, storage, url, options).await
+}
+
+/// [`write_output`] with the layout and the handling of existing files in `options`.
+///
+/// An existing single-file output is checked before `df` is executed; when it
+/// is skipped, nothing is read and it is reported in `ConvertStats::skipped`.
+pub async fn write_output_with(
+    df: DataFrame,
+    format: Arc<dyn DataFormat>,
+    storage: Arc<dyn Storage>,
+    url: &Url,
+    options: WriteOptions,
+) -> Result<ConvertStats> {
+    let started = std::time::Instant::now();
+    let result = execute_and_write(df, format, storage, url, &options).await;
+    match &result {
+        Ok(_) => metrics::conversion_duration(started.elapsed()),
+        Err(e) => metrics::conversion_error(e),
+    }
+    result
+}
+
+async fn execute_and_write(
+    df: DataFrame,
+    format: Arc<dyn DataFormat>,
+    storage: Arc<dyn Storage>,
+    url: &Url,
+    options: &WriteOptions,
+) -> Result<ConvertStats> {
+    let output_schema: SchemaRef = Arc::new(df.schema().into());
+    if options.partition_by.is_empty() && !options.existing_output.allows(storage.as_ref(), url).await? {
+        return Ok(ConvertStats {
+            output_schema,
+            outputs: Vec::new(),
+            skipped: vec![url.clone()],
+        });
+    }
+    let mut batches = execute_stream(df).await?;
+    if let Some(progress) = options.progress.clone() {
+        batches = batches.inspect_ok(move |batch| progress.add_rows_out(batch.num_rows())).boxed();
+    }
+    let (outputs, skipped) = if options.partition_by.is_empty() {
+        // A query without rows still writes its columns
+        let batches = formats::or_empty_batch(batches, output_schema.clone());
+        (vec![write_batches(format.as_ref(), batches, storage.as_ref(), url).await?], Vec::new())
+    } else {
+        let mut writer = PartitionedWriter::new(storage, format, url.clone(), options.partition_by.clone())
+            .with_max_open_partitions(options.max_open_partitions)
+            .with_existing_output(options.existing_output);
+        let written: Result<()> = async {
+            while let Some(batch) = batches.try_next().await? {
+                writer.write(&batch).await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = written {
+            writer.abort().await;
+            return Err(e);
+        }
+        writer.finish_with_skipped().await?
+    };
+    if let Some(progress) = &options.progress {
+        progress.finish();
+    }
+    Ok(ConvertStats {
+        output_schema,
+        outputs,
+        skipped,
+    })
+}
+
+/// Execute `df` and append its rows to the file at `url`, creating the file
+/// when it doesn't exist.
+///
+/// An existing file must have the same column names in the same order. Formats
+/// that can't be appended to, such as Parquet, fail before anything is written.
+pub async fn append_output(
+    df: DataFrame,
+    format: Arc<dyn DataFormat>,
+    storage: Arc<dyn Storage>,
+    url: &Url,
+) -> Result<ConvertStats> {
+    let output_schema: SchemaRef = Arc::new(df.schema().into());
+    let size = match storage.size(url).await {
+        Ok(size) => size,
+        Err(e) if e.is_not_found() => 0,
+        Err(e) => return Err(e.into()),
+    };
+    if size > 0 {
+        let existing = format.infer_schema(storage.clone(), url, size).await?;
+        check_append_schema(&existing, &output_schema, url)?;
+    }
+
+    let rows = Arc::new(AtomicUsize::new(0));
+    let row_count = rows.clone();
+    let batches = execute_stream(df)
+        .await?
+        .inspect_ok(move |batch| {
+            row_count.fetch_add(batch.num_rows(), Ordering::Relaxed);
+        })
+        .boxed();
+    let encoded = format.write_batches_for_append(batches, size == 0).await?;
+
+    // Keep the new rows off the last line of a file without a trailing newline
+    let mut data = Vec::with_capacity(encoded.len() + 1);
+    if size > 0 && storage.read_range(url, size - 1..size).await?.as_ref() != b"\n" {
+        data.push(b'\n');
+    }
+    data.extend_from_slice(&encoded);
+    let output = OutputInfo {
+        path: url.clone(),
+        rows: rows.load(Ordering::Relaxed),
+        bytes: data.len(),
+    };
+    storage.append(url, data.into()).await?;
+    tracing::debug!(rows = output.rows, bytes = output.bytes, "appended to file");
+    metrics::file_written();
+    Ok(ConvertStats {
+        output_schema,
+        outputs: vec![output],
+        skipped: Vec::new(),
+    })
+}
+
+fn check_append_schema(existing: &Schema, appended: &Schema, url: &Url) -> Result<()> {
+    let names = |schema: &Schema| schema.fields().iter().map(|f| f.name().clone()).collect::<Vec<_>>();
+    let (existing, appended) = (names(existing), names(appended));
+    if existing != appended {
+        return Err(Error::SchemaMismatch(format!(
+            "Can't append columns [{}] to {}, which has columns [{}]",
+            appended.join(", "),
+            url,
+            existing.join(", ")
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// A DataFusion partition that hands out a batch stream exactly once
+struct OneShotPartition {
+    schema: SchemaRef,
+    batches: Mutex<Option<BatchStream>>,
+}
+
+impl PartitionStream for OneShotPartition {
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    fn execute(&self, _ctx: Arc<TaskContext>) -> SendableRecordBatchStream {
+        let stream = match self.batches.lock().take() {
+            Some(batches) => batches.map_err(|e| DataFusionError::External(e.into())).boxed(),
+            None => futures::stream::once(futures::future::ready(Err(DataFusionError::Execution(
+                "Input stream has already been consumed".to_string(),
+            ))))
+            .boxed(),
+        };
+        Box::pin(RecordBatchStreamAdapter::new(self.schema.clone(), stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{CsvFormat, ParquetConfig, ParquetFormat};
+    use crate::storage::local::LocalStorage;
+    use crate::storage::memory::MemoryStorage;
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_stream_large_csv_to_parquet_bounds_batches_in_flight() -> Result<()> {
+        const ROWS: usize = 200_000;
+
+        let temp_dir = TempDir::new()?;
+        let input = temp_dir.path().join("large.csv");
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&input)?);
+        writeln!(file, "id,name")?;
+        for i in 0..ROWS {
+            writeln!(file, "{},name-{}", i, i)?;
+        }
+        file.flush()?;
+        drop(file);
+
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let input_url = Url::from_file_path(&input).unwrap();
+        let output_url = Url::from_file_path(temp_dir.path().join("large.parquet")).unwrap();
+
+        // Batches decoded but not yet handed to the writer, sampled as each one is decoded
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let taken = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let (counter, seen, max) = (decoded.clone(), taken.clone(), max_in_flight.clone());
+        let batches = read_batches(
+            storage.clone(),
+            vec![input_url],
+            Arc::new(CsvFormat::default()),
+            ReadOptions::new(EmptyInputPolicy::Error),
+        )
+        .inspect(move |_| {
+            let decoded = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            max.fetch_max(decoded.saturating_sub(seen.load(Ordering::SeqCst)), Ordering::SeqCst);
+        })
+        .boxed();
+        let (schema, batches) = peek_schema(bounded(batches, 4)).await?;
+        let df = stream_dataframe(schema, batches)?;
+        let output_format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(10_000),
+            ..ParquetConfig::default()
+        });
+        let counter = taken.clone();
+        let batches = execute_stream(df)
+            .await?
+            .inspect(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .boxed();
+        write_batches(&output_format, batches, storage.as_ref(), &output_url).await?;
+
+        // Far more batches than the bound are read
+        assert!(decoded.load(Ordering::SeqCst) > 10);
+        // Four queued, one held by the blocked reader and the ones being planned and written
+        assert!(max_in_flight.load(Ordering::SeqCst) <= 4 + 3, "{}", max_in_flight.load(Ordering::SeqCst));
+
+        let written = storage.read_all(&output_url).await?;
+        let rows: usize = output_format
+            .read_batches_from_stream(futures::stream::once(futures::future::ready(Ok(written))).boxed())?
+            .try_fold(0, |rows, batch| futures::future::ready(Ok(rows + batch.num_rows())))
+            .await?;
+        assert_eq!(rows, ROWS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_head_prints_requested_rows() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut csv = String::from("id,name\n");
+        for i in 0..10 {
+            csv.push_str(&format!("{},name-{}\n", i, i));
+        }
+        std::fs::write(temp_dir.path().join("input.csv"), csv)?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let csv_url = Url::from_file_path(temp_dir.path().join("input.csv")).unwrap();
+        let parquet_url = Url::from_file_path(temp_dir.path().join("input.parquet")).unwrap();
+
+        let read = |url: &Url, format: Arc<dyn DataFormat>| {
+            read_batches(storage.clone(), vec![url.clone()], format, ReadOptions::new(EmptyInputPolicy::Error))
+        };
+        let rows = head(read(&csv_url, Arc::new(CsvFormat::default())), 3).await?;
+        let table = arrow::util::pretty::pretty_format_batches(&rows)?.to_string();
+        // Borders and the header line aside, every line is a row
+        let data_rows: Vec<&str> = table.lines().filter(|line| line.starts_with('|')).skip(1).collect();
+        assert_eq!(data_rows.len(), 3, "{}", table);
+        assert!(data_rows[2].contains("name-2"), "{}", table);
+
+        // Small row groups: the rows come from the first one
+        let parquet = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(4),
+            ..ParquetConfig::default()
+        });
+        write_batches(&parquet, read(&csv_url, Arc::new(CsvFormat::default())), storage.as_ref(), &parquet_url).await?;
+        let rows = head(read(&parquet_url, Arc::new(parquet)), 3).await?;
+        assert_eq!(rows.iter().map(RecordBatch::num_rows).sum::<usize>(), 3);
+
+        let rows = head(read(&csv_url, Arc::new(CsvFormat::default())), 100).await?;
+        assert_eq!(rows.iter().map(RecordBatch::num_rows).sum::<usize>(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_query_forms() {
+        assert_eq!(filter_query("true"), "SELECT * FROM data");
+        assert_eq!(filter_query("a > 5"), "SELECT * FROM data WHERE a > 5");
+        assert_eq!(filter_query("WHERE a > 5"), "SELECT * FROM data WHERE a > 5");
+        assert_eq!(filter_query("SELECT a, b WHERE a > 5;"), "SELECT a, b FROM data WHERE a > 5");
+        assert_eq!(filter_query("SELECT a LIMIT 3"), "SELECT a FROM data LIMIT 3");
+        assert_eq!(filter_query("SELECT a"), "SELECT a FROM data");
+        assert_eq!(filter_query("select a from data"), "select a from data");
+    }
+
+    #[test]
+    fn test_filter_query_columns_named_like_keywords() {
+        assert_eq!(filter_query("selection = 1"), "SELECT * FROM data WHERE selection = 1");
+        assert_eq!(filter_query("where_id > 2"), "SELECT * FROM data WHERE where_id > 2");
+    }
+
+    #[test]
+    fn test_filter_query_where_after_any_whitespace() {
+        assert_eq!(filter_query("SELECT a\nWHERE a > 5"), "SELECT a FROM data WHERE a > 5");
+        assert_eq!(filter_query("SELECT a\tWHERE a > 5"), "SELECT a FROM data WHERE a > 5");
+        assert_eq!(filter_query("WHERE\ta > 5"), "SELECT * FROM data WHERE a > 5");
+    }
+
+    #[test]
+    fn test_filter_query_ignores_keywords_in_strings() {
+        assert_eq!(
+            filter_query("SELECT a WHERE b = 'x from y'"),
+            "SELECT a FROM data WHERE b = 'x from y'"
+        );
+        assert_eq!(filter_query("b = 'x from y'"), "SELECT * FROM data WHERE b = 'x from y'");
+    }
+
+    #[tokio::test]
+    async fn test_filtered_csv_to_parquet() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let mut csv = String::from("a,b,c\n");
+        for a in 0..10 {
+            csv.push_str(&format!("{},b{},c{}\n", a, a, a));
+        }
+        std::fs::write(temp_dir.path().join("input.csv"), csv)?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let input_url = Url::from_file_path(temp_dir.path().join("input.csv")).unwrap();
+        let output_url = Url::from_file_path(temp_dir.path().join("filtered.parquet")).unwrap();
+
+        let batches = read_batches(
+            storage.clone(),
+            vec![input_url],
+            Arc::new(CsvFormat::default()),
+            ReadOptions::new(EmptyInputPolicy::Error),
+        );
+        let (schema, batches) = peek_schema(batches).await?;
+        let df = apply_sql(stream_dataframe(schema, batches)?, "SELECT a, b WHERE a > 5").await?;
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let stats = write_output(df, format.clone(), storage.clone(), &output_url, &[], 16, None).await?;
+
+        // The query result, not the input, determines the written schema
+        let names: Vec<&str> = stats.output_schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(stats.total_rows(), 4);
+
+        let written = storage.read_all(&output_url).await?;
+        let mut values = Vec::new();
+        for batch in format.read(&written)?.collect().await? {
+            assert_eq!(batch.num_columns(), 2);
+            let column = batch.column(0).as_any().downcast_ref::<arrow::array::Int64Array>().unwrap();
+            values.extend(column.values().iter().copied());
+        }
+        values.sort();
+        assert_eq!(values, vec![6, 7, 8, 9]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_filter_sql_calls_registered_udf() -> Result<()> {
+        use arrow::array::{Array, ArrayRef, Int64Array};
+        use arrow::datatypes::DataType;
+        use datafusion::logical_expr::{create_udf, ColumnarValue, Volatility};
+
+        let double = create_udf(
+            "double",
+            vec![DataType::Int64],
+            Arc::new(DataType::Int64),
+            Volatility::Immutable,
+            Arc::new(|args: &[ColumnarValue]| -> datafusion::error::Result<ColumnarValue> {
+                let values = match &args[0] {
+                    ColumnarValue::Array(values) => values.clone(),
+                    ColumnarValue::Scalar(value) => value.to_array(),
+                };
+                let values = values.as_any().downcast_ref::<Int64Array>().unwrap();
+                let doubled: Int64Array = values.iter().map(|value| value.map(|value| value * 2)).collect();
+                Ok(ColumnarValue::Array(Arc::new(doubled) as ArrayRef))
+            }),
+        );
+        let setup: SessionSetup = Arc::new(move |ctx: &SessionContext| {
+            ctx.register_udf(double.clone());
+            Ok(())
+        });
+
+        let batch = RecordBatch::try_from_iter(vec![("id", Arc::new(Int64Array::from(vec![1, 2, 3])) as ArrayRef)])?;
+        let df = || SessionContext::new().read_batch(batch.clone());
+        assert!(apply_sql(df()?, "double(id) > 2").await.is_err());
+        let schema = plan_output_schema(batch.schema(), Some("double(id) > 2"), Some(&setup)).await?;
+        assert_eq!(schema.field(0).name(), "id");
+
+        let result = apply_sql_with(df()?, "SELECT id WHERE double(id) > 2", Some(&setup)).await?.collect().await?;
+        let ids: Vec<i64> = result
+            .iter()
+            .flat_map(|batch| batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap().values().to_vec())
+            .collect();
+        assert_eq!(ids, vec![2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_select_columns_into_parquet() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(temp_dir.path().join("input.csv"), "a,b,c,d\n1,x,2.5,true\n2,y,3.5,false\n")?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let input_url = Url::from_file_path(temp_dir.path().join("input.csv")).unwrap();
+        let output_url = Url::from_file_path(temp_dir.path().join("selected.parquet")).unwrap();
+
+        let columns = ColumnSelection::Select(vec!["c".to_string(), "a".to_string()]);
+        let options = ReadOptions::new(EmptyInputPolicy::Error).with_columns(columns);
+        let batches = read_batches(storage.clone(), vec![input_url.clone()], Arc::new(CsvFormat::default()), options);
+        let (schema, batches) = peek_schema(batches).await?;
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let df = stream_dataframe(schema, batches)?;
+        write_output(df, format.clone(), storage.clone(), &output_url, &[], 16, None).await?;
+
+        let written = storage.read_all(&output_url).await?;
+        for batch in format.read(&written)?.collect().await? {
+            let names: Vec<String> = batch.schema().fields().iter().map(|f| f.name().clone()).collect();
+            assert_eq!(names, vec!["c", "a"]);
+        }
+
+        // Unknown columns fail on the first batch
+        let columns = ColumnSelection::Drop(vec!["missing".to_string()]);
+        let options = ReadOptions::new(EmptyInputPolicy::Error).with_columns(columns);
+        let batches = read_batches(storage, vec![input_url], Arc::new(CsvFormat::default()), options);
+        assert!(peek_schema(batches).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append_rows_to_existing_csv() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(temp_dir.path().join("existing.csv"), "a,b\n1,x\n2,y\n")?;
+        std::fs::write(temp_dir.path().join("new.csv"), "a,b\n3,z\n4,w\n5,v\n")?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let existing_url = Url::from_file_path(temp_dir.path().join("existing.csv")).unwrap();
+        let new_url = Url::from_file_path(temp_dir.path().join("new.csv")).unwrap();
+
+        let format: Arc<dyn DataFormat> = Arc::new(CsvFormat::default());
+        let batches = read_batches(
+            storage.clone(),
+            vec![new_url],
+            format.clone(),
+            ReadOptions::new(EmptyInputPolicy::Error),
+        );
+        let (schema, batches) = peek_schema(batches).await?;
+        let df = stream_dataframe(schema, batches)?;
+        let stats = append_output(df, format.clone(), storage.clone(), &existing_url).await?;
+        assert_eq!(stats.total_rows(), 3);
+
+        let written = storage.read_all(&existing_url).await?;
+        let text = std::str::from_utf8(&written)?;
+        assert_eq!(text.lines().filter(|line| *line == "a,b").count(), 1);
+        let rows: usize = format.read(&written)?.collect().await?.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(rows, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_per_file_output_keeps_relative_path() -> Result<()> {
+        let root = Url::parse("s3://bucket/input/")?;
+        let output = Url::parse("s3://bucket/output")?;
+        let name = |input: &str| -> Result<String> {
+            Ok(per_file_output(&Url::parse(input)?, &root, &output, "parquet")?.to_string())
+        };
+        assert_eq!(name("s3://bucket/input/a.csv")?, "s3://bucket/output/a.parquet");
+        assert_eq!(name("s3://bucket/input/day=1/b.tar.csv")?, "s3://bucket/output/day=1/b.tar.parquet");
+        assert_eq!(name("s3://bucket/elsewhere/c.csv")?, "s3://bucket/output/c.parquet");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_convert_files_in_parallel() -> Result<()> {
+        const FILES: usize = 6;
+
+        let temp_dir = TempDir::new()?;
+        let input_dir = temp_dir.path().join("in");
+        std::fs::create_dir(&input_dir)?;
+        for i in 0..FILES {
+            let rows: String = (0..=i).map(|row| format!("{},{}\n", i, row)).collect();
+            std::fs::write(input_dir.join(format!("file{}.csv", i)), format!("file,row\n{}", rows))?;
+        }
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let root = Url::from_directory_path(&input_dir).unwrap();
+        let output_dir = Url::from_directory_path(temp_dir.path().join("out")).unwrap();
+        let conversion = FileConversion {
+            input_storage: storage.clone(),
+            input_format: Arc::new(CsvFormat::default()),
+            output_storage: storage.clone(),
+            output_format: Arc::new(ParquetFormat::default()),
+            read_options: ReadOptions::new(EmptyInputPolicy::Error),
+            filter_sql: None,
+            session_setup: None,
+            transforms: Vec::new(),
+            preserve_metadata: false,
+            existing_output: ExistingOutput::Error,
+            streaming: crate::config::Config::default().streaming,
+            cancellation: CancellationToken::new(),
+        };
+
+        let jobs = |names: &[String]| -> Result<Vec<(Url, Url)>> {
+            names
+                .iter()
+                .map(|name| {
+                    let input = root.join(name)?;
+                    let output = per_file_output(&input, &root, &output_dir, "parquet")?;
+                    Ok((input, output))
+                })
+                .collect()
+        };
+        let names: Vec<String> = (0..FILES).map(|i| format!("file{}.csv", i)).collect();
+        let outputs = conversion.convert_all(jobs(&names)?, 3).await?;
+
+        assert_eq!(outputs.len(), FILES);
+        for (i, output) in outputs.iter().enumerate() {
+            assert!(output.path.path().ends_with(&format!("/out/file{}.parquet", i)));
+            assert_eq!(output.rows, i + 1);
+        }
+
+        // A failing file is reported without stopping the others
+        std::fs::write(input_dir.join("empty.csv"), "")?;
+        std::fs::remove_dir_all(temp_dir.path().join("out"))?;
+        let mut with_failure = names.clone();
+        with_failure.insert(2, "empty.csv".to_string());
+        let err = conversion.convert_all(jobs(&with_failure)?, 3).await.unwrap_err();
+        assert!(err.to_string().starts_with("1 of 7 files failed"), "{}", err);
+        assert!(err.to_string().contains("empty.csv"));
+        for i in 0..FILES {
+            assert!(temp_dir.path().join(format!("out/file{}.parquet", i)).exists());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partitioned_output_reports_each_file() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(
+            temp_dir.path().join("input.csv"),
+            "region,id\neu,1\nus,2\neu,3\napac,4\neu,5\nus,6\n",
+        )?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let input_url = Url::from_file_path(temp_dir.path().join("input.csv")).unwrap();
+        let output_url = Url::from_directory_path(temp_dir.path().join("out")).unwrap();
+
+        let batches = read_batches(
+            storage.clone(),
+            vec![input_url],
+            Arc::new(CsvFormat::default()),
+            ReadOptions::new(EmptyInputPolicy::Error),
+        );
+        let (schema, batches) = peek_schema(batches).await?;
+        let df = stream_dataframe(schema.clone(), batches)?;
+        let stats = write_output(
+            df,
+            Arc::new(ParquetFormat::default()),
+            storage.clone(),
+            &output_url,
+            &["region".to_string()],
+            16,
+            None,
+        )
+        .await?;
+
+        assert_eq!(stats.output_schema.fields(), schema.fields());
+        let counts: Vec<(String, usize)> = stats
+            .outputs
+            .iter()
+            .map(|output| (output.path.path().split('/').rev().nth(1).unwrap().to_string(), output.rows))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("region=apac".to_string(), 1), ("region=eu".to_string(), 3), ("region=us".to_string(), 2)]
+        );
+        for output in &stats.outputs {
+            assert_eq!(output.bytes, storage.read_all(&output.path).await?.len());
+        }
+        assert_eq!(stats.total_rows(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_existing_outputs_fail_or_are_skipped() -> Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let input = Url::parse("memory:///input.csv")?;
+        storage.write(&input, bytes::Bytes::from("region,id\neu,1\nus,2\neu,3\n")).await?;
+        let output = Url::parse("memory:///out.csv")?;
+        storage.write(&output, bytes::Bytes::from("keep me\n")).await?;
+        let df = || async {
+            let batches = read_batches(
+                storage.clone(),
+                vec![input.clone()],
+                Arc::new(CsvFormat::default()),
+                ReadOptions::new(EmptyInputPolicy::Error),
+            );
+            let (schema, batches) = peek_schema(batches).await?;
+            stream_dataframe(schema, batches)
+        };
+        let format: Arc<dyn DataFormat> = Arc::new(CsvFormat::default());
+
+        let err = write_output_with(df().await?, format.clone(), storage.clone(), &output, WriteOptions::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::OutputExists(_))), "{}", err);
+
+        let stats = write_output_with(df().await?, format.clone(), storage.clone(), &output, WriteOptions::new(ExistingOutput::Skip))
+            .await?;
+        assert!(stats.outputs.is_empty());
+        assert_eq!(stats.skipped, vec![output.clone()]);
+        assert_eq!(storage.read_all(&output).await?, bytes::Bytes::from("keep me\n"));
+
+        let overwrite = WriteOptions::new(ExistingOutput::Overwrite);
+        let stats = write_output_with(df().await?, format.clone(), storage.clone(), &output, overwrite).await?;
+        assert_eq!(stats.total_rows(), 3);
+
+        // Partitioned outputs are checked file by file
+        let dir = Url::parse("memory:///partitioned/")?;
+        let existing = dir.join("region=eu/part-00000.parquet")?;
+        storage.write(&existing, bytes::Bytes::from("keep me")).await?;
+        let partitioned = |existing_output| WriteOptions::new(existing_output).with_partition_by(vec!["region".to_string()]);
+        let parquet: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let err = write_output_with(df().await?, parquet.clone(), storage.clone(), &dir, partitioned(ExistingOutput::Error))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("region=eu/part-00000.parquet"), "{}", err);
+        storage.delete(&dir.join("region=us/part-00000.parquet")?).await?;
+
+        let stats = write_output_with(df().await?, parquet, storage.clone(), &dir, partitioned(ExistingOutput::Skip)).await?;
+        assert_eq!(stats.skipped, vec![existing.clone()]);
+        assert_eq!(stats.outputs.len(), 1);
+        assert!(stats.outputs[0].path.path().ends_with("region=us/part-00000.parquet"));
+        assert_eq!(storage.read_all(&existing).await?, bytes::Bytes::from("keep me"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_success_marker_lists_outputs() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(temp_dir.path().join("input.csv"), "region,id\neu,1\nus,2\neu,3\n")?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let input_url = Url::from_file_path(temp_dir.path().join("input.csv")).unwrap();
+        let output_url = Url::from_directory_path(temp_dir.path().join("out")).unwrap();
+
+        let batches = read_batches(
+            storage.clone(),
+            vec![input_url],
+            Arc::new(CsvFormat::default()),
+            ReadOptions::new(EmptyInputPolicy::Error),
+        );
+        let (schema, batches) = peek_schema(batches).await?;
+        let df = stream_dataframe(schema, batches)?;
+        let partition_by = ["region".to_string()];
+        let stats = write_output(df, Arc::new(ParquetFormat::default()), storage.clone(), &output_url, &partition_by, 16, None)
+            .await?;
+        assert_eq!(stats.outputs.len(), 2);
+        write_success_marker(storage.as_ref(), &output_url, &stats.outputs).await?;
+
+        let marker = temp_dir.path().join("out").join(SUCCESS_MARKER);
+        assert_eq!(std::fs::read(marker)?.len(), 0);
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(temp_dir.path().join("out").join(MANIFEST))?)?;
+        assert_eq!(manifest["total_rows"], 3);
+        let files = manifest["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        for (file, output) in files.iter().zip(&stats.outputs) {
+            assert_eq!(file["path"], output.path.as_str());
+            assert_eq!(file["rows"], output.rows);
+            assert_eq!(file["bytes"], std::fs::metadata(output.path.to_file_path().unwrap())?.len());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bounded_limits_batches_in_flight() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let produced = Arc::new(AtomicUsize::new(0));
+        let counter = produced.clone();
+        let batches = futures::stream::iter(0..100)
+            .map(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(arrow::record_batch::RecordBatch::new_empty(Arc::new(Schema::empty())))
+            })
+            .boxed();
+
+        let mut stream = bounded(batches, 4);
+        stream.try_next().await?;
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        // One batch consumed, four queued and at most one held by the blocked sender
+        assert!(produced.load(Ordering::SeqCst) <= 6);
+        assert_eq!(stream.try_fold(1, |n, _| futures::future::ready(Ok(n + 1))).await?, 100);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_with_slow_consumer() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        const BATCHES: usize = 40;
+
+        let run = |enable_backpressure: bool| async move {
+            let produced = Arc::new(AtomicUsize::new(0));
+            let counter = produced.clone();
+            let batches = futures::stream::iter(0..BATCHES)
+                .map(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(arrow::record_batch::RecordBatch::new_empty(Arc::new(Schema::empty())))
+                })
+                .boxed();
+            let streaming = StreamingConfig {
+                enable_backpressure,
+                max_in_flight_batches: 3,
+                ..crate::config::Config::default().streaming
+            };
+
+            let mut stream = read_ahead(batches, &streaming);
+            let (mut consumed, mut max_buffered) = (0, 0);
+            while stream.try_next().await?.is_some() {
+                consumed += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                max_buffered = max_buffered.max(produced.load(Ordering::SeqCst) - consumed);
+            }
+            assert_eq!(consumed, BATCHES);
+            Ok::<_, anyhow::Error>(max_buffered)
+        };
+
+        // Queued batches plus the one held by the blocked reader
+        assert!(run(true).await? <= 4);
+        // Without backpressure the reader finishes while the consumer is still on its first batch
+        assert!(run(false).await? > 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_batches_rejects_mismatched_schemas() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(temp_dir.path().join("a.csv"), "id,name\n1,a\n")?;
+        std::fs::write(temp_dir.path().join("b.csv"), "id\n2\n")?;
+        let urls = ["a.csv", "b.csv"]
+            .iter()
+            .map(|name| Url::from_file_path(temp_dir.path().join(name)).unwrap())
+            .collect();
+
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let options = ReadOptions::new(EmptyInputPolicy::Error);
+        let result: crate::error::Result<Vec<_>> =
+            read_batches(storage, urls, Arc::new(CsvFormat::default()), options).try_collect().await;
+        let err = result.unwrap_err();
+        assert!(matches!(err, Error::SchemaDiff { .. }));
+        assert!(err.to_string().contains("does not match the first input"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancel_mid_stream() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let url = Url::from_file_path(temp_dir.path().join("out.parquet")).unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+
+        // One batch, then an input that never produces another
+        let batch = arrow::record_batch::RecordBatch::try_from_iter([(
+            "id",
+            Arc::new(arrow::array::Int64Array::from(vec![1, 2, 3])) as arrow::array::ArrayRef,
+        )])?;
+        let batches = futures::stream::iter([Ok(batch)]).chain(futures::stream::pending()).boxed();
+        let token = CancellationToken::new();
+        let (schema, batches) = peek_schema(with_cancellation(batches, token.clone())).await?;
+        let df = stream_dataframe(schema, batches)?;
+
+        let cancel = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        let write = write_output(df, Arc::new(ParquetFormat::default()), storage, &url, &[], 1, None);
+        let err = tokio::time::timeout(std::time::Duration::from_secs(5), write)
+            .await
+            .expect("cancelled conversion returns promptly")
+            .unwrap_err();
+        assert!(matches!(Error::from(err), Error::Cancelled));
+        assert!(!temp_dir.path().join("out.parquet").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plan_output_schema_checks_columns() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            arrow::datatypes::Field::new("id", arrow::datatypes::DataType::Int64, true),
+            arrow::datatypes::Field::new("name", arrow::datatypes::DataType::Utf8, true),
+        ]));
+
+        let output = plan_output_schema(schema.clone(), Some("SELECT name WHERE id > 1"), None).await?;
+        assert_eq!(output.fields().len(), 1);
+        assert_eq!(output.field(0).name(), "name");
+        assert_eq!(plan_output_schema(schema.clone(), None, None).await?, schema);
+
+        let err = plan_output_schema(schema, Some("nmae = 'a'"), None).await.unwrap_err();
+        assert!(err.to_string().contains("nmae"), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_parquet_metadata_survives_conversion() -> Result<()> {
+        use arrow::array::{Float64Array, Int64Array};
+        use arrow::datatypes::{DataType, Field};
+        use std::collections::HashMap;
+
+        let unit = HashMap::from([("unit".to_string(), "m".to_string())]);
+        let source = HashMap::from([("source".to_string(), "survey".to_string())]);
+        let schema = Arc::new(Schema::new_with_metadata(
+            vec![
+                Field::new("id", DataType::Int64, false),
+                Field::new("depth", DataType::Float64, false).with_metadata(unit.clone()),
+            ],
+            source.clone(),
+        ));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![1, 2, 3])), Arc::new(Float64Array::from(vec![0.5, 1.5, 2.5]))],
+        )?;
+        let temp_dir = TempDir::new()?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let input = Url::from_file_path(temp_dir.path().join("input.parquet")).unwrap();
+        storage.write(&input, format.write_batch(&batch)?).await?;
+
+        let batches = read_batches(storage.clone(), vec![input], format.clone(), ReadOptions::new(EmptyInputPolicy::Error));
+        let (input_schema, batches) = peek_schema(batches).await?;
+        assert_eq!(input_schema.field(1).metadata(), &unit);
+        let df = apply_sql(stream_dataframe(input_schema.clone(), batches)?, "depth > 1").await?;
+        let df = preserve_metadata(df, &input_schema).await?;
+        let output = Url::from_file_path(temp_dir.path().join("output.parquet")).unwrap();
+        let stats = write_output(df, format.clone(), storage.clone(), &output, &[], 1, None).await?;
+        assert_eq!(stats.total_rows(), 2);
+
+        let size = storage.size(&output).await?;
+        let written = format.infer_schema(storage.clone(), &output, size).await?;
+        assert_eq!(written.field_with_name("depth")?.metadata(), &unit);
+        assert_eq!(written.metadata(), &source);
+
+        // Formats without a metadata slot get it in a sidecar
+        let csv = Url::from_file_path(temp_dir.path().join("output.csv")).unwrap();
+        let sidecar = write_metadata_sidecar(storage.as_ref(), &csv, &stats.output_schema).await?.unwrap();
+        assert!(sidecar.path().ends_with("output.csv.meta.json"));
+        let json: serde_json::Value = serde_json::from_slice(&storage.read_all(&sidecar).await?)?;
+        assert_eq!(json["fields"]["depth"]["unit"], "m");
+        assert_eq!(json["metadata"]["source"], "survey");
+        assert!(json["fields"].get("id").is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_schema_stability_against_cached_fingerprint() -> Result<()> {
+        use crate::storage::memory::MemoryStorage;
+        use arrow::datatypes::{DataType, Field};
+
+        let storage = MemoryStorage::new();
+        let output = Url::parse("memory:///out/data.csv")?;
+        let schema = |id_type: DataType| -> SchemaRef {
+            Arc::new(Schema::new(vec![
+                Field::new("id", id_type, false),
+                Field::new("name", DataType::Utf8, true),
+            ]))
+        };
+
+        // The first run has nothing to compare against
+        check_schema_stability(&storage, &output, &schema(DataType::Int64)).await?;
+        let sidecar = write_schema_sidecar(&storage, &output, &schema(DataType::Int64)).await?;
+        assert!(sidecar.path().ends_with("data.csv.schema.json"));
+
+        check_schema_stability(&storage, &output, &schema(DataType::Int64)).await?;
+        let err = check_schema_stability(&storage, &output, &schema(DataType::Utf8)).await.unwrap_err();
+        assert!(err.to_string().contains("changed since it was cached"), "{}", err);
+        assert!(err.to_string().contains("\"Int64\""), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nested_parquet_columns_round_trip() -> Result<()> {
+        use arrow::array::{Array, ArrayRef, Float64Array, Int64Array, ListBuilder, StringBuilder, StructArray};
+        use arrow::datatypes::{DataType, Field, Fields};
+
+        let point_fields = Fields::from(vec![
+            Field::new("x", DataType::Float64, false),
+            Field::new("y", DataType::Float64, false),
+        ]);
+        let point = StructArray::new(
+            point_fields.clone(),
+            vec![
+                Arc::new(Float64Array::from(vec![1.0, 2.0, 3.0])) as ArrayRef,
+                Arc::new(Float64Array::from(vec![-1.0, -2.0, -3.0])) as ArrayRef,
+            ],
+            None,
+        );
+        let mut tags = ListBuilder::new(StringBuilder::new());
+        tags.values().append_value("a");
+        tags.values().append_value("b");
+        tags.append(true);
+        tags.append(false);
+        tags.values().append_value("c");
+        tags.append(true);
+        let tags = tags.finish();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("point", DataType::Struct(point_fields), false),
+            Field::new("tags", tags.data_type().clone(), true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![1, 2, 3])), Arc::new(point), Arc::new(tags)],
+        )?;
+
+        let temp_dir = TempDir::new()?;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let input = Url::from_file_path(temp_dir.path().join("input.parquet")).unwrap();
+        storage.write(&input, format.write_batch(&batch)?).await?;
+
+        let batches = read_batches(storage.clone(), vec![input.clone()], format.clone(), ReadOptions::new(EmptyInputPolicy::Error));
+        let (input_schema, batches) = peek_schema(batches).await?;
+        assert!(crate::schema::schema_diff(&schema, &input_schema, true).is_none());
+        let df = stream_dataframe(input_schema, batches)?;
+        let output = Url::from_file_path(temp_dir.path().join("output.parquet")).unwrap();
+        let stats = write_output(df, format.clone(), storage.clone(), &output, &[], 1, None).await?;
+        assert_eq!(stats.total_rows(), 3);
+
+        let size = storage.size(&output).await?;
+        let written: Vec<RecordBatch> = format.read_batches_from_storage(storage.clone(), &output, size).await?.try_collect().await?;
+        assert_eq!(arrow::compute::concat_batches(&written[0].schema(), &written)?, batch);
+
+        // Projection picks whole top-level columns, children included
+        let size = storage.size(&input).await?;
+        let projected: Vec<RecordBatch> = format
+            .read_row_groups_from_storage(storage.clone(), &input, size, vec![0], Some(vec![1]))
+            .await?
+            .try_collect()
+            .await?;
+        assert_eq!(projected[0].num_columns(), 1);
+        assert_eq!(projected[0].column(0).as_ref(), batch.column(1).as_ref());
+
+        // CSV can't hold them, and says so up front
+        let err = CsvFormat::default().write_batch(&batch).unwrap_err();
+        assert!(err.to_string().contains("nested column 'point'"), "{}", err);
+        Ok(())
+    }
+}
@@ -0,0 +1,732 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use bytes::Bytes;
+use datafusion::dataframe::DataFrame;
+use datafusion::execution::context::SessionContext;
+use datafusion::physical_plan::Statistics;
+use libloading::Library;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use url::Url;
+
+use crate::config::{PluginConfig, VersionCompatibility};
+use crate::error;
+use crate::formats::{
+    self, BatchStream, BatchWriter, DataFormat, DataSink, DataStream, FormatCapabilities, ReadOptions,
+};
+use crate::pipeline::SessionSetup;
+use crate::stats::InputStats;
+use crate::storage::Storage;
+
+#[cfg(feature = "wasm-plugins")]
+pub mod wasm;
+
+/// Version of the plugin API implemented by the host, compared against each
+/// plugin's `metadata().version`
+pub const HOST_API_VERSION: &str = "0.1.0";
+
+/// Binary interface version; bumped whenever `FormatPlugin` or `DataFormat`
+/// change layout, so libraries built against an older host are rejected
+pub const PLUGIN_ABI_VERSION: u32 = 3;
+
+/// Parse a `major.minor.patch` version, treating missing parts as zero
+fn parse_version(version: &str) -> Result<(u64, u64, u64)> {
+    let core = version.split(['-', '+']).next().unwrap_or_default();
+    let mut parts = core.split('.').map(|part| {
+        part.parse::<u64>()
+            .with_context(|| format!("Invalid version '{}'", version))
+    });
+    let major = parts.next().ok_or_else(|| anyhow!("Invalid version '{}'", version))??;
+    let minor = parts.next().transpose()?.unwrap_or(0);
+    let patch = parts.next().transpose()?.unwrap_or(0);
+    if parts.next().is_some() {
+        bail!("Invalid version '{}'", version);
+    }
+    Ok((major, minor, patch))
+}
+
+/// Check that a plugin built for `plugin_version` may run on `host_version`
+pub fn check_version_compatibility(
+    mode: VersionCompatibility,
+    host_version: &str,
+    plugin_version: &str,
+) -> Result<()> {
+    if mode == VersionCompatibility::Any {
+        return Ok(());
+    }
+    let host = parse_version(host_version)?;
+    let plugin = parse_version(plugin_version)?;
+    let compatible = match mode {
+        VersionCompatibility::Exact => host == plugin,
+        VersionCompatibility::Major => host.0 == plugin.0,
+        VersionCompatibility::Minor => (host.0, host.1) == (plugin.0, plugin.1),
+        VersionCompatibility::Any => true,
+    };
+    if !compatible {
+        bail!(
+            "Plugin version {} is not compatible with host API version {} ({:?} compatibility)",
+            plugin_version,
+            host_version,
+            mode
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginMetadata {
+    pub name: String,
+    pub version: String,
+    pub description: String,
+    /// File extensions handled by the plugin's format
+    #[serde(default)]
+    pub extensions: Vec<String>,
+}
+
+impl PluginMetadata {
+    pub fn new(name: impl Into<String>, version: impl Into<String>, description: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            description: description.into(),
+            extensions: Vec::new(),
+        }
+    }
+
+    pub fn with_extensions<I, S>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+}
+
+/// A registered plugin.
+///
+/// Code for `instance` lives in `library`, so the library is only unloaded
+/// once the last reference to the plugin is gone. Fields drop in declaration
+/// order, which releases the instance before the library.
+pub struct Plugin {
+    pub metadata: PluginMetadata,
+    pub instance: Arc<dyn FormatPlugin>,
+    /// Library the plugin was loaded from; `None` for plugins registered in-process
+    pub library: Option<Arc<Library>>,
+}
+
+/// A format created by a plugin, keeping the plugin's library loaded for as
+/// long as the format is in use
+struct PluginFormat {
+    format: Box<dyn DataFormat + Send + Sync>,
+    _library: Option<Arc<Library>>,
+}
+
+#[async_trait]
+impl DataFormat for PluginFormat {
+    fn read(&self, data: &Bytes) -> error::Result<DataFrame> {
+        self.format.read(data)
+    }
+
+    fn write(&self, df: &DataFrame) -> error::Result<Bytes> {
+        self.format.write(df)
+    }
+
+    fn write_batch(&self, batch: &RecordBatch) -> error::Result<Bytes> {
+        self.format.write_batch(batch)
+    }
+
+    fn capabilities(&self) -> FormatCapabilities {
+        self.format.capabilities()
+    }
+
+    fn read_batches_from_stream(&self, stream: DataStream) -> error::Result<BatchStream> {
+        self.format.read_batches_from_stream(stream)
+    }
+
+    fn read_batches_with(&self, stream: DataStream, options: &ReadOptions) -> error::Result<BatchStream> {
+        self.format.read_batches_with(stream, options)
+    }
+
+    fn batch_writer(&self, schema: SchemaRef) -> error::Result<Box<dyn BatchWriter + '_>> {
+        self.format.batch_writer(schema)
+    }
+
+    async fn read_batches_from_storage(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> error::Result<BatchStream> {
+        self.format.read_batches_from_storage(storage, url, size).await
+    }
+
+    async fn infer_schema(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> error::Result<SchemaRef> {
+        self.format.infer_schema(storage, url, size).await
+    }
+
+    async fn compute_stats(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> error::Result<InputStats> {
+        self.format.compute_stats(storage, url, size).await
+    }
+
+    async fn plan_statistics(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> error::Result<Statistics> {
+        self.format.plan_statistics(storage, url, size).await
+    }
+
+    async fn row_group_count(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> error::Result<Option<usize>> {
+        self.format.row_group_count(storage, url, size).await
+    }
+
+    async fn read_row_groups_from_storage(
+        &self,
+        storage: Arc<dyn Storage>,
+        url: &Url,
+        size: usize,
+        row_groups: Vec<usize>,
+        projection: Option<Vec<usize>>,
+    ) -> error::Result<BatchStream> {
+        self.format.read_row_groups_from_storage(storage, url, size, row_groups, projection).await
+    }
+
+    async fn write_batches(&self, batches: BatchStream) -> error::Result<Bytes> {
+        self.format.write_batches(batches).await
+    }
+
+    async fn write_batches_to(&self, batches: BatchStream, sink: DataSink) -> error::Result<()> {
+        self.format.write_batches_to(batches, sink).await
+    }
+
+    async fn write_batches_for_append(&self, batches: BatchStream, new_file: bool) -> error::Result<Bytes> {
+        self.format.write_batches_for_append(batches, new_file).await
+    }
+}
+
+pub struct PluginRegistry {
+    plugins: HashMap<String, Arc<Plugin>>,
+    formats: HashMap<String, Arc<Box<dyn DataFormat + Send + Sync>>>,
+    version_compatibility: VersionCompatibility,
+    /// Configuration passed to each plugin's `create_format`, keyed by plugin name
+    plugin_configs: HashMap<String, serde_json::Value>,
+}
+
+impl PluginRegistry {
+    pub fn new(version_compatibility: VersionCompatibility) -> Self {
+        Self {
+            plugins: HashMap::new(),
+            formats: HashMap::new(),
+            version_compatibility,
+            plugin_configs: HashMap::new(),
+        }
+    }
+
+    pub fn with_plugin_configs(mut self, plugin_configs: HashMap<String, serde_json::Value>) -> Self {
+        self.plugin_configs = plugin_configs;
+        self
+    }
+
+    pub fn register_format(&mut self, name: &str, format: Arc<Box<dyn DataFormat + Send + Sync>>) -> Result<()> {
+        self.formats.insert(name.to_string(), format);
+        Ok(())
+    }
+
+    pub fn get_format(&self, name: &str) -> Option<Arc<Box<dyn DataFormat + Send + Sync>>> {
+        self.formats.get(name).cloned()
+    }
+
+    /// Register a plugin and the format it creates under the plugin's name,
+    /// refusing plugins whose version is incompatible with the host.
+    ///
+    /// The format is also added to the global format registry, together with
+    /// the plugin's file extensions.
+    pub fn register_plugin(&mut self, instance: Arc<dyn FormatPlugin>, library: Option<Arc<Library>>) -> Result<()> {
+        let metadata = instance.metadata();
+        if let Err(e) = check_version_compatibility(self.version_compatibility, HOST_API_VERSION, &metadata.version) {
+            // Parameters drop in reverse order, which would unload the library
+            // while the instance's drop glue still lives in it
+            drop(instance);
+            drop(library);
+            return Err(e.context(format!("Refusing to register plugin '{}'", metadata.name)));
+        }
+        let create_format = || -> Box<dyn DataFormat + Send + Sync> {
+            Box::new(PluginFormat {
+                format: instance.create_format(self.plugin_configs.get(&metadata.name)),
+                _library: library.clone(),
+            })
+        };
+        self.formats.insert(metadata.name.clone(), Arc::new(create_format()));
+        formats::register_format(&metadata.name, create_format());
+        for extension in &metadata.extensions {
+            formats::register_extension(extension, &metadata.name);
+        }
+        self.plugins.insert(
+            metadata.name.clone(),
+            Arc::new(Plugin {
+                metadata,
+                instance,
+                library,
+            }),
+        );
+        Ok(())
+    }
+
+    pub fn get_plugin(&self, name: &str) -> Option<Arc<Plugin>> {
+        self.plugins.get(name).cloned()
+    }
+
+    /// Metadata of every registered plugin, ordered by name
+    pub fn list_plugins(&self) -> Vec<PluginMetadata> {
+        let mut plugins: Vec<PluginMetadata> = self.plugins.values().map(|plugin| plugin.metadata.clone()).collect();
+        plugins.sort_by(|a, b| a.name.cmp(&b.name));
+        plugins
+    }
+
+    /// The plugin whose metadata lists `extension`, with or without the leading dot
+    pub fn get_plugin_for_extension(&self, extension: &str) -> Option<Arc<Plugin>> {
+        let extension = formats::normalize_extension(extension);
+        self.plugins
+            .values()
+            .find(|plugin| {
+                plugin
+                    .metadata
+                    .extensions
+                    .iter()
+                    .any(|candidate| formats::normalize_extension(candidate) == extension)
+            })
+            .cloned()
+    }
+
+    pub fn load_plugin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let lib = unsafe { Library::new(path)? };
+
+        // Check the ABI before touching anything else the library exports
+        let abi_version: libloading::Symbol<unsafe extern "C" fn() -> u32> = unsafe { lib.get(b"plugin_abi_version") }
+            .with_context(|| format!("{} does not export a plugin ABI version", path.display()))?;
+        let abi_version = unsafe { abi_version() };
+        if abi_version != PLUGIN_ABI_VERSION {
+            bail!(
+                "{} was built for plugin ABI version {}, but the host requires {}",
+                path.display(),
+                abi_version,
+                PLUGIN_ABI_VERSION
+            );
+        }
+
+        let create_plugin: libloading::Symbol<unsafe extern "C" fn() -> *mut dyn FormatPlugin> =
+            unsafe { lib.get(b"create_plugin")? };
+        let instance: Box<dyn FormatPlugin> = unsafe { Box::from_raw(create_plugin()) };
+
+        self.register_plugin(Arc::from(instance), Some(Arc::new(lib)))
+            .with_context(|| format!("Failed to load plugin {}", path.display()))
+    }
+
+    /// Load a WebAssembly plugin, which runs sandboxed and needs no `unsafe`
+    #[cfg(feature = "wasm-plugins")]
+    pub fn load_wasm_plugin<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let instance = wasm::WasmPlugin::from_file(path)?;
+        self.register_plugin(Arc::new(instance), None)
+            .with_context(|| format!("Failed to load plugin {}", path.display()))
+    }
+}
+
+pub struct PluginManager {
+    registry: Arc<RwLock<PluginRegistry>>,
+    plugin_dir: PathBuf,
+}
+
+impl PluginManager {
+    pub fn new<P: Into<PathBuf>>(plugin_dir: P) -> Self {
+        Self::with_version_compatibility(plugin_dir, VersionCompatibility::Minor)
+    }
+
+    pub fn with_version_compatibility<P: Into<PathBuf>>(plugin_dir: P, version_compatibility: VersionCompatibility) -> Self {
+        Self {
+            registry: Arc::new(RwLock::new(PluginRegistry::new(version_compatibility))),
+            plugin_dir: plugin_dir.into(),
+        }
+    }
+
+    pub fn from_config(config: &PluginConfig) -> Self {
+        let registry = PluginRegistry::new(config.version_compatibility).with_plugin_configs(config.plugin_configs.clone());
+        Self {
+            registry: Arc::new(RwLock::new(registry)),
+            plugin_dir: config.directory.clone(),
+        }
+    }
+
+    pub async fn load_plugins(&self) -> Result<()> {
+        let mut entries = fs::read_dir(&self.plugin_dir).await?;
+        
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "so" || ext == "dylib") {
+                self.registry.write().load_plugin(&path)?;
+            }
+            #[cfg(feature = "wasm-plugins")]
+            if path.extension().is_some_and(|ext| ext == "wasm") {
+                self.registry.write().load_wasm_plugin(&path)?;
+            }
+        }
+        
+        Ok(())
+    }
+
+    pub async fn register_format(&self, name: &str, format: Arc<Box<dyn DataFormat + Send + Sync>>) -> Result<()> {
+        self.registry.write().register_format(name, format)
+    }
+
+    pub fn get_format(&self, name: &str) -> Option<Arc<Box<dyn DataFormat + Send + Sync>>> {
+        self.registry.read().get_format(name)
+    }
+
+    /// Register a plugin compiled into the host rather than loaded from a library
+    pub fn register_plugin(&self, plugin: Arc<dyn FormatPlugin>) -> Result<()> {
+        self.registry.write().register_plugin(plugin, None)
+    }
+
+    pub fn get_plugin(&self, name: &str) -> Option<Arc<Plugin>> {
+        self.registry.read().get_plugin(name)
+    }
+
+    pub fn list_plugins(&self) -> Vec<PluginMetadata> {
+        self.registry.read().list_plugins()
+    }
+
+    pub fn get_plugin_for_extension(&self, extension: &str) -> Option<Arc<Plugin>> {
+        self.registry.read().get_plugin_for_extension(extension)
+    }
+
+    /// Load the plugin library, or with the `wasm-plugins` feature the
+    /// WebAssembly module, at `path`
+    pub async fn load_plugin<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        #[cfg(feature = "wasm-plugins")]
+        if path.extension().is_some_and(|ext| ext == "wasm") {
+            return self.registry.write().load_wasm_plugin(path);
+        }
+        self.registry.write().load_plugin(path)
+    }
+
+    /// Session setup that registers the UDFs of every plugin, in name order,
+    /// including plugins loaded after it was created
+    pub fn session_setup(&self) -> SessionSetup {
+        let registry = self.registry.clone();
+        Arc::new(move |ctx: &SessionContext| {
+            let mut plugins: Vec<Arc<Plugin>> = registry.read().plugins.values().cloned().collect();
+            plugins.sort_by(|a, b| a.metadata.name.cmp(&b.metadata.name));
+            for plugin in plugins {
+                plugin
+                    .instance
+                    .register_udfs(ctx)
+                    .with_context(|| format!("Plugin '{}' failed to register its functions", plugin.metadata.name))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Trait that must be implemented by format plugins
+pub trait FormatPlugin: Send + Sync {
+    /// Create a new instance of the format, given the plugin's entry in
+    /// `plugins.plugin_configs` if there is one
+    fn create_format(&self, config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync>;
+    
+    /// Get metadata about the plugin
+    fn metadata(&self) -> PluginMetadata;
+
+    /// Register the plugin's scalar and aggregate UDFs on `ctx`, the session a
+    /// `--filter-sql` query runs in. Format-only plugins keep the default,
+    /// which registers nothing.
+    fn register_udfs(&self, ctx: &SessionContext) -> Result<()> {
+        let _ = ctx;
+        Ok(())
+    }
+}
+
+/// Macro for plugin declaration
+#[macro_export]
+macro_rules! declare_plugin {
+    ($plugin_type:ty, $create_fn:ident) => {
+        #[no_mangle]
+        pub extern "C" fn $create_fn() -> *mut dyn $crate::plugin::FormatPlugin {
+            let plugin = <$plugin_type>::default();
+            Box::into_raw(Box::new(plugin))
+        }
+
+        #[no_mangle]
+        pub extern "C" fn plugin_abi_version() -> u32 {
+            $crate::plugin::PLUGIN_ABI_VERSION
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{JsonConfig, JsonFormat};
+    use futures::TryStreamExt;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+
+    struct TestPlugin;
+
+    impl FormatPlugin for TestPlugin {
+        fn create_format(&self, _config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+            Box::new(JsonFormat::default())
+        }
+
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata::new("test-json", HOST_API_VERSION, "JSON for tests").with_extensions(["json", "jsonl"])
+        }
+    }
+
+    #[test]
+    fn test_list_registered_plugin() {
+        let manager = PluginManager::new("plugins");
+        manager.register_plugin(Arc::new(TestPlugin)).unwrap();
+
+        let plugins = manager.list_plugins();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].name, "test-json");
+        assert_eq!(plugins[0].version, HOST_API_VERSION);
+        assert_eq!(plugins[0].extensions, vec!["json", "jsonl"]);
+        assert!(manager.get_format("test-json").is_some());
+    }
+
+    #[test]
+    fn test_plugin_outlives_manager() {
+        let manager = PluginManager::new("plugins");
+        manager.register_plugin(Arc::new(TestPlugin)).unwrap();
+        let plugin = manager.get_plugin("test-json").unwrap();
+        let format = manager.get_format("test-json").unwrap();
+        drop(manager);
+
+        // The plugin and its format own everything they need once the manager is gone
+        assert_eq!(plugin.instance.metadata().name, "test-json");
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1, 2]))]).unwrap();
+        let encoded = format.write_batch(&batch).unwrap();
+        assert!(!encoded.is_empty());
+    }
+
+    struct LibraryPlugin;
+
+    impl FormatPlugin for LibraryPlugin {
+        fn create_format(&self, _config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+            Box::new(JsonFormat::default())
+        }
+
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata::new("library-json", HOST_API_VERSION, "JSON loaded from a library")
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_plugin_keeps_library_loaded_after_manager_is_dropped() {
+        // Any shared library will do; the process keeps libc mapped regardless
+        let library = Arc::new(unsafe { Library::new("libc.so.6") }.unwrap());
+        let manager = PluginManager::new("plugins");
+        manager.registry.write().register_plugin(Arc::new(LibraryPlugin), Some(library.clone())).unwrap();
+        // Replace the global registry's copy so only the handles below hold the library
+        formats::register_format("library-json", Box::new(JsonFormat::default()));
+        let plugin = manager.get_plugin("library-json").unwrap();
+        let format = manager.get_format("library-json").unwrap();
+        drop(manager);
+
+        assert!(Arc::ptr_eq(plugin.library.as_ref().unwrap(), &library));
+        assert_eq!(Arc::strong_count(&library), 3);
+        drop(plugin);
+        assert_eq!(Arc::strong_count(&library), 2);
+        drop(format);
+        assert_eq!(Arc::strong_count(&library), 1);
+    }
+
+    fn accepts(mode: VersionCompatibility, plugin_version: &str) -> bool {
+        check_version_compatibility(mode, "1.4.2", plugin_version).is_ok()
+    }
+
+    #[test]
+    fn test_exact_compatibility() {
+        assert!(accepts(VersionCompatibility::Exact, "1.4.2"));
+        assert!(!accepts(VersionCompatibility::Exact, "1.4.3"));
+        assert!(!accepts(VersionCompatibility::Exact, "1.5.2"));
+    }
+
+    #[test]
+    fn test_major_compatibility() {
+        assert!(accepts(VersionCompatibility::Major, "1.0.0"));
+        assert!(accepts(VersionCompatibility::Major, "1.9.7"));
+        assert!(!accepts(VersionCompatibility::Major, "2.4.2"));
+        assert!(!accepts(VersionCompatibility::Major, "0.4.2"));
+    }
+
+    #[test]
+    fn test_minor_compatibility() {
+        assert!(accepts(VersionCompatibility::Minor, "1.4.0"));
+        assert!(accepts(VersionCompatibility::Minor, "1.4.9-beta"));
+        assert!(!accepts(VersionCompatibility::Minor, "1.3.2"));
+        assert!(!accepts(VersionCompatibility::Minor, "2.4.2"));
+    }
+
+    #[test]
+    fn test_any_compatibility() {
+        assert!(accepts(VersionCompatibility::Any, "9.9.9"));
+        assert!(accepts(VersionCompatibility::Any, "not-a-version"));
+        assert!(!accepts(VersionCompatibility::Exact, "not-a-version"));
+    }
+
+    struct OutdatedPlugin;
+
+    impl FormatPlugin for OutdatedPlugin {
+        fn create_format(&self, _config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+            Box::new(JsonFormat::default())
+        }
+
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata::new("outdated", "99.0.0", "Built for another host")
+        }
+    }
+
+    #[test]
+    fn test_incompatible_plugin_is_not_registered() {
+        let manager = PluginManager::with_version_compatibility("plugins", VersionCompatibility::Major);
+        let err = manager.register_plugin(Arc::new(OutdatedPlugin)).unwrap_err();
+        assert!(format!("{:#}", err).contains("99.0.0"));
+        assert!(manager.list_plugins().is_empty());
+        assert!(manager.get_format("outdated").is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_incompatible_plugin_releases_instance_before_library() {
+        // Any shared library will do; the process keeps libc mapped regardless
+        let library = Arc::new(unsafe { Library::new("libc.so.6") }.unwrap());
+        let instance: Arc<dyn FormatPlugin> = Arc::new(OutdatedPlugin);
+        let mut registry = PluginRegistry::new(VersionCompatibility::Exact);
+
+        let err = registry.register_plugin(instance.clone(), Some(library.clone())).unwrap_err();
+        assert!(format!("{:#}", err).contains("Refusing to register plugin 'outdated'"));
+        assert_eq!(Arc::strong_count(&instance), 1);
+        assert_eq!(Arc::strong_count(&library), 1);
+        assert!(registry.get_plugin("outdated").is_none());
+    }
+
+    struct StubPlugin;
+
+    impl FormatPlugin for StubPlugin {
+        fn create_format(&self, _config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+            Box::new(JsonFormat::default())
+        }
+
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata::new("stub-format", HOST_API_VERSION, "Stub for registry tests").with_extensions([".STUB"])
+        }
+    }
+
+    #[test]
+    fn test_plugin_format_is_in_global_registry() {
+        assert!(formats::get_format("stub-format").is_none());
+        let manager = PluginManager::new("plugins");
+        manager.register_plugin(Arc::new(StubPlugin)).unwrap();
+
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1, 2]))]).unwrap();
+        let by_name = formats::get_format("stub-format").expect("registered by name");
+        let by_extension = formats::get_format_for_extension("stub").expect("registered by extension");
+        assert_eq!(by_name.write_batch(&batch).unwrap(), by_extension.write_batch(&batch).unwrap());
+
+        let plugin = manager.get_plugin_for_extension(".stub").expect("plugin by extension");
+        assert_eq!(plugin.metadata.name, "stub-format");
+        assert!(manager.get_plugin_for_extension("csv").is_none());
+    }
+
+    struct ConfigurablePlugin;
+
+    impl FormatPlugin for ConfigurablePlugin {
+        fn create_format(&self, config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+            let mut json_config = JsonConfig::default();
+            if let Some(batch_size) = config.and_then(|c| c.get("batch_size")).and_then(|v| v.as_u64()) {
+                json_config.batch_size = batch_size as usize;
+            }
+            Box::new(JsonFormat::new(json_config))
+        }
+
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata::new("configurable", HOST_API_VERSION, "JSON with a configurable batch size")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugin_config_reaches_format() -> Result<()> {
+        let mut config = crate::config::Config::default().plugins;
+        config
+            .plugin_configs
+            .insert("configurable".to_string(), serde_json::json!({ "batch_size": 3 }));
+        let manager = PluginManager::from_config(&config);
+        manager.register_plugin(Arc::new(ConfigurablePlugin))?;
+
+        let lines: String = (0..10).map(|i| format!("{{\"id\": {}}}\n", i)).collect();
+        let stream = futures::stream::once(async move { Ok(Bytes::from(lines)) });
+        let format = manager.get_format("configurable").unwrap();
+        let batches: Vec<RecordBatch> = format.read_batches_from_stream(Box::pin(stream))?.try_collect().await?;
+
+        let sizes: Vec<usize> = batches.iter().map(|batch| batch.num_rows()).collect();
+        assert_eq!(sizes, vec![3, 3, 3, 1]);
+        Ok(())
+    }
+
+    struct UdfPlugin;
+
+    impl FormatPlugin for UdfPlugin {
+        fn create_format(&self, _config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+            Box::new(JsonFormat::default())
+        }
+
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata::new("udf-plugin", HOST_API_VERSION, "Registers a negate UDF")
+        }
+
+        fn register_udfs(&self, ctx: &SessionContext) -> Result<()> {
+            use arrow::array::Array;
+            use datafusion::logical_expr::{create_udf, ColumnarValue, Volatility};
+
+            ctx.register_udf(create_udf(
+                "negate",
+                vec![DataType::Int64],
+                Arc::new(DataType::Int64),
+                Volatility::Immutable,
+                Arc::new(|args: &[ColumnarValue]| -> datafusion::error::Result<ColumnarValue> {
+                    let values = args[0].clone().into_array(1);
+                    let values = values.as_any().downcast_ref::<Int64Array>().unwrap();
+                    let negated: Int64Array = values.iter().map(|value| value.map(|value| -value)).collect();
+                    Ok(ColumnarValue::Array(Arc::new(negated)))
+                }),
+            ));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugin_udfs_reach_filter_sql() -> Result<()> {
+        let manager = PluginManager::new("plugins");
+        manager.register_plugin(Arc::new(UdfPlugin))?;
+
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1, 2, 3]))])?;
+        let df = SessionContext::new().read_batch(batch)?;
+        let setup = manager.session_setup();
+        let rows: usize = crate::pipeline::apply_sql_with(df, "negate(id) < -1", Some(&setup))
+            .await?
+            .collect()
+            .await?
+            .iter()
+            .map(|batch| batch.num_rows())
+            .sum();
+        assert_eq!(rows, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use arrow::datatypes::{Schema, SchemaRef};
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use bytes::Bytes;
+use datafusion::dataframe::DataFrame;
+use datafusion::datasource::MemTable;
+use datafusion::execution::context::SessionContext;
+use futures::{StreamExt, TryStreamExt};
+use url::Url;
+use wasmtime::{Engine, Instance, Memory, Module, Store};
+
+use super::{FormatPlugin, PluginMetadata};
+use crate::error::{Error, Result};
+use crate::formats::{read_decompressed, BatchStream, DataFormat, DataStream};
+use crate::storage::Storage;
+
+/// Interface version a WebAssembly plugin reports from `plugin_abi_version`
+pub const WASM_PLUGIN_ABI_VERSION: i32 = 1;
+
+/// A format plugin compiled to WebAssembly.
+///
+/// The module may not import anything, so it can only see the buffers the
+/// host copies into its memory. It must export:
+///
+/// - `memory`
+/// - `plugin_abi_version() -> i32`, returning `WASM_PLUGIN_ABI_VERSION`
+/// - `metadata() -> i64`, a JSON-encoded `PluginMetadata`
+/// - `alloc(len: i32) -> i32`, reserving `len` bytes for the host to write input into
+/// - `read(ptr: i32, len: i32) -> i64`, decoding a file into an Arrow IPC stream
+/// - `write(ptr: i32, len: i32) -> i64`, encoding an Arrow IPC stream into a file
+///
+/// and may export `infer_schema(ptr: i32, len: i32) -> i64`, returning an
+/// Arrow IPC stream with the schema and no batches. Functions returning `i64`
+/// pack their output buffer as `(ptr << 32) | len`; a negative value is a failure.
+///
+/// Every call runs in a fresh instance, so no state leaks between calls.
+#[derive(Clone)]
+pub struct WasmPlugin {
+    engine: Engine,
+    module: Module,
+    metadata: PluginMetadata,
+}
+
+impl WasmPlugin {
+    /// Compile the `.wasm` (or `.wat`) file at `path`
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path).with_context(|| format!("Failed to compile {}", path.display()))?;
+        Self::from_module(engine, module).with_context(|| format!("Failed to load plugin {}", path.display()))
+    }
+
+    /// Compile a module from its binary or text representation
+    pub fn from_bytes(bytes: impl AsRef<[u8]>) -> anyhow::Result<Self> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, bytes)?;
+        Self::from_module(engine, module)
+    }
+
+    fn from_module(engine: Engine, module: Module) -> anyhow::Result<Self> {
+        if module.imports().next().is_some() {
+            bail!("WebAssembly plugins may not import anything");
+        }
+
+        let mut instance = WasmInstance::new(&engine, &module)?;
+        let abi_version = instance.call_i32("plugin_abi_version")?;
+        if abi_version != WASM_PLUGIN_ABI_VERSION {
+            bail!(
+                "Module was built for WebAssembly plugin ABI version {}, but the host requires {}",
+                abi_version,
+                WASM_PLUGIN_ABI_VERSION
+            );
+        }
+        let metadata = instance.call_output("metadata")?;
+        let metadata = serde_json::from_slice(&metadata).context("Invalid plugin metadata")?;
+        Ok(Self { engine, module, metadata })
+    }
+
+    /// Call the export `name` with `input` in a fresh instance
+    fn call(&self, name: &str, input: &[u8]) -> Result<Vec<u8>> {
+        let output = WasmInstance::new(&self.engine, &self.module)?.call_with_input(name, input)?;
+        Ok(output)
+    }
+
+    fn exports(&self, name: &str) -> bool {
+        self.module.get_export(name).is_some()
+    }
+}
+
+impl FormatPlugin for WasmPlugin {
+    fn create_format(&self, _config: Option<&serde_json::Value>) -> Box<dyn DataFormat + Send + Sync> {
+        Box::new(WasmFormat { plugin: self.clone() })
+    }
+
+    fn metadata(&self) -> PluginMetadata {
+        self.metadata.clone()
+    }
+}
+
+struct WasmInstance {
+    store: Store<()>,
+    instance: Instance,
+    memory: Memory,
+}
+
+impl WasmInstance {
+    fn new(engine: &Engine, module: &Module) -> anyhow::Result<Self> {
+        let mut store = Store::new(engine, ());
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("Module does not export its memory"))?;
+        Ok(Self { store, instance, memory })
+    }
+
+    fn call_i32(&mut self, name: &str) -> anyhow::Result<i32> {
+        let func = self.instance.get_typed_func::<(), i32>(&mut self.store, name)?;
+        func.call(&mut self.store, ())
+    }
+
+    fn call_output(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let func = self.instance.get_typed_func::<(), i64>(&mut self.store, name)?;
+        let packed = func.call(&mut self.store, ())?;
+        self.read_output(name, packed)
+    }
+
+    fn call_with_input(&mut self, name: &str, input: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let len = i32::try_from(input.len()).context("Input is too large for a WebAssembly plugin")?;
+        let alloc = self.instance.get_typed_func::<i32, i32>(&mut self.store, "alloc")?;
+        let ptr = alloc.call(&mut self.store, len)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, input)?;
+
+        let func = self.instance.get_typed_func::<(i32, i32), i64>(&mut self.store, name)?;
+        let packed = func.call(&mut self.store, (ptr, len))?;
+        self.read_output(name, packed)
+    }
+
+    fn read_output(&self, name: &str, packed: i64) -> anyhow::Result<Vec<u8>> {
+        if packed < 0 {
+            bail!("WebAssembly plugin failed in '{}' with code {}", name, packed);
+        }
+        let ptr = (packed >> 32) as usize;
+        let len = (packed & 0xFFFF_FFFF) as usize;
+        let mut output = vec![0; len];
+        self.memory.read(&self.store, ptr, &mut output)?;
+        Ok(output)
+    }
+}
+
+/// A format backed by a WebAssembly plugin, exchanging batches as Arrow IPC streams
+struct WasmFormat {
+    plugin: WasmPlugin,
+}
+
+impl WasmFormat {
+    fn decode(&self, data: &[u8]) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+        decode_ipc(&self.plugin.call("read", data)?)
+    }
+
+    fn encode(&self, schema: &SchemaRef, batches: &[RecordBatch]) -> Result<Bytes> {
+        Ok(Bytes::from(self.plugin.call("write", &encode_ipc(schema, batches)?)?))
+    }
+}
+
+#[async_trait]
+impl DataFormat for WasmFormat {
+    fn read(&self, data: &Bytes) -> Result<DataFrame> {
+        let (schema, batches) = self.decode(data)?;
+        let table = MemTable::try_new(schema, vec![batches])?;
+        Ok(SessionContext::new().read_table(Arc::new(table))?)
+    }
+
+    fn write(&self, df: &DataFrame) -> Result<Bytes> {
+        let schema: SchemaRef = Arc::new(df.schema().into());
+        let batches = futures::executor::block_on(df.clone().collect())?;
+        self.encode(&schema, &batches)
+    }
+
+    fn write_batch(&self, batch: &RecordBatch) -> Result<Bytes> {
+        self.encode(&batch.schema(), std::slice::from_ref(batch))
+    }
+
+    /// The module decodes whole files, so the stream is buffered completely
+    fn read_batches_from_stream(&self, stream: DataStream) -> Result<BatchStream> {
+        let plugin = self.plugin.clone();
+        let batches = futures::stream::once(async move {
+            let chunks: Vec<Bytes> = stream.try_collect().await?;
+            let (_, batches) = WasmFormat { plugin }.decode(&chunks.concat())?;
+            Ok::<_, Error>(futures::stream::iter(batches.into_iter().map(Ok)))
+        })
+        .try_flatten();
+        Ok(batches.boxed())
+    }
+
+    /// Uses the module's `infer_schema` export when it has one, which also
+    /// works for files without rows
+    async fn infer_schema(&self, storage: Arc<dyn Storage>, url: &Url, size: usize) -> Result<SchemaRef> {
+        if !self.plugin.exports("infer_schema") {
+            let mut batches = self.read_batches_from_storage(storage, url, size).await?;
+            return match batches.try_next().await? {
+                Some(batch) => Ok(batch.schema()),
+                None => Err(Error::EmptyInput(format!("Cannot infer a schema from {}: it has no rows", url))),
+            };
+        }
+        let chunks: Vec<Bytes> = read_decompressed(storage.as_ref(), url).await?.try_collect().await?;
+        let (schema, _) = decode_ipc(&self.plugin.call("infer_schema", &chunks.concat())?)?;
+        Ok(schema)
+    }
+
+    async fn write_batches(&self, batches: BatchStream) -> Result<Bytes> {
+        let batches: Vec<RecordBatch> = batches.try_collect().await?;
+        let schema = batches
+            .first()
+            .map(|batch| batch.schema())
+            .unwrap_or_else(|| Arc::new(Schema::empty()));
+        self.encode(&schema, &batches)
+    }
+}
+
+fn encode_ipc(schema: &SchemaRef, batches: &[RecordBatch]) -> Result<Vec<u8>> {
+    let mut writer = StreamWriter::try_new(Vec::new(), schema)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+fn decode_ipc(data: &[u8]) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let reader = StreamReader::try_new(Cursor::new(data), None)?;
+    let schema = reader.schema();
+    let batches = reader.collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok((schema, batches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Int64Array, StringArray};
+    use arrow::datatypes::{DataType, Field};
+
+    /// A module whose `read` and `write` return their input, turning the
+    /// plugin into an Arrow IPC stream format
+    fn echo_module() -> String {
+        let metadata = r#"{"name":"wasm-echo","version":"0.1.0","description":"Echoes Arrow IPC","extensions":["arrows"]}"#;
+        format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (data (i32.const 0) "{data}")
+                (func (export "plugin_abi_version") (result i32) (i32.const {abi}))
+                (func (export "metadata") (result i64) (i64.const {len}))
+                (func (export "alloc") (param $len i32) (result i32)
+                    (local $ptr i32)
+                    (local.set $ptr (global.get $next))
+                    (global.set $next (i32.add (local.get $ptr) (local.get $len)))
+                    (if (i32.gt_u (global.get $next) (i32.mul (memory.size) (i32.const 65536)))
+                        (then (drop (memory.grow (i32.add (i32.shr_u (local.get $len) (i32.const 16)) (i32.const 1))))))
+                    (local.get $ptr))
+                (func $echo (param $ptr i32) (param $len i32) (result i64)
+                    (i64.or
+                        (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                        (i64.extend_i32_u (local.get $len))))
+                (export "read" (func $echo))
+                (export "write" (func $echo)))"#,
+            data = metadata.replace('"', "\\\""),
+            abi = WASM_PLUGIN_ABI_VERSION,
+            len = metadata.len(),
+        )
+    }
+
+    fn batch() -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+                Arc::new(StringArray::from(vec![Some("a"), None, Some("c")])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_metadata_is_read_from_module() -> anyhow::Result<()> {
+        let plugin = WasmPlugin::from_bytes(echo_module())?;
+        let metadata = plugin.metadata();
+        assert_eq!(metadata.name, "wasm-echo");
+        assert_eq!(metadata.extensions, vec!["arrows"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_echo_module_as_format() -> Result<()> {
+        let plugin = WasmPlugin::from_bytes(echo_module())?;
+        let format = plugin.create_format(None);
+
+        let encoded = format.write_batch(&batch())?;
+        let stream = futures::stream::once(async move { Ok(encoded) });
+        let batches: Vec<RecordBatch> = format.read_batches_from_stream(Box::pin(stream))?.try_collect().await?;
+        assert_eq!(batches, vec![batch()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_with_imports_is_rejected() {
+        let module = r#"(module (import "env" "log" (func)) (memory (export "memory") 1))"#;
+        assert!(WasmPlugin::from_bytes(module).is_err());
+    }
+
+    #[test]
+    fn test_abi_version_mismatch_is_rejected() {
+        let module = echo_module().replacen(
+            &format!("(i32.const {})", WASM_PLUGIN_ABI_VERSION),
+            &format!("(i32.const {})", WASM_PLUGIN_ABI_VERSION + 1),
+            1,
+        );
+        let err = WasmPlugin::from_bytes(module).err().unwrap();
+        assert!(format!("{:#}", err).contains("ABI version"));
+    }
+}
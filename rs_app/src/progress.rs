@@ -0,0 +1,272 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::ObjectMeta;
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::error::Result;
+use crate::storage::Storage;
+
+/// Progress of a running conversion; every counter only grows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes read from input storage so far
+    pub bytes_read: u64,
+    /// Rows decoded from the inputs so far
+    pub rows_in: u64,
+    /// Rows handed to the output writer so far
+    pub rows_out: u64,
+    /// Input file currently being read
+    pub file: Option<Url>,
+}
+
+struct Counters {
+    bytes_read: AtomicU64,
+    rows_in: AtomicU64,
+    rows_out: AtomicU64,
+    file: Mutex<Option<Url>>,
+    sender: mpsc::Sender<Progress>,
+    interval: Duration,
+    last_emitted: Mutex<Option<Instant>>,
+}
+
+/// Collects progress counters from the pipeline and sends [`Progress`]
+/// snapshots over a channel.
+///
+/// Snapshots are sent at most once per `interval`, or after every update
+/// when it is zero. Sending never waits: a snapshot is dropped when the
+/// channel is full.
+#[derive(Clone)]
+pub struct ProgressReporter {
+    counters: Arc<Counters>,
+}
+
+impl ProgressReporter {
+    /// A reporter and the receiving end of its channel, which buffers up to `capacity` snapshots
+    pub fn channel(capacity: usize, interval: Duration) -> (Self, mpsc::Receiver<Progress>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let counters = Counters {
+            bytes_read: AtomicU64::new(0),
+            rows_in: AtomicU64::new(0),
+            rows_out: AtomicU64::new(0),
+            file: Mutex::new(None),
+            sender,
+            interval,
+            last_emitted: Mutex::new(None),
+        };
+        (Self { counters: Arc::new(counters) }, receiver)
+    }
+
+    /// Current values of the counters
+    pub fn snapshot(&self) -> Progress {
+        Progress {
+            bytes_read: self.counters.bytes_read.load(Ordering::Relaxed),
+            rows_in: self.counters.rows_in.load(Ordering::Relaxed),
+            rows_out: self.counters.rows_out.load(Ordering::Relaxed),
+            file: self.counters.file.lock().clone(),
+        }
+    }
+
+    pub fn add_bytes_read(&self, bytes: usize) {
+        self.counters.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.emit(false);
+    }
+
+    pub fn add_rows_in(&self, rows: usize) {
+        self.counters.rows_in.fetch_add(rows as u64, Ordering::Relaxed);
+        self.emit(false);
+    }
+
+    pub fn add_rows_out(&self, rows: usize) {
+        self.counters.rows_out.fetch_add(rows as u64, Ordering::Relaxed);
+        self.emit(false);
+    }
+
+    /// Record that `file` is now being read
+    pub fn set_file(&self, file: &Url) {
+        *self.counters.file.lock() = Some(file.clone());
+        self.emit(false);
+    }
+
+    /// Send the final counters regardless of the interval
+    pub fn finish(&self) {
+        self.emit(true);
+    }
+
+    /// Wrap `storage` so that every byte read through it is counted
+    pub fn track_reads(&self, storage: Arc<dyn Storage>) -> Arc<dyn Storage> {
+        Arc::new(ProgressStorage {
+            inner: storage,
+            progress: self.clone(),
+        })
+    }
+
+    fn emit(&self, force: bool) {
+        {
+            let mut last_emitted = self.counters.last_emitted.lock();
+            let now = Instant::now();
+            let due = match *last_emitted {
+                Some(last) => now.duration_since(last) >= self.counters.interval,
+                None => true,
+            };
+            if !force && !due {
+                return;
+            }
+            *last_emitted = Some(now);
+        }
+        // A slow consumer misses snapshots rather than stalling the pipeline
+        let _ = self.counters.sender.try_send(self.snapshot());
+    }
+}
+
+impl std::fmt::Debug for ProgressReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ProgressReporter").field(&self.snapshot()).finish()
+    }
+}
+
+/// Storage that reports the bytes read through it to a [`ProgressReporter`]
+struct ProgressStorage {
+    inner: Arc<dyn Storage>,
+    progress: ProgressReporter,
+}
+
+#[async_trait]
+impl Storage for ProgressStorage {
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        self.inner.list_stream(prefix).await
+    }
+
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let progress = self.progress.clone();
+        let stream = self.inner.read(url).await?;
+        Ok(stream.inspect_ok(move |chunk| progress.add_bytes_read(chunk.len())).boxed())
+    }
+
+    async fn read_all(&self, url: &Url) -> Result<Bytes> {
+        let data = self.inner.read_all(url).await?;
+        self.progress.add_bytes_read(data.len());
+        Ok(data)
+    }
+
+    async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
+        self.inner.write(url, data).await
+    }
+
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let data = self.inner.read_range(url, range).await?;
+        self.progress.add_bytes_read(data.len());
+        Ok(data)
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        self.inner.size(url).await
+    }
+
+    async fn checksum(&self, url: &Url) -> Result<Option<String>> {
+        self.inner.checksum(url).await
+    }
+
+    async fn write_stream(&self, url: &Url, stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        self.inner.write_stream(url, stream).await
+    }
+
+    async fn append(&self, url: &Url, data: Bytes) -> Result<()> {
+        self.inner.append(url, data).await
+    }
+
+    async fn delete(&self, url: &Url) -> Result<()> {
+        self.inner.delete(url).await
+    }
+
+    async fn list_glob_meta(&self, pattern: &str) -> Result<Vec<(Url, ObjectMeta)>> {
+        self.inner.list_glob_meta(pattern).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{CsvFormat, EmptyInputPolicy, ParquetFormat};
+    use crate::pipeline::{self, ReadOptions};
+    use crate::storage::local::LocalStorage;
+
+    async fn convert(input: &Url, output: &Url, progress: &ProgressReporter) -> anyhow::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let options = ReadOptions::new(EmptyInputPolicy::Error).with_progress(progress.clone());
+        let batches = pipeline::read_batches(storage.clone(), vec![input.clone()], Arc::new(CsvFormat::default()), options);
+        let (schema, batches) = pipeline::peek_schema(batches).await?;
+        let df = pipeline::stream_dataframe(schema, batches)?;
+        pipeline::write_output(df, Arc::new(ParquetFormat::default()), storage, output, &[], 1, Some(progress)).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_progress_counters_are_monotonic() -> anyhow::Result<()> {
+        const ROWS: usize = 20_000;
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("input.csv");
+        let rows: String = (0..ROWS).map(|i| format!("{},name{}\n", i, i)).collect();
+        std::fs::write(&path, format!("id,name\n{}", rows))?;
+        let input = Url::from_file_path(&path).unwrap();
+        let output = Url::from_file_path(temp_dir.path().join("output.parquet")).unwrap();
+
+        let (progress, mut receiver) = ProgressReporter::channel(100_000, Duration::ZERO);
+        convert(&input, &output, &progress).await?;
+        drop(progress);
+
+        let mut events = Vec::new();
+        while let Some(event) = receiver.recv().await {
+            events.push(event);
+        }
+        assert!(events.len() > 1);
+        for pair in events.windows(2) {
+            assert!(pair[1].bytes_read >= pair[0].bytes_read);
+            assert!(pair[1].rows_in >= pair[0].rows_in);
+            assert!(pair[1].rows_out >= pair[0].rows_out);
+        }
+        let last = events.last().unwrap();
+        assert_eq!(last.rows_in, ROWS as u64);
+        assert_eq!(last.rows_out, ROWS as u64);
+        assert!(last.bytes_read >= std::fs::metadata(&path)?.len());
+        assert_eq!(last.file.as_ref(), Some(&input));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumer_does_not_block() -> anyhow::Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("input.csv");
+        let rows: String = (0..1000).map(|i| format!("{}\n", i)).collect();
+        std::fs::write(&path, format!("id\n{}", rows))?;
+        let input = Url::from_file_path(&path).unwrap();
+        let output = Url::from_file_path(temp_dir.path().join("output.parquet")).unwrap();
+
+        // The receiver is never read, so all but the first snapshot are dropped
+        let (progress, _receiver) = ProgressReporter::channel(1, Duration::ZERO);
+        tokio::time::timeout(Duration::from_secs(10), convert(&input, &output, &progress)).await??;
+        assert_eq!(progress.snapshot().rows_out, 1000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append_reaches_the_inner_storage() -> anyhow::Result<()> {
+        let inner = Arc::new(crate::storage::testing::MockStorage::new());
+        let (progress, _receiver) = ProgressReporter::channel(1, Duration::ZERO);
+        let storage = progress.track_reads(inner.clone());
+        let url = Url::parse("s3://bucket/out.csv")?;
+
+        storage.append(&url, Bytes::from_static(b"a\n")).await?;
+        assert_eq!((inner.appends(), inner.reads()), (1, 0));
+        assert_eq!(progress.snapshot().bytes_read, 0);
+        Ok(())
+    }
+}
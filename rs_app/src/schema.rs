@@ -0,0 +1,1082 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use arrow::array::{make_array, new_null_array, ArrayData, ArrayRef, StringArray};
+use arrow::compute::{can_cast_types, cast_with_options, CastOptions};
+use arrow::datatypes::{DataType, Field, FieldRef, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use arrow::util::pretty::pretty_format_batches;
+use bytes::Bytes;
+
+use crate::formats::DataFormat;
+
+/// Mismatching fields listed in a schema diff before the rest are summarized
+const MAX_REPORTED_DIFFERENCES: usize = 10;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Compute a stable fingerprint of an Arrow schema.
+///
+/// The hash covers field names, data types, nullability and metadata, with
+/// metadata entries sorted by key at every level of nesting, and is computed
+/// with FNV-1a so it stays the same across runs and Rust releases, making it
+/// suitable for persisting alongside written data, see
+/// [`crate::pipeline::check_schema_stability`].
+pub fn schema_fingerprint(schema: &SchemaRef) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write_metadata(schema.metadata());
+    hasher.write_usize(schema.fields().len());
+    for field in schema.fields() {
+        hasher.write_field(field);
+    }
+    hasher.finish()
+}
+
+/// Cast `batch` so that it exactly matches `target`.
+///
+/// Columns are matched by name and cast to the target type; the result uses
+/// the target's column order. Target columns missing from the batch are
+/// filled with nulls when nullable. Input columns absent from the target,
+/// missing non-nullable columns and casts Arrow can't perform are errors.
+pub fn coerce_batch(batch: &RecordBatch, target: &SchemaRef) -> Result<RecordBatch> {
+    let source = batch.schema();
+    if let Some(extra) = source.fields().iter().find(|f| target.field_with_name(f.name()).is_err()) {
+        return Err(anyhow!("Column '{}' does not exist in the target schema", extra.name()));
+    }
+
+    let options = CastOptions {
+        safe: false,
+        ..CastOptions::default()
+    };
+    let columns = target
+        .fields()
+        .iter()
+        .map(|field| -> Result<ArrayRef> {
+            let Ok(index) = source.index_of(field.name()) else {
+                if field.is_nullable() {
+                    return Ok(new_null_array(field.data_type(), batch.num_rows()));
+                }
+                return Err(anyhow!("Missing non-nullable column '{}'", field.name()));
+            };
+            let column = batch.column(index);
+            if column.data_type() == field.data_type() {
+                return Ok(column.clone());
+            }
+            if !can_cast_types(column.data_type(), field.data_type()) {
+                return Err(anyhow!(
+                    "Cannot coerce column '{}' from {} to {}",
+                    field.name(),
+                    column.data_type(),
+                    field.data_type()
+                ));
+            }
+            cast_with_options(column, field.data_type(), &options)
+                .map_err(|e| anyhow!("Failed to coerce column '{}': {}", field.name(), e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(RecordBatch::try_new(target.clone(), columns)?)
+}
+
+/// A rename or cast applied to a column of every batch, see [`transform_batch`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnTransform {
+    Rename { from: String, to: String },
+    Cast { column: String, to: DataType },
+}
+
+impl ColumnTransform {
+    /// Parse an `old=new` rename
+    pub fn parse_rename(spec: &str) -> Result<Self> {
+        let (from, to) = split_assignment(spec, "rename")?;
+        Ok(Self::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+
+    /// Parse a `column=Type` cast, see [`parse_data_type`]
+    pub fn parse_cast(spec: &str) -> Result<Self> {
+        let (column, to) = split_assignment(spec, "cast")?;
+        Ok(Self::Cast {
+            column: column.to_string(),
+            to: parse_data_type(to)?,
+        })
+    }
+}
+
+fn split_assignment<'a>(spec: &'a str, kind: &str) -> Result<(&'a str, &'a str)> {
+    match spec.split_once('=') {
+        Some((left, right)) if !left.trim().is_empty() && !right.trim().is_empty() => Ok((left.trim(), right.trim())),
+        _ => Err(anyhow!("Invalid {} '{}': expected <column>=<value>", kind, spec)),
+    }
+}
+
+/// Parse an Arrow type name as it is displayed, e.g. `Int64`, `Float32`,
+/// `Utf8` or `Date32`. Only primitive, string, binary and date types are supported.
+pub fn parse_data_type(name: &str) -> Result<DataType> {
+    Ok(match name.trim() {
+        "Boolean" => DataType::Boolean,
+        "Int8" => DataType::Int8,
+        "Int16" => DataType::Int16,
+        "Int32" => DataType::Int32,
+        "Int64" => DataType::Int64,
+        "UInt8" => DataType::UInt8,
+        "UInt16" => DataType::UInt16,
+        "UInt32" => DataType::UInt32,
+        "UInt64" => DataType::UInt64,
+        "Float32" => DataType::Float32,
+        "Float64" => DataType::Float64,
+        "Utf8" => DataType::Utf8,
+        "LargeUtf8" => DataType::LargeUtf8,
+        "Binary" => DataType::Binary,
+        "Date32" => DataType::Date32,
+        "Date64" => DataType::Date64,
+        other => return Err(anyhow!("Unknown data type '{}'", other)),
+    })
+}
+
+/// Apply `transforms` in order to the fields of `schema`.
+///
+/// Renaming or casting a column the schema doesn't have at that point,
+/// renaming onto an existing column and casts Arrow can't perform are errors.
+pub fn transform_schema(schema: &Schema, transforms: &[ColumnTransform]) -> Result<SchemaRef> {
+    let mut fields: Vec<Field> = schema.fields().iter().map(|f| f.as_ref().clone()).collect();
+    for transform in transforms {
+        match transform {
+            ColumnTransform::Rename { from, to } => {
+                let index = field_index(&fields, from)?;
+                if from != to && fields.iter().any(|f| f.name() == to) {
+                    return Err(anyhow!("Cannot rename column '{}' to '{}': the column already exists", from, to));
+                }
+                fields[index] = fields[index].clone().with_name(to);
+            }
+            ColumnTransform::Cast { column, to } => {
+                let index = field_index(&fields, column)?;
+                if !can_cast_types(fields[index].data_type(), to) {
+                    return Err(anyhow!(
+                        "Cannot cast column '{}' from {} to {}",
+                        column,
+                        fields[index].data_type(),
+                        to
+                    ));
+                }
+                fields[index] = fields[index].clone().with_data_type(to.clone());
+            }
+        }
+    }
+    Ok(std::sync::Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone())))
+}
+
+fn field_index(fields: &[Field], name: &str) -> Result<usize> {
+    fields
+        .iter()
+        .position(|f| f.name() == name)
+        .ok_or_else(|| anyhow!("Column '{}' does not exist", name))
+}
+
+/// Rename and cast the columns of `batch` as [`transform_schema`] describes.
+///
+/// Casts are not lossy: a value that can't be represented in the new type,
+/// such as `"abc"` cast to `Int64`, fails with the column's name.
+pub fn transform_batch(batch: RecordBatch, transforms: &[ColumnTransform]) -> Result<RecordBatch> {
+    let schema = transform_schema(&batch.schema(), transforms)?;
+    let options = CastOptions {
+        safe: false,
+        ..CastOptions::default()
+    };
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| -> Result<ArrayRef> {
+            if column.data_type() == field.data_type() {
+                return Ok(column.clone());
+            }
+            cast_with_options(column, field.data_type(), &options)
+                .map_err(|e| anyhow!("Failed to cast column '{}' to {}: {}", field.name(), field.data_type(), e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+/// Compute a schema every one of `schemas` can be cast to.
+///
+/// Columns keep the order in which they are first seen. A column's type is
+/// widened across schemas (`Int32` and `Int64` become `Int64`, integers and
+/// floats become `Float64`), and columns missing from some schemas become
+/// nullable. Types that can't be widened into one another are an error.
+pub fn unify_schemas<'a>(schemas: impl IntoIterator<Item = &'a Schema>) -> Result<SchemaRef> {
+    let schemas: Vec<&Schema> = schemas.into_iter().collect();
+    let mut fields: Vec<Field> = Vec::new();
+    for schema in &schemas {
+        for field in schema.fields() {
+            match fields.iter_mut().find(|f| f.name() == field.name()) {
+                Some(existing) => {
+                    let data_type = widen_type(existing.data_type(), field.data_type()).ok_or_else(|| {
+                        anyhow!(
+                            "Cannot unify column '{}': {} and {} have no common type",
+                            field.name(),
+                            existing.data_type(),
+                            field.data_type()
+                        )
+                    })?;
+                    let nullable = existing.is_nullable() || field.is_nullable();
+                    *existing = existing.clone().with_data_type(data_type).with_nullable(nullable);
+                }
+                None => fields.push(field.as_ref().clone()),
+            }
+        }
+    }
+    let fields = fields
+        .into_iter()
+        .map(|field| {
+            let everywhere = schemas.iter().all(|schema| schema.field_with_name(field.name()).is_ok());
+            let nullable = field.is_nullable() || !everywhere;
+            field.with_nullable(nullable)
+        })
+        .collect::<Vec<_>>();
+    Ok(std::sync::Arc::new(Schema::new(fields)))
+}
+
+/// The narrowest type both `left` and `right` can be cast to without losing values
+fn widen_type(left: &DataType, right: &DataType) -> Option<DataType> {
+    use DataType::*;
+
+    if left == right {
+        return Some(left.clone());
+    }
+    match (left, right) {
+        (Null, other) | (other, Null) => Some(other.clone()),
+        (Utf8, LargeUtf8) | (LargeUtf8, Utf8) => Some(LargeUtf8),
+        (Binary, LargeBinary) | (LargeBinary, Binary) => Some(LargeBinary),
+        _ => match (integer_width(left), integer_width(right)) {
+            (Some((left_bits, left_signed)), Some((right_bits, right_signed))) => {
+                let signed = left_signed || right_signed;
+                // A signed type needs twice the bits to hold every unsigned value
+                let widen = |bits: u32, is_signed: bool| if signed && !is_signed { bits * 2 } else { bits };
+                integer_type(widen(left_bits, left_signed).max(widen(right_bits, right_signed)), signed)
+                    .or(Some(Float64))
+            }
+            _ => match (float_width(left), float_width(right)) {
+                (Some(left_bits), Some(right_bits)) => float_type(left_bits.max(right_bits)),
+                (Some(_), None) if integer_width(right).is_some() => Some(Float64),
+                (None, Some(_)) if integer_width(left).is_some() => Some(Float64),
+                _ => None,
+            },
+        },
+    }
+}
+
+fn integer_width(data_type: &DataType) -> Option<(u32, bool)> {
+    use DataType::*;
+
+    match data_type {
+        Int8 => Some((8, true)),
+        Int16 => Some((16, true)),
+        Int32 => Some((32, true)),
+        Int64 => Some((64, true)),
+        UInt8 => Some((8, false)),
+        UInt16 => Some((16, false)),
+        UInt32 => Some((32, false)),
+        UInt64 => Some((64, false)),
+        _ => None,
+    }
+}
+
+fn integer_type(bits: u32, signed: bool) -> Option<DataType> {
+    use DataType::*;
+
+    match (bits, signed) {
+        (8, true) => Some(Int8),
+        (16, true) => Some(Int16),
+        (32, true) => Some(Int32),
+        (64, true) => Some(Int64),
+        (8, false) => Some(UInt8),
+        (16, false) => Some(UInt16),
+        (32, false) => Some(UInt32),
+        (64, false) => Some(UInt64),
+        _ => None,
+    }
+}
+
+fn float_width(data_type: &DataType) -> Option<u32> {
+    match data_type {
+        DataType::Float16 => Some(16),
+        DataType::Float32 => Some(32),
+        DataType::Float64 => Some(64),
+        _ => None,
+    }
+}
+
+fn float_type(bits: u32) -> Option<DataType> {
+    match bits {
+        16 => Some(DataType::Float16),
+        32 => Some(DataType::Float32),
+        64 => Some(DataType::Float64),
+        _ => None,
+    }
+}
+
+/// How a schema differs from the expected one, listing only the fields that differ.
+///
+/// `Display` prints one line per difference, so it stays readable for wide tables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaDiff {
+    /// Number of fields in the expected schema
+    pub expected_len: usize,
+    /// Number of fields in the actual schema
+    pub actual_len: usize,
+    /// Positions where the two schemas disagree, in order
+    pub fields: Vec<FieldDiff>,
+    /// Whether the schema-level metadata differs; only compared when strict
+    pub metadata_differs: bool,
+}
+
+/// A position where the expected and actual schemas disagree
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDiff {
+    pub index: usize,
+    /// The expected field, `None` when the actual schema has extra fields
+    pub expected: Option<FieldRef>,
+    /// The actual field, `None` when it is missing
+    pub actual: Option<FieldRef>,
+}
+
+impl std::fmt::Display for FieldDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe_type = |field: &Field| {
+            let nullable = if field.is_nullable() { "nullable" } else { "non-nullable" };
+            format!("{} ({})", field.data_type(), nullable)
+        };
+        let describe = |field: &Option<FieldRef>| match field {
+            Some(field) => format!("'{}' {}", field.name(), describe_type(field)),
+            None => "no field".to_string(),
+        };
+        match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) if expected.name() == actual.name() => {
+                let (expected_type, actual_type) = (describe_type(expected), describe_type(actual));
+                if expected_type == actual_type {
+                    write!(f, "field {} '{}': metadata differs", self.index, expected.name())
+                } else {
+                    write!(f, "field {} '{}': expected {}, found {}", self.index, expected.name(), expected_type, actual_type)
+                }
+            }
+            (expected, actual) => write!(
+                f,
+                "field {}: expected {}, found {}",
+                self.index,
+                describe(expected),
+                describe(actual)
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut lines = Vec::new();
+        if self.expected_len != self.actual_len {
+            lines.push(format!("expected {} fields, found {}", self.expected_len, self.actual_len));
+        }
+        lines.extend(self.fields.iter().take(MAX_REPORTED_DIFFERENCES).map(ToString::to_string));
+        if self.fields.len() > MAX_REPORTED_DIFFERENCES {
+            lines.push(format!("and {} more fields", self.fields.len() - MAX_REPORTED_DIFFERENCES));
+        }
+        if self.metadata_differs {
+            lines.push("schema metadata differs".to_string());
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for SchemaDiff {}
+
+/// Describe how `actual` differs from `expected`, or `None` if it conforms.
+///
+/// Field names and data types must match in order. Nullability and metadata
+/// only count when `strict` is set, also inside nested types; see
+/// [`types_conform`].
+pub fn schema_diff(expected: &Schema, actual: &Schema, strict: bool) -> Option<SchemaDiff> {
+    let len = expected.fields().len().max(actual.fields().len());
+    let fields: Vec<FieldDiff> = (0..len)
+        .filter_map(|index| {
+            let (left, right) = (expected.fields().get(index), actual.fields().get(index));
+            let conforms = match (left, right) {
+                (Some(left), Some(right)) => {
+                    left.name() == right.name() && fields_conform(left, right, strict)
+                }
+                _ => false,
+            };
+            (!conforms).then(|| FieldDiff {
+                index,
+                expected: left.cloned(),
+                actual: right.cloned(),
+            })
+        })
+        .collect();
+    let metadata_differs = strict && expected.metadata() != actual.metadata();
+
+    if fields.is_empty() && !metadata_differs {
+        return None;
+    }
+    Some(SchemaDiff {
+        expected_len: expected.fields().len(),
+        actual_len: actual.fields().len(),
+        fields,
+        metadata_differs,
+    })
+}
+
+fn fields_conform(expected: &Field, actual: &Field, strict: bool) -> bool {
+    types_conform(expected.data_type(), actual.data_type(), strict)
+        && (!strict || (expected.is_nullable() == actual.is_nullable() && expected.metadata() == actual.metadata()))
+}
+
+/// Whether a column of type `actual` can stand in for one of type `expected`.
+///
+/// Nested types are compared child by child: struct fields by name and type,
+/// list items and map keys and values by type only, since writers name them
+/// differently (`item` and `element`, `key` and `keys`). The nullability and
+/// metadata of children only count when `strict` is set.
+pub fn types_conform(expected: &DataType, actual: &DataType, strict: bool) -> bool {
+    use DataType::*;
+
+    match (expected, actual) {
+        (List(left), List(right)) | (LargeList(left), LargeList(right)) => fields_conform(left, right, strict),
+        (FixedSizeList(left, left_len), FixedSizeList(right, right_len)) => {
+            left_len == right_len && fields_conform(left, right, strict)
+        }
+        (Struct(left), Struct(right)) => {
+            left.len() == right.len()
+                && left
+                    .iter()
+                    .zip(right.iter())
+                    .all(|(left, right)| left.name() == right.name() && fields_conform(left, right, strict))
+        }
+        (Map(left, left_sorted), Map(right, right_sorted)) => match (left.data_type(), right.data_type()) {
+            (Struct(left), Struct(right)) => {
+                left_sorted == right_sorted
+                    && left.len() == right.len()
+                    && left.iter().zip(right.iter()).all(|(left, right)| fields_conform(left, right, strict))
+            }
+            _ => false,
+        },
+        _ => expected == actual,
+    }
+}
+
+/// Relabel `batch` with `expected` when the two conform under [`schema_diff`].
+///
+/// Batches that only differ in nullability or metadata, including that of
+/// nested children, take on `expected`, so every batch of a stream reports
+/// the same schema; a batch holding nulls in a column `expected` declares
+/// non-nullable is still an error.
+pub fn conform_batch(batch: RecordBatch, expected: &SchemaRef, strict: bool) -> Result<RecordBatch> {
+    if batch.schema() == *expected {
+        return Ok(batch);
+    }
+    if let Some(diff) = schema_diff(expected, &batch.schema(), strict) {
+        return Err(diff.into());
+    }
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(expected.fields())
+        .map(|(column, field)| -> Result<ArrayRef> {
+            if column.data_type() == field.data_type() {
+                return Ok(column.clone());
+            }
+            Ok(make_array(relabel_data(column.to_data(), field.data_type())?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(expected.clone(), columns)?)
+}
+
+/// `data` with the conforming type `to`, its children relabeled recursively
+fn relabel_data(data: ArrayData, to: &DataType) -> Result<ArrayData> {
+    if data.data_type() == to {
+        return Ok(data);
+    }
+    let child_types: Vec<&DataType> = match to {
+        DataType::List(field) | DataType::LargeList(field) | DataType::FixedSizeList(field, _) | DataType::Map(field, _) => {
+            vec![field.data_type()]
+        }
+        DataType::Struct(fields) => fields.iter().map(|field| field.data_type()).collect(),
+        _ => Vec::new(),
+    };
+    let children = data
+        .child_data()
+        .iter()
+        .zip(child_types)
+        .map(|(child, to)| relabel_data(child.clone(), to))
+        .collect::<Result<Vec<_>>>()?;
+    let builder = data.into_builder().data_type(to.clone());
+    let builder = if children.is_empty() { builder } else { builder.child_data(children) };
+    Ok(builder.build()?)
+}
+
+/// `schema` with the metadata of `source`: every field takes the metadata of
+/// the same-named field of `source`, and the schema its key-value metadata.
+/// Entries `schema` already has win over those of `source`.
+pub fn carry_metadata(schema: &Schema, source: &Schema) -> SchemaRef {
+    let fields: Vec<Field> = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let mut metadata = source
+                .field_with_name(field.name())
+                .map(|source| source.metadata().clone())
+                .unwrap_or_default();
+            metadata.extend(field.metadata().clone());
+            field.as_ref().clone().with_metadata(metadata)
+        })
+        .collect();
+    let mut metadata = source.metadata().clone();
+    metadata.extend(schema.metadata().clone());
+    std::sync::Arc::new(Schema::new_with_metadata(fields, metadata))
+}
+
+/// The schema-level and per-field metadata of `schema` as a JSON object with
+/// `metadata` and `fields` keys, or `None` when it has no metadata at all
+pub fn metadata_json(schema: &Schema) -> Option<serde_json::Value> {
+    let fields: serde_json::Map<String, serde_json::Value> = schema
+        .fields()
+        .iter()
+        .filter(|field| !field.metadata().is_empty())
+        .map(|field| (field.name().clone(), serde_json::json!(field.metadata())))
+        .collect();
+    if fields.is_empty() && schema.metadata().is_empty() {
+        return None;
+    }
+    Some(serde_json::json!({ "metadata": schema.metadata(), "fields": fields }))
+}
+
+/// Re-read written `data` with `format` and check its schema matches `expected`.
+///
+/// Field names and data types must match in order; this catches writer bugs
+/// and codecs that silently change column types.
+pub fn verify_written_schema(format: &dyn DataFormat, data: &Bytes, expected: &SchemaRef) -> Result<()> {
+    let actual: Schema = format.read(data)?.schema().into();
+    if let Some(diff) = schema_diff(expected, &actual, false) {
+        return Err(anyhow!("Written file schema does not match the expected schema:\n{}", diff));
+    }
+    Ok(())
+}
+
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write(&(value as u64).to_le_bytes());
+    }
+
+    fn write_str(&mut self, value: &str) {
+        // Length prefix keeps ("ab", "c") and ("a", "bc") distinct
+        self.write_usize(value.len());
+        self.write(value.as_bytes());
+    }
+
+    fn write_field(&mut self, field: &Field) {
+        self.write_str(field.name());
+        self.write_data_type(field.data_type());
+        self.write(&[field.is_nullable() as u8]);
+        self.write_metadata(field.metadata());
+    }
+
+    /// Nested types are hashed through their child fields, so the metadata of
+    /// those is sorted too; the `Debug` form of a leaf type has no maps in it
+    fn write_data_type(&mut self, data_type: &DataType) {
+        match data_type {
+            DataType::List(child) => {
+                self.write_str("List");
+                self.write_field(child);
+            }
+            DataType::LargeList(child) => {
+                self.write_str("LargeList");
+                self.write_field(child);
+            }
+            DataType::Map(child, sorted) => {
+                self.write_str("Map");
+                self.write(&[*sorted as u8]);
+                self.write_field(child);
+            }
+            DataType::FixedSizeList(child, size) => {
+                self.write_str("FixedSizeList");
+                self.write(&size.to_le_bytes());
+                self.write_field(child);
+            }
+            DataType::Struct(fields) => {
+                self.write_str("Struct");
+                self.write_usize(fields.len());
+                for field in fields {
+                    self.write_field(field);
+                }
+            }
+            DataType::Union(fields, mode) => {
+                self.write_str("Union");
+                self.write_str(&format!("{:?}", mode));
+                self.write_usize(fields.len());
+                for (type_id, field) in fields.iter() {
+                    self.write(&type_id.to_le_bytes());
+                    self.write_field(field);
+                }
+            }
+            DataType::Dictionary(key, value) => {
+                self.write_str("Dictionary");
+                self.write_data_type(key);
+                self.write_data_type(value);
+            }
+            DataType::RunEndEncoded(run_ends, values) => {
+                self.write_str("RunEndEncoded");
+                self.write_field(run_ends);
+                self.write_field(values);
+            }
+            leaf => self.write_str(&format!("{:?}", leaf)),
+        }
+    }
+
+    fn write_metadata(&mut self, metadata: &HashMap<String, String>) {
+        let mut entries: Vec<_> = metadata.iter().collect();
+        entries.sort();
+        self.write_usize(entries.len());
+        for (key, value) in entries {
+            self.write_str(key);
+            self.write_str(value);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Render the fields of `schema` as a table of name, type and nullability
+pub fn format_schema_table(schema: &Schema) -> Result<String> {
+    let column = |values: Vec<String>| -> ArrayRef { std::sync::Arc::new(StringArray::from(values)) };
+    let fields = schema.fields();
+    let batch = RecordBatch::try_from_iter(vec![
+        ("name", column(fields.iter().map(|f| f.name().clone()).collect())),
+        ("type", column(fields.iter().map(|f| f.data_type().to_string()).collect())),
+        ("nullable", column(fields.iter().map(|f| f.is_nullable().to_string()).collect())),
+    ])?;
+    Ok(pretty_format_batches(&[batch])?.to_string())
+}
+
+/// Describe the fields of `schema` as a JSON array of `{name, type, nullable}` objects
+pub fn format_schema_json(schema: &Schema) -> Result<String> {
+    let fields: Vec<serde_json::Value> = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            serde_json::json!({
+                "name": field.name(),
+                "type": field.data_type().to_string(),
+                "nullable": field.is_nullable(),
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&fields)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn schema(id_type: DataType) -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("id", id_type, false),
+            Field::new("name", DataType::Utf8, true),
+        ]))
+    }
+
+    #[test]
+    fn test_equal_schemas_have_equal_fingerprints() {
+        assert_eq!(
+            schema_fingerprint(&schema(DataType::Int64)),
+            schema_fingerprint(&schema(DataType::Int64))
+        );
+    }
+
+    #[test]
+    fn test_type_change_changes_fingerprint() {
+        assert_ne!(
+            schema_fingerprint(&schema(DataType::Int64)),
+            schema_fingerprint(&schema(DataType::Int32))
+        );
+    }
+
+    #[test]
+    fn test_coerce_batch_to_reference_schema() -> Result<()> {
+        use arrow::array::{Int32Array, Int64Array, StringArray};
+
+        let input = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("name", DataType::Utf8, true),
+                Field::new("id", DataType::Int64, false),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec!["a", "b"])),
+                Arc::new(Int64Array::from(vec![1, 2])),
+            ],
+        )?;
+        let target = schema(DataType::Int32);
+
+        let coerced = coerce_batch(&input, &target)?;
+        assert_eq!(coerced.schema(), target);
+        let ids = coerced.column(0).as_any().downcast_ref::<Int32Array>().unwrap();
+        assert_eq!(ids.values(), &[1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_coerce_batch_rejects_incompatible_columns() -> Result<()> {
+        use arrow::array::StringArray;
+
+        let input = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("id", DataType::Utf8, false),
+                Field::new("name", DataType::Utf8, true),
+            ])),
+            vec![
+                Arc::new(StringArray::from(vec!["not a number"])),
+                Arc::new(StringArray::from(vec!["a"])),
+            ],
+        )?;
+        assert!(coerce_batch(&input, &schema(DataType::Int32)).is_err());
+
+        let extra = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("other", DataType::Utf8, true)])),
+            vec![Arc::new(StringArray::from(vec!["x"]))],
+        )?;
+        assert!(coerce_batch(&extra, &schema(DataType::Int32)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_written_schema() -> Result<()> {
+        use crate::formats::ParquetFormat;
+        use arrow::array::{Int64Array, StringArray};
+
+        let expected = schema(DataType::Int64);
+        let batch = RecordBatch::try_new(
+            expected.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![1])),
+                Arc::new(StringArray::from(vec![Some("a")])),
+            ],
+        )?;
+        let format = ParquetFormat::default();
+        let data = format.write_batch(&batch)?;
+
+        verify_written_schema(&format, &data, &expected)?;
+
+        // Simulate a writer that produced the wrong column type
+        let err = verify_written_schema(&format, &data, &schema(DataType::Int32)).unwrap_err();
+        assert!(err.to_string().contains("field 0 'id': expected Int32"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_order_does_not_matter() {
+        let mut a = HashMap::new();
+        a.insert("k1".to_string(), "v1".to_string());
+        a.insert("k2".to_string(), "v2".to_string());
+        let mut b = HashMap::new();
+        b.insert("k2".to_string(), "v2".to_string());
+        b.insert("k1".to_string(), "v1".to_string());
+
+        let left = Arc::new(Schema::new_with_metadata(vec![Field::new("id", DataType::Int64, false)], a));
+        let right = Arc::new(Schema::new_with_metadata(vec![Field::new("id", DataType::Int64, false)], b));
+        assert_eq!(schema_fingerprint(&left), schema_fingerprint(&right));
+    }
+
+    #[test]
+    fn test_nested_metadata_order_does_not_matter() {
+        let nested = |metadata: &[(&str, &str)]| {
+            let metadata: HashMap<String, String> =
+                metadata.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            let item = Field::new("item", DataType::Float64, true).with_metadata(metadata);
+            Arc::new(Schema::new(vec![Field::new(
+                "point",
+                DataType::Struct(vec![Field::new("xs", DataType::List(Arc::new(item)), true)].into()),
+                true,
+            )]))
+        };
+        // Enough entries that two maps are unlikely to iterate in the same order
+        let entries: Vec<(String, String)> = (0..16).map(|i| (format!("k{}", i), format!("v{}", i))).collect();
+        let forward: Vec<(&str, &str)> = entries.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let backward: Vec<(&str, &str)> = forward.iter().rev().copied().collect();
+        assert_eq!(schema_fingerprint(&nested(&forward)), schema_fingerprint(&nested(&backward)));
+        assert_ne!(schema_fingerprint(&nested(&forward)), schema_fingerprint(&nested(&forward[1..])));
+    }
+
+    #[tokio::test]
+    async fn test_describe_inferred_csv_schema() -> Result<()> {
+        use crate::formats::CsvFormat;
+        use crate::storage::{local::LocalStorage, Storage};
+
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("sample.csv");
+        std::fs::write(&path, "id,price,label,active\n1,2.5,a,true\n2,1e3,,false\n")?;
+        let url = url::Url::from_file_path(&path).unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let size = storage.size(&url).await?;
+
+        let format: Arc<dyn DataFormat> = Arc::new(CsvFormat::default());
+        let schema = format.infer_schema(storage, &url, size).await?;
+        let table = format_schema_table(&schema)?;
+        for row in ["| id     | Int64   |", "| price  | Float64 |", "| label  | Utf8    |", "| active | Boolean |"] {
+            assert!(table.contains(row), "missing {:?} in\n{}", row, table);
+        }
+
+        let json: serde_json::Value = serde_json::from_str(&format_schema_json(&schema)?)?;
+        assert_eq!(json[0]["name"], "id");
+        assert_eq!(json[1]["type"], "Float64");
+        assert_eq!(json[2]["nullable"], true);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nullability_differences_conform_unless_strict() -> Result<()> {
+        use arrow::array::{Int64Array, StringArray};
+
+        let expected = schema(DataType::Int64);
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("id", DataType::Int64, true),
+                Field::new("name", DataType::Utf8, false),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![1])),
+                Arc::new(StringArray::from(vec!["a"])),
+            ],
+        )?;
+
+        assert_eq!(schema_diff(&expected, &batch.schema(), false), None);
+        assert_eq!(conform_batch(batch.clone(), &expected, false)?.schema(), expected);
+
+        let diff = schema_diff(&expected, &batch.schema(), true).unwrap().to_string();
+        assert!(diff.contains("field 0 'id': expected Int64 (non-nullable), found Int64 (nullable)"), "{}", diff);
+        assert!(conform_batch(batch, &expected, true).is_err());
+
+        // Nulls can't be relabelled into a non-nullable column
+        let nulls = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("id", DataType::Int64, true),
+                Field::new("name", DataType::Utf8, true),
+            ])),
+            vec![
+                Arc::new(Int64Array::from(vec![None::<i64>])),
+                Arc::new(StringArray::from(vec![Some("a")])),
+            ],
+        )?;
+        assert!(conform_batch(nulls, &expected, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_types_are_compared_child_by_child() -> Result<()> {
+        use arrow::array::{Array, ListArray};
+        use arrow::datatypes::{Fields, Int32Type};
+
+        let list = |name: &str, nullable: bool| DataType::List(Arc::new(Field::new(name, DataType::Int32, nullable)));
+        let point = |y: DataType| {
+            DataType::Struct(Fields::from(vec![
+                Field::new("x", DataType::Float64, true),
+                Field::new("y", y, true),
+            ]))
+        };
+
+        // Writers name list items differently; only their type counts
+        assert!(types_conform(&list("item", true), &list("element", false), false));
+        assert!(!types_conform(&list("item", true), &list("element", false), true));
+        assert!(!types_conform(&list("item", true), &DataType::List(Arc::new(Field::new("item", DataType::Int64, true))), false));
+        // Struct fields keep their names, and types are checked all the way down
+        assert!(types_conform(&point(DataType::Float64), &point(DataType::Float64), true));
+        assert!(!types_conform(&point(DataType::Float64), &point(DataType::Utf8), false));
+
+        let expected = Arc::new(Schema::new(vec![Field::new("values", list("item", true), true)]));
+        let values = ListArray::from_iter_primitive::<Int32Type, _, _>(vec![Some(vec![Some(1), Some(2)]), None]);
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("values", list("element", true), true)])),
+            vec![Arc::new(ListArray::new(
+                Arc::new(Field::new("element", DataType::Int32, true)),
+                values.offsets().clone(),
+                values.values().clone(),
+                values.nulls().cloned(),
+            ))],
+        )?;
+        let conformed = conform_batch(batch, &expected, false)?;
+        assert_eq!(conformed.schema(), expected);
+        assert_eq!(conformed.column(0).as_ref(), &values as &dyn Array);
+
+        let diff = schema_diff(
+            &Schema::new(vec![Field::new("p", point(DataType::Float64), true)]),
+            &Schema::new(vec![Field::new("p", point(DataType::Utf8), true)]),
+            false,
+        );
+        assert!(diff.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata_differences_conform_unless_strict() {
+        let metadata = HashMap::from([("origin".to_string(), "upload".to_string())]);
+        let expected = schema(DataType::Int64);
+        let fields: Vec<Field> = expected
+            .fields()
+            .iter()
+            .map(|f| f.as_ref().clone().with_metadata(metadata.clone()))
+            .collect();
+        let actual = Schema::new_with_metadata(fields, metadata);
+
+        assert_eq!(schema_diff(&expected, &actual, false), None);
+        let diff = schema_diff(&expected, &actual, true).unwrap().to_string();
+        assert!(diff.contains("field 0 'id': metadata differs"), "{}", diff);
+        assert!(diff.contains("schema metadata differs"), "{}", diff);
+    }
+
+    #[test]
+    fn test_schema_diff_lists_first_mismatches() {
+        let columns = |data_type: DataType| -> Schema {
+            Schema::new((0..12).map(|i| Field::new(format!("c{}", i), data_type.clone(), true)).collect::<Vec<_>>())
+        };
+        let (expected, actual) = (columns(DataType::Int64), columns(DataType::Utf8));
+
+        let diff = schema_diff(&expected, &actual, false).unwrap();
+        assert_eq!(diff.fields.len(), 12);
+        let message = diff.to_string();
+        assert!(message.starts_with("field 0 'c0': expected Int64 (nullable), found Utf8 (nullable)\n"), "{}", message);
+        assert!(message.contains("field 9 'c9'") && !message.contains("field 10 "), "{}", message);
+        assert!(message.ends_with("and 2 more fields"), "{}", message);
+    }
+
+    #[test]
+    fn test_schema_diff_lists_only_differing_fields() {
+        let fields: Vec<Field> = (0..20).map(|i| Field::new(format!("c{}", i), DataType::Int64, false)).collect();
+        let expected = Schema::new(fields.clone());
+        let mut actual = fields;
+        actual[4] = Field::new("c4", DataType::Utf8, false);
+        actual[17] = Field::new("renamed", DataType::Int64, true);
+        let actual = Schema::new(actual);
+
+        let diff = schema_diff(&expected, &actual, false).unwrap();
+        let indices: Vec<usize> = diff.fields.iter().map(|field| field.index).collect();
+        assert_eq!(indices, vec![4, 17]);
+        assert_eq!(diff.fields[0].actual.as_ref().map(|f| f.data_type().clone()), Some(DataType::Utf8));
+        assert!(!diff.metadata_differs);
+
+        assert_eq!(
+            diff.to_string(),
+            "field 4 'c4': expected Int64 (non-nullable), found Utf8 (non-nullable)\n\
+             field 17: expected 'c17' Int64 (non-nullable), found 'renamed' Int64 (nullable)"
+        );
+    }
+
+    #[test]
+    fn test_schema_diff_reports_missing_fields() {
+        let expected = Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]);
+        let actual = Schema::new(vec![Field::new("id", DataType::Int64, false)]);
+
+        let diff = schema_diff(&expected, &actual, false).unwrap().to_string();
+        assert_eq!(diff, "expected 2 fields, found 1\nfield 1: expected 'name' Utf8 (nullable), found no field");
+    }
+
+    #[test]
+    fn test_unify_schemas() -> Result<()> {
+        let first = Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("score", DataType::UInt32, false),
+        ]);
+        let second = Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("score", DataType::Int16, false),
+            Field::new("label", DataType::Utf8, false),
+        ]);
+
+        let unified = unify_schemas([&first, &second])?;
+        let expected = Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("score", DataType::Int64, false),
+            Field::new("label", DataType::Utf8, true),
+        ]);
+        assert_eq!(*unified, expected);
+
+        let floats = Schema::new(vec![Field::new("id", DataType::Float32, false)]);
+        assert_eq!(unify_schemas([&first, &floats])?.field(0).data_type(), &DataType::Float64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unify_schemas_rejects_incompatible_types() {
+        let text = Schema::new(vec![Field::new("value", DataType::Utf8, true)]);
+        let nested = Schema::new(vec![Field::new(
+            "value",
+            DataType::Struct(vec![Field::new("a", DataType::Int32, true)].into()),
+            true,
+        )]);
+        let err = unify_schemas([&text, &nested]).unwrap_err();
+        assert!(err.to_string().contains("Cannot unify column 'value'"), "{}", err);
+    }
+
+    #[test]
+    fn test_rename_then_cast_int_to_string() -> Result<()> {
+        let batch = RecordBatch::try_from_iter(vec![
+            ("id", Arc::new(arrow::array::Int64Array::from(vec![1, 20, 300])) as ArrayRef),
+            ("name", Arc::new(StringArray::from(vec!["a", "b", "c"])) as ArrayRef),
+        ])?;
+        // The cast refers to the column by its new name, so the order matters
+        let transforms = vec![
+            ColumnTransform::parse_rename("id=user_id")?,
+            ColumnTransform::parse_cast("user_id=Utf8")?,
+        ];
+
+        let transformed = transform_batch(batch, &transforms)?;
+        let schema = transformed.schema();
+        assert_eq!(schema.field(0).name(), "user_id");
+        assert_eq!(schema.field(0).data_type(), &DataType::Utf8);
+        assert_eq!(schema.field(1).name(), "name");
+        let ids = transformed.column(0).as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(ids.iter().collect::<Vec<_>>(), vec![Some("1"), Some("20"), Some("300")]);
+
+        let reversed = vec![transforms[1].clone(), transforms[0].clone()];
+        assert!(transform_schema(&schema, &reversed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_casts_name_the_column() -> Result<()> {
+        let schema = Schema::new(vec![Field::new("label", DataType::Utf8, true)]);
+        let nested = ColumnTransform::Cast {
+            column: "label".to_string(),
+            to: DataType::Struct(vec![Field::new("a", DataType::Int32, true)].into()),
+        };
+        let err = transform_schema(&schema, &[nested]).unwrap_err();
+        assert!(err.to_string().contains("Cannot cast column 'label'"), "{}", err);
+
+        let batch = RecordBatch::try_from_iter(vec![("label", Arc::new(StringArray::from(vec!["abc"])) as ArrayRef)])?;
+        let err = transform_batch(batch, &[ColumnTransform::parse_cast("label=Int64")?]).unwrap_err();
+        assert!(err.to_string().contains("Failed to cast column 'label' to Int64"), "{}", err);
+
+        assert!(ColumnTransform::parse_cast("label=Decimal").is_err());
+        assert!(ColumnTransform::parse_rename("label").is_err());
+        Ok(())
+    }
+}
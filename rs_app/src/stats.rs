@@ -0,0 +1,280 @@
+use arrow::array::{Array, ArrayRef, Float64Array, StringArray, UInt64Array};
+use arrow::compute::{cast, max, min};
+use arrow::datatypes::{DataType, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use arrow::util::pretty::pretty_format_batches;
+use datafusion::physical_plan::{ColumnStatistics, Statistics};
+use datafusion::scalar::ScalarValue;
+use futures::TryStreamExt;
+
+use crate::error::{Error, Result};
+use crate::formats::BatchStream;
+
+/// Null count and, for numeric columns, value range of one column
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnStats {
+    pub name: String,
+    pub null_count: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+/// Row count and per-column statistics of an input
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputStats {
+    pub rows: usize,
+    pub columns: Vec<ColumnStats>,
+}
+
+/// Integers up to this magnitude are held exactly by an f64
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Whether min/max are tracked for columns of `data_type`
+pub fn is_numeric(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32
+            | DataType::UInt64
+            | DataType::Float32
+            | DataType::Float64
+    )
+}
+
+pub(crate) fn merge_min(current: Option<f64>, value: Option<f64>) -> Option<f64> {
+    match (current, value) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+pub(crate) fn merge_max(current: Option<f64>, value: Option<f64>) -> Option<f64> {
+    match (current, value) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl InputStats {
+    /// Empty statistics for a table of `schema`
+    pub fn new(schema: &SchemaRef) -> Self {
+        Self {
+            rows: 0,
+            columns: schema
+                .fields()
+                .iter()
+                .map(|field| ColumnStats {
+                    name: field.name().clone(),
+                    null_count: 0,
+                    min: None,
+                    max: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Add the rows of `batch`, which must have the schema these stats were created for
+    pub fn update(&mut self, batch: &RecordBatch) -> Result<()> {
+        self.rows += batch.num_rows();
+        for (stats, column) in self.columns.iter_mut().zip(batch.columns()) {
+            stats.null_count += column.null_count();
+            if is_numeric(column.data_type()) {
+                let values = cast(column, &DataType::Float64)?;
+                let values = values.as_any().downcast_ref::<Float64Array>().expect("cast to Float64");
+                stats.min = merge_min(stats.min, min(values));
+                stats.max = merge_max(stats.max, max(values));
+            }
+        }
+        Ok(())
+    }
+
+    /// Combine the statistics of two inputs with the same columns
+    pub fn merge(mut self, other: InputStats) -> Result<Self> {
+        let names = |stats: &InputStats| stats.columns.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        if names(&self) != names(&other) {
+            return Err(Error::SchemaMismatch(format!(
+                "Cannot combine statistics of inputs with different columns: {:?} and {:?}",
+                names(&self),
+                names(&other)
+            )));
+        }
+        self.rows += other.rows;
+        for (column, other) in self.columns.iter_mut().zip(other.columns) {
+            column.null_count += other.null_count;
+            column.min = merge_min(column.min, other.min);
+            column.max = merge_max(column.max, other.max);
+        }
+        Ok(self)
+    }
+
+    /// Exact DataFusion statistics for a table of `schema` with these columns
+    pub fn to_statistics(&self, schema: &SchemaRef) -> Statistics {
+        // Bounds are tracked as f64; cast them back to the column type. Integer
+        // bounds past 2^53 may have been rounded, and float bounds leave out
+        // NaN, which DataFusion sorts above every number, so both are left out
+        // rather than let DataFusion answer MIN/MAX with a wrong value
+        let bound = |value: Option<f64>, data_type: &DataType| {
+            value
+                .filter(|v| !data_type.is_floating() && v.abs() <= MAX_EXACT_INTEGER)
+                .and_then(|v| {
+                    let array = cast(&Float64Array::from(vec![v]), data_type).ok()?;
+                    ScalarValue::try_from_array(&array, 0).ok()
+                })
+        };
+        let column_statistics = schema
+            .fields()
+            .iter()
+            .zip(&self.columns)
+            .map(|(field, column)| ColumnStatistics {
+                null_count: Some(column.null_count),
+                min_value: bound(column.min, field.data_type()),
+                max_value: bound(column.max, field.data_type()),
+                distinct_count: None,
+            })
+            .collect();
+        Statistics {
+            num_rows: Some(self.rows),
+            total_byte_size: None,
+            column_statistics: Some(column_statistics),
+            is_exact: true,
+        }
+    }
+
+    /// Render the statistics as a table with one row per column
+    pub fn to_table(&self) -> Result<String> {
+        let bound = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+        let columns: Vec<(&str, ArrayRef)> = vec![
+            (
+                "column",
+                std::sync::Arc::new(StringArray::from_iter_values(self.columns.iter().map(|c| c.name.as_str()))),
+            ),
+            (
+                "nulls",
+                std::sync::Arc::new(UInt64Array::from_iter_values(self.columns.iter().map(|c| c.null_count as u64))),
+            ),
+            (
+                "min",
+                std::sync::Arc::new(StringArray::from_iter_values(self.columns.iter().map(|c| bound(c.min)))),
+            ),
+            (
+                "max",
+                std::sync::Arc::new(StringArray::from_iter_values(self.columns.iter().map(|c| bound(c.max)))),
+            ),
+        ];
+        let batch = RecordBatch::try_from_iter(columns)?;
+        Ok(format!("Total rows: {}\n{}", self.rows, pretty_format_batches(&[batch])?))
+    }
+}
+
+/// Combine the DataFusion statistics of two files of the same table.
+///
+/// A figure is only kept when both sides have it.
+pub fn merge_statistics(left: Statistics, right: Statistics) -> Statistics {
+    let add = |a: Option<usize>, b: Option<usize>| Some(a? + b?);
+    let bound = |a: Option<ScalarValue>, b: Option<ScalarValue>, keep_left: fn(std::cmp::Ordering) -> bool| {
+        let (a, b) = (a?, b?);
+        match a.partial_cmp(&b)? {
+            ordering if keep_left(ordering) => Some(a),
+            _ => Some(b),
+        }
+    };
+    let column_statistics = left.column_statistics.zip(right.column_statistics).map(|(left, right)| {
+        left.into_iter()
+            .zip(right)
+            .map(|(a, b)| ColumnStatistics {
+                null_count: add(a.null_count, b.null_count),
+                min_value: bound(a.min_value, b.min_value, std::cmp::Ordering::is_le),
+                max_value: bound(a.max_value, b.max_value, std::cmp::Ordering::is_ge),
+                distinct_count: None,
+            })
+            .collect()
+    });
+    Statistics {
+        num_rows: add(left.num_rows, right.num_rows),
+        total_byte_size: add(left.total_byte_size, right.total_byte_size),
+        column_statistics,
+        is_exact: left.is_exact && right.is_exact,
+    }
+}
+
+/// Accumulate statistics over every batch of a stream of `schema`
+pub async fn collect_stats(schema: &SchemaRef, mut batches: BatchStream) -> Result<InputStats> {
+    let mut stats = InputStats::new(schema);
+    while let Some(batch) = batches.try_next().await? {
+        stats.update(&batch)?;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{CsvFormat, DataFormat};
+    use crate::storage::{local::LocalStorage, Storage};
+    use std::sync::Arc;
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_count_known_csv() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let path = temp_dir.path().join("known.csv");
+        std::fs::write(&path, "id,score,label\n1,2.5,a\n2,,b\n3,-4,\n4,10,d\n5,,e\n")?;
+        let url = Url::from_file_path(&path).unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let format: Arc<dyn DataFormat> = Arc::new(CsvFormat::default());
+
+        let size = storage.size(&url).await?;
+        let stats = format.compute_stats(storage, &url, size).await?;
+        assert_eq!(stats.rows, 5);
+        assert_eq!(stats.columns[0], ColumnStats { name: "id".into(), null_count: 0, min: Some(1.0), max: Some(5.0) });
+        assert_eq!(stats.columns[1].null_count, 2);
+        assert_eq!((stats.columns[1].min, stats.columns[1].max), (Some(-4.0), Some(10.0)));
+        assert_eq!(stats.columns[2], ColumnStats { name: "label".into(), null_count: 1, min: None, max: None });
+
+        let table = stats.to_table()?;
+        assert!(table.starts_with("Total rows: 5"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rounded_integer_bounds_are_not_exact_statistics() {
+        let schema = Arc::new(arrow::datatypes::Schema::new(vec![
+            arrow::datatypes::Field::new("small", DataType::Int64, false),
+            arrow::datatypes::Field::new("large", DataType::Int64, false),
+        ]));
+        let mut stats = InputStats::new(&schema);
+        stats.columns[0].min = Some(-3.0);
+        stats.columns[0].max = Some(7.0);
+        stats.columns[1].max = Some(i64::MAX as f64);
+
+        let columns = stats.to_statistics(&schema).column_statistics.unwrap();
+        assert_eq!(columns[0].min_value, Some(ScalarValue::Int64(Some(-3))));
+        assert_eq!(columns[0].max_value, Some(ScalarValue::Int64(Some(7))));
+        assert_eq!(columns[1].max_value, None);
+    }
+
+    #[test]
+    fn test_float_bounds_are_not_exact_statistics() {
+        let schema = Arc::new(arrow::datatypes::Schema::new(vec![
+            arrow::datatypes::Field::new("f32", DataType::Float32, true),
+            arrow::datatypes::Field::new("f64", DataType::Float64, true),
+        ]));
+        let mut stats = InputStats::new(&schema);
+        for column in &mut stats.columns {
+            column.min = Some(-1.5);
+            column.max = Some(2.5);
+        }
+
+        let statistics = stats.to_statistics(&schema);
+        assert!(statistics.is_exact);
+        for column in statistics.column_statistics.unwrap() {
+            assert_eq!((column.min_value, column.max_value), (None, None));
+            assert_eq!(column.null_count, Some(0));
+        }
+    }
+}
@@ -1,65 +1,264 @@
-use anyhow::Result;
+use std::ops::Range;
+
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::Stream;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
 use futures::StreamExt;
-use object_store::azure::MicrosoftAzureBuilder;
-use object_store::{ObjectStore, path::Path as ObjectPath};
+use object_store::azure::{AzureConfigKey, MicrosoftAzureBuilder};
+use object_store::{ObjectMeta, ObjectStore, path::Path as ObjectPath};
 use url::Url;
 
+use crate::config::RetryConfig;
+use crate::error::{Error, Result};
+use crate::metrics;
+use super::multipart::{self, MultipartConfig};
+use super::stats::{InstrumentedStore, StorageStatsCollector};
+use super::retry::with_retry;
+
+/// Credential source for Azure Blob Storage, in order of precedence
+#[derive(Debug, Clone, PartialEq)]
+enum AzureCredentials {
+    /// `AZURE_STORAGE_CONNECTION_STRING`
+    ConnectionString(String),
+    /// `AZURE_STORAGE_SAS_TOKEN` together with `AZURE_STORAGE_ACCOUNT`
+    SasToken { account: String, token: String },
+    /// `AZURE_STORAGE_ACCOUNT` and `AZURE_STORAGE_ACCESS_KEY`
+    AccessKey { account: String, key: String },
+}
+
+impl AzureCredentials {
+    fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+
+        if let Some(connection_string) = var("AZURE_STORAGE_CONNECTION_STRING") {
+            return Ok(Self::ConnectionString(connection_string));
+        }
+        if let (Some(account), Some(token)) = (var("AZURE_STORAGE_ACCOUNT"), var("AZURE_STORAGE_SAS_TOKEN")) {
+            return Ok(Self::SasToken { account, token });
+        }
+        if let (Some(account), Some(key)) = (var("AZURE_STORAGE_ACCOUNT"), var("AZURE_STORAGE_ACCESS_KEY")) {
+            return Ok(Self::AccessKey { account, key });
+        }
+        Err(Error::storage(
+            "No Azure credentials found; set AZURE_STORAGE_CONNECTION_STRING, \
+             AZURE_STORAGE_ACCOUNT with AZURE_STORAGE_SAS_TOKEN, or AZURE_STORAGE_ACCOUNT with AZURE_STORAGE_ACCESS_KEY"
+        ))
+    }
+}
+
 pub struct AzureStorage {
     store: Box<dyn ObjectStore>,
     container: String,
+    retry: RetryConfig,
+    multipart: MultipartConfig,
 }
 
 impl AzureStorage {
     pub fn new(container: String) -> Result<Self> {
-        let store = MicrosoftAzureBuilder::new()
-            .with_container_name(&container)
-            .build()?;
+        let store = Self::builder(&container, AzureCredentials::from_env()?)?.build()?;
         Ok(Self {
             store: Box::new(store),
             container,
+            retry: RetryConfig::default(),
+            multipart: MultipartConfig::default(),
         })
     }
 
+    fn builder(container: &str, credentials: AzureCredentials) -> Result<MicrosoftAzureBuilder> {
+        let builder = MicrosoftAzureBuilder::new().with_container_name(container);
+        let builder = match credentials {
+            AzureCredentials::ConnectionString(connection_string) => {
+                let mut builder = builder;
+                for (key, value) in parse_connection_string(&connection_string)? {
+                    builder = match key.as_str() {
+                        "AccountName" => builder.with_account(value),
+                        "AccountKey" => builder.with_access_key(value),
+                        "SharedAccessSignature" => builder.with_config(AzureConfigKey::SasKey, value),
+                        "BlobEndpoint" => builder.with_endpoint(value),
+                        _ => builder,
+                    };
+                }
+                builder
+            }
+            AzureCredentials::SasToken { account, token } => builder
+                .with_account(account)
+                .with_config(AzureConfigKey::SasKey, token.trim_start_matches('?')),
+            AzureCredentials::AccessKey { account, key } => builder
+                .with_account(account)
+                .with_access_key(key),
+        };
+        Ok(builder)
+    }
+
+    /// Use the given retry policy for storage operations
+    pub fn with_retry_config(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Use the given multipart settings for large writes
+    pub fn with_multipart_config(mut self, multipart: MultipartConfig) -> Self {
+        self.multipart = multipart;
+        self
+    }
+
+    /// Count the requests sent to the backend into `stats`; listings are
+    /// paged by 5000 entries
+    pub fn with_stats(mut self, stats: StorageStatsCollector) -> Self {
+        self.store = Box::new(InstrumentedStore::new(self.store, "azure", stats).with_list_page_size(5000));
+        self
+    }
+
     fn get_object_path(&self, url: &Url) -> Result<ObjectPath> {
         let path = url.path();
         Ok(ObjectPath::from(path))
     }
+
+    async fn read_all_with_retry(&self, path: &ObjectPath) -> Result<Bytes> {
+        let store = &self.store;
+        with_retry(&self.retry, || async move {
+            let data = store.get(path).await?.bytes().await?;
+            Ok(data)
+        })
+        .await
+    }
 }
 
 #[async_trait]
 impl super::Storage for AzureStorage {
-    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
-        let prefix = prefix.unwrap_or("");
-        let path = ObjectPath::from(prefix);
-        let mut entries = Vec::new();
-        let mut stream = self.store.list(Some(&path));
-        while let Some(entry) = stream.next().await {
-            let entry = entry?;
-            entries.push(entry.location.to_string());
-        }
-        Ok(entries)
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        Ok(super::list_object_store(self.store.as_ref(), prefix))
     }
 
-    async fn read(&self, url: &Url) -> Result<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + Sync + Unpin + 'static>> {
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
         let path = self.get_object_path(url)?;
-        let result = self.store.get(&path).await?;
-        let bytes = result.bytes().await?;
-        let stream = futures::stream::once(futures::future::ready(Ok(bytes)));
-        Ok(Box::new(Box::pin(stream)))
+        let store = &self.store;
+        let path = &path;
+        let result = with_retry(&self.retry, || async move { Ok(store.get(path).await?) }).await?;
+        Ok(result
+            .into_stream()
+            .map_err(Error::from)
+            .inspect_ok(|chunk| metrics::bytes_read("azure", chunk.len()))
+            .boxed())
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
     async fn read_all(&self, url: &Url) -> Result<Bytes> {
         let path = self.get_object_path(url)?;
-        let data = self.store.get(&path).await?.bytes().await?;
+        let data = self.read_all_with_retry(&path).await?;
+        tracing::debug!(bytes = data.len(), "read object");
+        metrics::bytes_read("azure", data.len());
         Ok(data)
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url, ?range))]
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let path = self.get_object_path(url)?;
+        let store = &self.store;
+        let path = &path;
+        let data = with_retry(&self.retry, || {
+            let range = range.clone();
+            async move { Ok(store.get_range(path, range).await?) }
+        })
+        .await?;
+        tracing::debug!(bytes = data.len(), "read object");
+        metrics::bytes_read("azure", data.len());
+        Ok(data)
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        let path = self.get_object_path(url)?;
+        let store = &self.store;
+        let path = &path;
+        with_retry(&self.retry, || async move { Ok(store.head(path).await?.size) }).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url, bytes = data.len()))]
     async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
         let path = self.get_object_path(url)?;
-        self.store.put(&path, data.into()).await?;
+        let store = self.store.as_ref();
+        let path = &path;
+        let config = &self.multipart;
+        with_retry(&self.retry, || multipart::put(store, path, data.clone(), config)).await?;
+        metrics::bytes_written("azure", data.len());
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn write_stream(&self, url: &Url, stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        let path = self.get_object_path(url)?;
+        let stream = stream
+            .inspect_ok(|chunk| metrics::bytes_written("azure", chunk.len()))
+            .boxed();
+        multipart::put_stream(self.store.as_ref(), &path, stream, &self.multipart).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn delete(&self, url: &Url) -> Result<()> {
+        let path = self.get_object_path(url)?;
+        let store = &self.store;
+        let path = &path;
+        with_retry(&self.retry, || async move { Ok(store.delete(path).await?) }).await
+    }
+}
+
+/// Split an Azure connection string (`Key=Value;Key=Value`) into its parts
+fn parse_connection_string(connection_string: &str) -> Result<Vec<(String, String)>> {
+    connection_string
+        .split(';')
+        .filter(|part| !part.trim().is_empty())
+        .map(|part| {
+            part.split_once('=')
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| Error::storage(format!("Invalid Azure connection string segment: {}", part)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_string_credentials() -> Result<()> {
+        let credentials = AzureCredentials::ConnectionString(
+            "DefaultEndpointsProtocol=https;AccountName=acct;AccountKey=a2V5;EndpointSuffix=core.windows.net".to_string(),
+        );
+        let builder = AzureStorage::builder("container", credentials)?;
+        assert_eq!(builder.get_config_value(&AzureConfigKey::AccountName).as_deref(), Some("acct"));
+        assert_eq!(builder.get_config_value(&AzureConfigKey::AccessKey).as_deref(), Some("a2V5"));
+        assert!(builder.build().is_ok());
         Ok(())
     }
+
+    #[test]
+    fn test_sas_token_credentials() -> Result<()> {
+        let credentials = AzureCredentials::SasToken {
+            account: "acct".to_string(),
+            token: "?sv=2022-11-02&sig=abc".to_string(),
+        };
+        let builder = AzureStorage::builder("container", credentials)?;
+        assert_eq!(builder.get_config_value(&AzureConfigKey::AccountName).as_deref(), Some("acct"));
+        assert!(builder.build().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_key_credentials() -> Result<()> {
+        let credentials = AzureCredentials::AccessKey {
+            account: "acct".to_string(),
+            key: "a2V5".to_string(),
+        };
+        let builder = AzureStorage::builder("container", credentials)?;
+        assert_eq!(builder.get_config_value(&AzureConfigKey::AccessKey).as_deref(), Some("a2V5"));
+        assert!(builder.build().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_connection_string() {
+        assert!(parse_connection_string("AccountName=acct;garbage").is_err());
+    }
 }
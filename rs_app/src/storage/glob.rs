@@ -0,0 +1,94 @@
+use crate::error::{Error, Result};
+
+/// Whether `pattern` contains any wildcard characters
+pub fn is_glob(pattern: &str) -> bool {
+    pattern.contains('*') || pattern.contains('?')
+}
+
+/// Split a URL pattern like `s3://bucket/year=*/*.parquet` into the base
+/// (`s3://bucket`) and the key pattern (`year=*/*.parquet`).
+///
+/// The pattern can't go through `Url::parse` because `?` would start a query.
+pub fn split_url_pattern(pattern: &str) -> Result<(&str, &str)> {
+    let scheme_end = pattern
+        .find("://")
+        .ok_or_else(|| Error::storage(format!("Invalid URL pattern: {}", pattern)))?
+        + 3;
+    let key_start = pattern[scheme_end..]
+        .find('/')
+        .map(|i| scheme_end + i)
+        .unwrap_or(pattern.len());
+    Ok((&pattern[..key_start], pattern[key_start..].trim_start_matches('/')))
+}
+
+/// The longest directory prefix of `pattern` that contains no wildcards,
+/// used to avoid listing the whole bucket
+pub fn literal_prefix(pattern: &str) -> &str {
+    let wildcard = pattern.find(['*', '?']).unwrap_or(pattern.len());
+    match pattern[..wildcard].rfind('/') {
+        Some(i) => &pattern[..=i],
+        None => "",
+    }
+}
+
+/// Match `path` against `pattern`, segment by segment.
+///
+/// `*` and `?` match within a single path segment; `**` matches any number of
+/// whole segments, including none.
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let path: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match_segments(&pattern, &path)
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((name, path_rest)) => match_segment(segment.as_bytes(), name.as_bytes()) && match_segments(rest, path_rest),
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => match_segment(&pattern[1..], name) || (!name.is_empty() && match_segment(pattern, &name[1..])),
+        (Some(b'?'), Some(_)) => match_segment(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => match_segment(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_url_pattern() -> Result<()> {
+        assert_eq!(split_url_pattern("s3://bucket/year=2023/*.parquet")?, ("s3://bucket", "year=2023/*.parquet"));
+        assert_eq!(split_url_pattern("file:///tmp/data/?.csv")?, ("file://", "tmp/data/?.csv"));
+        assert!(split_url_pattern("no-scheme/*.csv").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(literal_prefix("year=2023/*.parquet"), "year=2023/");
+        assert_eq!(literal_prefix("data/year=*/month=01/*.csv"), "data/");
+        assert_eq!(literal_prefix("*.csv"), "");
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("data/*.csv", "data/a.csv"));
+        assert!(!matches("data/*.csv", "data/sub/a.csv"));
+        assert!(matches("data/?.csv", "data/a.csv"));
+        assert!(!matches("data/?.csv", "data/ab.csv"));
+        assert!(matches("data/**/*.csv", "data/a.csv"));
+        assert!(matches("data/**/*.csv", "data/x/y/a.csv"));
+        assert!(!matches("data/**/*.csv", "other/a.csv"));
+    }
+}
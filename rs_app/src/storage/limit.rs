@@ -0,0 +1,141 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use object_store::ObjectMeta;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use url::Url;
+
+use super::Storage;
+use crate::error::{Error, Result};
+
+/// Storage that sends at most a fixed number of requests to the wrapped
+/// storage at once.
+///
+/// Wrappers sharing a semaphore share its permits, so one limit can cover
+/// the input and output storage of a conversion. A permit is held for one
+/// request: `read` releases it once the object is opened, and `write_stream`
+/// is not gated since an upload lasts as long as the conversion feeding it;
+/// its parts are bounded by `MultipartConfig::max_concurrency` instead.
+pub struct LimitedStorage {
+    inner: Arc<dyn Storage>,
+    semaphore: Arc<Semaphore>,
+}
+
+impl LimitedStorage {
+    pub fn new(inner: Arc<dyn Storage>, semaphore: Arc<Semaphore>) -> Self {
+        Self { inner, semaphore }
+    }
+
+    /// Wrap `inner` with its own limit of `max_concurrent_requests`
+    pub fn with_limit(inner: Arc<dyn Storage>, max_concurrent_requests: usize) -> Self {
+        Self::new(inner, Arc::new(Semaphore::new(max_concurrent_requests.max(1))))
+    }
+
+    async fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        self.semaphore
+            .acquire()
+            .await
+            .map_err(|_| Error::storage("Storage request limit was closed"))
+    }
+}
+
+#[async_trait]
+impl Storage for LimitedStorage {
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        let _permit = self.acquire().await?;
+        self.inner.list_stream(prefix).await
+    }
+
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let _permit = self.acquire().await?;
+        self.inner.read(url).await
+    }
+
+    async fn read_all(&self, url: &Url) -> Result<Bytes> {
+        let _permit = self.acquire().await?;
+        self.inner.read_all(url).await
+    }
+
+    async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
+        let _permit = self.acquire().await?;
+        self.inner.write(url, data).await
+    }
+
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let _permit = self.acquire().await?;
+        self.inner.read_range(url, range).await
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        let _permit = self.acquire().await?;
+        self.inner.size(url).await
+    }
+
+    async fn checksum(&self, url: &Url) -> Result<Option<String>> {
+        let _permit = self.acquire().await?;
+        self.inner.checksum(url).await
+    }
+
+    async fn write_stream(&self, url: &Url, stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        self.inner.write_stream(url, stream).await
+    }
+
+    async fn append(&self, url: &Url, data: Bytes) -> Result<()> {
+        let _permit = self.acquire().await?;
+        self.inner.append(url, data).await
+    }
+
+    async fn delete(&self, url: &Url) -> Result<()> {
+        let _permit = self.acquire().await?;
+        self.inner.delete(url).await
+    }
+
+    async fn list_glob_meta(&self, pattern: &str) -> Result<Vec<(Url, ObjectMeta)>> {
+        let _permit = self.acquire().await?;
+        self.inner.list_glob_meta(pattern).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::MockStorage;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_reads_never_exceed_the_limit() -> Result<()> {
+        const LIMIT: usize = 3;
+        let inner = Arc::new(MockStorage::new().with_latency(Duration::from_millis(20)));
+        let url = |i: usize| Url::parse(&format!("s3://bucket/file-{}.csv", i)).unwrap();
+        for i in 0..20 {
+            inner.put(&url(i), Bytes::from_static(b"data")).await?;
+        }
+        let storage = Arc::new(LimitedStorage::with_limit(inner.clone(), LIMIT));
+
+        let reads = (0..20).map(|i| {
+            let storage = storage.clone();
+            tokio::spawn(async move { storage.read_all(&url(i)).await })
+        });
+        for read in futures::future::join_all(reads).await {
+            read.expect("read task panicked")?;
+        }
+        assert_eq!(inner.max_in_flight(), LIMIT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append_reaches_the_inner_storage() -> Result<()> {
+        let inner = Arc::new(MockStorage::new());
+        let storage = LimitedStorage::with_limit(inner.clone(), 1);
+        let url = Url::parse("s3://bucket/out.csv")?;
+
+        storage.append(&url, Bytes::from_static(b"a\n")).await?;
+        storage.append(&url, Bytes::from_static(b"b\n")).await?;
+        assert_eq!((inner.appends(), inner.reads(), inner.writes()), (2, 0, 0));
+        assert_eq!(storage.read_all(&url).await?, Bytes::from_static(b"a\nb\n"));
+        Ok(())
+    }
+}
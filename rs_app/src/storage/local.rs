@@ -1,65 +1,226 @@
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
-use anyhow::Result;
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::Stream;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
 use futures::StreamExt;
 use object_store::local::LocalFileSystem;
-use object_store::{ObjectStore, path::Path as ObjectPath};
+use object_store::{ObjectMeta, ObjectStore, path::Path as ObjectPath};
+use percent_encoding::percent_decode_str;
 use tokio::fs;
 use url::Url;
 
+use crate::error::{Error, Result};
+use crate::metrics;
+use super::multipart::{self, MultipartConfig};
+use super::stats::{InstrumentedStore, StorageStatsCollector};
+
 pub struct LocalStorage {
     store: Box<dyn ObjectStore>,
+    root: Option<PathBuf>,
+    multipart: MultipartConfig,
 }
 
 impl LocalStorage {
-    pub fn new() -> Result<Self> {
-        let store = LocalFileSystem::new();
+    /// Create a local storage, optionally rooted at `root`
+    ///
+    /// Without a root, `file://` URL paths are resolved against the filesystem
+    /// root. With a root, they are resolved relative to it.
+    ///
+    /// Paths are normalized before use and any path that would escape the
+    /// root, whether through `..` segments or symlinks, is rejected.
+    pub fn new(root: Option<PathBuf>) -> Result<Self> {
+        let (store, root) = match root {
+            Some(root) => {
+                std::fs::create_dir_all(&root)?;
+                let root = std::fs::canonicalize(&root)?;
+                (LocalFileSystem::new_with_prefix(&root)?, Some(root))
+            }
+            None => (LocalFileSystem::new(), None),
+        };
         Ok(Self {
             store: Box::new(store),
+            root,
+            multipart: MultipartConfig::default(),
         })
     }
 
+    /// Use the given multipart settings for large writes
+    pub fn with_multipart_config(mut self, multipart: MultipartConfig) -> Self {
+        self.multipart = multipart;
+        self
+    }
+
+    /// Count the requests sent to the backend into `stats`
+    pub fn with_stats(mut self, stats: StorageStatsCollector) -> Self {
+        self.store = Box::new(InstrumentedStore::new(self.store, "local", stats));
+        self
+    }
+
     fn get_object_path(&self, url: &Url) -> Result<ObjectPath> {
-        let path = url.path();
-        Ok(ObjectPath::from(path))
+        let segments = normalize_segments(url.path())?;
+        if let Some(root) = &self.root {
+            ensure_within_root(root, &segments)?;
+        }
+        Ok(segments.iter().map(String::as_str).collect())
     }
 }
 
+/// Percent-decode `path` and resolve `.`/`..` segments, treating it as relative.
+///
+/// Fails if a `..` would climb above the starting directory.
+fn normalize_segments(path: &str) -> Result<Vec<String>> {
+    let decoded = percent_decode_str(path)
+        .decode_utf8()
+        .map_err(|e| Error::storage(format!("Invalid path {}: {}", path, e)))?;
+    let mut segments: Vec<String> = Vec::new();
+    for segment in decoded.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err(Error::storage(format!("Path escapes the storage base directory: {}", path)));
+                }
+            }
+            segment => segments.push(segment.to_string()),
+        }
+    }
+    Ok(segments)
+}
+
+/// Reject paths whose existing ancestors resolve (through symlinks) outside `root`
+fn ensure_within_root(root: &Path, segments: &[String]) -> Result<()> {
+    let mut candidate = root.to_path_buf();
+    candidate.extend(segments);
+    let existing = candidate.ancestors().find(|p| p.exists()).unwrap_or(root);
+    let resolved = std::fs::canonicalize(existing)?;
+    if !resolved.starts_with(root) {
+        return Err(Error::storage(format!(
+            "Path escapes the storage base directory: {}",
+            candidate.display()
+        )));
+    }
+    Ok(())
+}
+
 #[async_trait]
 impl super::Storage for LocalStorage {
-    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
-        let prefix = prefix.unwrap_or("");
-        let path = ObjectPath::from(prefix);
-        let mut entries = Vec::new();
-        let mut stream = self.store.list(Some(&path));
-        while let Some(entry) = stream.next().await {
-            let entry = entry?;
-            entries.push(entry.location.to_string());
-        }
-        Ok(entries)
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        Ok(super::list_object_store(self.store.as_ref(), prefix))
     }
 
-    async fn read(&self, url: &Url) -> Result<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + Sync + Unpin + 'static>> {
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
         let path = self.get_object_path(url)?;
         let result = self.store.get(&path).await?;
-        let bytes = result.bytes().await?;
-        let stream = futures::stream::once(futures::future::ready(Ok(bytes)));
-        Ok(Box::new(Box::pin(stream)))
+        Ok(result
+            .into_stream()
+            .map_err(Error::from)
+            .inspect_ok(|chunk| metrics::bytes_read("local", chunk.len()))
+            .boxed())
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
     async fn read_all(&self, url: &Url) -> Result<Bytes> {
         let path = self.get_object_path(url)?;
         let data = self.store.get(&path).await?.bytes().await?;
+        tracing::debug!(bytes = data.len(), "read object");
+        metrics::bytes_read("local", data.len());
+        Ok(data)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url, ?range))]
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let path = self.get_object_path(url)?;
+        let data = self.store.get_range(&path, range).await?;
+        tracing::debug!(bytes = data.len(), "read object");
+        metrics::bytes_read("local", data.len());
         Ok(data)
     }
 
+    async fn size(&self, url: &Url) -> Result<usize> {
+        let path = self.get_object_path(url)?;
+        Ok(self.store.head(&path).await?.size)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url, bytes = data.len()))]
     async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
         let path = self.get_object_path(url)?;
-        self.store.put(&path, data.into()).await?;
+        let bytes = data.len();
+        multipart::put(self.store.as_ref(), &path, data, &self.multipart).await?;
+        metrics::bytes_written("local", bytes);
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn write_stream(&self, url: &Url, stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        let path = self.get_object_path(url)?;
+        let stream = stream
+            .inspect_ok(|chunk| metrics::bytes_written("local", chunk.len()))
+            .boxed();
+        multipart::put_stream(self.store.as_ref(), &path, stream, &self.multipart).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn delete(&self, url: &Url) -> Result<()> {
+        let path = self.get_object_path(url)?;
+        self.store.delete(&path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_normalize_segments() -> Result<()> {
+        assert_eq!(normalize_segments("/a/./b/../c.txt")?, vec!["a", "c.txt"]);
+        assert_eq!(normalize_segments("/with%20space.csv")?, vec!["with space.csv"]);
+        assert!(normalize_segments("/../outside.txt").is_err());
+        assert!(normalize_segments("/a/..%2F..%2Foutside.txt").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encoded_traversal_is_rejected() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let storage = LocalStorage::new(Some(temp_dir.path().join("base")))?;
+        let url = Url::parse("file:///nested/..%2F..%2Foutside.txt")?;
+
+        assert!(storage.write(&url, Bytes::from("x")).await.is_err());
+        assert!(!temp_dir.path().join("outside.txt").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_absolute_path_is_relative_to_base() -> Result<()> {
+        let temp_dir = TempDir::new()?;
+        let storage = LocalStorage::new(Some(temp_dir.path().to_path_buf()))?;
+        let url = Url::parse("file:///etc/data.txt")?;
+
+        storage.write(&url, Bytes::from("x")).await?;
+        assert!(temp_dir.path().join("etc/data.txt").exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_escape_is_rejected() -> Result<()> {
+        let base = TempDir::new()?;
+        let outside = TempDir::new()?;
+        std::os::unix::fs::symlink(outside.path(), base.path().join("link"))?;
+        let storage = LocalStorage::new(Some(base.path().to_path_buf()))?;
+        let url = Url::parse("file:///link/escaped.txt")?;
+
+        assert!(storage.write(&url, Bytes::from("x")).await.is_err());
+        assert!(storage.read_all(&url).await.is_err());
+        assert!(!outside.path().join("escaped.txt").exists());
         Ok(())
     }
 }
@@ -0,0 +1,113 @@
+use std::ops::Range;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use object_store::memory::InMemory;
+use object_store::{ObjectMeta, ObjectStore, path::Path as ObjectPath};
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::metrics;
+use super::multipart::{self, MultipartConfig};
+use super::stats::{InstrumentedStore, StorageStatsCollector};
+
+/// Keeps objects in memory, keyed by the path of their URL, e.g. `memory:///out/data.parquet`.
+///
+/// Nothing is persisted and every instance starts empty, so it is meant for
+/// tests and for embedding the pipeline where the output is consumed in process.
+pub struct MemoryStorage {
+    store: Box<dyn ObjectStore>,
+    multipart: MultipartConfig,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self {
+            store: Box::new(InMemory::new()),
+            multipart: MultipartConfig::default(),
+        }
+    }
+
+    /// Use the given multipart settings for large writes
+    pub fn with_multipart_config(mut self, multipart: MultipartConfig) -> Self {
+        self.multipart = multipart;
+        self
+    }
+
+    /// Count the requests sent to the backend into `stats`
+    pub fn with_stats(mut self, stats: StorageStatsCollector) -> Self {
+        self.store = Box::new(InstrumentedStore::new(self.store, "memory", stats));
+        self
+    }
+
+    fn get_object_path(&self, url: &Url) -> ObjectPath {
+        ObjectPath::from(url.path())
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl super::Storage for MemoryStorage {
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        Ok(super::list_object_store(self.store.as_ref(), prefix))
+    }
+
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let path = self.get_object_path(url);
+        let result = self.store.get(&path).await?;
+        Ok(result
+            .into_stream()
+            .map_err(Error::from)
+            .inspect_ok(|chunk| metrics::bytes_read("memory", chunk.len()))
+            .boxed())
+    }
+
+    async fn read_all(&self, url: &Url) -> Result<Bytes> {
+        let path = self.get_object_path(url);
+        let data = self.store.get(&path).await?.bytes().await?;
+        metrics::bytes_read("memory", data.len());
+        Ok(data)
+    }
+
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let path = self.get_object_path(url);
+        let data = self.store.get_range(&path, range).await?;
+        metrics::bytes_read("memory", data.len());
+        Ok(data)
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        let path = self.get_object_path(url);
+        Ok(self.store.head(&path).await?.size)
+    }
+
+    async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
+        let path = self.get_object_path(url);
+        let bytes = data.len();
+        multipart::put(self.store.as_ref(), &path, data, &self.multipart).await?;
+        metrics::bytes_written("memory", bytes);
+        Ok(())
+    }
+
+    async fn write_stream(&self, url: &Url, stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        let path = self.get_object_path(url);
+        let stream = stream
+            .inspect_ok(|chunk| metrics::bytes_written("memory", chunk.len()))
+            .boxed();
+        multipart::put_stream(self.store.as_ref(), &path, stream, &self.multipart).await
+    }
+
+    async fn delete(&self, url: &Url) -> Result<()> {
+        let path = self.get_object_path(url);
+        self.store.delete(&path).await?;
+        Ok(())
+    }
+}
@@ -1,35 +1,327 @@
-use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Range;
+
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::Stream;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectMeta, ObjectStore};
 use url::Url;
 
+use crate::config::StorageConfig;
+use crate::error::{Error, Result};
+
 pub mod azure;
+pub mod glob;
+pub mod limit;
 pub mod local;
+pub mod memory;
+pub mod multipart;
+pub mod retry;
 pub mod s3;
+pub mod stats;
+pub mod stdio;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod verify;
 
 #[async_trait]
 pub trait Storage: Send + Sync {
-    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>>;
-    async fn read(&self, url: &Url) -> Result<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + Sync + Unpin + 'static>>;
+    /// Stream the objects whose keys start with `prefix`.
+    ///
+    /// Object store backends fetch the listing a page at a time as the
+    /// stream is read, so listing a large bucket doesn't hold it in memory.
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>>;
+
+    /// Keys of the objects whose keys start with `prefix`
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        self.list_stream(prefix)
+            .await?
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect()
+            .await
+    }
+
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>>;
     async fn read_all(&self, url: &Url) -> Result<Bytes>;
     async fn write(&self, url: &Url, data: Bytes) -> Result<()>;
+
+    /// Read the bytes in `range` of an object.
+    ///
+    /// The default implementation reads the whole object; object store
+    /// backends override it with a ranged request.
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let data = self.read_all(url).await?;
+        if range.end > data.len() {
+            return Err(Error::storage(format!(
+                "Range {:?} is out of bounds for {} ({} bytes)",
+                range,
+                url,
+                data.len()
+            )));
+        }
+        Ok(data.slice(range))
+    }
+
+    /// Size of an object in bytes
+    async fn size(&self, url: &Url) -> Result<usize> {
+        Ok(self.read_all(url).await?.len())
+    }
+
+    /// Whether an object exists at `url`.
+    ///
+    /// The default implementation asks for its size and treats "not found"
+    /// as `false`, so it costs one `HEAD` request on object stores.
+    async fn exists(&self, url: &Url) -> Result<bool> {
+        match self.size(url).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checksum of an object as reported by the backend, usually the hex MD5
+    /// of its content, or `None` when the backend doesn't report one
+    async fn checksum(&self, url: &Url) -> Result<Option<String>> {
+        let _ = url;
+        Ok(None)
+    }
+
+    /// Write a stream of chunks whose total length is not known upfront.
+    ///
+    /// The default implementation buffers the whole stream and calls `write`;
+    /// object store backends override it to use multipart uploads.
+    async fn write_stream(&self, url: &Url, mut stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        let mut buffer = bytes::BytesMut::new();
+        while let Some(chunk) = stream.next().await {
+            buffer.extend_from_slice(&chunk?);
+        }
+        self.write(url, buffer.freeze()).await
+    }
+
+    /// Append `data` to an object, creating it when it doesn't exist.
+    ///
+    /// The default implementation reads the whole object and writes it back
+    /// with `data` appended, since object stores can't append in place. It
+    /// is not atomic: a concurrent writer's changes may be lost.
+    async fn append(&self, url: &Url, data: Bytes) -> Result<()> {
+        let existing = match self.read_all(url).await {
+            Ok(existing) => existing,
+            Err(e) if e.is_not_found() => Bytes::new(),
+            Err(e) => return Err(e),
+        };
+        let mut combined = bytes::BytesMut::with_capacity(existing.len() + data.len());
+        combined.extend_from_slice(&existing);
+        combined.extend_from_slice(&data);
+        self.write(url, combined.freeze()).await
+    }
+
+    /// Delete an object, e.g. a partial output of a cancelled conversion
+    async fn delete(&self, url: &Url) -> Result<()> {
+        Err(Error::storage(format!("Deleting {} is not supported by this storage", url)))
+    }
+
+    /// Expand a URL pattern containing `*`, `?` or `**` into the matching
+    /// objects and their metadata, in the order the backend lists them.
+    ///
+    /// Only the part of the pattern before the first wildcard is listed, so
+    /// `s3://bucket/year=2023/*.parquet` never lists the whole bucket, and
+    /// only the matches are kept as the listing streams in.
+    async fn list_glob_meta(&self, pattern: &str) -> Result<Vec<(Url, ObjectMeta)>> {
+        let (base, key_pattern) = glob::split_url_pattern(pattern)?;
+        let prefix = glob::literal_prefix(key_pattern);
+        let mut listing = self.list_stream(Some(prefix)).await?;
+        let mut matches = Vec::new();
+        while let Some(meta) = listing.try_next().await? {
+            let location = meta.location.to_string();
+            if glob::matches(key_pattern, &location) {
+                matches.push((Url::parse(&format!("{}/{}", base, location))?, meta));
+            }
+        }
+        Ok(matches)
+    }
+
+    /// The URLs of the objects matching `pattern`, in `order`
+    async fn list_glob_ordered(&self, pattern: &str, order: InputOrder) -> Result<Vec<Url>> {
+        let mut matches = self.list_glob_meta(pattern).await?;
+        sort_matches(&mut matches, order);
+        Ok(matches.into_iter().map(|(url, _)| url).collect())
+    }
+
+    /// The URLs of the objects matching `pattern`, sorted by URL; see `list_glob_meta`
+    async fn list_glob(&self, pattern: &str) -> Result<Vec<Url>> {
+        self.list_glob_ordered(pattern, InputOrder::Lexical).await
+    }
 }
 
-pub fn from_url(url: &Url) -> Result<Box<dyn Storage>> {
+/// Order in which the objects matched by a glob are read.
+///
+/// Backends list objects in different orders, so matches are always sorted
+/// to make the output reproducible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum InputOrder {
+    /// By URL
+    #[default]
+    Lexical,
+    /// Oldest modification time first
+    Mtime,
+    /// Smallest object first
+    Size,
+}
+
+/// Sort glob matches into `order`; ties are broken by URL
+pub fn sort_matches(matches: &mut [(Url, ObjectMeta)], order: InputOrder) {
+    matches.sort_by(|(a_url, a), (b_url, b)| {
+        let by_order = match order {
+            InputOrder::Lexical => std::cmp::Ordering::Equal,
+            InputOrder::Mtime => a.last_modified.cmp(&b.last_modified),
+            InputOrder::Size => a.size.cmp(&b.size),
+        };
+        by_order.then_with(|| a_url.as_str().cmp(b_url.as_str()))
+    });
+}
+
+/// An entry of [`list_prefix`]: an object, or in a listing that isn't
+/// recursive, a directory holding objects further down
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListEntry {
+    Object {
+        url: Url,
+        size: usize,
+        last_modified: DateTime<Utc>,
+    },
+    Directory(Url),
+}
+
+impl ListEntry {
+    pub fn url(&self) -> &Url {
+        match self {
+            ListEntry::Object { url, .. } | ListEntry::Directory(url) => url,
+        }
+    }
+}
+
+/// One line of a listing: the size (or `DIR`), the last-modified time and the URL
+impl fmt::Display for ListEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListEntry::Object { url, size, last_modified } => write!(
+                f,
+                "{:>12}  {}  {}",
+                size,
+                last_modified.to_rfc3339_opts(SecondsFormat::Secs, true),
+                url
+            ),
+            ListEntry::Directory(url) => write!(f, "{:>12}  {:20}  {}", "DIR", "", url),
+        }
+    }
+}
+
+/// List the objects below the directory `prefix`, e.g. `s3://bucket/data/`.
+///
+/// Without `recursive`, objects in subdirectories are folded into a single
+/// `Directory` entry per subdirectory, like `ls`. The listing stops once
+/// `limit` entries are found, so a limit also bounds the requests sent.
+/// Entries are sorted by URL.
+pub async fn list_prefix(storage: &dyn Storage, prefix: &Url, recursive: bool, limit: Option<usize>) -> Result<Vec<ListEntry>> {
+    let (base, key) = glob::split_url_pattern(prefix.as_str())?;
+    let key = key.trim_end_matches('/');
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut listing = storage.list_stream((!key.is_empty()).then_some(key)).await?;
+    let mut directories = HashSet::new();
+    let mut entries = Vec::new();
+    while entries.len() < limit {
+        let Some(meta) = listing.try_next().await? else { break };
+        let location = meta.location.to_string();
+        let relative = location.strip_prefix(key).unwrap_or(&location).trim_start_matches('/');
+        match relative.split_once('/') {
+            Some((directory, _)) if !recursive => {
+                if directories.insert(directory.to_string()) {
+                    let path = if key.is_empty() { directory.to_string() } else { format!("{}/{}", key, directory) };
+                    entries.push(ListEntry::Directory(Url::parse(&format!("{}/{}/", base, path))?));
+                }
+            }
+            _ => entries.push(ListEntry::Object {
+                url: Url::parse(&format!("{}/{}", base, location))?,
+                size: meta.size,
+                last_modified: meta.last_modified,
+            }),
+        }
+    }
+    entries.sort_by(|a, b| a.url().as_str().cmp(b.url().as_str()));
+    Ok(entries)
+}
+
+/// Create the storage backend for a URL such as `s3://bucket/key`, `file:///tmp/data.csv`
+/// or `stdin:`/`stdout:` for the standard input and output of the process
+pub fn from_url(url: impl AsRef<str>) -> Result<Box<dyn Storage>> {
+    from_url_with_config(url, &StorageConfig::default())
+}
+
+/// Like [`from_url`], with the retry policy of `config` for remote backends
+/// and its multipart settings for writes
+pub fn from_url_with_config(url: impl AsRef<str>, config: &StorageConfig) -> Result<Box<dyn Storage>> {
+    open(url.as_ref(), config, None)
+}
+
+/// Like [`from_url_with_config`], counting the requests sent to the backend into `stats`
+pub fn from_url_with_stats(
+    url: impl AsRef<str>,
+    config: &StorageConfig,
+    stats: &stats::StorageStatsCollector,
+) -> Result<Box<dyn Storage>> {
+    open(url.as_ref(), config, Some(stats))
+}
+
+fn open(url: &str, config: &StorageConfig, stats: Option<&stats::StorageStatsCollector>) -> Result<Box<dyn Storage>> {
+    let url = Url::parse(url)?;
+    let multipart = multipart::MultipartConfig::from(config);
     match url.scheme() {
         "file" => {
-            let storage = local::LocalStorage::new()?;
+            let mut storage = local::LocalStorage::new(None)?.with_multipart_config(multipart);
+            if let Some(stats) = stats {
+                storage = storage.with_stats(stats.clone());
+            }
             Ok(Box::new(storage))
         }
         "s3" => {
-            let storage = s3::S3Storage::new(url.host_str().unwrap_or("").to_string())?;
+            let mut storage = s3::S3Storage::with_config(bucket_name(&url)?, &config.s3.clone().with_env())?
+                .with_retry_config(config.retry.clone())
+                .with_multipart_config(multipart);
+            if let Some(stats) = stats {
+                storage = storage.with_stats(stats.clone());
+            }
             Ok(Box::new(storage))
         }
         "azure" => {
-            let storage = azure::AzureStorage::new(url.host_str().unwrap_or("").to_string())?;
+            let mut storage = azure::AzureStorage::new(bucket_name(&url)?)?
+                .with_retry_config(config.retry.clone())
+                .with_multipart_config(multipart);
+            if let Some(stats) = stats {
+                storage = storage.with_stats(stats.clone());
+            }
             Ok(Box::new(storage))
         }
-        _ => Err(anyhow::anyhow!("Unsupported URL scheme")),
+        "stdin" | "stdout" => Ok(Box::new(stdio::StdioStorage::process())),
+        scheme => Err(Error::storage(format!("Unsupported URL scheme: {}", scheme))),
     }
 }
+
+/// Stream the listing of `store` below `prefix`, one page at a time
+fn list_object_store<'a>(store: &'a dyn ObjectStore, prefix: Option<&str>) -> BoxStream<'a, Result<ObjectMeta>> {
+    let prefix = ObjectPath::from(prefix.unwrap_or(""));
+    store.list(Some(&prefix)).map_err(Error::from).boxed()
+}
+
+fn bucket_name(url: &Url) -> Result<String> {
+    match url.host_str() {
+        Some(host) if !host.is_empty() => Ok(host.to_string()),
+        _ => Err(Error::storage(format!("Missing bucket or container name in URL: {}", url))),
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,335 @@
+use bytes::{Bytes, BytesMut};
+use futures::stream::{BoxStream, FuturesUnordered};
+use futures::StreamExt;
+use object_store::{MultipartUpload, ObjectStore, path::Path as ObjectPath};
+
+use crate::config::StorageConfig;
+use crate::error::Result;
+
+/// Settings controlling when and how multipart uploads are used
+#[derive(Debug, Clone)]
+pub struct MultipartConfig {
+    /// Writes larger than this many bytes use a multipart upload
+    pub threshold: usize,
+    /// Size of each uploaded part in bytes
+    pub part_size: usize,
+    /// Maximum number of parts uploaded concurrently
+    pub max_concurrency: usize,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 100 * 1024 * 1024,
+            part_size: 8 * 1024 * 1024,
+            max_concurrency: 10,
+        }
+    }
+}
+
+impl From<&StorageConfig> for MultipartConfig {
+    fn from(config: &StorageConfig) -> Self {
+        Self {
+            threshold: config.multipart_threshold,
+            part_size: config.write_buffer_size,
+            max_concurrency: config.max_concurrent_requests,
+        }
+    }
+}
+
+/// Write `data` to `path`, switching to a multipart upload above the configured threshold
+pub async fn put(store: &dyn ObjectStore, path: &ObjectPath, data: Bytes, config: &MultipartConfig) -> Result<()> {
+    if data.len() <= config.threshold {
+        store.put(path, data.into()).await?;
+        return Ok(());
+    }
+
+    let upload = store.put_multipart(path).await?;
+    upload_parts(upload, futures::stream::once(async move { Ok(data) }).boxed(), config).await?;
+    Ok(())
+}
+
+/// Write a stream of chunks to `path` without knowing its total length upfront.
+///
+/// Multipart uploads don't need a `Content-Length` for the whole object, so they
+/// are used whenever the store supports them. Stores without multipart support
+/// fall back to buffering the stream and issuing a single `put`.
+pub async fn put_stream(
+    store: &dyn ObjectStore,
+    path: &ObjectPath,
+    mut stream: BoxStream<'static, Result<Bytes>>,
+    config: &MultipartConfig,
+) -> Result<()> {
+    let upload = match store.put_multipart(path).await {
+        Ok(upload) => upload,
+        Err(object_store::Error::NotImplemented) | Err(object_store::Error::NotSupported { .. }) => {
+            let mut buffer = BytesMut::new();
+            while let Some(chunk) = stream.next().await {
+                buffer.extend_from_slice(&chunk?);
+            }
+            let bytes = buffer.len();
+            store.put(path, buffer.freeze().into()).await?;
+            tracing::debug!(%path, bytes, multipart = false, "uploaded object");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let bytes = upload_parts(upload, stream, config).await?;
+    tracing::debug!(%path, bytes, multipart = true, "uploaded object");
+    Ok(())
+}
+
+/// Upload `stream` in parts of `part_size` bytes, at most `max_concurrency`
+/// at a time, and complete the upload; returns the bytes uploaded.
+///
+/// If reading the stream, uploading a part or completing the upload fails,
+/// the upload is aborted so the parts already stored don't linger.
+async fn upload_parts(
+    mut upload: Box<dyn MultipartUpload>,
+    stream: BoxStream<'_, Result<Bytes>>,
+    config: &MultipartConfig,
+) -> Result<usize> {
+    match send_parts(upload.as_mut(), stream, config).await {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => {
+            upload.abort().await.ok();
+            Err(e)
+        }
+    }
+}
+
+async fn send_parts(
+    upload: &mut dyn MultipartUpload,
+    mut stream: BoxStream<'_, Result<Bytes>>,
+    config: &MultipartConfig,
+) -> Result<usize> {
+    let part_size = config.part_size.max(1);
+    let max_concurrency = config.max_concurrency.max(1);
+    let mut in_flight = FuturesUnordered::new();
+    let mut buffer = BytesMut::new();
+    let mut bytes = 0;
+    let mut parts = 0;
+    loop {
+        let chunk = stream.next().await.transpose()?;
+        if let Some(chunk) = &chunk {
+            bytes += chunk.len();
+            buffer.extend_from_slice(chunk);
+        }
+        // The last part may be short, and an empty object is one empty part
+        let last = chunk.is_none();
+        while buffer.len() >= part_size || (last && (!buffer.is_empty() || parts == 0)) {
+            while in_flight.len() >= max_concurrency {
+                in_flight.next().await.transpose()?;
+            }
+            let part = buffer.split_to(buffer.len().min(part_size)).freeze();
+            in_flight.push(upload.put_part(part.into()));
+            parts += 1;
+        }
+        if last {
+            break;
+        }
+    }
+    while let Some(result) = in_flight.next().await {
+        result?;
+    }
+    upload.complete().await?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use object_store::memory::InMemory;
+    use object_store::{
+        GetOptions, GetResult, ListResult, ObjectMeta, PutMultipartOpts, PutOptions, PutPayload, PutResult, UploadPart,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// In-memory store that requires a content length on `put` and records which API was used
+    #[derive(Debug, Default)]
+    struct RecordingStore {
+        inner: InMemory,
+        multipart_supported: bool,
+        reject_parts: bool,
+        puts: AtomicUsize,
+        multipart_uploads: AtomicUsize,
+        aborts: Arc<AtomicUsize>,
+    }
+
+    /// Upload whose parts are all rejected, counting how often it is aborted
+    #[derive(Debug)]
+    struct RejectingUpload {
+        inner: Box<dyn MultipartUpload>,
+        aborts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl MultipartUpload for RejectingUpload {
+        fn put_part(&mut self, _data: PutPayload) -> UploadPart {
+            Box::pin(async {
+                Err(object_store::Error::Generic {
+                    store: "RecordingStore",
+                    source: "part rejected".into(),
+                })
+            })
+        }
+
+        async fn complete(&mut self) -> object_store::Result<PutResult> {
+            self.inner.complete().await
+        }
+
+        async fn abort(&mut self) -> object_store::Result<()> {
+            self.aborts.fetch_add(1, Ordering::SeqCst);
+            self.inner.abort().await
+        }
+    }
+
+    impl std::fmt::Display for RecordingStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "RecordingStore")
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for RecordingStore {
+        async fn put_opts(&self, location: &ObjectPath, payload: PutPayload, opts: PutOptions) -> object_store::Result<PutResult> {
+            self.puts.fetch_add(1, Ordering::SeqCst);
+            self.inner.put_opts(location, payload, opts).await
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &ObjectPath,
+            opts: PutMultipartOpts,
+        ) -> object_store::Result<Box<dyn MultipartUpload>> {
+            if !self.multipart_supported {
+                return Err(object_store::Error::NotImplemented);
+            }
+            self.multipart_uploads.fetch_add(1, Ordering::SeqCst);
+            let upload = self.inner.put_multipart_opts(location, opts).await?;
+            if self.reject_parts {
+                return Ok(Box::new(RejectingUpload {
+                    inner: upload,
+                    aborts: self.aborts.clone(),
+                }));
+            }
+            Ok(upload)
+        }
+
+        async fn get_opts(&self, location: &ObjectPath, options: GetOptions) -> object_store::Result<GetResult> {
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+            self.inner.delete(location).await
+        }
+
+        fn list(&self, prefix: Option<&ObjectPath>) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(&self, prefix: Option<&ObjectPath>) -> object_store::Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    fn chunked_stream() -> (Bytes, BoxStream<'static, Result<Bytes>>) {
+        let chunks: Vec<Bytes> = (0..5).map(|i| Bytes::from(vec![i as u8; 300])).collect();
+        let expected = Bytes::from(chunks.concat());
+        (expected, futures::stream::iter(chunks.into_iter().map(Ok)).boxed())
+    }
+
+    #[tokio::test]
+    async fn test_streamed_write_uses_multipart() -> Result<()> {
+        let store = RecordingStore {
+            multipart_supported: true,
+            ..Default::default()
+        };
+        let path = ObjectPath::from("streamed.bin");
+        let (expected, stream) = chunked_stream();
+
+        put_stream(&store, &path, stream, &MultipartConfig::default()).await?;
+
+        assert_eq!(store.multipart_uploads.load(Ordering::SeqCst), 1);
+        assert_eq!(store.puts.load(Ordering::SeqCst), 0);
+        assert_eq!(store.get(&path).await?.bytes().await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streamed_write_buffers_without_multipart() -> Result<()> {
+        let store = RecordingStore::default();
+        let path = ObjectPath::from("buffered.bin");
+        let (expected, stream) = chunked_stream();
+
+        put_stream(&store, &path, stream, &MultipartConfig::default()).await?;
+
+        assert_eq!(store.puts.load(Ordering::SeqCst), 1);
+        assert_eq!(store.get(&path).await?.bytes().await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_is_aborted() -> Result<()> {
+        let store = RecordingStore {
+            multipart_supported: true,
+            reject_parts: true,
+            ..Default::default()
+        };
+        let path = ObjectPath::from("rejected.bin");
+        // Smaller than a part, so the only part is uploaded when the stream ends
+        let (_, stream) = chunked_stream();
+
+        assert!(put_stream(&store, &path, stream, &MultipartConfig::default()).await.is_err());
+        assert_eq!(store.aborts.load(Ordering::SeqCst), 1);
+        assert!(store.head(&path).await.is_err());
+
+        let config = MultipartConfig {
+            threshold: 10,
+            ..Default::default()
+        };
+        assert!(put(&store, &path, Bytes::from(vec![0u8; 100]), &config).await.is_err());
+        assert_eq!(store.aborts.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_large_write_round_trips() -> Result<()> {
+        let store = InMemory::new();
+        let path = ObjectPath::from("large.bin");
+        let config = MultipartConfig {
+            threshold: 1024,
+            part_size: 1000,
+            max_concurrency: 2,
+        };
+        let data = Bytes::from((0..10_000u32).map(|i| i as u8).collect::<Vec<_>>());
+
+        put(&store, &path, data.clone(), &config).await?;
+
+        assert_eq!(store.get(&path).await?.bytes().await?, data);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_small_write_round_trips() -> Result<()> {
+        let store = InMemory::new();
+        let path = ObjectPath::from("small.bin");
+        let data = Bytes::from("small");
+
+        put(&store, &path, data.clone(), &MultipartConfig::default()).await?;
+
+        assert_eq!(store.get(&path).await?.bytes().await?, data);
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::config::RetryConfig;
+use crate::error::{Error, Result};
+use crate::metrics;
+
+/// Run `op` until it succeeds, a non-retryable error occurs or `max_retries` is exhausted.
+///
+/// The delay between attempts starts at `initial_delay_ms`, is multiplied by
+/// `backoff_multiplier` after every failure and never exceeds `max_delay_ms`;
+/// with `jitter` a random part of it is waited instead.
+pub async fn with_retry<T, F, Fut>(config: &RetryConfig, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < config.max_retries && is_retryable(&e) => {
+                metrics::storage_retry();
+                tokio::time::sleep(backoff_delay(config, attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                metrics::error(&e);
+                return Err(e);
+            }
+        }
+    }
+}
+
+/// Delay to wait before retry number `attempt` (zero based)
+pub fn backoff_delay(config: &RetryConfig, attempt: u32) -> Duration {
+    backoff_delay_with(config, attempt, &mut rand::thread_rng())
+}
+
+/// Like [`backoff_delay`], drawing the jitter from `rng`.
+///
+/// Full jitter: the delay is uniform between zero and the capped
+/// exponential delay, which spreads out retries of operations that failed
+/// at the same time.
+pub fn backoff_delay_with(config: &RetryConfig, attempt: u32, rng: &mut impl Rng) -> Duration {
+    let delay = config.initial_delay_ms as f64 * config.backoff_multiplier.powi(attempt as i32);
+    let delay = delay.min(config.max_delay_ms as f64) as u64;
+    if config.jitter {
+        Duration::from_millis(rng.gen_range(0..=delay))
+    } else {
+        Duration::from_millis(delay)
+    }
+}
+
+/// Whether an error is transient (timeouts, 5xx, throttling) and worth retrying.
+///
+/// Storage errors are classified by their `object_store` variant and, for
+/// failed requests, by HTTP status: 408, 429 and 5xx are retried, other
+/// statuses are not. Errors that don't come from storage are never retried.
+pub fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::Timeout(_) => true,
+        Error::Io(e) => is_transient_io(e),
+        Error::Storage(source) => match source.downcast_ref::<object_store::Error>() {
+            Some(object_store::Error::Generic { source, .. }) => is_transient_request(source.as_ref()),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_transient_io(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Whether the request error behind an `object_store::Error::Generic` is transient
+fn is_transient_request(error: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    if let Some(status) = http_status(&error.to_string()) {
+        return status == 408 || status == 429 || (500..600).contains(&status);
+    }
+    // A request that got no response, e.g. a reset connection or a timeout,
+    // after object_store's own retries
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            return is_transient_io(io);
+        }
+        source = e.source();
+    }
+    error.to_string().starts_with("Error after ")
+}
+
+/// HTTP status of a failed `object_store` request.
+///
+/// object_store doesn't export its request error type, so the status is
+/// read from its message, e.g. `Client error with status 404 Not Found: ...`.
+fn http_status(message: &str) -> Option<u16> {
+    let (_, rest) = message.split_once("with status ")?;
+    rest.get(..3)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::FlakyStore;
+    use bytes::Bytes;
+    use object_store::{ObjectStore, path::Path as ObjectPath};
+    use std::sync::atomic::Ordering;
+
+    fn test_config() -> RetryConfig {
+        RetryConfig {
+            max_retries: 3,
+            initial_delay_ms: 1,
+            max_delay_ms: 5,
+            backoff_multiplier: 2.0,
+            jitter: false,
+        }
+    }
+
+    fn request_error(message: &str) -> Error {
+        object_store::Error::Generic {
+            store: "mock",
+            source: message.into(),
+        }
+        .into()
+    }
+
+    fn transient_error() -> Error {
+        request_error("Server error, body contains Error, with status 503 Service Unavailable: No Body")
+    }
+
+    #[test]
+    fn test_errors_are_classified_by_status_not_message() {
+        assert!(is_retryable(&transient_error()));
+        assert!(is_retryable(&request_error("Client error with status 429 Too Many Requests: SlowDown")));
+        assert!(!is_retryable(&request_error("Client error with status 400 Bad Request: 500 rows")));
+        assert!(!is_retryable(&Error::InvalidInput("row 500 timed out: 503".to_string())));
+        assert!(!is_retryable(&Error::storage("500 internal server error")));
+        assert!(is_retryable(&Error::Io(std::io::ErrorKind::ConnectionReset.into())));
+        assert!(is_retryable(&Error::Storage(Box::new(object_store::Error::Generic {
+            store: "mock",
+            source: Box::new(std::io::Error::from(std::io::ErrorKind::TimedOut)),
+        }))));
+    }
+
+    /// Store holding `data.csv` whose first `failures` reads fail with a 503
+    async fn flaky_store(failures: usize) -> FlakyStore {
+        let store = FlakyStore::new(failures);
+        store.put(&ObjectPath::from("data.csv"), Bytes::from("a,b\n").into()).await.unwrap();
+        store
+    }
+
+    async fn read(store: &FlakyStore, path: &str) -> Result<Bytes> {
+        let path = ObjectPath::from(path);
+        with_retry(&test_config(), || async { Ok(store.get(&path).await?.bytes().await?) }).await
+    }
+
+    #[tokio::test]
+    async fn test_retry_succeeds_after_transient_failures() {
+        let store = flaky_store(2).await;
+
+        assert_eq!(read(&store, "data.csv").await.unwrap(), Bytes::from("a,b\n"));
+        assert_eq!(store.gets().load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_fails_fast_on_not_found() {
+        let store = flaky_store(0).await;
+
+        assert!(read(&store, "missing.csv").await.is_err());
+        assert_eq!(store.gets().load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up_after_max_retries() {
+        let store = flaky_store(10).await;
+
+        assert!(is_retryable(&read(&store, "data.csv").await.unwrap_err()));
+        assert_eq!(store.gets().load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_backoff_delay_is_capped() {
+        let config = test_config();
+        assert_eq!(backoff_delay(&config, 0), Duration::from_millis(1));
+        assert_eq!(backoff_delay(&config, 1), Duration::from_millis(2));
+        assert_eq!(backoff_delay(&config, 10), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_jittered_delays_stay_within_the_cap() {
+        use rand::SeedableRng;
+
+        let config = RetryConfig {
+            max_retries: 10,
+            initial_delay_ms: 100,
+            max_delay_ms: 1000,
+            backoff_multiplier: 2.0,
+            jitter: true,
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let delays: Vec<Duration> = (0..10).map(|attempt| backoff_delay_with(&config, attempt, &mut rng)).collect();
+        for (attempt, delay) in delays.iter().enumerate() {
+            let cap = backoff_delay(&RetryConfig { jitter: false, ..config.clone() }, attempt as u32);
+            assert!(*delay <= cap, "attempt {}: {:?} > {:?}", attempt, delay, cap);
+            assert!(*delay <= Duration::from_millis(config.max_delay_ms));
+        }
+        let mut distinct = delays.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert!(distinct.len() > 1, "{:?}", delays);
+    }
+}
@@ -1,71 +1,442 @@
-use anyhow::Result;
+use std::ops::Range;
+
 use async_trait::async_trait;
 use bytes::Bytes;
-use futures::Stream;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
 use futures::StreamExt;
-use object_store::aws::AmazonS3Builder;
-use object_store::{ObjectStore, path::Path as ObjectPath};
+use object_store::aws::{AmazonS3Builder, AmazonS3ConfigKey};
+use object_store::{ObjectMeta, ObjectStore, path::Path as ObjectPath};
 use url::Url;
 
+use crate::config::{RetryConfig, S3Config};
+use crate::error::{Error, Result};
+use crate::metrics;
+use super::multipart::{self, MultipartConfig};
+use super::stats::{InstrumentedStore, StorageStatsCollector};
+use super::retry::with_retry;
+
+/// Credential source for S3, in order of precedence
+#[derive(Debug, Clone, PartialEq)]
+enum S3Credentials {
+    /// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, with an optional `AWS_SESSION_TOKEN`
+    Static {
+        access_key_id: String,
+        secret_access_key: String,
+        session_token: Option<String>,
+    },
+    /// `AWS_SKIP_SIGNATURE=true`; requests are sent unsigned, which only works for public buckets
+    Anonymous,
+    /// `AWS_ROLE_ARN` with `AWS_WEB_IDENTITY_TOKEN_FILE`, exchanged for temporary
+    /// credentials through STS `AssumeRoleWithWebIdentity`
+    WebIdentity { role_arn: String, token_file: String },
+    /// The ECS task role when `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` is set,
+    /// otherwise the EC2 instance profile
+    DefaultChain { container_credentials_relative_uri: Option<String> },
+}
+
+impl S3Credentials {
+    fn from_env() -> Result<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let var = |name: &str| var(name).filter(|v| !v.is_empty());
+
+        if let (Some(access_key_id), Some(secret_access_key)) = (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            return Ok(Self::Static {
+                access_key_id,
+                secret_access_key,
+                session_token: var("AWS_SESSION_TOKEN"),
+            });
+        }
+        if var("AWS_SKIP_SIGNATURE").is_some_and(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes")) {
+            return Ok(Self::Anonymous);
+        }
+        match (var("AWS_ROLE_ARN"), var("AWS_WEB_IDENTITY_TOKEN_FILE")) {
+            (Some(role_arn), Some(token_file)) => {
+                // object_store only reads the token when the first request is
+                // signed; check it up front so a bad mount fails with a clear error
+                if !std::path::Path::new(&token_file).is_file() {
+                    return Err(Error::storage(format!(
+                        "AWS_WEB_IDENTITY_TOKEN_FILE {} does not exist",
+                        token_file
+                    )));
+                }
+                Ok(Self::WebIdentity { role_arn, token_file })
+            }
+            (Some(_), None) | (None, Some(_)) => Err(Error::storage(
+                "Assume-role needs both AWS_ROLE_ARN and AWS_WEB_IDENTITY_TOKEN_FILE",
+            )),
+            (None, None) => Ok(Self::DefaultChain {
+                container_credentials_relative_uri: var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+            }),
+        }
+    }
+}
+
 pub struct S3Storage {
     store: Box<dyn ObjectStore>,
     bucket: String,
+    retry: RetryConfig,
+    multipart: MultipartConfig,
 }
 
 impl S3Storage {
     pub fn new(bucket: String) -> Result<Self> {
-        let store = AmazonS3Builder::new()
-            .with_bucket_name(&bucket)
-            .with_allow_http(true)
-            .with_region(std::env::var("AWS_DEFAULT_REGION").unwrap_or_else(|_| "us-east-1".to_string()))
-            .with_access_key_id(std::env::var("AWS_ACCESS_KEY_ID")?)
-            .with_secret_access_key(std::env::var("AWS_SECRET_ACCESS_KEY")?)
-            .with_endpoint("https://s3.us-east-1.amazonaws.com")
-            .with_skip_signature(true)
-            .build()?;
+        Self::with_config(bucket, &S3Config::from_env())
+    }
+
+    /// Create a storage for `bucket` using an explicit S3 configuration
+    ///
+    /// Static keys from the environment take precedence. Without them the
+    /// default AWS credential chain is used: web identity assume-role, then
+    /// the ECS task role, then the EC2 instance profile.
+    pub fn with_config(bucket: String, config: &S3Config) -> Result<Self> {
+        let store = Self::builder(&bucket, config, S3Credentials::from_env()?).build()?;
         Ok(Self {
             store: Box::new(store),
             bucket,
+            retry: RetryConfig::default(),
+            multipart: MultipartConfig::default(),
         })
     }
 
+    fn builder(bucket: &str, config: &S3Config, credentials: S3Credentials) -> AmazonS3Builder {
+        let mut builder = AmazonS3Builder::new()
+            .with_bucket_name(bucket)
+            .with_region(&config.region);
+
+        // Without an explicit endpoint object_store derives it from the region
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if config.force_path_style {
+            builder = builder.with_virtual_hosted_style_request(false);
+        }
+
+        match credentials {
+            S3Credentials::Static { access_key_id, secret_access_key, session_token } => {
+                builder = builder
+                    .with_access_key_id(access_key_id)
+                    .with_secret_access_key(secret_access_key);
+                if let Some(token) = session_token {
+                    builder = builder.with_token(token);
+                }
+                builder
+            }
+            S3Credentials::Anonymous => builder.with_skip_signature(true),
+            // object_store picks up AWS_ROLE_ARN and AWS_WEB_IDENTITY_TOKEN_FILE
+            // itself when no static keys are configured
+            S3Credentials::WebIdentity { .. } => builder,
+            S3Credentials::DefaultChain { container_credentials_relative_uri } => match container_credentials_relative_uri {
+                Some(uri) => builder.with_config(AmazonS3ConfigKey::ContainerCredentialsRelativeUri, uri),
+                None => builder,
+            },
+        }
+    }
+
+    /// Use the given retry policy for storage operations
+    pub fn with_retry_config(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Use the given multipart settings for large writes
+    pub fn with_multipart_config(mut self, multipart: MultipartConfig) -> Self {
+        self.multipart = multipart;
+        self
+    }
+
+    /// Count the requests sent to the backend into `stats`; listings are
+    /// paged by 1000 entries
+    pub fn with_stats(mut self, stats: StorageStatsCollector) -> Self {
+        self.store = Box::new(InstrumentedStore::new(self.store, "s3", stats).with_list_page_size(1000));
+        self
+    }
+
     fn get_object_path(&self, url: &Url) -> Result<ObjectPath> {
         let path = url.path();
         Ok(ObjectPath::from(path))
     }
+
+    async fn read_all_with_retry(&self, path: &ObjectPath) -> Result<Bytes> {
+        let store = &self.store;
+        with_retry(&self.retry, || async move {
+            let data = store.get(path).await?.bytes().await?;
+            Ok(data)
+        })
+        .await
+    }
 }
 
 #[async_trait]
 impl super::Storage for S3Storage {
-    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
-        let prefix = prefix.unwrap_or("");
-        let path = ObjectPath::from(prefix);
-        let mut entries = Vec::new();
-        let mut stream = self.store.list(Some(&path));
-        while let Some(entry) = stream.next().await {
-            let entry = entry?;
-            entries.push(entry.location.to_string());
-        }
-        Ok(entries)
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        Ok(super::list_object_store(self.store.as_ref(), prefix))
     }
 
-    async fn read(&self, url: &Url) -> Result<Box<dyn Stream<Item = Result<Bytes, anyhow::Error>> + Send + Sync + Unpin + 'static>> {
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
         let path = self.get_object_path(url)?;
-        let result = self.store.get(&path).await?;
-        let bytes = result.bytes().await?;
-        let stream = futures::stream::once(futures::future::ready(Ok(bytes)));
-        Ok(Box::new(Box::pin(stream)))
+        let store = &self.store;
+        let path = &path;
+        let result = with_retry(&self.retry, || async move { Ok(store.get(path).await?) }).await?;
+        Ok(result
+            .into_stream()
+            .map_err(Error::from)
+            .inspect_ok(|chunk| metrics::bytes_read("s3", chunk.len()))
+            .boxed())
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
     async fn read_all(&self, url: &Url) -> Result<Bytes> {
         let path = self.get_object_path(url)?;
-        let data = self.store.get(&path).await?.bytes().await?;
+        let data = self.read_all_with_retry(&path).await?;
+        tracing::debug!(bytes = data.len(), "read object");
+        metrics::bytes_read("s3", data.len());
         Ok(data)
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url, ?range))]
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let path = self.get_object_path(url)?;
+        let store = &self.store;
+        let path = &path;
+        let data = with_retry(&self.retry, || {
+            let range = range.clone();
+            async move { Ok(store.get_range(path, range).await?) }
+        })
+        .await?;
+        tracing::debug!(bytes = data.len(), "read object");
+        metrics::bytes_read("s3", data.len());
+        Ok(data)
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        let path = self.get_object_path(url)?;
+        let store = &self.store;
+        let path = &path;
+        with_retry(&self.retry, || async move { Ok(store.head(path).await?.size) }).await
+    }
+
+    /// The ETag of an object uploaded in one part is the MD5 of its content.
+    /// Multipart ETags, which end in `-<parts>`, and the ETags of objects
+    /// encrypted with SSE-KMS or SSE-C are not an MD5 of the content.
+    async fn checksum(&self, url: &Url) -> Result<Option<String>> {
+        let path = self.get_object_path(url)?;
+        let store = &self.store;
+        let path = &path;
+        let meta = with_retry(&self.retry, || async move { Ok(store.head(path).await?) }).await?;
+        Ok(meta
+            .e_tag
+            .map(|e_tag| e_tag.trim_matches('"').to_lowercase()))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url, bytes = data.len()))]
     async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
         let path = self.get_object_path(url)?;
-        self.store.put(&path, data.into()).await?;
+        let store = self.store.as_ref();
+        let path = &path;
+        let config = &self.multipart;
+        with_retry(&self.retry, || multipart::put(store, path, data.clone(), config)).await?;
+        metrics::bytes_written("s3", data.len());
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn write_stream(&self, url: &Url, stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        let path = self.get_object_path(url)?;
+        let stream = stream
+            .inspect_ok(|chunk| metrics::bytes_written("s3", chunk.len()))
+            .boxed();
+        multipart::put_stream(self.store.as_ref(), &path, stream, &self.multipart).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn delete(&self, url: &Url) -> Result<()> {
+        let path = self.get_object_path(url)?;
+        let store = &self.store;
+        let path = &path;
+        with_retry(&self.retry, || async move { Ok(store.delete(path).await?) }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> S3Credentials {
+        S3Credentials::Static {
+            access_key_id: "key".to_string(),
+            secret_access_key: "secret".to_string(),
+            session_token: None,
+        }
+    }
+
+    fn from_vars(vars: &[(&str, &str)]) -> Result<S3Credentials> {
+        S3Credentials::from_vars(|name| {
+            vars.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn test_region_does_not_hardcode_us_east_1_endpoint() {
+        let config = S3Config {
+            region: "us-west-2".to_string(),
+            ..S3Config::default()
+        };
+        let builder = S3Storage::builder("bucket", &config, credentials());
+
+        assert_eq!(builder.get_config_value(&AmazonS3ConfigKey::Region).as_deref(), Some("us-west-2"));
+        assert_ne!(
+            builder.get_config_value(&AmazonS3ConfigKey::Endpoint).as_deref(),
+            Some("https://s3.us-east-1.amazonaws.com")
+        );
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn test_s3_compatible_endpoint() {
+        let config = S3Config {
+            endpoint: Some("http://localhost:9000".to_string()),
+            force_path_style: true,
+            ..S3Config::default()
+        };
+        let builder = S3Storage::builder("bucket", &config, credentials());
+
+        assert_eq!(
+            builder.get_config_value(&AmazonS3ConfigKey::Endpoint).as_deref(),
+            Some("http://localhost:9000")
+        );
+        assert_eq!(
+            builder.get_config_value(&AmazonS3ConfigKey::VirtualHostedStyleRequest).as_deref(),
+            Some("false")
+        );
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn test_signing_depends_on_credentials() {
+        let config = S3Config::default();
+
+        let signed = S3Storage::builder("bucket", &config, credentials());
+        assert_eq!(signed.get_config_value(&AmazonS3ConfigKey::AccessKeyId).as_deref(), Some("key"));
+        assert_ne!(signed.get_config_value(&AmazonS3ConfigKey::SkipSignature).as_deref(), Some("true"));
+
+        let anonymous = S3Storage::builder("bucket", &config, S3Credentials::Anonymous);
+        assert_eq!(anonymous.get_config_value(&AmazonS3ConfigKey::SkipSignature).as_deref(), Some("true"));
+    }
+
+    #[test]
+    fn test_static_keys_take_precedence() -> Result<()> {
+        let credentials = from_vars(&[
+            ("AWS_ACCESS_KEY_ID", "key"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("AWS_SESSION_TOKEN", "token"),
+            ("AWS_ROLE_ARN", "arn:aws:iam::123456789012:role/reader"),
+            ("AWS_WEB_IDENTITY_TOKEN_FILE", "/does/not/exist"),
+            ("AWS_SKIP_SIGNATURE", "true"),
+        ])?;
+        assert_eq!(
+            credentials,
+            S3Credentials::Static {
+                access_key_id: "key".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: Some("token".to_string()),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_chain_without_static_keys() -> Result<()> {
+        let credentials = from_vars(&[("AWS_ACCESS_KEY_ID", "key")])?;
+        assert_eq!(credentials, S3Credentials::DefaultChain { container_credentials_relative_uri: None });
+
+        let builder = S3Storage::builder("bucket", &S3Config::default(), credentials);
+        assert_eq!(builder.get_config_value(&AmazonS3ConfigKey::AccessKeyId), None);
+        assert_eq!(builder.get_config_value(&AmazonS3ConfigKey::SecretAccessKey), None);
+        assert_ne!(builder.get_config_value(&AmazonS3ConfigKey::SkipSignature).as_deref(), Some("true"));
+        assert!(builder.build().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_chain_uses_container_credentials() -> Result<()> {
+        let credentials = from_vars(&[("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI", "/v2/credentials/abc")])?;
+        let builder = S3Storage::builder("bucket", &S3Config::default(), credentials);
+        assert_eq!(
+            builder.get_config_value(&AmazonS3ConfigKey::ContainerCredentialsRelativeUri).as_deref(),
+            Some("/v2/credentials/abc")
+        );
+        assert_ne!(builder.get_config_value(&AmazonS3ConfigKey::SkipSignature).as_deref(), Some("true"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_web_identity_credentials() -> Result<()> {
+        let token_file = tempfile::NamedTempFile::new()?;
+        let token_path = token_file.path().to_str().unwrap();
+        let credentials = from_vars(&[
+            ("AWS_ROLE_ARN", "arn:aws:iam::123456789012:role/reader"),
+            ("AWS_WEB_IDENTITY_TOKEN_FILE", token_path),
+        ])?;
+        assert_eq!(
+            credentials,
+            S3Credentials::WebIdentity {
+                role_arn: "arn:aws:iam::123456789012:role/reader".to_string(),
+                token_file: token_path.to_string(),
+            }
+        );
+
+        let builder = S3Storage::builder("bucket", &S3Config::default(), credentials);
+        assert_eq!(builder.get_config_value(&AmazonS3ConfigKey::AccessKeyId), None);
+        assert_ne!(builder.get_config_value(&AmazonS3ConfigKey::SkipSignature).as_deref(), Some("true"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_incomplete_web_identity_is_rejected() {
+        assert!(from_vars(&[("AWS_ROLE_ARN", "arn:aws:iam::123456789012:role/reader")]).is_err());
+        assert!(from_vars(&[
+            ("AWS_ROLE_ARN", "arn:aws:iam::123456789012:role/reader"),
+            ("AWS_WEB_IDENTITY_TOKEN_FILE", "/does/not/exist"),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_skip_signature_opt_in() -> Result<()> {
+        assert_eq!(from_vars(&[("AWS_SKIP_SIGNATURE", "true")])?, S3Credentials::Anonymous);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_retried_with_the_configured_policy() -> Result<()> {
+        use std::sync::atomic::Ordering;
+        use super::super::testing::FlakyStore;
+        use super::super::Storage;
+
+        let store = FlakyStore::new(2);
+        let gets = store.gets();
+        store.put(&ObjectPath::from("/data.csv"), Bytes::from("a,b\n").into()).await?;
+        let storage = S3Storage {
+            store: Box::new(store),
+            bucket: "bucket".to_string(),
+            retry: RetryConfig::default(),
+            multipart: MultipartConfig::default(),
+        }
+        .with_retry_config(RetryConfig {
+            initial_delay_ms: 1,
+            max_delay_ms: 1,
+            ..RetryConfig::default()
+        });
+
+        assert_eq!(storage.read_all(&Url::parse("s3://bucket/data.csv")?).await?, Bytes::from("a,b\n"));
+        assert_eq!(gets.load(Ordering::SeqCst), 3);
         Ok(())
     }
 }
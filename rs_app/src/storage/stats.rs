@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, StringArray, UInt64Array};
+use arrow::record_batch::RecordBatch;
+use arrow::util::pretty::pretty_format_batches;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::path::Path as ObjectPath;
+use object_store::{
+    GetOptions, GetResult, GetResultPayload, ListResult, MultipartUpload, ObjectMeta, ObjectStore, PutMultipartOpts,
+    PutOptions, PutPayload, PutResult, UploadPart,
+};
+use parking_lot::Mutex;
+
+use crate::error::Result;
+
+/// Kind of request a storage operation sends to its backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Operation {
+    Get,
+    Head,
+    Put,
+    Post,
+    List,
+    Delete,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Get => "GET",
+            Operation::Head => "HEAD",
+            Operation::Put => "PUT",
+            Operation::Post => "POST",
+            Operation::List => "LIST",
+            Operation::Delete => "DELETE",
+        })
+    }
+}
+
+/// Requests of one operation and the bytes they transferred
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationStats {
+    pub requests: usize,
+    pub bytes: usize,
+}
+
+/// Requests and bytes by backend and operation, as taken by
+/// [`StorageStatsCollector::snapshot`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    operations: BTreeMap<(&'static str, Operation), OperationStats>,
+}
+
+impl StorageStats {
+    /// Requests and bytes of `operation` on `backend`, zero if there were none
+    pub fn get(&self, backend: &str, operation: Operation) -> OperationStats {
+        self.iter()
+            .find(|(b, o, _)| *b == backend && *o == operation)
+            .map(|(_, _, stats)| stats)
+            .unwrap_or_default()
+    }
+
+    /// Every backend and operation with at least one request, sorted
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Operation, OperationStats)> + '_ {
+        self.operations.iter().map(|(&(backend, operation), &stats)| (backend, operation, stats))
+    }
+
+    pub fn total_requests(&self) -> usize {
+        self.operations.values().map(|stats| stats.requests).sum()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.operations.values().map(|stats| stats.bytes).sum()
+    }
+
+    /// A table of the requests and bytes per backend and operation
+    pub fn to_table(&self) -> Result<String> {
+        let columns: Vec<(&str, ArrayRef)> = vec![
+            ("backend", Arc::new(StringArray::from_iter_values(self.iter().map(|(backend, _, _)| backend)))),
+            (
+                "operation",
+                Arc::new(StringArray::from_iter_values(self.iter().map(|(_, operation, _)| operation.to_string()))),
+            ),
+            (
+                "requests",
+                Arc::new(UInt64Array::from_iter_values(self.iter().map(|(_, _, stats)| stats.requests as u64))),
+            ),
+            ("bytes", Arc::new(UInt64Array::from_iter_values(self.iter().map(|(_, _, stats)| stats.bytes as u64)))),
+        ];
+        let batch = RecordBatch::try_from_iter(columns)?;
+        Ok(format!(
+            "Storage requests: {} ({} bytes)\n{}",
+            self.total_requests(),
+            self.total_bytes(),
+            pretty_format_batches(&[batch])?
+        ))
+    }
+}
+
+/// Shared counts of the requests made through every [`InstrumentedStore`]
+/// created from it; clones count into the same totals
+#[derive(Debug, Clone, Default)]
+pub struct StorageStatsCollector {
+    operations: Arc<Mutex<BTreeMap<(&'static str, Operation), OperationStats>>>,
+}
+
+impl StorageStatsCollector {
+    /// The counts so far
+    pub fn snapshot(&self) -> StorageStats {
+        StorageStats { operations: self.operations.lock().clone() }
+    }
+
+    fn request(&self, backend: &'static str, operation: Operation) {
+        self.operations.lock().entry((backend, operation)).or_default().requests += 1;
+    }
+
+    fn bytes(&self, backend: &'static str, operation: Operation, bytes: usize) {
+        self.operations.lock().entry((backend, operation)).or_default().bytes += bytes;
+    }
+}
+
+/// Object store that counts the requests it sends to the wrapped store, and
+/// the bytes they transfer, by operation.
+///
+/// Each object store call is one request: a ranged read is one GET, every
+/// part of a multipart upload one PUT, and starting and completing the upload
+/// one POST each. Calls the storage backends retry are counted once per
+/// attempt. Listings count one LIST per page of `list_page_size` entries, the
+/// page size the backend uses by default. Bytes of reads are counted as the
+/// response streams through.
+#[derive(Debug)]
+pub struct InstrumentedStore {
+    inner: Box<dyn ObjectStore>,
+    backend: &'static str,
+    collector: StorageStatsCollector,
+    list_page_size: Option<usize>,
+}
+
+impl InstrumentedStore {
+    /// Count the requests to `inner` as `backend`, e.g. `s3`, into `collector`
+    pub fn new(inner: Box<dyn ObjectStore>, backend: &'static str, collector: StorageStatsCollector) -> Self {
+        Self {
+            inner,
+            backend,
+            collector,
+            list_page_size: None,
+        }
+    }
+
+    /// Count a LIST for every `entries` listed entries; without it a listing is one request
+    pub fn with_list_page_size(mut self, entries: usize) -> Self {
+        self.list_page_size = Some(entries.max(1));
+        self
+    }
+
+    fn request(&self, operation: Operation) {
+        self.collector.request(self.backend, operation);
+    }
+
+    fn bytes(&self, operation: Operation, bytes: usize) {
+        self.collector.bytes(self.backend, operation, bytes);
+    }
+
+    /// Count the first page of a listing now and every further page as its first entry arrives
+    fn count_pages<'a>(
+        &self,
+        stream: BoxStream<'a, object_store::Result<ObjectMeta>>,
+    ) -> BoxStream<'a, object_store::Result<ObjectMeta>> {
+        self.request(Operation::List);
+        let (backend, collector, page_size) = (self.backend, self.collector.clone(), self.list_page_size);
+        let mut entries: usize = 0;
+        stream
+            .inspect_ok(move |_| {
+                if page_size.is_some_and(|page_size| entries > 0 && entries.is_multiple_of(page_size)) {
+                    collector.request(backend, Operation::List);
+                }
+                entries += 1;
+            })
+            .boxed()
+    }
+}
+
+impl fmt::Display for InstrumentedStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Instrumented({})", self.inner)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for InstrumentedStore {
+    async fn put_opts(
+        &self,
+        location: &ObjectPath,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> object_store::Result<PutResult> {
+        self.request(Operation::Put);
+        let bytes = payload.content_length();
+        let result = self.inner.put_opts(location, payload, opts).await?;
+        self.bytes(Operation::Put, bytes);
+        Ok(result)
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &ObjectPath,
+        opts: PutMultipartOpts,
+    ) -> object_store::Result<Box<dyn MultipartUpload>> {
+        self.request(Operation::Post);
+        let upload = self.inner.put_multipart_opts(location, opts).await?;
+        Ok(Box::new(InstrumentedUpload {
+            inner: upload,
+            backend: self.backend,
+            collector: self.collector.clone(),
+        }))
+    }
+
+    async fn get_opts(&self, location: &ObjectPath, options: GetOptions) -> object_store::Result<GetResult> {
+        let operation = if options.head { Operation::Head } else { Operation::Get };
+        self.request(operation);
+        let mut result = self.inner.get_opts(location, options).await?;
+        result.payload = match result.payload {
+            GetResultPayload::Stream(stream) => {
+                let (backend, collector) = (self.backend, self.collector.clone());
+                GetResultPayload::Stream(
+                    stream
+                        .inspect_ok(move |chunk| collector.bytes(backend, operation, chunk.len()))
+                        .boxed(),
+                )
+            }
+            // Local files are read by the caller, so count the whole range now
+            payload @ GetResultPayload::File(..) => {
+                self.bytes(operation, result.range.end - result.range.start);
+                payload
+            }
+        };
+        Ok(result)
+    }
+
+    async fn get_range(&self, location: &ObjectPath, range: Range<usize>) -> object_store::Result<Bytes> {
+        self.request(Operation::Get);
+        let data = self.inner.get_range(location, range).await?;
+        self.bytes(Operation::Get, data.len());
+        Ok(data)
+    }
+
+    async fn head(&self, location: &ObjectPath) -> object_store::Result<ObjectMeta> {
+        self.request(Operation::Head);
+        self.inner.head(location).await
+    }
+
+    async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+        self.request(Operation::Delete);
+        self.inner.delete(location).await
+    }
+
+    fn list(&self, prefix: Option<&ObjectPath>) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+        self.count_pages(self.inner.list(prefix))
+    }
+
+    fn list_with_offset(
+        &self,
+        prefix: Option<&ObjectPath>,
+        offset: &ObjectPath,
+    ) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+        self.count_pages(self.inner.list_with_offset(prefix, offset))
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&ObjectPath>) -> object_store::Result<ListResult> {
+        let result = self.inner.list_with_delimiter(prefix).await?;
+        let entries = result.objects.len() + result.common_prefixes.len();
+        let pages = self.list_page_size.map_or(1, |page_size| entries.div_ceil(page_size).max(1));
+        for _ in 0..pages {
+            self.request(Operation::List);
+        }
+        Ok(result)
+    }
+
+    async fn copy(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.request(Operation::Put);
+        self.inner.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.request(Operation::Put);
+        self.inner.copy_if_not_exists(from, to).await
+    }
+}
+
+/// Multipart upload of an [`InstrumentedStore`], counting every part it sends
+#[derive(Debug)]
+struct InstrumentedUpload {
+    inner: Box<dyn MultipartUpload>,
+    backend: &'static str,
+    collector: StorageStatsCollector,
+}
+
+#[async_trait]
+impl MultipartUpload for InstrumentedUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        let bytes = data.content_length();
+        self.collector.request(self.backend, Operation::Put);
+        let (backend, collector) = (self.backend, self.collector.clone());
+        let part = self.inner.put_part(data);
+        Box::pin(async move {
+            part.await?;
+            collector.bytes(backend, Operation::Put, bytes);
+            Ok(())
+        })
+    }
+
+    async fn complete(&mut self) -> object_store::Result<PutResult> {
+        self.collector.request(self.backend, Operation::Post);
+        self.inner.complete().await
+    }
+
+    async fn abort(&mut self) -> object_store::Result<()> {
+        self.collector.request(self.backend, Operation::Delete);
+        self.inner.abort().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::multipart::MultipartConfig;
+    use crate::storage::Storage;
+    use object_store::memory::InMemory;
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_get_count_matches_reads() -> Result<()> {
+        let collector = StorageStatsCollector::default();
+        let storage = MemoryStorage::new().with_stats(collector.clone());
+        let url = Url::parse("memory:///data/input.csv").unwrap();
+        storage.write(&url, Bytes::from_static(b"id,name\n1,a\n")).await?;
+
+        for _ in 0..3 {
+            storage.read_all(&url).await?;
+        }
+        storage.read_range(&url, 0..2).await?;
+        let streamed: Vec<Bytes> = storage.read(&url).await?.try_collect().await?;
+        assert_eq!(streamed.concat().len(), 12);
+        assert_eq!(storage.size(&url).await?, 12);
+
+        let stats = collector.snapshot();
+        assert_eq!(stats.get("memory", Operation::Get), OperationStats { requests: 5, bytes: 3 * 12 + 2 + 12 });
+        assert_eq!(stats.get("memory", Operation::Put), OperationStats { requests: 1, bytes: 12 });
+        assert_eq!(stats.get("memory", Operation::Head).requests, 1);
+        assert_eq!(stats.get("s3", Operation::Get), OperationStats::default());
+        assert_eq!(stats.total_requests(), 7);
+        assert!(stats.to_table()?.contains("GET"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_every_part_of_a_multipart_upload_is_counted() -> Result<()> {
+        let collector = StorageStatsCollector::default();
+        let storage = MemoryStorage::new()
+            .with_multipart_config(MultipartConfig {
+                threshold: 10,
+                part_size: 10,
+                max_concurrency: 2,
+            })
+            .with_stats(collector.clone());
+        let url = Url::parse("memory:///out/data.bin").unwrap();
+
+        storage.write(&url, Bytes::from(vec![0u8; 35])).await?;
+
+        let stats = collector.snapshot();
+        assert_eq!(stats.get("memory", Operation::Put), OperationStats { requests: 4, bytes: 35 });
+        // Starting and completing the upload
+        assert_eq!(stats.get("memory", Operation::Post).requests, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_every_list_page_is_counted() -> Result<()> {
+        let collector = StorageStatsCollector::default();
+        let store = InstrumentedStore::new(Box::new(InMemory::new()), "s3", collector.clone()).with_list_page_size(2);
+        for i in 0..5 {
+            store.put(&ObjectPath::from(format!("in/{}.csv", i)), Bytes::new().into()).await?;
+        }
+
+        let listed: Vec<ObjectMeta> = store.list(Some(&ObjectPath::from("in"))).try_collect().await?;
+        assert_eq!(listed.len(), 5);
+        assert_eq!(collector.snapshot().get("s3", Operation::List).requests, 3);
+
+        store.list_with_delimiter(Some(&ObjectPath::from("in"))).await?;
+        assert_eq!(collector.snapshot().get("s3", Operation::List).requests, 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use std::ops::Range;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use object_store::ObjectMeta;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Mutex, OnceCell};
+use url::Url;
+
+use crate::error::{Error, Result};
+use crate::metrics;
+
+/// URL of the standard input, also written as `-` on the command line
+pub const STDIN: &str = "stdin:";
+/// URL of the standard output, also written as `-` on the command line
+pub const STDOUT: &str = "stdout:";
+
+/// Whether `url` is `stdin:` or `stdout:`
+pub fn is_stdio(url: &Url) -> bool {
+    matches!(url.scheme(), "stdin" | "stdout")
+}
+
+/// `input` with the `-` shorthand replaced by `stdin:`
+pub fn input_arg(input: &str) -> &str {
+    if input == "-" { STDIN } else { input }
+}
+
+/// `output` with the `-` shorthand replaced by `stdout:`
+pub fn output_arg(output: &str) -> &str {
+    if output == "-" { STDOUT } else { output }
+}
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Reads `stdin:` from an input stream and writes `stdout:` to an output stream.
+///
+/// The input can't be rewound, but formats read it more than once, e.g. to
+/// sniff its format and infer its schema before decoding it. So the whole
+/// input is buffered in memory on first access and every read is served
+/// from that buffer. Writes go straight to the output as they arrive.
+pub struct StdioStorage {
+    input: Mutex<Option<Reader>>,
+    buffered: OnceCell<Bytes>,
+    output: Mutex<Writer>,
+}
+
+impl StdioStorage {
+    pub fn new(input: impl AsyncRead + Send + Unpin + 'static, output: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        Self {
+            input: Mutex::new(Some(Box::new(input))),
+            buffered: OnceCell::new(),
+            output: Mutex::new(Box::new(output)),
+        }
+    }
+
+    /// Storage for the standard input and output of this process
+    pub fn process() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+
+    /// The whole input, read to the end on the first call
+    async fn input(&self, url: &Url) -> Result<Bytes> {
+        if url.scheme() != "stdin" {
+            return Err(Error::storage(format!("Cannot read {}, only {}", url, STDIN)));
+        }
+        let data = self
+            .buffered
+            .get_or_try_init(|| async {
+                let mut reader = self
+                    .input
+                    .lock()
+                    .await
+                    .take()
+                    .ok_or_else(|| Error::storage("Standard input was already consumed"))?;
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data).await?;
+                tracing::debug!(bytes = data.len(), "buffered standard input");
+                metrics::bytes_read("stdio", data.len());
+                Ok::<_, Error>(Bytes::from(data))
+            })
+            .await?;
+        Ok(data.clone())
+    }
+
+    fn check_output(url: &Url) -> Result<()> {
+        if url.scheme() != "stdout" {
+            return Err(Error::storage(format!("Cannot write {}, only {}", url, STDOUT)));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl super::Storage for StdioStorage {
+    async fn list_stream(&self, _prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        Err(Error::storage("Standard input and output can't be listed"))
+    }
+
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let data = self.input(url).await?;
+        Ok(futures::stream::once(futures::future::ready(Ok(data))).boxed())
+    }
+
+    async fn read_all(&self, url: &Url) -> Result<Bytes> {
+        self.input(url).await
+    }
+
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        let data = self.input(url).await?;
+        if range.end > data.len() {
+            return Err(Error::storage(format!(
+                "Range {:?} is out of bounds for {} ({} bytes)",
+                range,
+                url,
+                data.len()
+            )));
+        }
+        Ok(data.slice(range))
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        Ok(self.input(url).await?.len())
+    }
+
+    async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
+        Self::check_output(url)?;
+        let mut output = self.output.lock().await;
+        output.write_all(&data).await?;
+        output.flush().await?;
+        metrics::bytes_written("stdio", data.len());
+        Ok(())
+    }
+
+    async fn write_stream(&self, url: &Url, mut stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        Self::check_output(url)?;
+        let mut output = self.output.lock().await;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            output.write_all(&chunk).await?;
+            metrics::bytes_written("stdio", chunk.len());
+        }
+        output.flush().await?;
+        Ok(())
+    }
+
+    /// Writing to the output always appends to what was written before
+    async fn append(&self, url: &Url, data: Bytes) -> Result<()> {
+        self.write(url, data).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::{CsvFormat, DataFormat, EmptyInputPolicy, ParquetFormat};
+    use crate::pipeline::{self, ReadOptions};
+    use crate::storage::Storage;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_csv_from_stdin_to_parquet_on_stdout() -> anyhow::Result<()> {
+        let csv = "id,name\n1,a\n2,b\n3,c\n";
+        let (output, mut captured) = tokio::io::duplex(1024 * 1024);
+        let storage: Arc<dyn Storage> = Arc::new(StdioStorage::new(csv.as_bytes(), output));
+        let stdin = Url::parse(input_arg("-"))?;
+        let stdout = Url::parse(output_arg("-"))?;
+        assert!(is_stdio(&stdin) && is_stdio(&stdout));
+
+        // The input is read once and served from the buffer afterwards
+        assert_eq!(storage.size(&stdin).await?, csv.len());
+        assert_eq!(storage.read_range(&stdin, 0..2).await?, Bytes::from("id"));
+
+        let batches = pipeline::read_batches(
+            storage.clone(),
+            vec![stdin],
+            Arc::new(CsvFormat::default()),
+            ReadOptions::new(EmptyInputPolicy::Error),
+        );
+        let (schema, batches) = pipeline::peek_schema(batches).await?;
+        let df = pipeline::stream_dataframe(schema, batches)?;
+        let format: Arc<dyn DataFormat> = Arc::new(ParquetFormat::default());
+        let stats = pipeline::write_output(df, format.clone(), storage.clone(), &stdout, &[], 1, None).await?;
+        assert_eq!(stats.total_rows(), 3);
+
+        let mut written = vec![0; stats.outputs[0].bytes];
+        captured.read_exact(&mut written).await?;
+        let rows: usize = format.read(&Bytes::from(written))?.collect().await?.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(rows, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrong_direction_is_rejected() -> Result<()> {
+        let storage = StdioStorage::new(&b"data"[..], tokio::io::sink());
+        assert!(storage.read_all(&Url::parse(STDOUT)?).await.is_err());
+        assert!(storage.write(&Url::parse(STDIN)?, Bytes::from("x")).await.is_err());
+        assert!(storage.list(None).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::memory::InMemory;
+use object_store::{
+    GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore, PutMultipartOpts, PutOptions, PutPayload,
+    PutResult, path::Path as ObjectPath,
+};
+use parking_lot::Mutex;
+use url::Url;
+
+use super::Storage;
+use crate::error::{Error, Result};
+
+/// Objects live in an object store, in memory unless [`MockStorage::with_store`]
+/// gives another, keyed by the path of their URL as in `MemoryStorage`.
+///
+/// Whole and ranged reads, writes, appends and concurrent reads are recorded, and
+/// the builder methods make it list a fixed listing, answer slowly, corrupt
+/// writes or report a fixed ETag.
+pub struct MockStorage {
+    store: Box<dyn ObjectStore>,
+    listing: Option<Vec<ObjectMeta>>,
+    latency: Option<Duration>,
+    corrupt_writes: usize,
+    e_tag: Option<String>,
+    reads: AtomicUsize,
+    ranges: Mutex<Vec<Range<usize>>>,
+    writes: AtomicUsize,
+    appends: AtomicUsize,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl MockStorage {
+    pub fn new() -> Self {
+        Self::with_store(Box::new(InMemory::new()))
+    }
+
+    pub fn with_store(store: Box<dyn ObjectStore>) -> Self {
+        Self {
+            store,
+            listing: None,
+            latency: None,
+            corrupt_writes: 0,
+            e_tag: None,
+            reads: AtomicUsize::new(0),
+            ranges: Mutex::new(Vec::new()),
+            writes: AtomicUsize::new(0),
+            appends: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// List `objects` in this order, whatever the prefix
+    pub fn with_listing(mut self, objects: Vec<ObjectMeta>) -> Self {
+        self.listing = Some(objects);
+        self
+    }
+
+    /// Take `latency` to answer every read
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Store the first `writes` writes with their first byte flipped
+    pub fn corrupting(mut self, writes: usize) -> Self {
+        self.corrupt_writes = writes;
+        self
+    }
+
+    /// Report `e_tag` as the checksum of every object instead of its MD5
+    pub fn with_e_tag(mut self, e_tag: &str) -> Self {
+        self.e_tag = Some(e_tag.to_string());
+        self
+    }
+
+    /// Store `data` at `url` without recording a write
+    pub async fn put(&self, url: &Url, data: Bytes) -> Result<()> {
+        self.store.put(&Self::path(url), data.into()).await?;
+        Ok(())
+    }
+
+    /// Number of whole-object reads, by `read` or `read_all`
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+
+    /// Ranges read by `read_range`, in order
+    pub fn ranges(&self) -> Vec<Range<usize>> {
+        self.ranges.lock().clone()
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::SeqCst)
+    }
+
+    /// Number of `append` calls, which don't count as reads or writes
+    pub fn appends(&self) -> usize {
+        self.appends.load(Ordering::SeqCst)
+    }
+
+    /// Most reads that were answered at once
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    fn path(url: &Url) -> ObjectPath {
+        ObjectPath::from(url.path())
+    }
+
+    async fn answer<T>(&self, read: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        if let Some(latency) = self.latency {
+            tokio::time::sleep(latency).await;
+        }
+        let result = read.await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+impl Default for MockStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MockStorage {
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        match &self.listing {
+            Some(objects) => Ok(futures::stream::iter(objects.clone().into_iter().map(Ok)).boxed()),
+            None => Ok(super::list_object_store(self.store.as_ref(), prefix)),
+        }
+    }
+
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let result = self.answer(async { Ok(self.store.get(&Self::path(url)).await?) }).await?;
+        Ok(result.into_stream().map_err(Error::from).boxed())
+    }
+
+    async fn read_all(&self, url: &Url) -> Result<Bytes> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.answer(async { Ok(self.store.get(&Self::path(url)).await?.bytes().await?) }).await
+    }
+
+    async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
+        let writes = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        let data = if writes <= self.corrupt_writes && !data.is_empty() {
+            let mut corrupted = data.to_vec();
+            corrupted[0] ^= 0xff;
+            Bytes::from(corrupted)
+        } else {
+            data
+        };
+        self.put(url, data).await
+    }
+
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        self.ranges.lock().push(range.clone());
+        self.answer(async { Ok(self.store.get_range(&Self::path(url), range).await?) }).await
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        Ok(self.store.head(&Self::path(url)).await?.size)
+    }
+
+    async fn checksum(&self, url: &Url) -> Result<Option<String>> {
+        if let Some(e_tag) = &self.e_tag {
+            return Ok(Some(e_tag.clone()));
+        }
+        let data = self.store.get(&Self::path(url)).await?.bytes().await?;
+        Ok(Some(format!("{:x}", md5::compute(data))))
+    }
+
+    async fn append(&self, url: &Url, data: Bytes) -> Result<()> {
+        self.appends.fetch_add(1, Ordering::SeqCst);
+        let existing = match self.store.get(&Self::path(url)).await {
+            Ok(existing) => existing.bytes().await?,
+            Err(object_store::Error::NotFound { .. }) => Bytes::new(),
+            Err(e) => return Err(e.into()),
+        };
+        self.put(url, Bytes::from([existing, data].concat())).await
+    }
+
+    async fn delete(&self, url: &Url) -> Result<()> {
+        self.store.delete(&Self::path(url)).await?;
+        Ok(())
+    }
+}
+
+/// In-memory object store whose first `failures` reads fail with a
+/// `503 Service Unavailable`, counting every read attempt
+#[derive(Debug)]
+pub struct FlakyStore {
+    inner: InMemory,
+    failures: usize,
+    gets: Arc<AtomicUsize>,
+}
+
+impl FlakyStore {
+    pub fn new(failures: usize) -> Self {
+        Self {
+            inner: InMemory::new(),
+            failures,
+            gets: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Counter of read attempts, which stays readable once the store is boxed
+    pub fn gets(&self) -> Arc<AtomicUsize> {
+        self.gets.clone()
+    }
+}
+
+impl std::fmt::Display for FlakyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FlakyStore")
+    }
+}
+
+#[async_trait]
+impl ObjectStore for FlakyStore {
+    async fn put_opts(&self, location: &ObjectPath, payload: PutPayload, opts: PutOptions) -> object_store::Result<PutResult> {
+        self.inner.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &ObjectPath,
+        opts: PutMultipartOpts,
+    ) -> object_store::Result<Box<dyn MultipartUpload>> {
+        self.inner.put_multipart_opts(location, opts).await
+    }
+
+    async fn get_opts(&self, location: &ObjectPath, options: GetOptions) -> object_store::Result<GetResult> {
+        if self.gets.fetch_add(1, Ordering::SeqCst) < self.failures {
+            return Err(object_store::Error::Generic {
+                store: "FlakyStore",
+                source: "Server error, body contains Error, with status 503 Service Unavailable: No Body".into(),
+            });
+        }
+        self.inner.get_opts(location, options).await
+    }
+
+    async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+        self.inner.delete(location).await
+    }
+
+    fn list(&self, prefix: Option<&ObjectPath>) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+        self.inner.list(prefix)
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&ObjectPath>) -> object_store::Result<ListResult> {
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.inner.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.inner.copy_if_not_exists(from, to).await
+    }
+}
@@ -1,134 +1,354 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bytes::Bytes;
-    use std::env;
-    use tokio;
-    use tempfile::TempDir;
-    use futures_util::TryStreamExt;
-
-    async fn test_storage_operations(storage: Box<dyn Storage>) -> Result<()> {
-        // Test put operation with nested paths
-        let test_data = Bytes::from("Hello, World!");
-        storage.put("nested/path/test.txt", test_data.clone()).await?;
-
-        // Test get operation
-        let retrieved_data = storage.get("nested/path/test.txt").await?;
-        assert_eq!(retrieved_data, test_data);
-
-        // Test list operation with no prefix
-        let mut list_stream = storage.list(None).await?;
-        let mut found = false;
-        while let Some(path) = list_stream.try_next().await? {
-            if path.contains("test.txt") {
-                found = true;
-                break;
-            }
-        }
-        assert!(found, "test.txt should be found in listing");
-
-        // Test list operation with prefix
-        let mut list_stream = storage.list(Some("nested/")).await?;
-        let mut found = false;
-        while let Some(path) = list_stream.try_next().await? {
-            if path.contains("test.txt") {
-                found = true;
-                break;
-            }
-        }
-        assert!(found, "test.txt should be found with prefix");
-
-        // Test non-existent file
-        let result = storage.get("nonexistent.txt").await;
-        assert!(result.is_err(), "Getting non-existent file should fail");
-
-        // Test empty file
-        let empty_data = Bytes::from("");
-        storage.put("empty.txt", empty_data.clone()).await?;
-        let retrieved_empty = storage.get("empty.txt").await?;
-        assert_eq!(retrieved_empty, empty_data);
-
-        // Test large file
-        let large_data = Bytes::from("a".repeat(1024 * 1024)); // 1MB
-        storage.put("large.txt", large_data.clone()).await?;
-        let retrieved_large = storage.get("large.txt").await?;
-        assert_eq!(retrieved_large, large_data);
-
-        Ok(())
+use super::*;
+use super::local::LocalStorage;
+use super::testing::MockStorage;
+use bytes::Bytes;
+use std::env;
+use std::sync::Arc;
+use tokio;
+use tempfile::TempDir;
+
+fn url_for(base: &str, key: &str) -> Url {
+    Url::parse(&format!("{}/{}", base.trim_end_matches('/'), key.trim_start_matches('/'))).unwrap()
+}
+
+async fn test_storage_operations(storage: Box<dyn Storage>, base: &str) -> Result<()> {
+    // Test put operation with nested paths
+    let test_data = Bytes::from("Hello, World!");
+    storage.write(&url_for(base, "nested/path/test.txt"), test_data.clone()).await?;
+
+    // Test get operation
+    let retrieved_data = storage.read_all(&url_for(base, "nested/path/test.txt")).await?;
+    assert_eq!(retrieved_data, test_data);
+
+    // Test list operation with no prefix
+    let listing = storage.list(None).await?;
+    assert!(
+        listing.iter().any(|path| path.contains("test.txt")),
+        "test.txt should be found in listing"
+    );
+
+    // Test list operation with prefix
+    let prefix = url_for(base, "nested/");
+    let listing = storage.list(Some(prefix.path().trim_start_matches('/'))).await?;
+    assert!(
+        listing.iter().any(|path| path.contains("test.txt")),
+        "test.txt should be found with prefix"
+    );
+
+    // Test non-existent file
+    let result = storage.read_all(&url_for(base, "nonexistent.txt")).await;
+    assert!(result.is_err(), "Getting non-existent file should fail");
+
+    // Test empty file
+    let empty_data = Bytes::from("");
+    storage.write(&url_for(base, "empty.txt"), empty_data.clone()).await?;
+    let retrieved_empty = storage.read_all(&url_for(base, "empty.txt")).await?;
+    assert_eq!(retrieved_empty, empty_data);
+
+    // Test large file
+    let large_data = Bytes::from("a".repeat(1024 * 1024)); // 1MB
+    storage.write(&url_for(base, "large.txt"), large_data.clone()).await?;
+    let retrieved_large = storage.read_all(&url_for(base, "large.txt")).await?;
+    assert_eq!(retrieved_large, large_data);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_storage() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let storage = LocalStorage::new(Some(temp_dir.path().to_path_buf()))?;
+    test_storage_operations(Box::new(storage), "file:///").await
+}
+
+#[tokio::test]
+async fn test_memory_storage() -> Result<()> {
+    let storage = memory::MemoryStorage::new();
+    let url = url_for("memory:///", "out/data.csv");
+    assert!(!storage.exists(&url).await?);
+    storage.write(&url, Bytes::from("id\n1\n")).await?;
+    assert!(storage.exists(&url).await?);
+    test_storage_operations(Box::new(storage), "memory:///").await
+}
+
+#[tokio::test]
+async fn test_local_storage_edge_cases() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let storage = LocalStorage::new(Some(temp_dir.path().to_path_buf()))?;
+
+    // Test with absolute paths (should be handled relative to base)
+    let data = Bytes::from("test");
+    storage.write(&url_for("file:///", "/absolute/path.txt"), data.clone()).await?;
+    let retrieved = storage.read_all(&url_for("file:///", "/absolute/path.txt")).await?;
+    assert_eq!(retrieved, data);
+    assert!(temp_dir.path().join("absolute/path.txt").exists());
+
+    // Test with .. in path (URL parsing normalizes it, so the write stays inside the base)
+    storage.write(&url_for("file:///", "../outside.txt"), data.clone()).await?;
+    assert!(temp_dir.path().join("outside.txt").exists());
+    assert!(!temp_dir.path().parent().unwrap().join("outside.txt").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_list_glob() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    for name in ["a.csv", "b.csv", "notes.txt", "sub/c.csv"] {
+        let path = temp_dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, "id\n1\n")?;
     }
+    let storage = LocalStorage::new(None)?;
+    let base = format!("file://{}", temp_dir.path().display());
 
-    #[tokio::test]
-    async fn test_local_storage() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let storage = LocalStorage::new(temp_dir.path().to_string_lossy().to_string());
-        test_storage_operations(Box::new(storage)).await
+    let matched = storage.list_glob(&format!("{}/*.csv", base)).await?;
+    let names: Vec<_> = matched.iter().map(|u| u.path().rsplit('/').next().unwrap().to_string()).collect();
+    assert_eq!(names, vec!["a.csv", "b.csv"]);
+
+    let matched = storage.list_glob(&format!("{}/**/*.csv", base)).await?;
+    assert_eq!(matched.len(), 3);
+    for url in &matched {
+        assert!(storage.read_all(url).await.is_ok());
     }
+    Ok(())
+}
 
-    #[tokio::test]
-    async fn test_local_storage_edge_cases() -> Result<()> {
-        let temp_dir = TempDir::new()?;
-        let storage = LocalStorage::new(temp_dir.path().to_string_lossy().to_string());
-        
-        // Test with absolute paths (should be handled relative to base)
-        let data = Bytes::from("test");
-        storage.put("/absolute/path.txt", data.clone()).await?;
-        let retrieved = storage.get("/absolute/path.txt").await?;
-        assert_eq!(retrieved, data);
-
-        // Test with .. in path (should be normalized)
-        let result = storage.put("../outside.txt", data.clone()).await;
-        assert!(result.is_err(), "Should not allow writing outside base directory");
-
-        Ok(())
+#[tokio::test]
+async fn test_list_prefix() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let storage = LocalStorage::new(None)?;
+    let base = format!("file://{}", temp_dir.path().display());
+    let files = [("a.csv", "id\n1\n"), ("b.csv", "id\n1\n2\n"), ("sub/c.csv", "id\n"), ("sub/deeper/d.csv", "")];
+    for (name, data) in files {
+        storage.write(&url_for(&base, name), Bytes::from(data)).await?;
     }
+    let prefix = Url::parse(&format!("{}/", base))?;
 
-    #[tokio::test]
-    #[ignore] // Run only when AWS credentials are available
-    async fn test_s3_storage() -> Result<()> {
-        // Ensure AWS credentials are set
-        env::var("AWS_ACCESS_KEY_ID").expect("AWS_ACCESS_KEY_ID must be set for test");
-        env::var("AWS_SECRET_ACCESS_KEY").expect("AWS_SECRET_ACCESS_KEY must be set for test");
-        
-        let test_bucket = env::var("TEST_S3_BUCKET").expect("TEST_S3_BUCKET must be set for test");
-        let url = format!("s3://{}/test-prefix", test_bucket);
-        let storage = from_url(&url).await?;
-        test_storage_operations(storage).await
+    let entries = list_prefix(&storage, &prefix, true, None).await?;
+    assert_eq!(entries.len(), files.len());
+    for (entry, (name, data)) in entries.iter().zip(files) {
+        assert_eq!(entry.url(), &url_for(&base, name));
+        assert!(matches!(entry, ListEntry::Object { size, .. } if *size == data.len()));
+        let line = entry.to_string();
+        assert!(line.trim_start().starts_with(&format!("{} ", data.len())), "{}", line);
+        assert!(line.ends_with(&format!(" {}", url_for(&base, name))), "{}", line);
     }
 
-    #[tokio::test]
-    #[ignore] // Run only when Azure credentials are available
-    async fn test_azure_storage() -> Result<()> {
-        // Ensure Azure credentials are set
-        env::var("AZURE_STORAGE_ACCOUNT").expect("AZURE_STORAGE_ACCOUNT must be set for test");
-        env::var("AZURE_STORAGE_ACCESS_KEY").expect("AZURE_STORAGE_ACCESS_KEY must be set for test");
-        
-        let test_container = env::var("TEST_AZURE_CONTAINER")
-            .expect("TEST_AZURE_CONTAINER must be set for test");
-        let url = format!("azure://{}/test-prefix", test_container);
-        let storage = from_url(&url).await?;
-        test_storage_operations(storage).await
+    let entries = list_prefix(&storage, &prefix, false, None).await?;
+    let urls: Vec<String> = entries.iter().map(|entry| entry.url().to_string()).collect();
+    assert_eq!(urls, vec![
+        url_for(&base, "a.csv").to_string(),
+        url_for(&base, "b.csv").to_string(),
+        url_for(&base, "sub/").to_string(),
+    ]);
+    assert!(entries[2].to_string().trim_start().starts_with("DIR"));
+
+    assert_eq!(list_prefix(&storage, &prefix, true, Some(2)).await?.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore] // Run only when AWS credentials are available
+async fn test_s3_storage() -> Result<()> {
+    // Ensure AWS credentials are set
+    env::var("AWS_ACCESS_KEY_ID").expect("AWS_ACCESS_KEY_ID must be set for test");
+    env::var("AWS_SECRET_ACCESS_KEY").expect("AWS_SECRET_ACCESS_KEY must be set for test");
+
+    let test_bucket = env::var("TEST_S3_BUCKET").expect("TEST_S3_BUCKET must be set for test");
+    let url = format!("s3://{}/test-prefix", test_bucket);
+    let storage = from_url(&url)?;
+    test_storage_operations(storage, &url).await
+}
+
+#[tokio::test]
+#[ignore] // Run only when Azure credentials are available
+async fn test_azure_storage() -> Result<()> {
+    // Ensure Azure credentials are set
+    env::var("AZURE_STORAGE_ACCOUNT").expect("AZURE_STORAGE_ACCOUNT must be set for test");
+    env::var("AZURE_STORAGE_ACCESS_KEY").expect("AZURE_STORAGE_ACCESS_KEY must be set for test");
+
+    let test_container = env::var("TEST_AZURE_CONTAINER")
+        .expect("TEST_AZURE_CONTAINER must be set for test");
+    let url = format!("azure://{}/test-prefix", test_container);
+    let storage = from_url(&url)?;
+    test_storage_operations(storage, &url).await
+}
+
+#[tokio::test]
+async fn test_url_parsing() {
+    // Test invalid URL
+    let result = from_url("invalid://test");
+    assert!(result.is_err());
+    let result = from_url("not a url");
+    assert!(result.is_err());
+
+    // Test missing bucket/container
+    let result = from_url("s3:///path");
+    assert!(result.is_err());
+    let result = from_url("azure:///path");
+    assert!(result.is_err());
+
+    // Test valid URLs
+    let result = from_url("file:///tmp/test");
+    assert!(result.is_ok());
+
+    // Without credentials S3 falls back to unsigned requests for public buckets
+    let result = from_url("s3://bucket/path");
+    assert!(result.is_ok());
+
+    let result = from_url("azure://container/path");
+    assert!(result.is_err()); // Should fail due to missing credentials
+
+    // Accepts both &str and parsed URLs
+    let url = Url::parse("file:///tmp/test").unwrap();
+    assert!(from_url(&url).is_ok());
+}
+
+/// Objects listed per page by `PagedStore`
+const PAGE_SIZE: usize = 1000;
+
+/// Object store with `objects` keys whose listing is served a page at a time,
+/// like the paginated listings of S3 and Azure
+#[derive(Debug)]
+struct PagedStore {
+    objects: usize,
+    pages: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl std::fmt::Display for PagedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PagedStore")
+    }
+}
+
+#[async_trait]
+impl ObjectStore for PagedStore {
+    async fn put_opts(
+        &self,
+        _location: &ObjectPath,
+        _payload: object_store::PutPayload,
+        _opts: object_store::PutOptions,
+    ) -> object_store::Result<object_store::PutResult> {
+        Err(object_store::Error::NotImplemented)
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        _location: &ObjectPath,
+        _opts: object_store::PutMultipartOpts,
+    ) -> object_store::Result<Box<dyn object_store::MultipartUpload>> {
+        Err(object_store::Error::NotImplemented)
+    }
+
+    async fn get_opts(
+        &self,
+        _location: &ObjectPath,
+        _options: object_store::GetOptions,
+    ) -> object_store::Result<object_store::GetResult> {
+        Err(object_store::Error::NotImplemented)
     }
 
-    #[tokio::test]
-    async fn test_url_parsing() {
-        // Test invalid URL
-        let result = from_url("invalid://test").await;
-        assert!(result.is_err());
-
-        // Test missing bucket/container
-        let result = from_url("s3:///path").await;
-        assert!(result.is_err());
-        let result = from_url("azure:///path").await;
-        assert!(result.is_err());
-
-        // Test valid URLs
-        let result = from_url("file:///tmp/test").await;
-        assert!(result.is_ok());
-        
-        let result = from_url("s3://bucket/path").await;
-        assert!(result.is_err()); // Should fail due to missing credentials
-
-        let result = from_url("azure://container/path").await;
-        assert!(result.is_err()); // Should fail due to missing credentials
+    async fn delete(&self, _location: &ObjectPath) -> object_store::Result<()> {
+        Err(object_store::Error::NotImplemented)
     }
+
+    /// Every tenth key is a CSV file, the rest are Parquet files
+    fn list(&self, _prefix: Option<&ObjectPath>) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+        futures::stream::iter(0..self.objects.div_ceil(PAGE_SIZE))
+            .map(move |page| {
+                self.pages.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let keys = page * PAGE_SIZE..((page + 1) * PAGE_SIZE).min(self.objects);
+                futures::stream::iter(keys.map(|i| {
+                    let extension = if i % 10 == 0 { "csv" } else { "parquet" };
+                    Ok(ObjectMeta {
+                        location: ObjectPath::from(format!("data/part-{:05}.{}", i, extension)),
+                        last_modified: Default::default(),
+                        size: 0,
+                        e_tag: None,
+                        version: None,
+                    })
+                }))
+            })
+            .flatten()
+            .boxed()
+    }
+
+    async fn list_with_delimiter(
+        &self,
+        _prefix: Option<&ObjectPath>,
+    ) -> object_store::Result<object_store::ListResult> {
+        Err(object_store::Error::NotImplemented)
+    }
+
+    async fn copy(&self, _from: &ObjectPath, _to: &ObjectPath) -> object_store::Result<()> {
+        Err(object_store::Error::NotImplemented)
+    }
+
+    async fn copy_if_not_exists(&self, _from: &ObjectPath, _to: &ObjectPath) -> object_store::Result<()> {
+        Err(object_store::Error::NotImplemented)
+    }
+}
+
+#[tokio::test]
+async fn test_list_stream_fetches_pages_as_it_is_read() -> Result<()> {
+    let pages = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let storage = MockStorage::with_store(Box::new(PagedStore {
+        objects: 10 * PAGE_SIZE,
+        pages: pages.clone(),
+    }));
+    let pages = || pages.load(std::sync::atomic::Ordering::SeqCst);
+
+    let first: Vec<ObjectMeta> = storage.list_stream(None).await?.take(5).try_collect().await?;
+    assert_eq!(first.len(), 5);
+    assert_eq!(first[0].location.as_ref(), "data/part-00000.csv");
+    assert_eq!(pages(), 1);
+
+    // Collecting the listing still reads every page
+    assert_eq!(storage.list(None).await?.len(), 10 * PAGE_SIZE);
+    assert_eq!(pages(), 11);
+
+    let matched = storage.list_glob("mem://bucket/data/*.csv").await?;
+    assert_eq!(matched.len(), PAGE_SIZE);
+    assert_eq!(matched[1].as_str(), "mem://bucket/data/part-00010.csv");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_glob_matches_are_sorted() -> Result<()> {
+    // (name, size, seconds since the epoch it was modified)
+    let files = [("c.csv", 10, 200), ("a.csv", 30, 300), ("notes.txt", 1, 0), ("d.csv", 20, 100), ("b.csv", 20, 400)];
+    // Listed in a fixed, shuffled order, as a backend without ordering guarantees might
+    let storage = MockStorage::new().with_listing(
+        files
+            .iter()
+            .map(|(name, size, modified)| ObjectMeta {
+                location: ObjectPath::from(format!("data/{}", name)),
+                last_modified: DateTime::from_timestamp(*modified, 0).unwrap(),
+                size: *size,
+                e_tag: None,
+                version: None,
+            })
+            .collect(),
+    );
+    let names = |urls: Vec<Url>| -> Vec<String> {
+        urls.iter().map(|url| url.path().rsplit('/').next().unwrap().to_string()).collect()
+    };
+
+    let pattern = "mem://bucket/data/*.csv";
+    assert_eq!(names(storage.list_glob(pattern).await?), vec!["a.csv", "b.csv", "c.csv", "d.csv"]);
+    assert_eq!(
+        names(storage.list_glob_ordered(pattern, InputOrder::Lexical).await?),
+        vec!["a.csv", "b.csv", "c.csv", "d.csv"]
+    );
+    assert_eq!(
+        names(storage.list_glob_ordered(pattern, InputOrder::Mtime).await?),
+        vec!["d.csv", "c.csv", "a.csv", "b.csv"]
+    );
+    // Equal sizes fall back to the URL
+    assert_eq!(
+        names(storage.list_glob_ordered(pattern, InputOrder::Size).await?),
+        vec!["c.csv", "b.csv", "d.csv", "a.csv"]
+    );
+    Ok(())
 }
@@ -0,0 +1,234 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use object_store::ObjectMeta;
+use parking_lot::Mutex;
+use url::Url;
+
+use super::retry::backoff_delay;
+use super::Storage;
+use crate::config::RetryConfig;
+use crate::error::{Error, Result};
+use crate::metrics;
+
+/// Storage that checks every write against the checksum the backend
+/// reports for the written object.
+///
+/// The MD5 of the data, hashed as it streams through for `write_stream`, is
+/// compared with `Storage::checksum`. ETags of multipart uploads and of
+/// objects encrypted with SSE-KMS or SSE-C are not an MD5 of the content, so
+/// when the reported checksum differs the object is read back and hashed
+/// before it counts as a mismatch. `write` rewrites mismatching objects;
+/// `write_stream` can't replay its stream and fails instead. Backends that
+/// report no checksum are not verified.
+pub struct VerifiedStorage {
+    inner: Arc<dyn Storage>,
+    retry: RetryConfig,
+}
+
+impl VerifiedStorage {
+    /// Rewrite mismatching objects up to `retry.max_retries` times
+    pub fn new(inner: Arc<dyn Storage>, retry: RetryConfig) -> Self {
+        Self { inner, retry }
+    }
+
+    /// The MD5 of the stored object if it isn't `expected`
+    async fn mismatch(&self, url: &Url, expected: &str) -> Result<Option<String>> {
+        match self.inner.checksum(url).await? {
+            Some(reported) if reported != expected => {
+                let mut context = md5::Context::new();
+                let mut stream = self.inner.read(url).await?;
+                while let Some(chunk) = stream.next().await {
+                    context.consume(chunk?);
+                }
+                let actual = format!("{:x}", context.compute());
+                Ok(Some(actual).filter(|actual| actual != expected))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for VerifiedStorage {
+    async fn list_stream(&self, prefix: Option<&str>) -> Result<BoxStream<'_, Result<ObjectMeta>>> {
+        self.inner.list_stream(prefix).await
+    }
+
+    async fn read(&self, url: &Url) -> Result<BoxStream<'static, Result<Bytes>>> {
+        self.inner.read(url).await
+    }
+
+    async fn read_all(&self, url: &Url) -> Result<Bytes> {
+        self.inner.read_all(url).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn write(&self, url: &Url, data: Bytes) -> Result<()> {
+        let expected = format!("{:x}", md5::compute(&data));
+        let mut attempt = 0;
+        loop {
+            self.inner.write(url, data.clone()).await?;
+            let actual = match self.mismatch(url, &expected).await? {
+                Some(actual) => actual,
+                None => return Ok(()),
+            };
+            if attempt >= self.retry.max_retries {
+                return Err(Error::storage(format!(
+                    "Checksum of {} is {} after {} attempts, expected {}",
+                    url,
+                    actual,
+                    attempt + 1,
+                    expected
+                )));
+            }
+            tracing::warn!(%actual, %expected, attempt, "checksum mismatch after write, retrying");
+            metrics::storage_retry();
+            tokio::time::sleep(backoff_delay(&self.retry, attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn read_range(&self, url: &Url, range: Range<usize>) -> Result<Bytes> {
+        self.inner.read_range(url, range).await
+    }
+
+    async fn size(&self, url: &Url) -> Result<usize> {
+        self.inner.size(url).await
+    }
+
+    async fn checksum(&self, url: &Url) -> Result<Option<String>> {
+        self.inner.checksum(url).await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(url = %url))]
+    async fn write_stream(&self, url: &Url, stream: BoxStream<'static, Result<Bytes>>) -> Result<()> {
+        let context = Arc::new(Mutex::new(md5::Context::new()));
+        let hasher = context.clone();
+        let stream = stream.inspect_ok(move |chunk| hasher.lock().consume(chunk)).boxed();
+        self.inner.write_stream(url, stream).await?;
+
+        let expected = format!("{:x}", context.lock().clone().compute());
+        match self.mismatch(url, &expected).await? {
+            Some(actual) => Err(Error::storage(format!(
+                "Checksum of {} is {} after a streamed write, expected {}",
+                url, actual, expected
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends go to the inner storage unverified: the object's checksum
+    /// covers what was there before as well, and outputs such as stdout
+    /// can't be read back
+    async fn append(&self, url: &Url, data: Bytes) -> Result<()> {
+        self.inner.append(url, data).await
+    }
+
+    async fn delete(&self, url: &Url) -> Result<()> {
+        self.inner.delete(url).await
+    }
+
+    async fn list_glob_meta(&self, pattern: &str) -> Result<Vec<(Url, ObjectMeta)>> {
+        self.inner.list_glob_meta(pattern).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::MockStorage;
+
+    fn retry(max_retries: u32) -> RetryConfig {
+        RetryConfig {
+            max_retries,
+            initial_delay_ms: 1,
+            max_delay_ms: 1,
+            backoff_multiplier: 1.0,
+            jitter: false,
+        }
+    }
+
+    fn chunks(data: &'static [u8]) -> BoxStream<'static, Result<Bytes>> {
+        futures::stream::iter(data.chunks(3).map(|chunk| Ok(Bytes::from_static(chunk)))).boxed()
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_checksum_is_rewritten() -> Result<()> {
+        let inner = Arc::new(MockStorage::new().corrupting(1));
+        let storage = VerifiedStorage::new(inner.clone(), retry(3));
+        let url = Url::parse("s3://bucket/out.csv")?;
+
+        storage.write(&url, Bytes::from_static(b"a,b\n1,2\n")).await?;
+        assert_eq!(inner.writes(), 2);
+        assert_eq!(storage.read_all(&url).await?, Bytes::from_static(b"a,b\n1,2\n"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_persistent_mismatch_fails_after_max_retries() -> Result<()> {
+        let inner = Arc::new(MockStorage::new().corrupting(usize::MAX));
+        let storage = VerifiedStorage::new(inner.clone(), retry(2));
+        let url = Url::parse("s3://bucket/out.csv")?;
+
+        assert!(storage.write(&url, Bytes::from_static(b"data")).await.is_err());
+        assert_eq!(inner.writes(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streamed_write_is_verified() -> Result<()> {
+        let url = Url::parse("s3://bucket/out.csv")?;
+
+        let intact = Arc::new(MockStorage::new());
+        VerifiedStorage::new(intact.clone(), retry(3)).write_stream(&url, chunks(b"a,b\n1,2\n")).await?;
+        assert_eq!(intact.reads(), 0);
+
+        let corrupting = Arc::new(MockStorage::new().corrupting(1));
+        let storage = VerifiedStorage::new(corrupting.clone(), retry(3));
+        let result = storage.write_stream(&url, chunks(b"a,b\n1,2\n")).await;
+        assert!(result.unwrap_err().to_string().contains("streamed write"));
+        // A stream can't be replayed, so it isn't rewritten
+        assert_eq!(corrupting.writes(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_non_md5_etag_is_checked_by_reading_back() -> Result<()> {
+        let url = Url::parse("s3://bucket/out.csv")?;
+
+        // Multipart uploads report the MD5 of the part MD5s, SSE-KMS an opaque hash
+        for e_tag in ["9b2cf535f27731c974343645a3985328-2", "0123456789abcdef0123456789abcdef"] {
+            let inner = Arc::new(MockStorage::new().with_e_tag(e_tag));
+            let storage = VerifiedStorage::new(inner.clone(), retry(3));
+            storage.write_stream(&url, chunks(b"a,b\n1,2\n")).await?;
+            storage.write(&url, Bytes::from_static(b"data")).await?;
+            assert_eq!(inner.reads(), 2);
+            assert_eq!(inner.writes(), 2);
+        }
+
+        let inner = Arc::new(MockStorage::new().corrupting(1).with_e_tag("9b2cf535f27731c974343645a3985328-2"));
+        let storage = VerifiedStorage::new(inner.clone(), retry(3));
+        storage.write(&url, Bytes::from_static(b"data")).await?;
+        assert_eq!(inner.writes(), 2);
+        assert_eq!(storage.read_all(&url).await?, Bytes::from_static(b"data"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_append_reaches_the_inner_storage() -> Result<()> {
+        let inner = Arc::new(MockStorage::new());
+        let storage = VerifiedStorage::new(inner.clone(), retry(0));
+        let url = Url::parse("s3://bucket/out.csv")?;
+
+        storage.append(&url, Bytes::from_static(b"a\n")).await?;
+        storage.append(&url, Bytes::from_static(b"b\n")).await?;
+        assert_eq!((inner.appends(), inner.writes()), (2, 0));
+        assert_eq!(storage.read_all(&url).await?, Bytes::from_static(b"a\nb\n"));
+        Ok(())
+    }
+}
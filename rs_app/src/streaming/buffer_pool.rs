@@ -1,81 +1,128 @@
 use std::sync::Arc;
+use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
 use parking_lot::Mutex;
-use tokio::sync::Semaphore;
-
-/// A pool of reusable buffers for streaming operations
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A pool of reusable buffers for streaming operations.
+///
+/// Every buffer handed out holds `buffer_size` bytes of the `max_memory`
+/// budget until its guard is dropped, so `acquire` waits once the budget is
+/// used up instead of allocating past it. Clones share the same buffers and
+/// budget.
+#[derive(Clone)]
 pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+/// State shared by a pool, its clones and the guards it hands out
+struct PoolInner {
     /// Available buffers
-    buffers: Arc<Mutex<Vec<BytesMut>>>,
-    /// Semaphore to control buffer allocation
-    semaphore: Arc<Semaphore>,
+    buffers: Mutex<Vec<BytesMut>>,
+    /// Bytes of the memory budget not held by a guard, one permit per byte
+    memory: Arc<Semaphore>,
     /// Size of each buffer
     buffer_size: usize,
     /// Maximum memory allowed for all buffers
     max_memory: usize,
 }
 
+impl PoolInner {
+    /// Return a buffer to the pool; buffers that grew past `buffer_size` are
+    /// dropped so idle buffers never exceed their share of the budget
+    fn return_buffer(&self, mut buffer: BytesMut) {
+        if buffer.capacity() > self.buffer_size {
+            return;
+        }
+        buffer.clear();
+        self.buffers.lock().push(buffer);
+    }
+}
+
 impl BufferPool {
-    /// Create a new buffer pool
-    pub fn new(config: &crate::config::StreamingConfig) -> Self {
-        let buffer_size = config.max_buffer_memory / config.buffer_pool_size;
-        let buffers = (0..config.buffer_pool_size)
-            .map(|_| BytesMut::with_capacity(buffer_size))
-            .collect();
-
-        Self {
-            buffers: Arc::new(Mutex::new(buffers)),
-            semaphore: Arc::new(Semaphore::new(config.buffer_pool_size)),
-            buffer_size,
-            max_memory: config.max_buffer_memory,
+    /// Create a pool of `buffer_size`-byte buffers using at most `max_memory` bytes
+    pub fn new(buffer_size: usize, max_memory: usize) -> Result<Self> {
+        if buffer_size == 0 || buffer_size > u32::MAX as usize {
+            return Err(anyhow!("Invalid buffer size {}", buffer_size));
+        }
+        if max_memory < buffer_size {
+            return Err(anyhow!(
+                "Buffer pool memory {} is smaller than a single {}-byte buffer",
+                max_memory,
+                buffer_size
+            ));
         }
+        Ok(Self {
+            inner: Arc::new(PoolInner {
+                buffers: Mutex::new(Vec::new()),
+                memory: Arc::new(Semaphore::new(max_memory)),
+                buffer_size,
+                max_memory,
+            }),
+        })
     }
 
-    /// Acquire a buffer from the pool
+    /// One `buffer_size` buffer for each of `max_concurrent_streams` streams
+    pub fn from_config(config: &crate::config::StreamingConfig) -> Result<Self> {
+        Self::new(config.buffer_size, config.buffer_size * config.max_concurrent_streams.max(1))
+    }
+
+    /// Acquire a buffer from the pool, waiting while the memory budget is in use
     pub async fn acquire(&self) -> BufferGuard {
-        let _permit = self.semaphore.acquire().await.unwrap();
-        let buffer = self.buffers.lock().pop().unwrap_or_else(|| {
-            BytesMut::with_capacity(self.buffer_size)
+        let permit = Arc::clone(&self.inner.memory)
+            .acquire_many_owned(self.inner.buffer_size as u32)
+            .await
+            .expect("buffer pool semaphore is never closed");
+        self.guard(permit)
+    }
+
+    /// Acquire a buffer only if the memory budget allows it right now
+    pub fn try_acquire(&self) -> Option<BufferGuard> {
+        let permit = Arc::clone(&self.inner.memory)
+            .try_acquire_many_owned(self.inner.buffer_size as u32)
+            .ok()?;
+        Some(self.guard(permit))
+    }
+
+    fn guard(&self, permit: OwnedSemaphorePermit) -> BufferGuard {
+        let buffer = self.inner.buffers.lock().pop().unwrap_or_else(|| {
+            BytesMut::with_capacity(self.inner.buffer_size)
         });
 
         BufferGuard {
             buffer: Some(buffer),
-            pool: Arc::new(self.clone()),
+            pool: Arc::clone(&self.inner),
+            _permit: permit,
         }
     }
 
-    /// Return a buffer to the pool
-    fn return_buffer(&self, mut buffer: BytesMut) {
-        buffer.clear();
-        self.buffers.lock().push(buffer);
-    }
-
     /// Get the buffer size
     pub fn buffer_size(&self) -> usize {
-        self.buffer_size
+        self.inner.buffer_size
     }
 
     /// Get the maximum memory limit
     pub fn max_memory(&self) -> usize {
-        self.max_memory
+        self.inner.max_memory
     }
-}
 
-impl Clone for BufferPool {
-    fn clone(&self) -> Self {
-        Self {
-            buffers: Arc::clone(&self.buffers),
-            semaphore: Arc::clone(&self.semaphore),
-            buffer_size: self.buffer_size,
-            max_memory: self.max_memory,
-        }
+    /// Bytes of the budget currently held by guards
+    pub fn used_memory(&self) -> usize {
+        self.inner.max_memory - self.inner.memory.available_permits()
+    }
+
+    /// Number of buffers waiting in the pool for reuse
+    pub fn idle_buffers(&self) -> usize {
+        self.inner.buffers.lock().len()
     }
 }
 
-/// RAII guard for a buffer from the pool
+/// RAII guard for a buffer from the pool; releases its share of the memory
+/// budget when dropped
 pub struct BufferGuard {
     buffer: Option<BytesMut>,
-    pool: Arc<BufferPool>,
+    pool: Arc<PoolInner>,
+    _permit: OwnedSemaphorePermit,
 }
 
 impl BufferGuard {
@@ -89,7 +136,9 @@ impl BufferGuard {
         self.buffer.as_mut().unwrap()
     }
 
-    /// Convert the buffer into Bytes, consuming the guard
+    /// Convert the buffer into Bytes, consuming the guard.
+    ///
+    /// The buffer leaves the pool, so its memory stops counting against the budget.
     pub fn into_bytes(mut self) -> Bytes {
         self.buffer.take().unwrap().freeze()
     }
@@ -106,21 +155,12 @@ impl Drop for BufferGuard {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::StreamingConfig;
+    use std::time::Duration;
 
     #[tokio::test]
     async fn test_buffer_pool() {
-        let config = StreamingConfig {
-            max_buffer_memory: 1024 * 1024,
-            buffer_pool_size: 4,
-            read_timeout_secs: 30,
-            write_timeout_secs: 30,
-            enable_backpressure: true,
-            max_in_flight_batches: 2,
-        };
+        let pool = BufferPool::new(256 * 1024, 1024 * 1024).unwrap();
 
-        let pool = BufferPool::new(&config);
-        
         // Test acquiring buffers
         let mut guards = Vec::new();
         for _ in 0..4 {
@@ -128,7 +168,8 @@ mod tests {
         }
 
         // Verify buffer size
-        assert_eq!(pool.buffer_size(), 262144); // 1MB / 4
+        assert_eq!(pool.buffer_size(), 262144);
+        assert_eq!(pool.used_memory(), 1024 * 1024);
 
         // Write to buffers
         for guard in &mut guards {
@@ -137,9 +178,66 @@ mod tests {
 
         // Release buffers
         drop(guards);
+        assert_eq!(pool.used_memory(), 0);
 
         // Verify we can acquire again
         let guard = pool.acquire().await;
         assert_eq!(guard.get_ref().capacity(), 262144);
     }
+
+    #[tokio::test]
+    async fn test_acquire_blocks_at_memory_budget() {
+        let pool = BufferPool::new(1024, 3 * 1024).unwrap();
+        let mut guards = vec![pool.acquire().await, pool.acquire().await, pool.acquire().await];
+
+        // A fourth buffer would exceed the budget, so acquiring waits
+        assert!(pool.try_acquire().is_none());
+        assert!(tokio::time::timeout(Duration::from_millis(50), pool.acquire()).await.is_err());
+        assert_eq!(pool.used_memory(), 3 * 1024);
+
+        // Releasing a buffer unblocks a waiting acquire
+        let waiting = {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.acquire().await.get_ref().capacity() })
+        };
+        guards.pop();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_grown_buffers_are_not_pooled() {
+        let pool = BufferPool::new(1024, 4 * 1024).unwrap();
+        let mut grown = pool.acquire().await;
+        grown.get_mut().extend_from_slice(&[0u8; 4096]);
+        let normal = pool.acquire().await;
+        drop(grown);
+        drop(normal);
+
+        assert_eq!(pool.idle_buffers(), 1);
+        assert_eq!(pool.acquire().await.get_ref().capacity(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_released_buffers_return_to_the_same_pool() {
+        let pool = BufferPool::new(1024, 2 * 1024).unwrap();
+        let clone = pool.clone();
+
+        let guard = pool.acquire().await;
+        let ptr = guard.get_ref().as_ptr();
+        drop(guard);
+
+        // The buffer is back in the pool shared by every clone, and reused
+        assert_eq!(pool.idle_buffers(), 1);
+        assert_eq!(clone.idle_buffers(), 1);
+        let guard = clone.acquire().await;
+        assert_eq!(guard.get_ref().as_ptr(), ptr);
+        assert_eq!(pool.idle_buffers(), 0);
+        assert_eq!(pool.used_memory(), 1024);
+    }
+
+    #[test]
+    fn test_budget_must_fit_a_buffer() {
+        assert!(BufferPool::new(1024, 512).is_err());
+        assert!(BufferPool::new(0, 512).is_err());
+    }
 }
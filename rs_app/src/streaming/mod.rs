@@ -0,0 +1,3 @@
+pub mod buffer_pool;
+
+pub use buffer_pool::{BufferGuard, BufferPool};
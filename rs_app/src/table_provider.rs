@@ -1,42 +1,178 @@
 use std::any::Any;
-use std::pin::Pin;
 use std::sync::Arc;
-use std::task::{Context, Poll};
 
-use anyhow::Result;
 use arrow::datatypes::SchemaRef;
-use arrow::record_batch::RecordBatch;
+use datafusion::common::DFSchema;
 use datafusion::datasource::TableProvider;
 use datafusion::error::DataFusionError;
-use datafusion::execution::context::{SessionState, TaskContext};
+use datafusion::execution::context::{SessionContext, SessionState};
 use datafusion::logical_expr::{Expr, TableProviderFilterPushDown, TableType};
-use datafusion::physical_plan::{
-    DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
-    SendableRecordBatchStream, Statistics,
-};
-use datafusion::physical_plan::expressions::PhysicalSortExpr;
-use futures::{ready, Stream, StreamExt};
+use datafusion::physical_expr::create_physical_expr;
+use datafusion::physical_plan::{ExecutionPlan, Statistics};
+use futures::{StreamExt, TryStreamExt};
+use url::Url;
 
-use crate::formats::DataFormat;
+use crate::error;
+use crate::execution::{BatchSource, FormatExecPlan};
+use crate::formats::{BatchStream, DataFormat, EmptyInputPolicy};
+use crate::pipeline::{self, ReadOptions};
+use crate::schema;
+use crate::stats;
+use crate::storage::{self, Storage};
 
+/// A table over one or more files in storage, decoded with a `DataFormat`.
+///
+/// Every scan re-reads the files, so the provider can be queried repeatedly.
+/// When the format reports exact statistics, as Parquet does from its footer,
+/// DataFusion answers unfiltered `COUNT(*)`, `MIN` and `MAX` from them
+/// without reading any data; other queries scan the files.
+///
+/// Formats that read a row group at a time, like Parquet, are scanned in up
+/// to one partition per row group, capped by the session's
+/// `target_partitions`, so DataFusion can decode the row groups in parallel.
 pub struct FormatTableProvider {
-    format: Box<dyn DataFormat + Send + Sync>,
+    format: Arc<dyn DataFormat>,
+    storage: Arc<dyn Storage>,
+    urls: Vec<Url>,
     schema: SchemaRef,
-    data: Pin<Box<dyn Stream<Item = Result<RecordBatch, anyhow::Error>> + Send + Sync + 'static>>,
+    /// Planning statistics of all files, read on the first scan
+    statistics: tokio::sync::OnceCell<Statistics>,
+    /// Every row group as `(file, index)`, or `None` when the format reads
+    /// files whole; read on the first scan
+    row_groups: tokio::sync::OnceCell<Option<Vec<(Url, usize)>>>,
 }
 
 impl FormatTableProvider {
-    pub fn new(
-        format: Box<dyn DataFormat + Send + Sync>,
-        schema: SchemaRef,
-        data: Pin<Box<dyn Stream<Item = Result<RecordBatch, anyhow::Error>> + Send + Sync + 'static>>,
-    ) -> Self {
+    pub fn new(format: Arc<dyn DataFormat>, storage: Arc<dyn Storage>, urls: Vec<Url>, schema: SchemaRef) -> Self {
         Self {
             format,
+            storage,
+            urls,
             schema,
-            data,
+            statistics: tokio::sync::OnceCell::new(),
+            row_groups: tokio::sync::OnceCell::new(),
         }
     }
+
+    /// Combined planning statistics of every file
+    async fn plan_statistics(&self) -> error::Result<Statistics> {
+        let mut combined: Option<Statistics> = None;
+        for url in &self.urls {
+            let size = self.storage.size(url).await?;
+            let file = self.format.plan_statistics(self.storage.clone(), url, size).await?;
+            combined = Some(match combined {
+                Some(combined) => stats::merge_statistics(combined, file),
+                None => file,
+            });
+        }
+        Ok(combined.unwrap_or_default())
+    }
+
+    /// Row groups of every file, in file order
+    async fn row_groups(&self) -> error::Result<Option<Vec<(Url, usize)>>> {
+        let mut row_groups = Vec::new();
+        for url in &self.urls {
+            let size = self.storage.size(url).await?;
+            // Empty files have no rows to read
+            if size == 0 {
+                continue;
+            }
+            match self.format.row_group_count(self.storage.clone(), url, size).await? {
+                Some(count) => row_groups.extend((0..count).map(|index| (url.clone(), index))),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(row_groups))
+    }
+}
+
+/// Split `row_groups` into at most `partitions` runs of consecutive row
+/// groups, each listed per file, whose lengths differ by at most one
+fn partition_row_groups(row_groups: &[(Url, usize)], partitions: usize) -> Vec<Vec<(Url, Vec<usize>)>> {
+    let partitions = partitions.clamp(1, row_groups.len().max(1));
+    let (length, longer) = (row_groups.len() / partitions, row_groups.len() % partitions);
+    let mut remaining = row_groups;
+    (0..partitions)
+        .map(|partition| {
+            let (run, rest) = remaining.split_at(length + usize::from(partition < longer));
+            remaining = rest;
+            let mut files: Vec<(Url, Vec<usize>)> = Vec::new();
+            for (url, index) in run {
+                match files.last_mut() {
+                    Some((last, indices)) if last == url => indices.push(*index),
+                    _ => files.push((url.clone(), vec![*index])),
+                }
+            }
+            files
+        })
+        .collect()
+}
+
+/// Columns to decode when the scan only needs `projection` and the columns
+/// `filters` refer to, in table order, and `projection` renumbered to index
+/// them; `None` when a filter refers to a column the table doesn't have
+fn pushdown_columns(schema: &SchemaRef, projection: &[usize], filters: &[Expr]) -> Option<(Vec<usize>, Vec<usize>)> {
+    let mut columns = projection.to_vec();
+    for filter in filters {
+        for column in filter.to_columns().ok()? {
+            columns.push(schema.index_of(&column.name).ok()?);
+        }
+    }
+    columns.sort_unstable();
+    columns.dedup();
+    // Decoding no columns at all would lose the row count
+    if columns.is_empty() {
+        return None;
+    }
+    let projection = projection.iter().map(|index| columns.binary_search(index).ok()).collect::<Option<_>>()?;
+    Some((columns, projection))
+}
+
+/// Stream the listed row groups of each file, relabelled with the table's
+/// `schema`, decoding only the columns at `projection` when it is set
+fn read_row_groups(
+    storage: Arc<dyn Storage>,
+    format: Arc<dyn DataFormat>,
+    schema: SchemaRef,
+    files: Vec<(Url, Vec<usize>)>,
+    projection: Option<Vec<usize>>,
+) -> BatchStream {
+    futures::stream::iter(files)
+        .then(move |(url, row_groups)| {
+            let (storage, format, schema, projection) =
+                (storage.clone(), format.clone(), schema.clone(), projection.clone());
+            async move {
+                let size = storage.size(&url).await?;
+                let batches = format.read_row_groups_from_storage(storage, &url, size, row_groups, projection).await?;
+                Ok::<_, error::Error>(batches.and_then(move |batch| {
+                    let batch = schema::conform_batch(batch, &schema, false).map_err(|e| {
+                        error::Error::schema_mismatch(format!("Schema of {} does not match the table", url), e)
+                    });
+                    futures::future::ready(batch)
+                }))
+            }
+        })
+        .try_flatten()
+        .boxed()
+}
+
+/// Register the file at `url` as table `name` of `ctx`.
+///
+/// The storage backend is picked from the URL scheme and the schema is
+/// inferred up front; rows are only read when a query scans the table.
+pub async fn register_as_table(
+    ctx: &SessionContext,
+    name: &str,
+    url: &Url,
+    format: Box<dyn DataFormat>,
+) -> error::Result<()> {
+    let storage: Arc<dyn Storage> = storage::from_url(url)?.into();
+    let format: Arc<dyn DataFormat> = format.into();
+    let size = storage.size(url).await?;
+    let schema = format.infer_schema(storage.clone(), url, size).await?;
+    let provider = FormatTableProvider::new(format, storage, vec![url.clone()], schema);
+    ctx.register_table(name, Arc::new(provider))?;
+    Ok(())
 }
 
 #[async_trait::async_trait]
@@ -55,199 +191,344 @@ impl TableProvider for FormatTableProvider {
 
     async fn scan(
         &self,
-        _state: &SessionState,
+        state: &SessionState,
         projection: Option<&Vec<usize>>,
         filters: &[Expr],
         limit: Option<usize>,
     ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
-        let data = Box::pin(futures::stream::once(futures::future::ready(Ok(RecordBatch::new_empty(self.schema.clone())))));
-        let exec = FormatExecPlan::new(
-            data,
-            self.schema.clone(),
-            projection.cloned(),
-            filters.to_vec(),
-            limit,
-        );
+        // Formats that skip unselected columns while decoding only read the
+        // projected columns and those the filters need; the scan then works
+        // on that narrower schema
+        let pushdown = match projection {
+            Some(projection) if self.format.capabilities().projection_pushdown => {
+                pushdown_columns(&self.schema, projection, filters)
+            }
+            _ => None,
+        };
+        let (schema, read_columns, projection) = match pushdown {
+            Some((columns, projection)) => (Arc::new(self.schema.project(&columns)?), Some(columns), Some(projection)),
+            None => (self.schema.clone(), None, projection.cloned()),
+        };
+
+        let df_schema = DFSchema::try_from(schema.as_ref().clone())?;
+        let filters = filters
+            .iter()
+            .map(|filter| create_physical_expr(filter, &df_schema, &schema, state.execution_props()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let row_groups = self
+            .row_groups
+            .get_or_try_init(|| self.row_groups())
+            .await
+            .map_err(|e| DataFusionError::External(e.into()))?;
+        let (format, storage, urls) = (self.format.clone(), self.storage.clone(), self.urls.clone());
+        let (source, partitions): (BatchSource, usize) = match row_groups {
+            Some(row_groups) if !row_groups.is_empty() => {
+                let partitions = partition_row_groups(row_groups, state.config().target_partitions());
+                let count = partitions.len();
+                let (schema, read_columns) = (schema.clone(), read_columns.clone());
+                let source: BatchSource = Arc::new(move |partition: usize| {
+                    let files = partitions[partition].clone();
+                    read_row_groups(storage.clone(), format.clone(), schema.clone(), files, read_columns.clone())
+                });
+                (source, count)
+            }
+            _ => {
+                let mut options = ReadOptions::new(EmptyInputPolicy::EmptyOutput);
+                if read_columns.is_some() {
+                    let names = schema.fields().iter().map(|field| field.name().clone()).collect();
+                    options = options.with_columns(pipeline::ColumnSelection::Select(names));
+                }
+                let source: BatchSource = Arc::new(move |_: usize| {
+                    pipeline::read_batches(storage.clone(), urls.clone(), format.clone(), options.clone())
+                });
+                (source, 1)
+            }
+        };
+        let mut statistics = self
+            .statistics
+            .get_or_try_init(|| self.plan_statistics())
+            .await
+            .map_err(|e| DataFusionError::External(e.into()))?
+            .clone();
+        if let (Some(indices), Some(columns)) = (&read_columns, &statistics.column_statistics) {
+            statistics.column_statistics = Some(indices.iter().map(|&i| columns[i].clone()).collect());
+        }
+        let exec = FormatExecPlan::new(source, schema, projection, filters, limit)
+            .map_err(|e| DataFusionError::External(e.into()))?
+            .with_partitions(partitions)
+            .with_statistics(statistics);
         Ok(Arc::new(exec))
     }
 
+    /// Filters are evaluated while scanning, but DataFusion re-applies them
+    /// so rows are never returned incorrectly
     fn supports_filter_pushdown(
         &self,
         _filter: &Expr,
     ) -> Result<TableProviderFilterPushDown, DataFusionError> {
-        Ok(TableProviderFilterPushDown::Unsupported)
+        Ok(TableProviderFilterPushDown::Inexact)
     }
 }
 
-pub struct FormatExecPlan {
-    stream: Pin<Box<dyn Stream<Item = Result<RecordBatch, DataFusionError>> + Send + Sync + 'static>>,
-    schema: SchemaRef,
-    projection: Option<Vec<usize>>,
-    filters: Vec<Expr>,
-    limit: Option<usize>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::CsvFormat;
+    use crate::storage::local::LocalStorage;
+    use arrow::array::Int64Array;
+    use tempfile::TempDir;
 
-impl std::fmt::Debug for FormatExecPlan {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("FormatExecPlan")
-            .field("schema", &self.schema)
-            .field("projection", &self.projection)
-            .finish()
-    }
-}
+    #[tokio::test]
+    async fn test_filtered_scan_returns_matching_rows() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(temp_dir.path().join("data.csv"), "id,name\n1,a\n2,b\n3,c\n4,d\n")?;
+        let url = Url::from_file_path(temp_dir.path().join("data.csv")).unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let format: Arc<dyn DataFormat> = Arc::new(CsvFormat::default());
 
-impl FormatExecPlan {
-    pub fn new(
-        stream: Pin<Box<dyn Stream<Item = Result<RecordBatch, DataFusionError>> + Send + Sync + 'static>>,
-        schema: SchemaRef,
-        projection: Option<Vec<usize>>,
-        filters: Vec<Expr>,
-        limit: Option<usize>,
-    ) -> Self {
-        Self {
-            stream,
-            schema,
-            projection,
-            filters,
-            limit,
-        }
-    }
-}
+        let (schema, _) = pipeline::peek_schema(pipeline::read_batches(
+            storage.clone(),
+            vec![url.clone()],
+            format.clone(),
+            ReadOptions::new(EmptyInputPolicy::Error),
+        ))
+        .await?;
+        let provider = FormatTableProvider::new(format, storage, vec![url], schema);
 
-impl Clone for FormatExecPlan {
-    fn clone(&self) -> Self {
-        Self {
-            stream: Box::pin(futures::stream::empty()),
-            schema: self.schema.clone(),
-            projection: self.projection.clone(),
-            filters: self.filters.clone(),
-            limit: self.limit,
-        }
-    }
-}
+        let ctx = SessionContext::new();
+        ctx.register_table("data", Arc::new(provider))?;
+        let batches = ctx.sql("SELECT id FROM data WHERE id >= 2 AND name <> 'c'").await?.collect().await?;
 
-impl DisplayAs for FormatExecPlan {
-    fn fmt_as(&self, _t: DisplayFormatType, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "FormatExecPlan")
-    }
-}
+        let ids: Vec<i64> = batches
+            .iter()
+            .flat_map(|batch| {
+                let column = batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+                column.values().to_vec()
+            })
+            .collect();
+        assert_eq!(ids, vec![2, 4]);
 
-impl ExecutionPlan for FormatExecPlan {
-    fn as_any(&self) -> &dyn Any {
-        self
+        // The provider can be scanned again
+        let batches = ctx.sql("SELECT count(*) FROM data").await?.collect().await?;
+        let count = batches[0].column(0).as_any().downcast_ref::<Int64Array>().unwrap().value(0);
+        assert_eq!(count, 4);
+        Ok(())
     }
 
-    fn schema(&self) -> SchemaRef {
-        self.schema.clone()
-    }
+    #[tokio::test]
+    async fn test_register_as_table() -> anyhow::Result<()> {
+        let temp_dir = TempDir::new()?;
+        std::fs::write(temp_dir.path().join("people.csv"), "id,name\n1,a\n2,b\n3,c\n")?;
+        let url = Url::from_file_path(temp_dir.path().join("people.csv")).unwrap();
 
-    fn output_partitioning(&self) -> Partitioning {
-        Partitioning::UnknownPartitioning(1)
-    }
+        let ctx = SessionContext::new();
+        register_as_table(&ctx, "people", &url, Box::new(CsvFormat::default())).await?;
+        let batches = ctx.sql("SELECT name FROM people WHERE id > 1 ORDER BY id").await?.collect().await?;
 
-    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
-        None
+        let names: Vec<String> = batches
+            .iter()
+            .flat_map(|batch| {
+                let column = batch.column(0).as_any().downcast_ref::<arrow::array::StringArray>().unwrap();
+                column.iter().map(|name| name.unwrap().to_string()).collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(names, vec!["b", "c"]);
+        Ok(())
     }
 
-    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
-        vec![]
-    }
+    #[tokio::test]
+    async fn test_parquet_metadata_reaches_table_schema() -> anyhow::Result<()> {
+        use crate::formats::ParquetFormat;
+        use arrow::datatypes::{DataType, Field, Schema};
+        use arrow::record_batch::RecordBatch;
+        use std::collections::HashMap;
 
-    fn with_new_children(
-        self: Arc<Self>,
-        _children: Vec<Arc<dyn ExecutionPlan>>,
-    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
-        Ok(self)
-    }
+        let unit = HashMap::from([("unit".to_string(), "m".to_string())]);
+        let schema = Arc::new(Schema::new_with_metadata(
+            vec![Field::new("depth", DataType::Int64, false).with_metadata(unit.clone())],
+            HashMap::from([("source".to_string(), "survey".to_string())]),
+        ));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int64Array::from(vec![1, 2]))])?;
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("depths.parquet");
+        std::fs::write(&path, ParquetFormat::default().write_batch(&batch)?)?;
+        let url = Url::from_file_path(&path).unwrap();
 
-    fn execute(
-        &self,
-        _partition: usize,
-        _context: Arc<TaskContext>,
-    ) -> Result<SendableRecordBatchStream, DataFusionError> {
-        let stream = Box::pin(futures::stream::once(futures::future::ready(Ok(RecordBatch::new_empty(self.schema.clone())))));
-        let stream = FormatStream::new(
-            stream,
-            self.schema.clone(),
-            self.projection.clone(),
-            self.filters.clone(),
-            self.limit,
-        );
-        Ok(Box::pin(stream))
-    }
-
-    fn statistics(&self) -> Statistics {
-        Statistics::default()
+        let ctx = SessionContext::new();
+        register_as_table(&ctx, "depths", &url, Box::new(ParquetFormat::default())).await?;
+        let table = ctx.table("depths").await?;
+        assert_eq!(table.schema().field_with_unqualified_name("depth")?.field().metadata(), &unit);
+        assert_eq!(table.schema().metadata().get("source").map(String::as_str), Some("survey"));
+        Ok(())
     }
-}
 
-pub struct FormatStream {
-    inner: Pin<Box<dyn Stream<Item = Result<RecordBatch, DataFusionError>> + Send + Sync + 'static>>,
-    schema: SchemaRef,
-    projection: Option<Vec<usize>>,
-    filters: Vec<Expr>,
-    limit: Option<usize>,
-    count: usize,
-}
+    #[tokio::test]
+    async fn test_parquet_row_groups_are_scanned_as_partitions() -> anyhow::Result<()> {
+        use crate::formats::{ParquetConfig, ParquetFormat};
+        use arrow::datatypes::{DataType, Field, Schema};
+        use arrow::record_batch::RecordBatch;
+        use datafusion::prelude::SessionConfig;
 
-impl FormatStream {
-    fn new(
-        inner: Pin<Box<dyn Stream<Item = Result<RecordBatch, DataFusionError>> + Send + Sync + 'static>>,
-        schema: SchemaRef,
-        projection: Option<Vec<usize>>,
-        filters: Vec<Expr>,
-        limit: Option<usize>,
-    ) -> Self {
-        Self {
-            inner,
-            schema,
-            projection,
-            filters,
-            limit,
-            count: 0,
-        }
-    }
-}
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from_iter_values(0..12))])?;
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(3),
+            ..ParquetConfig::default()
+        });
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("data.parquet");
+        std::fs::write(&path, format.write_batch(&batch)?)?;
+        let url = Url::from_file_path(&path).unwrap();
 
-impl Stream for FormatStream {
-    type Item = Result<RecordBatch, DataFusionError>;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let provider = FormatTableProvider::new(Arc::new(format), storage, vec![url], schema);
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(8));
+        let plan = provider.scan(&ctx.state(), None, &[], None).await?;
+        assert_eq!(plan.output_partitioning().partition_count(), 4);
 
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if let Some(limit) = self.limit {
-            if self.count >= limit {
-                return Poll::Ready(None);
+        let mut ids = Vec::new();
+        for partition in 0..4 {
+            let batches: Vec<RecordBatch> = plan.execute(partition, ctx.task_ctx())?.try_collect().await?;
+            for batch in &batches {
+                ids.extend(batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap().values().iter().copied());
             }
         }
+        ids.sort_unstable();
+        assert_eq!(ids, (0..12).collect::<Vec<i64>>());
+        assert!(plan.execute(4, ctx.task_ctx()).is_err());
 
-        let result = ready!(self.inner.as_mut().poll_next(cx));
-        match result {
-            Some(Ok(batch)) => {
-                let mut filtered_batch = batch;
+        // Fewer target partitions group consecutive row groups together
+        let ctx = SessionContext::new_with_config(SessionConfig::new().with_target_partitions(3));
+        let plan = provider.scan(&ctx.state(), None, &[], None).await?;
+        assert_eq!(plan.output_partitioning().partition_count(), 3);
+        let count = ctx.read_table(Arc::new(provider))?.count().await?;
+        assert_eq!(count, 12);
+        Ok(())
+    }
 
-                // Apply filters
-                for _filter in &self.filters {
-                    // We don't support runtime filter evaluation yet
-                    // Just pass through the data for now
-                }
+    #[tokio::test]
+    async fn test_projection_is_pushed_down_to_parquet() -> anyhow::Result<()> {
+        use crate::formats::{ParquetConfig, ParquetFormat};
+        use arrow::array::StringArray;
+        use arrow::datatypes::{DataType, Field, Schema};
+        use arrow::record_batch::RecordBatch;
 
-                // Apply projection
-                if let Some(ref projection) = self.projection {
-                    filtered_batch = filtered_batch
-                        .project(projection)
-                        .map_err(|e| DataFusionError::Execution(format!("Error projecting batch: {}", e)))?;
-                }
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::Utf8, false),
+            Field::new("c", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3, 4])),
+                Arc::new(StringArray::from(vec!["w", "x", "y", "z"])),
+                Arc::new(Int64Array::from(vec![10, 20, 30, 40])),
+            ],
+        )?;
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(2),
+            ..ParquetConfig::default()
+        });
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir.path().join("data.parquet");
+        std::fs::write(&path, format.write_batch(&batch)?)?;
+        let url = Url::from_file_path(&path).unwrap();
 
-                self.count += filtered_batch.num_rows();
-                Poll::Ready(Some(Ok(filtered_batch)))
-            }
-            Some(Err(e)) => Poll::Ready(Some(Err(e))),
-            None => Poll::Ready(None),
-        }
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(None)?);
+        let provider = FormatTableProvider::new(Arc::new(format), storage, vec![url], schema.clone());
+        let ctx = SessionContext::new();
+        let filter = datafusion::prelude::col("a").gt(datafusion::prelude::lit(1i64));
+        let plan = provider.scan(&ctx.state(), Some(&vec![2]), &[filter.clone()], None).await?;
+        assert_eq!(plan.schema().fields().len(), 1);
+        assert_eq!(plan.schema().field(0).name(), "c");
+
+        ctx.register_table("data", Arc::new(provider))?;
+        let batches = ctx.sql("SELECT c FROM data WHERE a > 1 ORDER BY c").await?.collect().await?;
+        let values: Vec<i64> = batches
+            .iter()
+            .flat_map(|batch| batch.column(0).as_any().downcast_ref::<Int64Array>().unwrap().values().to_vec())
+            .collect();
+        assert_eq!(values, vec![20, 30, 40]);
+
+        // The filter's column is decoded too, and the projection renumbered
+        let (columns, projection) = pushdown_columns(&schema, &[2], &[filter]).unwrap();
+        assert_eq!(columns, vec![0, 2]);
+        assert_eq!(projection, vec![1]);
+        assert!(pushdown_columns(&schema, &[], &[]).is_none());
+        Ok(())
     }
-}
 
-impl RecordBatchStream for FormatStream {
-    fn schema(&self) -> SchemaRef {
-        self.schema.clone()
+    #[test]
+    fn test_partition_row_groups_keeps_runs_per_file() {
+        let (a, b) = (Url::parse("file:///a.parquet").unwrap(), Url::parse("file:///b.parquet").unwrap());
+        let row_groups = vec![(a.clone(), 0), (a.clone(), 1), (a.clone(), 2), (b.clone(), 0), (b.clone(), 1)];
+
+        let partitions = partition_row_groups(&row_groups, 2);
+        assert_eq!(partitions, vec![vec![(a.clone(), vec![0, 1, 2])], vec![(b.clone(), vec![0, 1])]]);
+        let partitions = partition_row_groups(&row_groups, 4);
+        assert_eq!(partitions[0], vec![(a.clone(), vec![0, 1])]);
+        assert_eq!(partitions[1], vec![(a.clone(), vec![2])]);
+        assert_eq!(partitions[3], vec![(b, vec![1])]);
+        assert_eq!(partition_row_groups(&row_groups, 16).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_parquet_aggregates_are_answered_from_the_footer() -> anyhow::Result<()> {
+        use crate::formats::{ParquetConfig, ParquetFormat};
+        use crate::storage::memory::MemoryStorage;
+        use crate::storage::stats::{Operation, StorageStatsCollector};
+        use arrow::datatypes::{DataType, Field, Schema};
+        use arrow::record_batch::RecordBatch;
+        use futures::StreamExt;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+                Arc::new(arrow::array::StringArray::from(vec!["a", "b", "c"])),
+            ],
+        )?;
+        // Many small row groups, so decoding any data shows up as many reads
+        let format = ParquetFormat::new(ParquetConfig {
+            max_row_group_size: Some(3),
+            ..ParquetConfig::default()
+        });
+        let data = format
+            .write_batches(futures::stream::iter(0..100).map(move |_| Ok(batch.clone())).boxed())
+            .await?;
+        let url = Url::parse("memory:///data.parquet")?;
+        let stats = StorageStatsCollector::default();
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new().with_stats(stats.clone()));
+        storage.write(&url, data).await?;
+        let reads = || stats.snapshot().get("memory", Operation::Get).requests;
+
+        let provider = FormatTableProvider::new(Arc::new(format), storage, vec![url], schema);
+        let ctx = SessionContext::new();
+        ctx.register_table("data", Arc::new(provider))?;
+
+        let batches = ctx.sql("SELECT COUNT(*), MIN(id), MAX(id) FROM data").await?.collect().await?;
+        let value = |column: usize| batches[0].column(column).as_any().downcast_ref::<Int64Array>().unwrap().value(0);
+        assert_eq!((value(0), value(1), value(2)), (300, 1, 3));
+        // Only the footer is read, once for the statistics and once to plan the
+        // partitions, each with at most one more read if the metadata is larger
+        assert!(reads() <= 4, "{} reads", reads());
+
+        // String columns have no bounds in the statistics, so the data is scanned
+        let before = reads();
+        let batches = ctx.sql("SELECT MAX(name) FROM data").await?.collect().await?;
+        let column = batches[0].column(0).as_any().downcast_ref::<arrow::array::StringArray>().unwrap();
+        assert_eq!(column.value(0), "c");
+        assert!(reads() > before + 4, "{} reads", reads() - before);
+
+        // So is a filtered count
+        let count = ctx.sql("SELECT COUNT(*) FROM data WHERE id = 2").await?.collect().await?;
+        assert_eq!(count[0].column(0).as_any().downcast_ref::<Int64Array>().unwrap().value(0), 100);
+        Ok(())
     }
 }
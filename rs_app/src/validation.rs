@@ -1,6 +1,10 @@
 use crate::config::*;
+use crate::formats::{
+    check_compression_supported, is_auto_delimiter, parse_column_type, parse_delimiter, parse_encoding, parse_timezone,
+    parse_writer_version,
+    supported_compressions, FieldSeparator,
+};
 use anyhow::{Result, anyhow};
-use std::path::Path;
 
 /// Validates the entire configuration
 pub fn validate_config(config: &Config) -> Result<()> {
@@ -24,25 +28,40 @@ fn validate_formats(config: &FormatConfig) -> Result<()> {
     if config.csv.max_sample_bytes == 0 {
         return Err(anyhow!("CSV max sample bytes cannot be zero"));
     }
+    if !is_auto_delimiter(&config.csv.delimiter) {
+        parse_delimiter(&config.csv.delimiter, config.csv.multi_char_delimiter)?;
+    }
+    if let Some(pattern) = &config.csv.delimiter_regex {
+        FieldSeparator::regex(pattern)?;
+    }
+    for (column, data_type) in &config.csv.column_types {
+        parse_column_type(data_type).map_err(|e| anyhow!("Invalid type for CSV column '{}': {}", column, e))?;
+    }
+    if let Some(timezone) = &config.csv.default_timezone {
+        parse_timezone(timezone)?;
+    }
 
     // Parquet validation
     if config.parquet.batch_size == 0 {
         return Err(anyhow!("Parquet batch size cannot be zero"));
     }
-    if config.parquet.row_group_size == 0 {
-        return Err(anyhow!("Parquet row group size cannot be zero"));
+    if config.parquet.schema_sample_size == 0 {
+        return Err(anyhow!("Parquet schema sample size cannot be zero"));
     }
-    if config.parquet.page_size == 0 {
-        return Err(anyhow!("Parquet page size cannot be zero"));
+
+    // The codec must be known and compiled into this build
+    check_compression_supported(&config.parquet.compression, &supported_compressions())
+        .map_err(|e| anyhow!("Invalid Parquet compression codec: {}", e))?;
+    parse_writer_version(config.parquet.writer_version.as_deref(), config.parquet.data_page_version.as_deref())?;
+    for (column, encoding) in &config.parquet.column_encodings {
+        parse_encoding(encoding).map_err(|e| anyhow!("Invalid encoding for Parquet column '{}': {}", column, e))?;
     }
-    if config.parquet.dictionary_page_size == 0 {
-        return Err(anyhow!("Parquet dictionary page size cannot be zero"));
+    let parquet = &config.parquet;
+    if let Some(column) =
+        parquet.dictionary_enabled_columns.iter().find(|column| parquet.dictionary_disabled_columns.contains(column))
+    {
+        return Err(anyhow!("Parquet column '{}' has dictionary encoding both enabled and disabled", column));
     }
-    
-    match config.parquet.compression.as_str() {
-        "snappy" | "gzip" | "brotli" | "lz4" | "zstd" | "none" => Ok(()),
-        _ => Err(anyhow!("Invalid Parquet compression codec: {}", config.parquet.compression)),
-    }?;
 
     // Default format validation
     if config.default.batch_size == 0 {
@@ -52,30 +71,33 @@ fn validate_formats(config: &FormatConfig) -> Result<()> {
         return Err(anyhow!("Default schema sample size cannot be zero"));
     }
 
+    // Adaptive batch size validation
+    let adaptive = &config.adaptive_batch_size;
+    if adaptive.target_batch_bytes == 0 {
+        return Err(anyhow!("Adaptive target batch bytes cannot be zero"));
+    }
+    if adaptive.min_batch_rows == 0 {
+        return Err(anyhow!("Adaptive min batch rows cannot be zero"));
+    }
+    if adaptive.max_batch_rows < adaptive.min_batch_rows {
+        return Err(anyhow!("Adaptive max batch rows cannot be less than min batch rows"));
+    }
+
     Ok(())
 }
 
 /// Validates plugin configuration
 fn validate_plugins(config: &PluginConfig) -> Result<()> {
-    if config.enable_plugins {
-        if let Some(dir) = &config.plugin_dir {
-            if !dir.exists() {
-                return Err(anyhow!("Plugin directory does not exist: {:?}", dir));
-            }
-            if !dir.is_dir() {
-                return Err(anyhow!("Plugin path is not a directory: {:?}", dir));
-            }
-        } else {
-            return Err(anyhow!("Plugin directory must be specified when plugins are enabled"));
-        }
+    if config.directory.is_file() {
+        return Err(anyhow!("Plugin path is not a directory: {:?}", config.directory));
     }
 
-    if config.load_timeout_secs == 0 {
-        return Err(anyhow!("Plugin load timeout cannot be zero"));
+    if config.default_timeout == 0 {
+        return Err(anyhow!("Plugin timeout cannot be zero"));
     }
 
-    if config.enable_hot_reload && config.hot_reload_interval_secs == 0 {
-        return Err(anyhow!("Hot reload interval cannot be zero when hot reload is enabled"));
+    if config.max_instances == 0 {
+        return Err(anyhow!("Max plugin instances cannot be zero"));
     }
 
     Ok(())
@@ -100,8 +122,8 @@ fn validate_storage(config: &StorageConfig) -> Result<()> {
     if config.retry.max_delay_ms < config.retry.initial_delay_ms {
         return Err(anyhow!("Max retry delay cannot be less than initial delay"));
     }
-    if config.retry.backoff_multiplier <= 1.0 {
-        return Err(anyhow!("Backoff multiplier must be greater than 1.0"));
+    if config.retry.backoff_multiplier.is_nan() || config.retry.backoff_multiplier < 1.0 {
+        return Err(anyhow!("Backoff multiplier cannot be less than 1.0"));
     }
 
     Ok(())
@@ -109,17 +131,14 @@ fn validate_storage(config: &StorageConfig) -> Result<()> {
 
 /// Validates streaming configuration
 fn validate_streaming(config: &StreamingConfig) -> Result<()> {
-    if config.max_buffer_memory == 0 {
-        return Err(anyhow!("Max buffer memory cannot be zero"));
-    }
-    if config.buffer_pool_size == 0 {
-        return Err(anyhow!("Buffer pool size cannot be zero"));
+    if config.buffer_size == 0 {
+        return Err(anyhow!("Stream buffer size cannot be zero"));
     }
-    if config.read_timeout_secs == 0 {
-        return Err(anyhow!("Read timeout cannot be zero"));
+    if config.max_concurrent_streams == 0 {
+        return Err(anyhow!("Max concurrent streams cannot be zero"));
     }
-    if config.write_timeout_secs == 0 {
-        return Err(anyhow!("Write timeout cannot be zero"));
+    if config.timeout == 0 {
+        return Err(anyhow!("Stream timeout cannot be zero"));
     }
     if config.max_in_flight_batches == 0 {
         return Err(anyhow!("Max in-flight batches cannot be zero"));
@@ -130,11 +149,11 @@ fn validate_streaming(config: &StreamingConfig) -> Result<()> {
 
 /// Validates processing configuration
 fn validate_processing(config: &ProcessingConfig) -> Result<()> {
-    if config.max_memory_bytes == 0 {
-        return Err(anyhow!("Max memory bytes cannot be zero"));
+    if config.memory_limit == 0 {
+        return Err(anyhow!("Memory limit cannot be zero"));
     }
-    if config.parallel_threads == 0 {
-        return Err(anyhow!("Parallel threads cannot be zero"));
+    if config.num_threads == 0 {
+        return Err(anyhow!("Number of threads cannot be zero"));
     }
 
     Ok(())
@@ -157,6 +176,62 @@ mod tests {
         assert!(validate_config(&config).is_err());
     }
 
+    #[test]
+    fn test_unknown_csv_column_type() {
+        let mut config = Config::default();
+        config.formats.csv.column_types.insert("zip".to_string(), "Utf8".to_string());
+        assert!(validate_config(&config).is_ok());
+        config.formats.csv.column_types.insert("amount".to_string(), "Money".to_string());
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("amount"), "{}", err);
+    }
+
+    #[test]
+    fn test_unknown_csv_timezone() {
+        let mut config = Config::default();
+        config.formats.csv.default_timezone = Some("America/New_York".to_string());
+        assert!(validate_config(&config).is_ok());
+        config.formats.csv.default_timezone = Some("Mars/Olympus".to_string());
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("Mars/Olympus"), "{}", err);
+    }
+
+    #[test]
+    fn test_csv_delimiter_spec() {
+        let mut config = Config::default();
+        config.formats.csv.delimiter = "\\t".to_string();
+        assert!(validate_config(&config).is_ok());
+        config.formats.csv.delimiter = "||".to_string();
+        assert!(validate_config(&config).is_err());
+        config.formats.csv.multi_char_delimiter = true;
+        assert!(validate_config(&config).is_ok());
+        config.formats.csv.delimiter = "auto".to_string();
+        config.formats.csv.multi_char_delimiter = false;
+        assert!(validate_config(&config).is_ok());
+    }
+
+    #[test]
+    fn test_parquet_writer_version() {
+        let mut config = Config::default();
+        config.formats.parquet.data_page_version = Some("v2".to_string());
+        assert!(validate_config(&config).is_ok());
+        config.formats.parquet.writer_version = Some("1.0".to_string());
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("requires writer version 2.0"), "{}", err);
+        config.formats.parquet.writer_version = Some("3.0".to_string());
+        assert!(validate_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_parquet_column_encodings() {
+        let mut config = Config::default();
+        config.formats.parquet.column_encodings.insert("id".to_string(), "DELTA_BINARY_PACKED".to_string());
+        assert!(validate_config(&config).is_ok());
+        config.formats.parquet.column_encodings.insert("name".to_string(), "DELTA".to_string());
+        let err = validate_config(&config).unwrap_err();
+        assert!(err.to_string().contains("'name'"), "{}", err);
+    }
+
     #[test]
     fn test_invalid_compression() {
         let mut config = Config::default();
@@ -164,11 +239,25 @@ mod tests {
         assert!(validate_config(&config).is_err());
     }
 
+    #[test]
+    fn test_uncompiled_compression_is_rejected() {
+        let mut config = Config::default();
+        for codec in ["snappy", "gzip", "brotli", "lz4", "zstd"] {
+            config.formats.parquet.compression = codec.to_string();
+            let result = validate_config(&config);
+            // Codecs that this build can't write fail validation rather than at write time
+            assert_eq!(result.is_ok(), supported_compressions().contains(&codec), "{}", codec);
+            if let Err(e) = result {
+                assert!(e.to_string().contains("feature"), "{}", e);
+            }
+        }
+    }
+
     #[test]
     fn test_invalid_plugin_config() {
         let mut config = Config::default();
-        config.plugins.enable_plugins = true;
-        assert!(validate_config(&config).is_err()); // Should fail without plugin dir
+        config.plugins.default_timeout = 0;
+        assert!(validate_config(&config).is_err());
     }
 
     #[test]
@@ -177,5 +266,12 @@ mod tests {
         config.storage.retry.max_delay_ms = 
             config.storage.retry.initial_delay_ms / 2;
         assert!(validate_config(&config).is_err());
+
+        // A multiplier of 1.0 is a constant backoff
+        let mut config = Config::default();
+        config.storage.retry.backoff_multiplier = 1.0;
+        assert!(validate_config(&config).is_ok());
+        config.storage.retry.backoff_multiplier = 0.5;
+        assert!(validate_config(&config).is_err());
     }
 }